@@ -8,6 +8,25 @@ import time
 SOCKET_PATH = os.environ.get("OLA_SOCKET", "/run/ola/ola.sock")
 DEFAULT_TIMEOUT = float(os.environ.get("OLA_CLIENT_TIMEOUT", "5.0"))
 
+# verify_once results this client understands; see "Verification Results" in core/README.md
+RESULT_SCHEMA_VERSION = 1
+
+# `code` of a failed verify_once. Treat any code not listed here as a failure too.
+REASON_CODES = (
+    "NO_FACE",
+    "MULTIPLE_FACES",
+    "LOW_QUALITY",
+    "NOT_ENROLLED",
+    "NEEDS_REENROLL",
+    "LIVENESS_FAILED",
+    "NO_MATCH",
+    "AMBIGUOUS_MATCH",
+    "CAMERA_BUSY",
+    "LOCKED_OUT",
+    "TIMEOUT",
+    "MODEL_UNAVAILABLE",
+)
+
 import itertools
 
 _id_gen = itertools.count(1)
@@ -16,7 +35,7 @@ class OlaClient:
     def __init__(self, socket_path=SOCKET_PATH):
         self.socket_path = socket_path
 
-    def _send(self, method, params=None):
+    def _send(self, method, params=None, timeout=DEFAULT_TIMEOUT, on_guidance=None):
         req = {
             "id": next(_id_gen),
             "method": method,
@@ -24,7 +43,7 @@ class OlaClient:
         }
         
         s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
-        s.settimeout(DEFAULT_TIMEOUT) # 2 second timeout
+        s.settimeout(timeout)
 
         try:
             s.connect(self.socket_path)
@@ -37,21 +56,18 @@ class OlaClient:
             msg = json.dumps(req) + "\n"
             s.sendall(msg.encode("utf-8"))
             
-            # Read response (line-based)
-            data = b""
+            # Read response (line-based); guidance notifications come first
+            f = s.makefile("rb")
             while True:
-                chunk = s.recv(4096)
-                if not chunk:
-                    break
-                data += chunk
-                if b"\n" in chunk:
-                    break
-            
-            if not data:
-                 return {"id": req["id"], "result": None, "error": "Empty response from server"}
-            
-            line = data.split(b"\n", 1)[0]
-            return json.loads(line.decode("utf-8"))
+                line = f.readline()
+                if not line:
+                    return {"id": req["id"], "result": None, "error": "Empty response from server"}
+                msg = json.loads(line.decode("utf-8"))
+                if msg.get("method") == "guidance" and "result" not in msg:
+                    if on_guidance:
+                        on_guidance(msg["params"])
+                    continue
+                return msg
             
         except socket.timeout:
              return {"id": req["id"], "result": None, "error": "Request timed out"}
@@ -66,15 +82,138 @@ class OlaClient:
     def list_cameras(self):
         return self._send("list_cameras")
 
-    def capture_thumbnail(self, index=0):
-        return self._send("capture_thumbnail", {"index": index})
+    def capture_thumbnail(self, camera=None, fmt="png", max_width=320, max_height=240, quality=85, wait_ms=0):
+        """`camera` is a stable ID from list_cameras; omit it for the default camera.
+        `wait_ms` queues for a camera another application is using instead of
+        failing with camera_busy straight away."""
+        params = {
+            "format": fmt,
+            "max_width": max_width,
+            "max_height": max_height,
+            "quality": quality,
+            "wait_ms": wait_ms,
+        }
+        if camera is not None:
+            params["camera"] = camera
+        return self._send("capture_thumbnail", params, DEFAULT_TIMEOUT + wait_ms / 1000)
+
+    def verify_once(self, camera=None, wait_ms=0, challenge=None, timeout_ms=None, on_guidance=None):
+        """`challenge` ("turn_left" or "turn_right") asks the user to turn their
+        head during capture; tell them which way before calling. `timeout_ms`
+        bounds how long the daemon keeps looking (it clamps it to 0.5-10s).
+        `on_guidance` is called with each quality issue, e.g. "hold still"."""
+        params = {"wait_ms": wait_ms}
+        if camera is not None:
+            params["camera"] = camera
+        if challenge is not None:
+            params["challenge"] = challenge
+        if timeout_ms is not None:
+            params["timeout_ms"] = timeout_ms
+        if on_guidance is not None:
+            params["guidance"] = True
+        return self._send("verify_once", params, DEFAULT_TIMEOUT + (wait_ms + 10000) / 1000, on_guidance)
 
-    def verify_once(self):
-        return self._send("verify_once")
+    def identify(self, camera=None, wait_ms=0, uids=None, challenge=None, timeout_ms=None, on_guidance=None):
+        """Who is in front of the camera. Only root and the daemon's user may
+        pass `uids` naming others; everyone else is matched against themselves."""
+        params = {"wait_ms": wait_ms}
+        if camera is not None:
+            params["camera"] = camera
+        if uids is not None:
+            params["uids"] = uids
+        if challenge is not None:
+            params["challenge"] = challenge
+        if timeout_ms is not None:
+            params["timeout_ms"] = timeout_ms
+        if on_guidance is not None:
+            params["guidance"] = True
+        return self._send("identify", params, DEFAULT_TIMEOUT + (wait_ms + 10000) / 1000, on_guidance)
 
     def status(self):
         return self._send("status")
 
+    def list_templates(self, uid=None):
+        """Templates of the calling user; root may pass another `uid`."""
+        return self._send("list_templates", {"uid": uid} if uid is not None else {})
+
+    def rename_template(self, template_id, label, uid=None):
+        params = {"id": template_id, "label": label}
+        if uid is not None:
+            params["uid"] = uid
+        return self._send("rename_template", params)
+
+    def delete_template(self, template_id, uid=None):
+        params = {"id": template_id}
+        if uid is not None:
+            params["uid"] = uid
+        return self._send("delete_template", params)
+
+    def list_stale_users(self):
+        """Root only: users with templates from another recognition model."""
+        return self._send("list_stale_users", timeout=DEFAULT_TIMEOUT + 60)
+
+    def migrate_templates(self, uid=None):
+        """Root only: re-embeds stale templates that kept their face crop."""
+        return self._send("migrate_templates", {"uid": uid} if uid is not None else {}, DEFAULT_TIMEOUT + 60)
+
+    def reload_models(self):
+        """Root only: verifies the models the manifest lists and swaps them in together."""
+        return self._send("reload_models", timeout=DEFAULT_TIMEOUT + 60)
+
+    def enroll(self, camera=None, max_frames=60, on_progress=None, label=None, retain_crops=False):
+        """Enrolls the calling user's face. Enrollment state lives on one
+        connection, so everything runs over a single socket. `on_progress` is
+        called with each enroll_frame result, e.g. to show its `guidance`.
+        Returns the enroll_commit response; stopping early stores nothing.
+        `retain_crops` keeps the face crops so templates survive a model upgrade."""
+        s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        s.settimeout(DEFAULT_TIMEOUT + 15)
+        s.connect(self.socket_path)
+        f = s.makefile("rwb")
+
+        def call(method, params=None):
+            f.write((json.dumps({"id": next(_id_gen), "method": method, "params": params or {}}) + "\n").encode("utf-8"))
+            f.flush()
+            return json.loads(f.readline().decode("utf-8"))
+
+        try:
+            params = {"camera": camera} if camera is not None else {}
+            if label is not None:
+                params["label"] = label
+            if retain_crops:
+                params["retain_crops"] = True
+            resp = call("enroll_begin", params)
+            if resp.get("error"):
+                return resp
+            for _ in range(max_frames):
+                resp = call("enroll_frame")
+                if resp.get("error"):
+                    return resp
+                if on_progress:
+                    on_progress(resp["result"])
+                if resp["result"]["complete"]:
+                    return call("enroll_commit")
+            call("enroll_cancel")
+            return {"id": None, "result": None, "error": "Enrollment did not complete"}
+        finally:
+            f.close()
+            s.close()
+
+    def watch_cameras(self):
+        """Yields camera_added / camera_removed notifications until interrupted."""
+        s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        s.connect(self.socket_path)
+        f = s.makefile("rwb")
+        try:
+            f.write((json.dumps({"id": next(_id_gen), "method": "subscribe"}) + "\n").encode("utf-8"))
+            f.flush()
+            f.readline()  # subscribe acknowledgement
+            for line in f:
+                yield json.loads(line.decode("utf-8"))
+        finally:
+            f.close()
+            s.close()
+
 def main():
     client = OlaClient()
     if len(sys.argv) > 1:
@@ -84,16 +223,42 @@ def main():
         elif cmd == "list_cameras":
             print(client.list_cameras())
         elif cmd == "capture_thumbnail":
-            idx = int(sys.argv[2]) if len(sys.argv) > 2 else 0
-            print(client.capture_thumbnail(idx))
+            camera = sys.argv[2] if len(sys.argv) > 2 else None
+            print(client.capture_thumbnail(camera))
         elif cmd == "verify_once":
-            print(client.verify_once())
+            camera = sys.argv[2] if len(sys.argv) > 2 else None
+            challenge = sys.argv[3] if len(sys.argv) > 3 else None
+            if challenge:
+                print(f"Look at the camera, then slowly turn your head {challenge.removeprefix('turn_')}")
+            print(client.verify_once(camera, challenge=challenge))
+        elif cmd == "identify":
+            camera = sys.argv[2] if len(sys.argv) > 2 else None
+            print(client.identify(camera))
         elif cmd == "status":
             print(client.status())
+        elif cmd == "enroll":
+            camera = sys.argv[2] if len(sys.argv) > 2 else None
+            print(client.enroll(camera, on_progress=lambda p: print(p["guidance"] or "done", p)))
+        elif cmd == "templates":
+            print(client.list_templates())
+        elif cmd == "rename_template" and len(sys.argv) > 3:
+            print(client.rename_template(sys.argv[2], sys.argv[3]))
+        elif cmd == "delete_template" and len(sys.argv) > 2:
+            print(client.delete_template(sys.argv[2]))
+        elif cmd == "stale_users":
+            print(client.list_stale_users())
+        elif cmd == "migrate_templates":
+            uid = int(sys.argv[2]) if len(sys.argv) > 2 else None
+            print(client.migrate_templates(uid))
+        elif cmd == "reload_models":
+            print(client.reload_models())
+        elif cmd == "watch":
+            for note in client.watch_cameras():
+                print(note)
         else:
             print(f"Unknown command: {cmd}")
     else:
-        print("Usage: python3 ola_client.py [ping|list_cameras|capture_thumbnail|verify_once|status]")
+        print("Usage: python3 ola_client.py [ping|list_cameras|capture_thumbnail|verify_once|identify|status|enroll|templates|rename_template|delete_template|stale_users|migrate_templates|reload_models|watch]")
 
 if __name__ == "__main__":
     main()
@@ -0,0 +1,68 @@
+// benches/capture.rs
+//! Time-to-first-frame and per-frame latency through the capture path.
+//!
+//! Run with `cargo bench --bench capture`. Always measures the synthetic
+//! source; set `OLA_BENCH_DEVICE=/dev/videoN` to also compare MMAP streaming
+//! against read() on real hardware.
+use ola_core::capture_stats::{CaptureStats, TimedSource};
+use ola_core::frame::PixelFormat;
+use ola_core::frame_source::{FrameSource, SourceConfig, SyntheticSource, V4l2Io};
+use std::hint::black_box;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 480;
+const OPENS: u32 = 5;
+const FRAMES: u32 = 120;
+
+fn bench(label: &str, open: impl Fn() -> anyhow::Result<Box<dyn FrameSource>>) {
+    let stats = Arc::new(Mutex::new(CaptureStats::default()));
+    let mut first_frame_ms = Vec::new();
+    for round in 0..OPENS {
+        let started = Instant::now();
+        let source = match open() {
+            Ok(s) => s,
+            Err(e) => {
+                println!("{:<22} unavailable: {:#}", label, e);
+                return;
+            }
+        };
+        let mut timed = TimedSource::new(source, started, stats.clone());
+        // Only the first round streams for long; the rest measure reopen cost
+        let frames = if round == 0 { FRAMES } else { 1 };
+        for _ in 0..frames {
+            black_box(timed.next_frame().unwrap().data.len());
+        }
+        first_frame_ms.push(stats.lock().unwrap().report().time_to_first_frame_ms.unwrap_or(0.0));
+    }
+
+    let report = stats.lock().unwrap().report();
+    let latency = report.frame_latency_ms.expect("frames were captured");
+    let mean_first = first_frame_ms.iter().sum::<f64>() / first_frame_ms.len() as f64;
+    println!(
+        "{:<22} first frame {:>8.2} ms  latency mean {:>6.3} ms  max {:>6.3} ms  ({})",
+        label,
+        mean_first,
+        latency.mean_ms,
+        latency.max_ms,
+        report.source.unwrap_or("?")
+    );
+}
+
+fn main() {
+    println!("capture: {}x{}, {} opens, {} frames", WIDTH, HEIGHT, OPENS, FRAMES);
+    for format in [PixelFormat::Yuyv, PixelFormat::Grey] {
+        bench(&format!("synthetic {:?}", format), || {
+            Ok(Box::new(SyntheticSource::new(WIDTH, HEIGHT, format)?) as Box<dyn FrameSource>)
+        });
+    }
+
+    if let Ok(device) = std::env::var("OLA_BENCH_DEVICE") {
+        for (label, io) in [("v4l2 mmap", V4l2Io::Mmap), ("v4l2 read", V4l2Io::Read)] {
+            let config = SourceConfig { width: WIDTH, height: HEIGHT, v4l2_io: io, ..Default::default() };
+            bench(label, || config.open(Path::new(&device)));
+        }
+    }
+}
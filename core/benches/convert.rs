@@ -0,0 +1,97 @@
+// benches/convert.rs
+//! Per-frame conversion cost at the default capture size.
+//!
+//! Run with `cargo bench --bench convert`. Uses std timing only so it works
+//! offline and on stable.
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, ImageEncoder};
+use ola_core::convert::{to_luma8, to_rgb8};
+use ola_core::frame::{Frame, PixelFormat};
+use ola_core::frame_source::{FrameSource, SyntheticSource};
+use std::borrow::Cow;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 480;
+const ITERATIONS: u32 = 200;
+
+fn frame_for(format: PixelFormat) -> Frame<'static> {
+    let mut yuyv = SyntheticSource::new(WIDTH, HEIGHT, PixelFormat::Yuyv).unwrap();
+    let base = yuyv.next_frame().unwrap().into_owned();
+    let (w, h) = (WIDTH as usize, HEIGHT as usize);
+
+    let data: Vec<u8> = match format {
+        PixelFormat::Yuyv => base.data.to_vec(),
+        PixelFormat::Uyvy => base.data.chunks(2).flat_map(|p| [p[1], p[0]]).collect(),
+        PixelFormat::Nv12 => {
+            let mut d: Vec<u8> = base.data.iter().step_by(2).copied().collect();
+            for y in (0..h).step_by(2) {
+                let row = &base.data[y * w * 2..][..w * 2];
+                for m in row.chunks(4) {
+                    d.extend([m[1], m[3]]);
+                }
+            }
+            d
+        }
+        PixelFormat::Grey => base.data.iter().step_by(2).copied().collect(),
+        PixelFormat::Y16 => base.data.iter().step_by(2).flat_map(|&l| [0, l]).collect(),
+        PixelFormat::Mjpeg => {
+            let rgb = to_rgb8(&base).unwrap();
+            let mut d = Vec::new();
+            JpegEncoder::new_with_quality(&mut d, 85)
+                .write_image(rgb.as_raw(), WIDTH, HEIGHT, ColorType::Rgb8)
+                .unwrap();
+            d
+        }
+        _ => unreachable!(),
+    };
+
+    Frame {
+        format,
+        width: WIDTH,
+        height: HEIGHT,
+        stride: format.min_stride(WIDTH).unwrap_or(0),
+        sequence: 1,
+        timestamp: Duration::ZERO,
+        data: Cow::Owned(data),
+    }
+}
+
+fn bench(label: &str, mut f: impl FnMut()) {
+    // Warm caches and the allocator before timing
+    for _ in 0..10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_frame = start.elapsed() / ITERATIONS;
+    println!(
+        "{:<14} {:>9.3} ms/frame {:>8.1} fps",
+        label,
+        per_frame.as_secs_f64() * 1000.0,
+        1.0 / per_frame.as_secs_f64()
+    );
+}
+
+fn main() {
+    println!("convert: {}x{}, {} iterations", WIDTH, HEIGHT, ITERATIONS);
+    for format in [
+        PixelFormat::Yuyv,
+        PixelFormat::Uyvy,
+        PixelFormat::Nv12,
+        PixelFormat::Grey,
+        PixelFormat::Y16,
+        PixelFormat::Mjpeg,
+    ] {
+        let frame = frame_for(format);
+        bench(&format!("{:?} rgb8", format), || {
+            black_box(to_rgb8(black_box(&frame)).unwrap());
+        });
+        bench(&format!("{:?} luma8", format), || {
+            black_box(to_luma8(black_box(&frame)).unwrap());
+        });
+    }
+}
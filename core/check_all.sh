@@ -16,6 +16,7 @@ cargo build
 echo ">>> Running Integration Tests (Dev Mode)..."
 export OLA_RUNMODE=dev
 export OLA_SOCKET_PATH=/tmp/ola.sock
+export OLA_CAMERA_BACKEND=${OLA_CAMERA_BACKEND:-synthetic}
 export RUST_LOG=info
 
 # Start server in background
@@ -0,0 +1,327 @@
+// src/adapt.rs
+//! Adaptive template updates, so enrollments keep up with a changing face.
+//!
+//! Off unless `/etc/ola/adapt.conf` (or `OLA_ADAPT_CONFIG`) turns it on:
+//!
+//! ```text
+//! enable
+//! # how far past the user's accept threshold, in the metric's units
+//! margin 0.15
+//! # updates per user in any 24 hours
+//! daily 2
+//! # adaptive templates kept per user; the oldest makes way
+//! keep 3
+//! ```
+//!
+//! After a successful `verify_once`, the embedding of its best frame becomes
+//! an adaptive template if it scores `margin` past the user's threshold
+//! against their enrolled templates. Scoring against what was enrolled, never
+//! against earlier adaptive templates, keeps a run of updates from walking
+//! the templates towards someone else's face. Enrolled templates are never
+//! replaced or removed here.
+//!
+//! Every update is appended to the audit log (`/var/lib/ola/audit.log`, or
+//! `OLA_AUDIT_LOG`) as one JSON line before the templates change, and the log
+//! is what the daily cap counts, so a restart does not reset it.
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::matcher::{self, Metric};
+use super::templates::{Origin, Template};
+
+pub const DEFAULT_CONFIG: &str = "/etc/ola/adapt.conf";
+
+pub const DEFAULT_AUDIT_LOG: &str = "/var/lib/ola/audit.log";
+
+/// Period the daily cap applies to.
+pub const CAP_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Label of adaptive templates.
+const LABEL: &str = "adaptive";
+
+/// `event` of an adaptive update in the audit log.
+const EVENT: &str = "adaptive_update";
+
+pub fn config_path() -> PathBuf {
+    std::env::var_os("OLA_ADAPT_CONFIG").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG))
+}
+
+pub fn audit_path() -> PathBuf {
+    std::env::var_os("OLA_AUDIT_LOG").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_AUDIT_LOG))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptConfig {
+    pub enabled: bool,
+    /// Added to the accept threshold (taken off, for L2) to get the update threshold.
+    pub margin: f32,
+    /// Updates per user per `CAP_PERIOD`.
+    pub daily: usize,
+    /// Adaptive templates kept per user.
+    pub keep: usize,
+}
+
+impl Default for AdaptConfig {
+    fn default() -> Self {
+        Self { enabled: false, margin: 0.15, daily: 2, keep: 3 }
+    }
+}
+
+impl AdaptConfig {
+    /// Reads `path`; a missing file means adaptation stays off.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let count = |s: &str| -> anyhow::Result<usize> {
+                match s.parse::<usize>() {
+                    Ok(c) if c > 0 => Ok(c),
+                    _ => bail!("line {}: expected a positive count, got '{}'", n + 1, s),
+                }
+            };
+            match words[..] {
+                [] => {}
+                ["enable"] => config.enabled = true,
+                // Zero would let updates in at the accept threshold itself
+                ["margin", m] => {
+                    config.margin = match m.parse::<f32>() {
+                        Ok(m) if m.is_finite() && m > 0.0 => m,
+                        _ => bail!("line {}: margin must be a positive number", n + 1),
+                    }
+                }
+                ["daily", c] => config.daily = count(c)?,
+                ["keep", c] => config.keep = count(c)?,
+                _ => bail!("line {}: expected 'enable', 'margin', 'daily' or 'keep': {}", n + 1, line),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The score an update needs, given the user's accept threshold.
+    pub fn required(&self, metric: Metric, threshold: f32) -> f32 {
+        match metric {
+            Metric::Cosine => threshold + self.margin,
+            Metric::L2 => (threshold - self.margin).max(0.0),
+        }
+    }
+
+    /// What a successful verification of a user with `templates` may store,
+    /// given `recent` updates in the last `CAP_PERIOD`; or why nothing.
+    pub fn plan(&self, metric: Metric, threshold: f32, templates: &[Template], probe: Probe, recent: usize) -> Result<Update, &'static str> {
+        if !self.enabled {
+            return Err("disabled");
+        }
+        if recent >= self.daily {
+            return Err("daily_cap");
+        }
+        let enrolled = templates.iter().filter(|t| t.origin == Origin::Enrolled && t.compatible_with(probe.model_id));
+        let Some(best) = matcher::best_match(metric, probe.embedding, enrolled) else {
+            return Err("no_enrolled_template");
+        };
+        let required = self.required(metric, threshold);
+        if !metric.accepts(best.score, required) {
+            return Err("below_update_threshold");
+        }
+        let mut adaptive: Vec<&Template> = templates.iter().filter(|t| t.origin == Origin::Adaptive).collect();
+        adaptive.sort_by_key(|t| t.created_at_ms);
+        let replaces = (adaptive.len() >= self.keep).then(|| adaptive[0].id.clone());
+
+        let mut template = Template::new(LABEL, probe.model, probe.model_id, probe.camera, probe.embedding.to_vec());
+        template.origin = Origin::Adaptive;
+        Ok(Update { template, replaces, matched: best.template_id, score: best.score, required })
+    }
+}
+
+/// The best frame of a successful verification.
+#[derive(Debug, Clone)]
+pub struct Probe<'a> {
+    pub embedding: &'a [f32],
+    pub model: &'a str,
+    pub model_id: &'a str,
+    pub camera: Option<String>,
+}
+
+/// A new adaptive template, and the one it takes the place of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Update {
+    pub template: Template,
+    pub replaces: Option<String>,
+    /// The enrolled template the probe scored best against, and how well.
+    pub matched: String,
+    pub score: f32,
+    pub required: f32,
+}
+
+impl Update {
+    pub fn audit_entry(&self, uid: u32) -> AuditEntry {
+        AuditEntry {
+            at_ms: self.template.created_at_ms,
+            event: EVENT.to_string(),
+            uid,
+            template_id: self.template.id.clone(),
+            replaced: self.replaces.clone(),
+            matched: self.matched.clone(),
+            score: self.score,
+            required: self.required,
+            model_id: self.template.model_id.clone(),
+            camera: self.template.camera.clone(),
+        }
+    }
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at_ms: u64,
+    pub event: String,
+    pub uid: u32,
+    /// The template added, and the one removed to make room.
+    pub template_id: String,
+    pub replaced: Option<String>,
+    pub matched: String,
+    pub score: f32,
+    pub required: f32,
+    pub model_id: String,
+    pub camera: Option<String>,
+}
+
+/// Append-only JSON lines, readable by root only.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf() }
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)
+            .with_context(|| format!("opening {}", self.path.display()))?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line).with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    /// Adaptive updates of `uid` at or after `since_ms`. No log means none;
+    /// lines that do not parse are skipped.
+    pub fn updates_since(&self, uid: u32, since_ms: u64) -> anyhow::Result<usize> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.path.display())),
+        };
+        Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+            .filter(|e| e.event == EVENT && e.uid == uid && e.at_ms >= since_ms)
+            .count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(embedding: &[f32]) -> Probe<'_> {
+        Probe { embedding, model: "arcface", model_id: "arcface-1", camera: Some("usb-cam".into()) }
+    }
+
+    fn enrolled(id: &str, embedding: Vec<f32>) -> Template {
+        Template { id: id.into(), ..Template::new("face", "arcface", "arcface-1", None, embedding) }
+    }
+
+    #[test]
+    fn config_is_off_by_default_and_needs_a_real_margin() {
+        assert!(!AdaptConfig::default().enabled);
+        let config = AdaptConfig::parse("enable\nmargin 0.2 # stricter\ndaily 1\nkeep 5\n").unwrap();
+        assert_eq!(config, AdaptConfig { enabled: true, margin: 0.2, daily: 1, keep: 5 });
+        assert!((config.required(Metric::Cosine, 0.45) - 0.65).abs() < 1e-6);
+        assert!((config.required(Metric::L2, 1.05) - 0.85).abs() < 1e-6);
+        for bad in ["margin 0", "margin -0.1", "daily 0", "keep many", "adapt on"] {
+            assert!(AdaptConfig::parse(bad).is_err(), "{}", bad);
+        }
+        let missing = std::env::temp_dir().join(format!("ola-no-adapt-{}.conf", std::process::id()));
+        assert_eq!(AdaptConfig::load(&missing).unwrap(), AdaptConfig::default());
+    }
+
+    #[test]
+    fn updates_need_a_strong_match_against_the_enrollment() {
+        let config = AdaptConfig { enabled: true, ..Default::default() };
+        let plan = |templates: &[Template], embedding: &[f32], recent| config.plan(Metric::Cosine, 0.45, templates, probe(embedding), recent);
+        let original = enrolled("e1", vec![1.0, 0.0]);
+        let only = std::slice::from_ref(&original);
+
+        // cos = 0.8 clears 0.45 + 0.15; cos = 0.5 only passes verification
+        let update = plan(only, &[0.8, 0.6], 0).unwrap();
+        assert_eq!((update.matched.as_str(), update.replaces), ("e1", None));
+        assert_eq!((update.template.origin, update.template.label.as_str()), (Origin::Adaptive, LABEL));
+        assert!(update.template.compatible_with("arcface-1"));
+        assert_eq!(plan(only, &[0.5, 0.866], 0), Err("below_update_threshold"));
+        assert_eq!(plan(only, &[0.8, 0.6], config.daily), Err("daily_cap"));
+        assert_eq!(AdaptConfig::default().plan(Metric::Cosine, 0.45, only, probe(&[1.0, 0.0]), 0), Err("disabled"));
+
+        // An adaptive template close to the probe does not vouch for it
+        let mut drifted = enrolled("a1", vec![0.5, 0.866]);
+        drifted.origin = Origin::Adaptive;
+        assert_eq!(plan(&[original.clone(), drifted.clone()], &[0.5, 0.866], 0), Err("below_update_threshold"));
+        assert_eq!(plan(&[drifted.clone()], &[0.5, 0.866], 0), Err("no_enrolled_template"));
+
+        // Once `keep` adaptive templates exist the oldest one makes way, never an enrolled one
+        let mut pool = vec![original];
+        for (i, created) in [(0, 30), (1, 10), (2, 20)] {
+            let mut t = enrolled(&format!("a{}", i), vec![0.9, 0.436]);
+            (t.origin, t.created_at_ms) = (Origin::Adaptive, created);
+            pool.push(t);
+        }
+        assert_eq!(plan(&pool, &[0.8, 0.6], 1).unwrap().replaces.as_deref(), Some("a1"));
+    }
+
+    #[test]
+    fn audit_log_counts_recent_updates_per_user() {
+        let dir = std::env::temp_dir().join(format!("ola-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let log = AuditLog::new(&dir.join("audit.log"));
+        assert_eq!(log.updates_since(1000, 0).unwrap(), 0);
+
+        let config = AdaptConfig { enabled: true, ..Default::default() };
+        let update = config.plan(Metric::Cosine, 0.45, &[enrolled("e1", vec![1.0, 0.0])], probe(&[0.8, 0.6]), 0).unwrap();
+        let entry = update.audit_entry(1000);
+        log.append(&entry).unwrap();
+        log.append(&AuditEntry { at_ms: 5, ..entry.clone() }).unwrap();
+        log.append(&AuditEntry { uid: 1001, ..entry.clone() }).unwrap();
+        fs::OpenOptions::new().append(true).open(dir.join("audit.log")).unwrap().write_all(b"garbage\n").unwrap();
+
+        assert_eq!(log.updates_since(1000, 0).unwrap(), 2);
+        assert_eq!(log.updates_since(1000, entry.at_ms).unwrap(), 1);
+        assert_eq!(log.updates_since(1001, 0).unwrap(), 1);
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(dir.join("audit.log")).unwrap().permissions().mode() & 0o777, 0o600);
+        let line = fs::read_to_string(dir.join("audit.log")).unwrap();
+        let first: AuditEntry = serde_json::from_str(line.lines().next().unwrap()).unwrap();
+        assert_eq!((first.template_id, first.matched.as_str()), (update.template.id, "e1"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,97 @@
+// src/bin/ola-eval.rs
+//! Measures recognition accuracy offline, with the models, match config and
+//! frame quality settings the daemon would use.
+//!
+//! ```text
+//! ola-eval <dataset> [--metric cosine|l2] [--target-far 0.001]... [--max-pairs N]
+//! ```
+//!
+//! `<dataset>` holds one directory of images per person (see `eval`). The
+//! report is printed to stdout as JSON; progress goes to the log.
+use anyhow::{bail, Context};
+use ola_core::detector::{Detector, OnnxDetector};
+use ola_core::embedder::{Embedder, OnnxEmbedder};
+use ola_core::eval::{self, Dataset, Report};
+use ola_core::exposure::{self, ExposureConfig};
+use ola_core::matcher::{self, MatchConfig, Metric};
+use ola_core::models::{ModelRegistry, Role};
+use ola_core::quality::{self, QualityConfig};
+use std::path::PathBuf;
+
+const USAGE: &str = "usage: ola-eval <dataset> [--metric cosine|l2] [--target-far RATE]... [--max-pairs N]";
+
+struct Args {
+    dataset: PathBuf,
+    metric: Option<Metric>,
+    target_fars: Vec<f64>,
+    max_pairs: usize,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut dataset = None;
+    let mut parsed = Args { dataset: PathBuf::new(), metric: None, target_fars: Vec::new(), max_pairs: eval::DEFAULT_MAX_PAIRS };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().with_context(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--metric" => parsed.metric = Some(Metric::parse(&value()?)?),
+            "--target-far" => {
+                let rate: f64 = value()?.parse().context("invalid --target-far")?;
+                if !(0.0..=1.0).contains(&rate) {
+                    bail!("--target-far must be between 0 and 1");
+                }
+                parsed.target_fars.push(rate);
+            }
+            "--max-pairs" => parsed.max_pairs = value()?.parse().context("invalid --max-pairs")?,
+            "-h" | "--help" => bail!("{}", USAGE),
+            _ if arg.starts_with('-') => bail!("unknown option {}\n{}", arg, USAGE),
+            _ if dataset.is_none() => dataset = Some(PathBuf::from(&arg)),
+            _ => bail!("unexpected argument {}\n{}", arg, USAGE),
+        }
+    }
+    parsed.dataset = dataset.with_context(|| USAGE)?;
+    if parsed.target_fars.is_empty() {
+        parsed.target_fars = eval::DEFAULT_TARGET_FARS.to_vec();
+    }
+    Ok(parsed)
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = parse_args(std::env::args().skip(1))?;
+
+    // The same models and thresholds the daemon reads
+    let mut config = MatchConfig::load(&matcher::config_path()).context("match configuration")?;
+    if let Some(metric) = args.metric {
+        // Another metric's configured threshold would mean nothing
+        config = MatchConfig { metric, ..Default::default() };
+    }
+    // Checked against the model manifest, as the daemon checks them
+    let models = ModelRegistry::from_env();
+    let model = |role: Role| models.read(role)?.with_context(|| format!("no {} listed in the model manifest", role.name()));
+    let mut detector = OnnxDetector::from_registry(&model(Role::Detector)?)?;
+    let mut embedder = OnnxEmbedder::from_registry(&model(Role::Embedder)?)?;
+    // Faces are enhanced and gated as the daemon's frames are
+    let quality = QualityConfig::load(&quality::config_path()).context("quality configuration")?;
+    let exposure = ExposureConfig::load(&exposure::config_path()).context("exposure configuration")?;
+
+    let dataset = Dataset::load(&args.dataset)?;
+    log::info!("{} images of {} people", dataset.images(), dataset.identities.len());
+    let (samples, failures) = eval::embed_dataset(&dataset, &mut detector, &mut embedder, &quality, &exposure)?;
+    if !failures.is_empty() {
+        log::warn!("{} images had no usable face and were left out", failures.len());
+    }
+    let scores = eval::score_pairs(config.metric, &samples, args.max_pairs);
+    let report = Report {
+        metric: config.metric,
+        detector: detector.name().to_string(),
+        embedder: embedder.name().to_string(),
+        identities: dataset.identities.len(),
+        images: dataset.images(),
+        failures,
+        genuine_pairs: scores.genuine.len(),
+        impostor_pairs: scores.impostor.len(),
+        rates: eval::evaluate(config.metric, scores, config.threshold.unwrap_or_else(|| config.metric.default_threshold()), &args.target_fars),
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -1,69 +1,1709 @@
+use base64::Engine;
 use glob::glob;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{imageops, ColorType, GrayImage, ImageEncoder, RgbImage};
+use log::warn;
 use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::camera_id::IdResolver;
+use super::camera_lock::{CameraBusy, Holder};
+use super::camera_pair::{self, CameraPair};
+use super::convert;
+use super::detector::{BoundingBox, Detection, Detector};
+use super::embedder::Embedder;
+use super::exposure::{self, Exposure, ExposureReport};
+use super::frame_source::FrameSource;
+use super::identify::{Candidate, Ranking, TemplateIndex};
+use super::ir::{self, SensorKind};
+use super::liveness::{Challenge, LiveFrame, Liveness, LivenessResult};
+use super::matcher::{self, MatchConfig, Metric};
+use super::quality::{Issue, QualityConfig};
+use super::templates::Template;
+use super::v4l2;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CameraCapabilities {
+    /// Node delivers image frames (single- or multi-planar).
+    pub video_capture: bool,
+    /// Node only delivers metadata (UVC metadata nodes, not usable for capture).
+    pub metadata_capture: bool,
+    pub streaming: bool,
+    pub read_write: bool,
+}
+
+impl CameraCapabilities {
+    pub fn from_bits(caps: u32) -> Self {
+        Self {
+            video_capture: caps & (v4l2::V4L2_CAP_VIDEO_CAPTURE | v4l2::V4L2_CAP_VIDEO_CAPTURE_MPLANE) != 0,
+            metadata_capture: caps & v4l2::V4L2_CAP_META_CAPTURE != 0,
+            streaming: caps & v4l2::V4L2_CAP_STREAMING != 0,
+            read_write: caps & v4l2::V4L2_CAP_READWRITE != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameSize {
+    pub width: u32,
+    pub height: u32,
+    /// Supported frame rates in frames per second.
+    pub frame_rates: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FormatInfo {
+    pub fourcc: String,
+    pub description: String,
+    pub sizes: Vec<FrameSize>,
+}
+
+/// Everything a `DeviceProbe` learns about a single node.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceDescription {
+    pub driver: String,
+    pub card: String,
+    pub bus_info: String,
+    pub capabilities: CameraCapabilities,
+    pub formats: Vec<FormatInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CameraInfo {
+    /// Stable identifier that survives reboots and replugs (see `camera_id`).
+    pub id: String,
     pub path: String,
     pub name: String,
     pub index: usize,
+    pub driver: String,
+    pub card: String,
+    pub bus_info: String,
+    /// USB `vid:pid`, when the node sits on a USB device.
+    pub usb_id: Option<String>,
+    pub capabilities: CameraCapabilities,
+    pub formats: Vec<FormatInfo>,
+    /// RGB or IR for capture nodes, `None` for metadata-only nodes.
+    pub sensor: Option<SensorKind>,
+    /// The UVC metadata node paired with this capture node.
+    pub metadata_node: Option<String>,
+    /// ID of the other sensor of an RGB+IR module (see `camera_pair`).
+    pub paired_with: Option<String>,
+}
+
+/// Source of device nodes and their descriptions.
+///
+/// The real implementation talks to the kernel; tests substitute a fake table so
+/// enumeration can be exercised on machines without cameras.
+pub trait DeviceProbe {
+    fn nodes(&self) -> Vec<PathBuf>;
+    fn probe(&self, path: &Path) -> anyhow::Result<DeviceDescription>;
+
+    fn stable_id(&self, _path: &Path) -> Option<String> {
+        None
+    }
+
+    fn usb_id(&self, _path: &Path) -> Option<String> {
+        None
+    }
+}
+
+/// Probes `<dev_dir>/video*` through VIDIOC_QUERYCAP / VIDIOC_ENUM_FMT.
+pub struct V4l2Probe {
+    dev_dir: PathBuf,
+    ids: IdResolver,
+}
+
+impl V4l2Probe {
+    pub fn new(dev_dir: &Path) -> Self {
+        Self { dev_dir: dev_dir.to_path_buf(), ids: IdResolver::new(dev_dir, Path::new("/sys")) }
+    }
+}
+
+impl Default for V4l2Probe {
+    fn default() -> Self {
+        Self::new(Path::new("/dev"))
+    }
+}
+
+impl DeviceProbe for V4l2Probe {
+    fn nodes(&self) -> Vec<PathBuf> {
+        let pattern = self.dev_dir.join("video*");
+        glob(&pattern.to_string_lossy())
+            .map(|paths| paths.flatten().collect())
+            .unwrap_or_default()
+    }
+
+    fn probe(&self, path: &Path) -> anyhow::Result<DeviceDescription> {
+        let file = v4l2::open_device(path)?;
+        let cap = v4l2::query_cap(&file)?;
+
+        // device_caps describes this node; capabilities covers the whole physical device
+        let bits = if cap.capabilities & v4l2::V4L2_CAP_DEVICE_CAPS != 0 {
+            cap.device_caps
+        } else {
+            cap.capabilities
+        };
+        let capabilities = CameraCapabilities::from_bits(bits);
+
+        let mut formats = Vec::new();
+        if capabilities.video_capture {
+            for desc in v4l2::enum_formats(&file, v4l2::V4L2_BUF_TYPE_VIDEO_CAPTURE) {
+                formats.push(FormatInfo {
+                    fourcc: v4l2::fourcc_to_string(desc.pixelformat),
+                    description: v4l2::cstr_field(&desc.description),
+                    sizes: probe_sizes(&file, desc.pixelformat),
+                });
+            }
+        }
+
+        Ok(DeviceDescription {
+            driver: v4l2::cstr_field(&cap.driver),
+            card: v4l2::cstr_field(&cap.card),
+            bus_info: v4l2::cstr_field(&cap.bus_info),
+            capabilities,
+            formats,
+        })
+    }
+
+    fn stable_id(&self, path: &Path) -> Option<String> {
+        self.ids.stable_id(path)
+    }
+
+    fn usb_id(&self, path: &Path) -> Option<String> {
+        self.ids.usb_id(path)
+    }
+}
+
+fn probe_sizes(file: &std::fs::File, pixel_format: u32) -> Vec<FrameSize> {
+    let mut sizes = Vec::new();
+    for size in v4l2::enum_frame_sizes(file, pixel_format) {
+        let dims = if size.type_ == v4l2::V4L2_FRMSIZE_TYPE_DISCRETE {
+            let d = size.discrete();
+            vec![(d.width, d.height)]
+        } else {
+            // Report the extremes of a stepwise/continuous range
+            let s = size.stepwise;
+            vec![(s.min_width, s.min_height), (s.max_width, s.max_height)]
+        };
+        for (width, height) in dims {
+            let frame_rates = v4l2::enum_frame_intervals(file, pixel_format, width, height)
+                .iter()
+                .filter(|ival| ival.stepwise.min.numerator != 0)
+                .map(|ival| ival.stepwise.min.denominator as f64 / ival.stepwise.min.numerator as f64)
+                .collect();
+            sizes.push(FrameSize { width, height, frame_rates });
+        }
+    }
+    sizes
+}
+
+fn node_index(path: &Path) -> Option<usize> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("video")?
+        .parse()
+        .ok()
+}
+
+/// How an RPC names the camera it wants.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CameraSelector {
+    /// The configured auth camera, or the first capture device.
+    Default,
+    Id(String),
+    /// Legacy `/dev/videoN` index.
+    Index(usize),
+}
+
+impl CameraSelector {
+    /// Reads `camera` (stable ID) or the older `index` from RPC params.
+    pub fn from_params(params: Option<&serde_json::Value>) -> Self {
+        let Some(p) = params else { return CameraSelector::Default };
+        if let Some(id) = p.get("camera").and_then(|c| c.as_str()) {
+            return CameraSelector::Id(id.to_string());
+        }
+        match p.get("index").and_then(|i| i.as_u64()) {
+            Some(i) => CameraSelector::Index(i as usize),
+            None => CameraSelector::Default,
+        }
+    }
+
+    /// Picks a capture node from `cameras`. A pinned auth camera that is not
+    /// attached is an error rather than a reason to use some other camera.
+    pub fn resolve<'a>(&self, cameras: &'a [CameraInfo], auth_camera: Option<&str>) -> anyhow::Result<&'a CameraInfo> {
+        let capture = |c: &&CameraInfo| c.capabilities.video_capture;
+        match self {
+            CameraSelector::Id(id) => cameras.iter().filter(capture).find(|c| &c.id == id)
+                .ok_or_else(|| anyhow::anyhow!("camera '{}' not found", id)),
+            CameraSelector::Index(i) => cameras.iter().filter(capture).find(|c| c.index == *i)
+                .ok_or_else(|| anyhow::anyhow!("camera index {} not found", i)),
+            CameraSelector::Default => match auth_camera {
+                Some(id) => CameraSelector::Id(id.to_string()).resolve(cameras, None)
+                    .map_err(|_| anyhow::anyhow!("auth camera '{}' is not connected", id)),
+                None => cameras.iter().find(capture)
+                    .ok_or_else(|| anyhow::anyhow!("no capture camera found")),
+            },
+        }
+    }
 }
 
 pub fn list_cameras() -> Vec<CameraInfo> {
+    list_cameras_with(&V4l2Probe::default())
+}
+
+pub fn list_cameras_with(probe: &dyn DeviceProbe) -> Vec<CameraInfo> {
     let mut cameras = Vec::new();
-    
-    // Simple glob-based probing that doesn't require native libraries (v4l/opencv)
-    // This ensures the core compiles and runs even if system deps are missing.
-    let pattern = "/dev/video*";
-    
-    if let Ok(paths) = glob(pattern) {
-        for entry in paths {
-            if let Ok(path) = entry {
-                let path_str = path.to_string_lossy().to_string();
-                
-                // Extract index
-                let index_str = path_str.trim_start_matches("/dev/video");
-                if let Ok(index) = index_str.parse::<usize>() {
-                    // In this stub mode, we can't query the device name, so we use a generic one.
-                    // Later, when v4l/opencv is enabled, we will get the real name.
-                    let name = format!("Camera Device {}", index);
-                    
-                    cameras.push(CameraInfo {
-                        path: path_str,
-                        name,
-                        index,
-                    });
-                }
+
+    for path in probe.nodes() {
+        let Some(index) = node_index(&path) else { continue };
+        let path_str = path.to_string_lossy().to_string();
+
+        // A node we cannot open (permissions, unplugged mid-scan) is still listed,
+        // just without the details the driver would have given us.
+        let desc = match probe.probe(&path) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Failed to probe {}: {}", path_str, e);
+                DeviceDescription::default()
             }
-        }
+        };
+
+        let name = if desc.card.is_empty() {
+            format!("Camera Device {}", index)
+        } else {
+            desc.card.clone()
+        };
+
+        cameras.push(CameraInfo {
+            // Without a stable ID the node name is the best we can do
+            id: probe.stable_id(&path).unwrap_or_else(|| format!("video{}", index)),
+            path: path_str,
+            name,
+            index,
+            driver: desc.driver,
+            card: desc.card,
+            bus_info: desc.bus_info,
+            usb_id: probe.usb_id(&path),
+            capabilities: desc.capabilities,
+            formats: desc.formats,
+            sensor: None,
+            metadata_node: None,
+            paired_with: None,
+        });
     }
-    
+
+    cameras.sort_by_key(|c| c.index);
+    ir::classify(&mut cameras);
+    camera_pair::link_pairs(&mut cameras);
     cameras
 }
 
-pub fn capture_thumbnail(_index: usize) -> anyhow::Result<String> {
-    // STUB: Return a placeholder base64 image (a small red dot)
-    // This proves we can transmit "large" strings over IPC without crashing.
-    // Real implementation will use OpenCV to capture and encode.
-    let placeholder = "iVBORw0KGgoAAAANSUhEUgAAAAUAAAAFCAYAAACNbyblAAAAHElEQVQI12P4//8/w38GIAXDIBKE0DHxgljNBAAO9TXL0Y4OHwAAAABJRU5ErkJggg==";
-    Ok(placeholder.to_string())
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+}
+
+impl ThumbnailFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Png => "image/png",
+            ThumbnailFormat::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// Client-tunable `capture_thumbnail` parameters.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ThumbnailOptions {
+    pub format: ThumbnailFormat,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// JPEG quality (1-100); ignored for PNG.
+    pub quality: u8,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        Self { format: ThumbnailFormat::Png, max_width: 320, max_height: 240, quality: 85 }
+    }
+}
+
+impl ThumbnailOptions {
+    pub const MAX_DIMENSION: u32 = 1920;
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=100).contains(&self.quality) {
+            anyhow::bail!("quality must be between 1 and 100");
+        }
+        if self.max_width == 0 || self.max_height == 0
+            || self.max_width > Self::MAX_DIMENSION || self.max_height > Self::MAX_DIMENSION
+        {
+            anyhow::bail!("max_width/max_height must be between 1 and {}", Self::MAX_DIMENSION);
+        }
+        Ok(())
+    }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Thumbnail {
+    /// Base64-encoded image bytes.
+    pub image: String,
+    pub width: u32,
+    pub height: u32,
+    pub mime_type: String,
+    /// Wall-clock capture time in milliseconds since the Unix epoch.
+    pub captured_at_ms: u64,
+}
+
+pub fn capture_thumbnail(source: &mut dyn FrameSource, options: &ThumbnailOptions) -> anyhow::Result<Thumbnail> {
+    options.validate()?;
+
+    let frame = source.next_frame()?;
+    let captured_at_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let rgb = convert::to_rgb8(&frame)?;
+
+    let (width, height) = fit_within(rgb.width(), rgb.height(), options.max_width, options.max_height);
+    let rgb = if (width, height) == rgb.dimensions() {
+        rgb
+    } else {
+        imageops::resize(&rgb, width, height, imageops::FilterType::Triangle)
+    };
+
+    let mut encoded = Vec::new();
+    match options.format {
+        ThumbnailFormat::Png => PngEncoder::new(&mut encoded)
+            .write_image(rgb.as_raw(), width, height, ColorType::Rgb8)?,
+        ThumbnailFormat::Jpeg => JpegEncoder::new_with_quality(&mut encoded, options.quality)
+            .write_image(rgb.as_raw(), width, height, ColorType::Rgb8)?,
+    }
+
+    Ok(Thumbnail {
+        image: base64::engine::general_purpose::STANDARD.encode(&encoded),
+        width,
+        height,
+        mime_type: options.format.mime_type().to_string(),
+        captured_at_ms,
+    })
+}
+
+/// Largest size with the source aspect ratio that fits the bounds. Never upscales.
+fn fit_within(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    if width <= max_width && height <= max_height {
+        return (width, height);
+    }
+    let scale = f64::min(max_width as f64 / width as f64, max_height as f64 / height as f64);
+    (
+        ((width as f64 * scale).round() as u32).max(1),
+        ((height as f64 * scale).round() as u32).max(1),
+    )
+}
+
+/// Version of the `VerificationResult` JSON layout. Bumped when a field
+/// changes meaning or goes away; new fields and codes do not bump it.
+pub const RESULT_SCHEMA_VERSION: u32 = 1;
+
+/// A second face at least this big relative to the first makes it unclear
+/// who is in front of the camera.
+const AMBIGUOUS_FACE_RATIO: f32 = 0.8;
+
+/// Least overlap (intersection over union) between the face boxes of
+/// consecutive frames for them to count as the same face. A head turning at
+/// camera frame rates keeps well above it; a photo swapped in does not.
+const TRACK_IOU: f32 = 0.3;
+
+/// Why a verification did not succeed, for clients to branch on. `reason`
+/// carries the finer detail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReasonCode {
+    NoFace,
+    /// Faces were found, but never one clearly in front of the others.
+    MultipleFaces,
+    /// Frames were too dark, blurred or small to match.
+    LowQuality,
+    NotEnrolled,
+    /// Enrolled only with templates from another recognition model.
+    NeedsReenroll,
+    LivenessFailed,
+    NoMatch,
+    /// Identification only: the best two users scored too close to call.
+    AmbiguousMatch,
+    CameraBusy,
+    /// Too many recent failures; see `diagnostics.retry_after_ms`.
+    LockedOut,
+    /// The deadline passed before enough frames were seen to decide.
+    Timeout,
+    /// No face detector or embedder is installed.
+    ModelUnavailable,
+}
+
+/// What a verification looked at, for logs and troubleshooting.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostics {
+    /// Stable ID of the camera verified on, and of its IR partner.
+    pub camera: Option<String>,
+    pub ir_camera: Option<String>,
+    pub detector: Option<String>,
+    pub embedder: Option<String>,
+    /// Frames in which a face was found.
+    pub face_frames: usize,
+    /// Frames skipped because it was unclear whose face to match.
+    pub ambiguous_frames: usize,
+    /// Frames skipped because the face failed a quality check.
+    #[serde(default)]
+    pub low_quality_frames: usize,
+    /// Processes holding the camera, with `CAMERA_BUSY`.
+    pub camera_holders: Vec<Holder>,
+    /// When the user may try again, with `LOCKED_OUT`.
+    pub retry_after_ms: Option<u64>,
+    /// Adaptive template this verification stored; see `adapt`.
+    #[serde(default)]
+    pub template_update: Option<String>,
+    /// What exposure control did; see `exposure`.
+    #[serde(default)]
+    pub exposure: Option<ExposureReport>,
+}
+
+/// Outcome of `verify_once`. Every field is always present, `null` when it
+/// does not apply, so the layout is the same whatever the outcome.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VerificationResult {
+    pub schema_version: u32,
+    pub ok: bool,
+    /// `None` exactly when `ok`.
+    pub code: Option<ReasonCode>,
+    /// Detail behind `code`, e.g. `liveness_motion` or `embedder_unavailable`.
+    pub reason: Option<String>,
+    /// Faces the detector found in the first (RGB) frame that had any.
+    #[serde(default)]
+    pub faces: Vec<Detection>,
+    /// Best score against the user's templates, averaged over the frames:
+    /// cosine similarity or L2 distance, depending on `metric`.
+    #[serde(default)]
+    pub score: Option<f32>,
+    #[serde(default)]
+    pub threshold: Option<f32>,
+    #[serde(default)]
+    pub metric: Option<Metric>,
+    /// The template that scored best in the most frames, whether or not it passed.
+    #[serde(default)]
+    pub template_id: Option<String>,
+    /// Frames captured and looked at.
+    #[serde(default)]
+    pub frames: usize,
+    /// Best score of each frame that had a face, in capture order.
+    #[serde(default)]
+    pub frame_scores: Vec<f32>,
+    #[serde(default)]
+    pub elapsed_ms: u64,
+    /// Per-check liveness scores, once a face was found and matching was possible.
+    #[serde(default)]
+    pub liveness: Option<LivenessResult>,
+    #[serde(default)]
+    pub diagnostics: Diagnostics,
+    /// Embedding of the best-scoring frame of a success, for adaptive
+    /// updates. Stays in the daemon.
+    #[serde(skip)]
+    pub probe: Option<Vec<f32>>,
+}
+
+impl Default for VerificationResult {
+    fn default() -> Self {
+        Self {
+            schema_version: RESULT_SCHEMA_VERSION,
+            ok: false,
+            code: None,
+            reason: None,
+            faces: Vec::new(),
+            score: None,
+            threshold: None,
+            metric: None,
+            template_id: None,
+            frames: 0,
+            frame_scores: Vec::new(),
+            elapsed_ms: 0,
+            liveness: None,
+            diagnostics: Diagnostics::default(),
+            probe: None,
+        }
+    }
+}
+
+impl VerificationResult {
+    pub fn rejected(code: ReasonCode, reason: &str, faces: Vec<Detection>) -> Self {
+        Self { code: Some(code), reason: Some(reason.into()), faces, ..Default::default() }
+    }
+
+    /// The camera was held by someone else for as long as the client would wait.
+    pub fn camera_busy(busy: &CameraBusy) -> Self {
+        let mut res = Self::rejected(ReasonCode::CameraBusy, &busy.to_string(), vec![]);
+        res.diagnostics.camera_holders = busy.holders.clone();
+        res
+    }
+
+    pub fn locked_out(retry_after: Duration) -> Self {
+        let mut res = Self::rejected(ReasonCode::LockedOut, "locked_out", vec![]);
+        res.diagnostics.retry_after_ms = Some(retry_after.as_millis() as u64);
+        res
+    }
+}
+
+/// Outcome of `identify`. Shares codes, liveness and diagnostics with
+/// `VerificationResult`, and the same rule that every field is present.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentificationResult {
+    pub schema_version: u32,
     pub ok: bool,
+    pub code: Option<ReasonCode>,
     pub reason: Option<String>,
+    /// The identified user, exactly when `ok`.
+    pub uid: Option<u32>,
+    /// Users in scope with their mean scores, best first.
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    /// How far the best candidate's score is ahead of the runner-up's.
+    #[serde(default)]
+    pub lead: Option<f32>,
+    /// Lead needed to identify anyone.
+    #[serde(default)]
+    pub margin: Option<f32>,
+    #[serde(default)]
+    pub metric: Option<Metric>,
+    #[serde(default)]
+    pub faces: Vec<Detection>,
+    #[serde(default)]
+    pub frames: usize,
+    #[serde(default)]
+    pub elapsed_ms: u64,
+    #[serde(default)]
+    pub liveness: Option<LivenessResult>,
+    #[serde(default)]
+    pub diagnostics: Diagnostics,
+}
+
+impl Default for IdentificationResult {
+    fn default() -> Self {
+        Self {
+            schema_version: RESULT_SCHEMA_VERSION,
+            ok: false,
+            code: None,
+            reason: None,
+            uid: None,
+            candidates: Vec::new(),
+            lead: None,
+            margin: None,
+            metric: None,
+            faces: Vec::new(),
+            frames: 0,
+            elapsed_ms: 0,
+            liveness: None,
+            diagnostics: Diagnostics::default(),
+        }
+    }
 }
 
-pub fn verify_once(_timeout_ms: u64) -> anyhow::Result<VerificationResult> {
-    // STUB: Simulate a verification attempt.
-    // For now, we'll just sleep a bit and return true to simulate success.
-    // In the future, this will capture frames and run the ONNX model.
-    
-    std::thread::sleep(std::time::Duration::from_millis(500));
-    
-    // Hardcoded success for testing flow
-    Ok(VerificationResult {
-        ok: true,
-        reason: None,
+impl IdentificationResult {
+    pub fn camera_busy(busy: &CameraBusy) -> Self {
+        let mut res = Self { code: Some(ReasonCode::CameraBusy), reason: Some(busy.to_string()), ..Default::default() };
+        res.diagnostics.camera_holders = busy.holders.clone();
+        res
+    }
+
+    /// The identified user is locked out of `verify_once`, so not identified either.
+    pub fn locked_out(mut self, retry_after: Duration) -> Self {
+        (self.ok, self.uid) = (false, None);
+        (self.code, self.reason) = (Some(ReasonCode::LockedOut), Some("locked_out".into()));
+        self.diagnostics.retry_after_ms = Some(retry_after.as_millis() as u64);
+        self
+    }
+}
+
+/// Models and templates one verification runs against. Missing models make
+/// verification fail closed with `detector_unavailable` or `embedder_unavailable`.
+/// Without `liveness` the checks are skipped, which only tests should do.
+pub struct Verifier<'a> {
+    pub detector: Option<&'a mut dyn Detector>,
+    pub embedder: Option<&'a mut dyn Embedder>,
+    pub templates: &'a [Template],
+    pub metric: Metric,
+    pub threshold: f32,
+    pub liveness: Option<&'a Liveness>,
+    pub challenge: Option<Challenge>,
+    /// Frames whose face fails it are not matched. `None` skips the checks,
+    /// like `liveness`.
+    pub quality: Option<&'a QualityConfig>,
+    /// Told about each new quality issue while frames are captured, so the
+    /// user can fix it before the deadline.
+    pub guide: Option<&'a dyn Fn(Issue)>,
+    /// Steers the camera toward a well-lit face, and enhances frames when it
+    /// cannot. `None` leaves frames as captured.
+    pub exposure: Option<&'a mut Exposure>,
+}
+
+/// What one identification runs against: every user in `scope` (everyone
+/// when `None`) that `index` has templates of.
+pub struct Identifier<'a> {
+    pub detector: Option<&'a mut dyn Detector>,
+    pub embedder: Option<&'a mut dyn Embedder>,
+    pub index: &'a TemplateIndex,
+    pub config: &'a MatchConfig,
+    pub scope: Option<&'a [u32]>,
+    pub liveness: Option<&'a Liveness>,
+    pub challenge: Option<Challenge>,
+    pub quality: Option<&'a QualityConfig>,
+    pub guide: Option<&'a dyn Fn(Issue)>,
+    pub exposure: Option<&'a mut Exposure>,
+}
+
+/// Verifies on frames from `source` until the outcome is clear or
+/// `timeout_ms` has passed. The first frame is always looked at.
+pub fn verify_once(
+    source: &mut dyn FrameSource,
+    verifier: Verifier<'_>,
+    timeout_ms: u64,
+) -> anyhow::Result<VerificationResult> {
+    verify_frames(verifier, timeout_ms, || {
+        let frame = source.next_frame()?;
+        Ok((convert::to_rgb8(&frame)?, None))
     })
 }
+
+/// Verification on time-aligned RGB+IR pairs, so liveness can compare both spectra.
+pub fn verify_pair(
+    pair: &mut CameraPair,
+    verifier: Verifier<'_>,
+    timeout_ms: u64,
+) -> anyhow::Result<VerificationResult> {
+    verify_frames(verifier, timeout_ms, || {
+        let frames = pair.next_pair()?;
+        log::debug!("verify_pair: RGB/IR skew {:?}", frames.skew);
+        Ok((convert::to_rgb8(&frames.rgb)?, Some(convert::to_luma8(&frames.ir)?)))
+    })
+}
+
+/// Identification on frames from `source`; see `verify_once`.
+pub fn identify_once(
+    source: &mut dyn FrameSource,
+    identifier: Identifier<'_>,
+    timeout_ms: u64,
+) -> anyhow::Result<IdentificationResult> {
+    identify_frames(identifier, timeout_ms, || {
+        let frame = source.next_frame()?;
+        Ok((convert::to_rgb8(&frame)?, None))
+    })
+}
+
+/// Identification on time-aligned RGB+IR pairs; see `verify_pair`.
+pub fn identify_pair(
+    pair: &mut CameraPair,
+    identifier: Identifier<'_>,
+    timeout_ms: u64,
+) -> anyhow::Result<IdentificationResult> {
+    identify_frames(identifier, timeout_ms, || {
+        let frames = pair.next_pair()?;
+        log::debug!("identify_pair: RGB/IR skew {:?}", frames.skew);
+        Ok((convert::to_rgb8(&frames.rgb)?, Some(convert::to_luma8(&frames.ir)?)))
+    })
+}
+
+/// The most confident face is the one in front of the camera, unless another
+/// one is about as big. Then it is unclear whose face to match.
+pub fn is_ambiguous(detected: &[Detection]) -> bool {
+    matches!(detected, [a, b, ..] if b.bbox.width >= a.bbox.width * AMBIGUOUS_FACE_RATIO)
+}
+
+/// Whether a gallery holds templates that probes can be compared with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enrolled {
+    Yes,
+    /// Only templates from another model or embedding schema.
+    Stale,
+    No,
+}
+
+/// What the faces of a scan are matched against: one user's templates when
+/// verifying, everyone's when identifying.
+trait Gallery {
+    /// Matches probes from the model `model_id` from now on, against the
+    /// templates it produced.
+    fn select(&mut self, model_id: &str) -> Enrolled;
+    /// Matches one frame's probe; `false` when nothing enrolled has its size.
+    fn add(&mut self, probe: &[f32]) -> bool;
+    /// Whether more frames are unlikely to change the outcome.
+    fn confident(&self) -> bool;
+}
+
+/// One user's templates and the fused best matches against them.
+struct Templates<'a> {
+    templates: &'a [Template],
+    model_id: String,
+    metric: Metric,
+    fusion: matcher::Fusion,
+    /// The probe that scored best so far, and its score.
+    best: Option<(f32, Vec<f32>)>,
+}
+
+impl Gallery for Templates<'_> {
+    fn select(&mut self, model_id: &str) -> Enrolled {
+        self.model_id = model_id.to_string();
+        match self.templates {
+            [] => Enrolled::No,
+            all if all.iter().any(|t| t.compatible_with(model_id)) => Enrolled::Yes,
+            _ => Enrolled::Stale,
+        }
+    }
+
+    fn add(&mut self, probe: &[f32]) -> bool {
+        let usable = self.templates.iter().filter(|t| t.compatible_with(&self.model_id));
+        match matcher::best_match(self.metric, probe, usable) {
+            Some(best) => {
+                if self.best.as_ref().is_none_or(|(score, _)| self.metric.better(best.score, *score)) {
+                    self.best = Some((best.score, probe.to_vec()));
+                }
+                self.fusion.add(best);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn confident(&self) -> bool {
+        self.fusion.confident()
+    }
+}
+
+impl Gallery for Ranking<'_> {
+    fn select(&mut self, model_id: &str) -> Enrolled {
+        Ranking::select(self, model_id)
+    }
+
+    fn add(&mut self, probe: &[f32]) -> bool {
+        Ranking::add(self, probe)
+    }
+
+    fn confident(&self) -> bool {
+        Ranking::confident(self)
+    }
+}
+
+/// What a scan checks frames with, besides the gallery.
+struct FrameChecks<'a> {
+    liveness: Option<&'a Liveness>,
+    challenge: Option<Challenge>,
+    quality: Option<&'a QualityConfig>,
+    guide: Option<&'a dyn Fn(Issue)>,
+    exposure: Option<&'a mut Exposure>,
+}
+
+/// What a scan saw, before the gallery has its say.
+struct Scan {
+    frames: usize,
+    /// Faces of the first frame that had any.
+    faces: Vec<Detection>,
+    liveness: Option<LivenessResult>,
+    /// Whether liveness got all the frames it wanted.
+    live_complete: bool,
+    elapsed_ms: u64,
+    diagnostics: Diagnostics,
+    /// Why nothing could be matched at all, or the scores cannot be trusted,
+    /// when that is the case.
+    rejected: Option<(ReasonCode, &'static str)>,
+    /// Quality issue of the last frame that had one.
+    issue: Option<Issue>,
+}
+
+impl Scan {
+    /// Why the outcome cannot rest on the scores of the `matched` frames, if
+    /// it cannot.
+    fn failure(&self, matched: usize) -> Option<(ReasonCode, String)> {
+        if let Some((code, reason)) = self.rejected {
+            return Some((code, reason.into()));
+        }
+        if matched == 0 {
+            return Some(match (self.diagnostics.face_frames, self.issue) {
+                (0, _) => (ReasonCode::NoFace, "no_face".into()),
+                (_, Some(issue)) => (ReasonCode::LowQuality, issue.code().into()),
+                // Exposure never settled for the burst to begin
+                _ if self.diagnostics.ambiguous_frames == 0 => (ReasonCode::Timeout, "timeout".into()),
+                _ => (ReasonCode::MultipleFaces, "multiple_faces".into()),
+            });
+        }
+        if let Some(check) = self.liveness.as_ref().and_then(|l| l.failed.as_ref()) {
+            return Some((ReasonCode::LivenessFailed, format!("liveness_{}", check)));
+        }
+        // Too few good frames to decide on is the frames' fault, not the clock's
+        if matched < matcher::CONFIDENT_FRAMES {
+            if let Some(issue) = self.issue {
+                return Some((ReasonCode::LowQuality, issue.code().into()));
+            }
+        }
+        // Out of time before the burst was complete or the scores said much
+        if !self.live_complete || matched < matcher::CONFIDENT_FRAMES {
+            return Some((ReasonCode::Timeout, "timeout".into()));
+        }
+        None
+    }
+}
+
+/// Matches the most confident face of each frame against `gallery`, while
+/// the first frames double as the liveness burst. Faces failing the quality
+/// checks are not matched, and the guide hears about each new issue. Each
+/// face's luminance is fed back to exposure control, which enhances later
+/// frames once the camera cannot be brightened further. The burst waits
+/// for exposure to settle and then holds it still, and liveness sees the
+/// frames as captured, so nothing but the face changes between them. From
+/// the burst on, the face must stay in every frame and keep overlapping its
+/// box in the one before (`TRACK_IOU`), so the face liveness judged is the
+/// one matched and a photo cannot take over once the checks have passed.
+/// Capturing stops once the gallery is confident and liveness has its
+/// frames, or at the deadline. `capture` returns the next RGB image and its
+/// IR partner, if any.
+fn scan(
+    detector: Option<&mut dyn Detector>,
+    embedder: Option<&mut dyn Embedder>,
+    mut checks: FrameChecks<'_>,
+    gallery: &mut dyn Gallery,
+    timeout_ms: u64,
+    mut capture: impl FnMut() -> anyhow::Result<(RgbImage, Option<GrayImage>)>,
+) -> anyhow::Result<Scan> {
+    let started = Instant::now();
+    let deadline = started + Duration::from_millis(timeout_ms);
+    let mut scan = Scan {
+        frames: 1,
+        faces: Vec::new(),
+        liveness: None,
+        live_complete: false,
+        elapsed_ms: 0,
+        diagnostics: Diagnostics::default(),
+        rejected: None,
+        issue: None,
+    };
+    let finish = |mut scan: Scan| {
+        scan.elapsed_ms = started.elapsed().as_millis() as u64;
+        Ok(scan)
+    };
+
+    let (mut image, mut ir) = capture()?;
+    let mut raw = imageops::grayscale(&image);
+    image = enhance(checks.exposure.as_deref(), image);
+    let Some(detector) = detector else {
+        scan.rejected = Some((ReasonCode::ModelUnavailable, "detector_unavailable"));
+        return finish(scan);
+    };
+    scan.diagnostics.detector = Some(detector.name().to_string());
+    let mut detected = detector.detect(&image)?;
+    let Some(embedder) = embedder else {
+        scan.rejected = Some(match detected.is_empty() {
+            true => (ReasonCode::NoFace, "no_face"),
+            false => (ReasonCode::ModelUnavailable, "embedder_unavailable"),
+        });
+        scan.faces = detected;
+        return finish(scan);
+    };
+    scan.diagnostics.embedder = Some(embedder.name().to_string());
+    let enrolled = gallery.select(embedder.model_id());
+    if enrolled != Enrolled::Yes {
+        scan.rejected = Some(match (detected.is_empty(), enrolled) {
+            (true, _) => (ReasonCode::NoFace, "no_face"),
+            (false, Enrolled::Stale) => (ReasonCode::NeedsReenroll, "needs_reenroll"),
+            (false, _) => (ReasonCode::NotEnrolled, "not_enrolled"),
+        });
+        scan.faces = detected;
+        return finish(scan);
+    }
+
+    let wanted = checks.liveness.map(|l| l.frames_needed(checks.challenge)).unwrap_or(0);
+    let mut live_frames = Vec::new();
+    // Where the face was in the last frame, once the burst has begun
+    let mut tracked: Option<BoundingBox> = None;
+    // The issue the guide was last told about, so it hears each one once
+    let mut guided = None;
+    loop {
+        log::debug!("scan: frame {}, {} face(s)", scan.frames, detected.len());
+        let ambiguous = is_ambiguous(&detected);
+        if ambiguous {
+            scan.diagnostics.ambiguous_frames += 1;
+        }
+        if !detected.is_empty() {
+            scan.diagnostics.face_frames += 1;
+        }
+        let luma = imageops::grayscale(&image);
+        // Exposure holds still once the burst has begun, which it only does
+        // once exposure has settled
+        let holding = !live_frames.is_empty();
+        if let Some(exposure) = checks.exposure.as_deref_mut().filter(|_| !holding) {
+            exposure.observe(exposure::luminance(&luma, detected.first().map(|d| &d.bbox)));
+        }
+        let steady = checks.exposure.as_deref().is_none_or(|e| e.steady());
+        let in_burst = live_frames.len() < wanted && (holding || steady);
+        let watched = holding || in_burst;
+        if watched {
+            let face = detected.first().map(|d| d.bbox);
+            if tracked.is_some_and(|last| face.is_none_or(|face| last.iou(&face) < TRACK_IOU)) {
+                // Not the face liveness has been looking at
+                scan.rejected = Some((ReasonCode::LivenessFailed, "liveness_tracking"));
+                break;
+            }
+            tracked = face.or(tracked);
+        }
+        if let Some(face) = detected.first().filter(|_| !ambiguous && (wanted == 0 || watched)) {
+            let issue = checks.quality.and_then(|gate| gate.assess(&luma, face).issues.first().copied());
+            if let Some(issue) = issue {
+                scan.diagnostics.low_quality_frames += 1;
+                scan.issue = Some(issue);
+                if guided != Some(issue) {
+                    if let Some(guide) = checks.guide {
+                        guide(issue);
+                    }
+                    guided = Some(issue);
+                }
+            } else {
+                guided = None;
+                let probe = embedder.embed(&image, face)?;
+                if !gallery.add(&probe) {
+                    // Every usable template has a different embedding size
+                    scan.rejected = Some((ReasonCode::NotEnrolled, "not_enrolled"));
+                    scan.faces = detected;
+                    return finish(scan);
+                }
+            }
+        }
+        if scan.faces.is_empty() {
+            scan.faces = detected.clone();
+        }
+        if in_burst {
+            live_frames.push(LiveFrame { luma: raw, ir, face: detected.into_iter().next() });
+            if live_frames.len() == wanted {
+                scan.liveness = checks.liveness.map(|l| l.assess(&live_frames, checks.challenge));
+            }
+        }
+        scan.live_complete = live_frames.len() >= wanted;
+        // A failed liveness check settles it whatever the scores say
+        let spoofed = scan.liveness.as_ref().is_some_and(|l| !l.live);
+        if spoofed || (gallery.confident() && scan.live_complete) || Instant::now() >= deadline {
+            break;
+        }
+        (image, ir) = capture()?;
+        raw = imageops::grayscale(&image);
+        image = enhance(checks.exposure.as_deref(), image);
+        scan.frames += 1;
+        detected = detector.detect(&image)?;
+    }
+    scan.diagnostics.exposure = checks.exposure.map(|e| e.report());
+    finish(scan)
+}
+
+fn enhance(exposure: Option<&Exposure>, image: RgbImage) -> RgbImage {
+    match exposure {
+        Some(exposure) => exposure.prepare(image),
+        None => image,
+    }
+}
+
+/// Fuses the scores against the user's templates (see `matcher::Fusion`).
+fn verify_frames(
+    verifier: Verifier<'_>,
+    timeout_ms: u64,
+    capture: impl FnMut() -> anyhow::Result<(RgbImage, Option<GrayImage>)>,
+) -> anyhow::Result<VerificationResult> {
+    let (metric, threshold) = (verifier.metric, verifier.threshold);
+    let mut gallery = Templates {
+        templates: verifier.templates,
+        model_id: String::new(),
+        metric,
+        fusion: matcher::Fusion::new(metric, threshold),
+        best: None,
+    };
+    let checks = FrameChecks {
+        liveness: verifier.liveness,
+        challenge: verifier.challenge,
+        quality: verifier.quality,
+        guide: verifier.guide,
+        exposure: verifier.exposure,
+    };
+    let scan = scan(verifier.detector, verifier.embedder, checks, &mut gallery, timeout_ms, capture)?;
+    let (fusion, best) = (gallery.fusion, gallery.best);
+    let failure = scan.failure(fusion.frames());
+    let mut res = VerificationResult {
+        faces: scan.faces,
+        frames: scan.frames,
+        frame_scores: fusion.scores(),
+        elapsed_ms: scan.elapsed_ms,
+        liveness: scan.liveness,
+        diagnostics: scan.diagnostics,
+        ..Default::default()
+    };
+    // Scores are reported whatever the outcome, once there are any
+    if fusion.frames() > 0 {
+        res.score = fusion.mean();
+        res.threshold = Some(threshold);
+        res.metric = Some(metric);
+        res.template_id = fusion.template_id().map(str::to_string);
+    }
+    match failure {
+        Some((code, reason)) => (res.code, res.reason) = (Some(code), Some(reason)),
+        None if fusion.accepted() => {
+            res.ok = true;
+            res.probe = best.map(|(_, probe)| probe);
+        }
+        None => (res.code, res.reason) = (Some(ReasonCode::NoMatch), Some("no_match".into())),
+    }
+    Ok(res)
+}
+
+/// Ranks every user in scope (see `identify::Ranking`).
+fn identify_frames(
+    identifier: Identifier<'_>,
+    timeout_ms: u64,
+    capture: impl FnMut() -> anyhow::Result<(RgbImage, Option<GrayImage>)>,
+) -> anyhow::Result<IdentificationResult> {
+    let mut ranking = Ranking::new(identifier.index, identifier.config, identifier.scope);
+    let checks = FrameChecks {
+        liveness: identifier.liveness,
+        challenge: identifier.challenge,
+        quality: identifier.quality,
+        guide: identifier.guide,
+        exposure: identifier.exposure,
+    };
+    let scan = scan(identifier.detector, identifier.embedder, checks, &mut ranking, timeout_ms, capture)?;
+    let failure = scan.failure(ranking.frames());
+    let mut res = IdentificationResult {
+        faces: scan.faces,
+        frames: scan.frames,
+        elapsed_ms: scan.elapsed_ms,
+        liveness: scan.liveness,
+        diagnostics: scan.diagnostics,
+        ..Default::default()
+    };
+    if ranking.frames() > 0 {
+        res.candidates = ranking.candidates();
+        res.lead = ranking.lead();
+        res.margin = Some(identifier.config.identify_margin());
+        res.metric = Some(identifier.config.metric);
+    }
+    let verdict = match failure {
+        Some(failure) => Err(failure),
+        None => ranking.decide().map_err(|(code, reason)| (code, reason.to_string())),
+    };
+    match verdict {
+        Ok(uid) => (res.ok, res.uid) = (true, Some(uid)),
+        Err((code, reason)) => (res.code, res.reason) = (Some(code), Some(reason)),
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct FakeProbe {
+        devices: HashMap<PathBuf, DeviceDescription>,
+    }
+
+    impl DeviceProbe for FakeProbe {
+        fn nodes(&self) -> Vec<PathBuf> {
+            let mut nodes: Vec<_> = self.devices.keys().cloned().collect();
+            nodes.push(PathBuf::from("/dev/video-broken"));
+            nodes.push(PathBuf::from("/dev/video7"));
+            nodes
+        }
+
+        fn probe(&self, path: &Path) -> anyhow::Result<DeviceDescription> {
+            self.devices
+                .get(path)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("permission denied"))
+        }
+    }
+
+    fn webcam() -> DeviceDescription {
+        DeviceDescription {
+            driver: "uvcvideo".into(),
+            card: "Integrated Camera: Integrated C".into(),
+            bus_info: "usb-0000:00:14.0-8".into(),
+            capabilities: CameraCapabilities::from_bits(
+                v4l2::V4L2_CAP_VIDEO_CAPTURE | v4l2::V4L2_CAP_STREAMING,
+            ),
+            formats: vec![FormatInfo {
+                fourcc: "YUYV".into(),
+                description: "YUYV 4:2:2".into(),
+                sizes: vec![FrameSize { width: 640, height: 480, frame_rates: vec![30.0] }],
+            }],
+        }
+    }
+
+    fn metadata_node() -> DeviceDescription {
+        DeviceDescription {
+            driver: "uvcvideo".into(),
+            card: "Integrated Camera: Integrated C".into(),
+            bus_info: "usb-0000:00:14.0-8".into(),
+            capabilities: CameraCapabilities::from_bits(
+                v4l2::V4L2_CAP_META_CAPTURE | v4l2::V4L2_CAP_STREAMING,
+            ),
+            formats: vec![],
+        }
+    }
+
+    #[test]
+    fn lists_probed_devices_in_index_order() {
+        let probe = FakeProbe {
+            devices: HashMap::from([
+                (PathBuf::from("/dev/video10"), metadata_node()),
+                (PathBuf::from("/dev/video2"), webcam()),
+            ]),
+        };
+
+        let cameras = list_cameras_with(&probe);
+        let indices: Vec<_> = cameras.iter().map(|c| c.index).collect();
+        assert_eq!(indices, vec![2, 7, 10]);
+
+        let cam = &cameras[0];
+        assert_eq!(cam.id, "video2");
+        assert_eq!(cam.name, "Integrated Camera: Integrated C");
+        assert_eq!(cam.driver, "uvcvideo");
+        assert!(cam.capabilities.video_capture);
+        assert_eq!(cam.formats[0].fourcc, "YUYV");
+        assert_eq!(cam.formats[0].sizes[0].frame_rates, vec![30.0]);
+
+        let meta = &cameras[2];
+        assert!(meta.capabilities.metadata_capture);
+        assert!(!meta.capabilities.video_capture);
+    }
+
+    #[test]
+    fn classifies_ir_node_and_pairs_metadata() {
+        let mut ir_cam = webcam();
+        ir_cam.card = "Integrated Camera: Integrated I".into();
+        ir_cam.formats[0].fourcc = "GREY".into();
+        let probe = FakeProbe {
+            devices: HashMap::from([
+                (PathBuf::from("/dev/video0"), webcam()),
+                (PathBuf::from("/dev/video1"), metadata_node()),
+                (PathBuf::from("/dev/video2"), ir_cam),
+                (PathBuf::from("/dev/video3"), metadata_node()),
+            ]),
+        };
+        let cameras = list_cameras_with(&probe);
+
+        assert_eq!(cameras[0].sensor, Some(SensorKind::Rgb));
+        assert_eq!(cameras[0].metadata_node.as_deref(), Some("/dev/video1"));
+        assert_eq!(cameras[1].sensor, None);
+        assert_eq!(cameras[2].sensor, Some(SensorKind::Ir));
+        assert_eq!(cameras[2].metadata_node.as_deref(), Some("/dev/video3"));
+        assert_eq!(cameras[0].paired_with.as_deref(), Some("video2"));
+        assert_eq!(cameras[2].paired_with.as_deref(), Some("video0"));
+        // video7 failed to probe: no formats, no bus, so neither IR nor paired
+        assert_eq!(cameras[4].sensor, None);
+    }
+
+    #[test]
+    fn thumbnail_is_scaled_and_encoded() {
+        use crate::frame::PixelFormat;
+        use crate::frame_source::SyntheticSource;
+
+        let mut src = SyntheticSource::new(640, 480, PixelFormat::Yuyv).unwrap();
+        let opts = ThumbnailOptions { format: ThumbnailFormat::Jpeg, max_width: 160, max_height: 160, quality: 70 };
+        let thumb = capture_thumbnail(&mut src, &opts).unwrap();
+        assert_eq!((thumb.width, thumb.height), (160, 120));
+        assert_eq!(thumb.mime_type, "image/jpeg");
+
+        let bytes = base64::engine::general_purpose::STANDARD.decode(&thumb.image).unwrap();
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (160, 120));
+    }
+
+    /// Reports a fixed set of faces for every image.
+    struct FakeDetector(Vec<Detection>);
+
+    impl Detector for FakeDetector {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn detect(&mut self, _image: &image::RgbImage) -> anyhow::Result<Vec<Detection>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// A template from `FakeEmbedder`.
+    fn template(id: &str, embedding: Vec<f32>) -> Template {
+        Template { id: id.into(), model_id: "fake".into(), schema: crate::embedder::EMBEDDING_SCHEMA, embedding, ..Default::default() }
+    }
+
+    /// The face `FakeDetector` finds, unless a test needs it elsewhere.
+    fn face() -> Detection {
+        Detection { bbox: BoundingBox { x: 10.0, y: 8.0, width: 20.0, height: 20.0 }, landmarks: vec![], confidence: 0.9 }
+    }
+
+    /// Matches by cosine against `templates`, with every check off.
+    fn verifier<'a>(
+        detector: Option<&'a mut dyn Detector>,
+        embedder: Option<&'a mut dyn Embedder>,
+        templates: &'a [Template],
+        threshold: f32,
+    ) -> Verifier<'a> {
+        Verifier {
+            detector,
+            embedder,
+            templates,
+            metric: Metric::Cosine,
+            threshold,
+            liveness: None,
+            challenge: None,
+            quality: None,
+            guide: None,
+            exposure: None,
+        }
+    }
+
+    /// Embeds every face as the same fixed vector.
+    struct FakeEmbedder(Vec<f32>);
+
+    impl Embedder for FakeEmbedder {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn embed(&mut self, _image: &image::RgbImage, _face: &Detection) -> anyhow::Result<Vec<f32>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn verification_needs_models_a_face_and_a_matching_template() {
+        use crate::liveness::LivenessConfig;
+        use crate::frame::PixelFormat;
+        use crate::frame_source::SyntheticSource;
+
+        let face = face();
+        let templates = vec![template("a", vec![1.0, 0.0]), template("b", vec![0.6, 0.8])];
+        let mut src = SyntheticSource::new(64, 48, PixelFormat::Yuyv).unwrap();
+        let mut run = |faces: Vec<Detection>, embedding: Option<Vec<f32>>, templates: &[Template], threshold: f32, timeout: u64| {
+            let mut detector = FakeDetector(faces);
+            let mut embedder = embedding.map(FakeEmbedder);
+            let verifier = verifier(Some(&mut detector), embedder.as_mut().map(|e| e as &mut dyn Embedder), templates, threshold);
+            verify_once(&mut src, verifier, timeout).unwrap()
+        };
+
+        let res = run(vec![], None, &templates, 0.5, 2000);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::NoFace), Some("no_face")));
+        let res = run(vec![face.clone()], None, &templates, 0.5, 2000);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::ModelUnavailable), Some("embedder_unavailable")));
+        assert_eq!((res.faces, res.frames), (vec![face.clone()], 1));
+        let res = run(vec![face.clone()], Some(vec![0.0, 1.0]), &[], 0.5, 2000);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::NotEnrolled), Some("not_enrolled")));
+        // Templates from another model are only good for enrolling again
+        let stale = [Template { model_id: "older".into(), ..template("a", vec![0.0, 1.0]) }];
+        let res = run(vec![face.clone()], Some(vec![0.0, 1.0]), &stale, 0.5, 2000);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::NeedsReenroll), Some("needs_reenroll")));
+        let mixed = [stale[0].clone(), templates[0].clone()];
+        let res = run(vec![face.clone()], Some(vec![0.0, 1.0]), &mixed, 0.5, 2000);
+        assert_eq!((res.ok, res.code, res.template_id.as_deref()), (false, Some(ReasonCode::NoMatch), Some("a")));
+
+        // Clear accepts and rejects are settled in a few frames, long before the deadline
+        let res = run(vec![face.clone()], Some(vec![0.0, 1.0]), &templates, 0.5, 2000);
+        assert!(res.ok && res.code.is_none(), "{:?}", res);
+        assert_eq!(res.template_id.as_deref(), Some("b"));
+        assert_eq!((res.threshold, res.metric), (Some(0.5), Some(Metric::Cosine)));
+        assert_eq!(res.diagnostics.face_frames, res.frames);
+        assert_eq!(res.diagnostics.embedder.as_deref(), Some("fake"));
+        assert!((res.score.unwrap() - 0.8).abs() < 1e-6);
+        assert_eq!(res.probe.as_deref(), Some(&[0.0, 1.0][..]));
+        assert_eq!((res.frames, res.frame_scores.len()), (matcher::CONFIDENT_FRAMES, matcher::CONFIDENT_FRAMES));
+        assert!(res.elapsed_ms < 2000);
+
+        let res = run(vec![face.clone()], Some(vec![0.0, 1.0]), &templates, 0.95, 2000);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::NoMatch), Some("no_match")));
+        assert_eq!(res.template_id.as_deref(), Some("b"));
+        assert_eq!(res.frames, matcher::CONFIDENT_FRAMES);
+
+        // Close to the threshold, and without a face, it keeps looking until the deadline
+        let res = run(vec![face.clone()], Some(vec![0.0, 1.0]), &templates, 0.78, 100);
+        assert!(res.ok, "{:?}", res);
+        assert!(res.elapsed_ms >= 100 && res.frames > matcher::CONFIDENT_FRAMES, "{:?}", res);
+        assert_eq!(res.frame_scores.len(), res.frames);
+        let res = run(vec![], Some(vec![0.0, 1.0]), &templates, 0.5, 100);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::NoFace), Some("no_face")));
+        assert!(res.elapsed_ms >= 100 && res.frames > 1 && res.frame_scores.is_empty(), "{:?}", res);
+
+        // Two faces of a size: unclear whose to match, so none is
+        let mut other = face.clone();
+        other.bbox.x += 25.0;
+        let res = run(vec![face.clone(), other], Some(vec![0.0, 1.0]), &templates, 0.5, 100);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::MultipleFaces), Some("multiple_faces")));
+        assert_eq!(res.diagnostics.ambiguous_frames, res.frames);
+        let mut small = face.clone();
+        small.bbox.width = 8.0;
+        assert!(run(vec![face.clone(), small], Some(vec![0.0, 1.0]), &templates, 0.5, 2000).ok);
+
+        // One frame is not enough to decide on
+        let res = run(vec![face.clone()], Some(vec![0.0, 1.0]), &templates, 0.5, 0);
+        assert_eq!((res.ok, res.code, res.frames), (false, Some(ReasonCode::Timeout), 1));
+        assert_eq!(res.frame_scores.len(), 1);
+
+        // A face that never moves is a photo as far as liveness goes, whatever
+        // it scores, and the reason says which check failed
+        let mut detector = FakeDetector(vec![face.clone()]);
+        let mut embedder = FakeEmbedder(vec![0.0, 1.0]);
+        let checks = Liveness::from_config(&LivenessConfig::default());
+        let live = Verifier { liveness: Some(&checks), ..verifier(Some(&mut detector), Some(&mut embedder), &templates, 0.5) };
+        let res = verify_once(&mut src, live, 2000).unwrap();
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::LivenessFailed), Some("liveness_motion")));
+        let liveness = res.liveness.unwrap();
+        assert!(liveness.checks.iter().any(|c| c.check == "motion" && c.score == 0.0), "{:?}", liveness);
+        assert_eq!(res.template_id.as_deref(), Some("b"));
+        // Stopped as soon as the burst was judged
+        assert_eq!(res.frames, LivenessConfig::default().frames);
+
+        let no_models = verifier(None, None, &templates, 0.5);
+        let res = verify_once(&mut src, no_models, 2000).unwrap();
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::ModelUnavailable), Some("detector_unavailable")));
+    }
+
+    /// Hands out `first` for the first `after` calls, then `then`.
+    struct Switching<T> {
+        first: T,
+        then: T,
+        after: usize,
+        calls: usize,
+    }
+
+    impl<T: Clone> Switching<T> {
+        fn new(first: T, then: T, after: usize) -> Self {
+            Self { first, then, after, calls: 0 }
+        }
+
+        fn next(&mut self) -> T {
+            self.calls += 1;
+            if self.calls <= self.after { self.first.clone() } else { self.then.clone() }
+        }
+    }
+
+    impl Detector for Switching<Vec<Detection>> {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn detect(&mut self, _image: &image::RgbImage) -> anyhow::Result<Vec<Detection>> {
+            Ok(self.next())
+        }
+    }
+
+    impl Embedder for Switching<Vec<f32>> {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn embed(&mut self, _image: &image::RgbImage, _face: &Detection) -> anyhow::Result<Vec<f32>> {
+            Ok(self.next())
+        }
+    }
+
+    /// Finds every burst live.
+    struct AlwaysLive;
+
+    impl crate::liveness::LivenessCheck for AlwaysLive {
+        fn name(&self) -> &'static str {
+            "always"
+        }
+
+        fn score(&self, _frames: &[LiveFrame]) -> Option<f32> {
+            Some(1.0)
+        }
+    }
+
+    #[test]
+    fn matched_faces_must_be_the_live_one() {
+        use crate::liveness::LivenessConfig;
+        use crate::frame::PixelFormat;
+        use crate::frame_source::SyntheticSource;
+
+        let face = face();
+        let mut elsewhere = face.clone();
+        elsewhere.bbox.x += 30.0;
+        let templates = vec![template("b", vec![0.0, 1.0])];
+        let config = LivenessConfig::default();
+        let checks = Liveness::new(vec![Box::new(AlwaysLive)], &config);
+        let mut src = SyntheticSource::new(64, 48, PixelFormat::Yuyv).unwrap();
+        let mut run = |detector: &mut dyn Detector, embedder: &mut dyn Embedder, threshold: f32, timeout: u64| {
+            let verifier = Verifier {
+                liveness: Some(&checks),
+                ..verifier(Some(detector), Some(embedder), &templates, threshold)
+            };
+            verify_once(&mut src, verifier, timeout).unwrap()
+        };
+
+        // Close to the threshold, the live face gets every frame until the
+        // deadline, not just the burst's
+        let res = run(&mut FakeDetector(vec![face.clone()]), &mut FakeEmbedder(vec![0.6, 0.8]), 0.78, 100);
+        assert!(res.ok, "{:?}", res);
+        assert!(res.elapsed_ms >= 100 && res.frames > config.frames, "{:?}", res);
+
+        // A live face that scores just short of the threshold, then a photo
+        // of the enrolled user held up beside it once liveness has passed
+        let swap = config.frames + 2;
+        let mut detector = Switching::new(vec![face.clone()], vec![elsewhere], swap);
+        let mut embedder = Switching::new(vec![0.6, 0.8], vec![0.0, 1.0], swap);
+        let res = run(&mut detector, &mut embedder, 0.82, 2000);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::LivenessFailed), Some("liveness_tracking")), "{:?}", res);
+        assert!(res.liveness.unwrap().live);
+        assert_eq!((res.frames, res.frame_scores.len()), (swap + 1, swap));
+
+        // Or in its place, after stepping out of view for a moment
+        let mut detector = Switching::new(vec![face], vec![], swap);
+        let mut embedder = Switching::new(vec![0.6, 0.8], vec![0.0, 1.0], swap);
+        let res = run(&mut detector, &mut embedder, 0.82, 2000);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::LivenessFailed), Some("liveness_tracking")), "{:?}", res);
+    }
+
+    #[test]
+    fn low_quality_frames_are_not_matched() {
+        use crate::exposure::ExposureConfig;
+        use crate::frame::PixelFormat;
+        use crate::frame_source::SyntheticSource;
+        use std::cell::RefCell;
+
+        let face = face();
+        let templates = vec![template("a", vec![0.0, 1.0])];
+        let mut src = SyntheticSource::new(64, 48, PixelFormat::Yuyv).unwrap();
+        let seen = RefCell::new(Vec::new());
+        let guide = |issue| seen.borrow_mut().push(issue);
+        let mut run = |quality: &QualityConfig, exposure: Option<&mut Exposure>| {
+            let mut detector = FakeDetector(vec![face.clone()]);
+            let mut embedder = FakeEmbedder(vec![0.0, 1.0]);
+            let verifier = Verifier {
+                quality: Some(quality),
+                guide: Some(&guide),
+                exposure,
+                ..verifier(Some(&mut detector), Some(&mut embedder), &templates, 0.5)
+            };
+            verify_once(&mut src, verifier, 100).unwrap()
+        };
+
+        // Nothing synthetic is this bright, so every frame is too dark to match
+        let res = run(&QualityConfig::parse("brightness 250 255").unwrap(), None);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::LowQuality), Some("too_dark")));
+        assert!(res.frames > 1 && res.frame_scores.is_empty(), "{:?}", res);
+        assert_eq!((res.diagnostics.low_quality_frames, res.diagnostics.face_frames), (res.frames, res.frames));
+        // The guide hears about an issue once, not on every frame
+        assert_eq!(*seen.borrow(), vec![Issue::TooDark]);
+
+        let res = run(&QualityConfig::parse("disable all").unwrap(), None);
+        assert!(res.ok && res.diagnostics.low_quality_frames == 0, "{:?}", res);
+        assert_eq!(seen.borrow().len(), 1);
+
+        // Without camera controls, frames are enhanced until the face is bright enough
+        let gate = QualityConfig::parse("brightness 200 255").unwrap();
+        assert_eq!(run(&gate, None).code, Some(ReasonCode::LowQuality));
+        let mut exposure = Exposure::software(ExposureConfig::parse("target 240").unwrap());
+        let res = run(&gate, Some(&mut exposure));
+        assert!(res.ok, "{:?}", res);
+        let report = res.diagnostics.exposure.unwrap();
+        assert!(report.enhanced && report.luminance.unwrap() > 200.0, "{:?}", report);
+        // Only the first frame went in dark
+        assert_eq!(res.diagnostics.low_quality_frames, 1);
+    }
+
+    /// A camera with a brightness control and nothing else.
+    struct FakeBrightness(std::sync::Arc<std::sync::Mutex<i32>>);
+
+    impl crate::exposure::CameraControls for FakeBrightness {
+        fn query(&mut self, id: u32) -> std::io::Result<Option<crate::exposure::Range>> {
+            Ok((id == crate::v4l2::V4L2_CID_BRIGHTNESS).then_some(crate::exposure::Range { min: -64, max: 64, step: 1 }))
+        }
+
+        fn get(&mut self, _id: u32) -> std::io::Result<i32> {
+            Ok(*self.0.lock().unwrap())
+        }
+
+        fn set(&mut self, _id: u32, value: i32) -> std::io::Result<()> {
+            *self.0.lock().unwrap() = value;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn liveness_sees_frames_as_captured() {
+        use crate::detector::Point;
+        use crate::exposure::ExposureConfig;
+        use crate::liveness::{LivenessConfig, Motion};
+        use std::sync::{Arc, Mutex};
+
+        // A dim photo: dark eyes that never blink in a face that never moves
+        let face = Detection {
+            bbox: BoundingBox { x: 16.0, y: 8.0, width: 32.0, height: 32.0 },
+            landmarks: vec![Point { x: 24.0, y: 18.0 }, Point { x: 40.0, y: 18.0 }],
+            confidence: 0.9,
+        };
+        let scene = |offset: i32| {
+            RgbImage::from_fn(64, 48, |x, y| {
+                let eye = [(24, 18), (40, 18)].iter().any(|&(ex, ey): &(u32, u32)| x.abs_diff(ex) < 3 && y.abs_diff(ey) < 3);
+                let v = match (16..48).contains(&x) && (8..40).contains(&y) {
+                    true if eye => 30,
+                    true => 70,
+                    false => 40,
+                };
+                let v = (v + offset).clamp(0, 255) as u8;
+                image::Rgb([v, v, v])
+            })
+        };
+        let templates = vec![template("a", vec![0.0, 1.0])];
+        let checks = Liveness::new(vec![Box::new(Motion)], &LivenessConfig::default());
+        let run = |exposure: &mut Exposure, brightness: &Arc<Mutex<i32>>| {
+            let mut detector = FakeDetector(vec![face.clone()]);
+            let mut embedder = FakeEmbedder(vec![0.0, 1.0]);
+            let verifier = Verifier {
+                liveness: Some(&checks),
+                exposure: Some(exposure),
+                ..verifier(Some(&mut detector), Some(&mut embedder), &templates, 0.5)
+            };
+            verify_frames(verifier, 2000, || Ok((scene(*brightness.lock().unwrap()), None))).unwrap()
+        };
+        let motion = |res: &VerificationResult| {
+            let liveness = res.liveness.as_ref().unwrap();
+            liveness.checks.iter().find(|c| c.check == "motion").map(|c| c.score)
+        };
+
+        // Brightening the camera mid-burst would look like the eyes opening
+        let brightness = Arc::new(Mutex::new(0));
+        let mut exposure = Exposure::take(Box::new(FakeBrightness(brightness.clone())), ExposureConfig::default()).unwrap();
+        let res = run(&mut exposure, &brightness);
+        assert_eq!((res.ok, res.code), (false, Some(ReasonCode::LivenessFailed)), "{:?}", res);
+        assert_eq!(motion(&res), Some(0.0));
+        assert!(*brightness.lock().unwrap() > 0 && res.diagnostics.exposure.as_ref().unwrap().adjustments > 0);
+        // The burst only began once the change had settled
+        assert!(res.frames > LivenessConfig::default().frames, "{:?}", res);
+        drop(exposure);
+
+        // And so would enhancement, were liveness to see it
+        let mut exposure = Exposure::software(ExposureConfig::parse("target 200").unwrap());
+        let res = run(&mut exposure, &Arc::new(Mutex::new(0)));
+        assert!(res.diagnostics.exposure.as_ref().unwrap().enhanced);
+        assert_eq!(motion(&res), Some(0.0));
+    }
+
+    #[test]
+    fn identification_picks_one_user_or_none() {
+        use crate::frame::PixelFormat;
+        use crate::frame_source::SyntheticSource;
+
+        let face = face();
+        let index = TemplateIndex::from_templates([
+            (1000, template("a", vec![1.0, 0.0])),
+            (1001, template("b", vec![0.6, 0.8])),
+            (1002, template("c", vec![0.0, 1.0])),
+            (1003, Template { id: "d".into(), embedding: vec![0.6, 0.8], ..Default::default() }),
+        ]);
+        let config = MatchConfig::default();
+        let mut src = SyntheticSource::new(64, 48, PixelFormat::Yuyv).unwrap();
+        let mut run = |embedding: Vec<f32>, scope: Option<&[u32]>| {
+            let mut detector = FakeDetector(vec![face.clone()]);
+            let mut embedder = FakeEmbedder(embedding);
+            let identifier = Identifier {
+                detector: Some(&mut detector),
+                embedder: Some(&mut embedder),
+                index: &index,
+                config: &config,
+                scope,
+                liveness: None,
+                challenge: None,
+                quality: None,
+                guide: None,
+                exposure: None,
+            };
+            identify_once(&mut src, identifier, 2000).unwrap()
+        };
+
+        let res = run(vec![0.6, 0.8], None);
+        assert!(res.ok && res.code.is_none(), "{:?}", res);
+        assert_eq!(res.uid, Some(1001));
+        let ranked: Vec<u32> = res.candidates.iter().map(|c| c.uid).collect();
+        assert_eq!(ranked, vec![1001, 1002, 1000]);
+        assert_eq!(res.frames, matcher::CONFIDENT_FRAMES);
+
+        // Halfway between two users is nobody's
+        let res = run(vec![0.894, 0.447], None);
+        assert_eq!((res.ok, res.uid, res.code), (false, None, Some(ReasonCode::AmbiguousMatch)));
+        assert!(res.lead.unwrap() < res.margin.unwrap());
+        let res = run(vec![0.894, 0.447], Some(&[1000, 1002]));
+        assert_eq!((res.ok, res.uid), (true, Some(1000)));
+        let res = run(vec![-1.0, 0.0], None);
+        assert_eq!((res.ok, res.code, res.reason.as_deref()), (false, Some(ReasonCode::NoMatch), Some("no_match")));
+        let res = run(vec![0.6, 0.8], Some(&[4242]));
+        assert_eq!((res.ok, res.code), (false, Some(ReasonCode::NotEnrolled)));
+        let res = run(vec![0.6, 0.8], Some(&[1003]));
+        assert_eq!((res.ok, res.code), (false, Some(ReasonCode::NeedsReenroll)));
+
+        // Locked-out users are reported as such, not identified
+        let res = run(vec![0.6, 0.8], None).locked_out(Duration::from_secs(5));
+        assert_eq!((res.ok, res.uid, res.code), (false, None, Some(ReasonCode::LockedOut)));
+    }
+
+    #[test]
+    fn result_layout_is_stable() {
+        let res = VerificationResult::locked_out(Duration::from_secs(30));
+        let json = serde_json::to_value(&res).unwrap();
+        assert_eq!(json["schema_version"], RESULT_SCHEMA_VERSION);
+        assert_eq!(json["code"], "LOCKED_OUT");
+        assert_eq!(json["diagnostics"]["retry_after_ms"], 30000);
+        // Fields that do not apply are there as null, not missing
+        let keys: Vec<&str> = json.as_object().unwrap().keys().map(|k| k.as_str()).collect();
+        for key in ["ok", "reason", "score", "threshold", "metric", "template_id", "liveness", "faces", "frames"] {
+            assert!(keys.contains(&key), "{} missing from {}", key, json);
+        }
+
+        let busy = CameraBusy { device: "/dev/video0".into(), holders: vec![Holder { pid: 42, comm: "zoom".into() }] };
+        let res = VerificationResult::camera_busy(&busy);
+        assert_eq!((res.code, res.reason.as_deref()), (Some(ReasonCode::CameraBusy), Some("camera_busy: /dev/video0 is in use by zoom (pid 42)")));
+        assert_eq!(res.diagnostics.camera_holders, busy.holders);
+    }
+
+    #[test]
+    fn fit_within_keeps_aspect_and_never_upscales() {
+        assert_eq!(fit_within(640, 480, 320, 240), (320, 240));
+        assert_eq!(fit_within(640, 480, 100, 1000), (100, 75));
+        assert_eq!(fit_within(64, 48, 320, 240), (64, 48));
+    }
+
+    #[test]
+    fn selector_prefers_pinned_auth_camera() {
+        let probe = FakeProbe {
+            devices: HashMap::from([
+                (PathBuf::from("/dev/video0"), webcam()),
+                (PathBuf::from("/dev/video2"), webcam()),
+                (PathBuf::from("/dev/video3"), metadata_node()),
+            ]),
+        };
+        let cameras = list_cameras_with(&probe);
+
+        assert_eq!(CameraSelector::Default.resolve(&cameras, None).unwrap().id, "video0");
+        assert_eq!(CameraSelector::Default.resolve(&cameras, Some("video2")).unwrap().id, "video2");
+        // A missing pinned camera must not silently fall back to another one
+        assert!(CameraSelector::Default.resolve(&cameras, Some("usb-gone")).is_err());
+        // Metadata nodes are never capture targets
+        assert!(CameraSelector::Id("video3".into()).resolve(&cameras, None).is_err());
+        assert_eq!(CameraSelector::Index(2).resolve(&cameras, None).unwrap().path, "/dev/video2");
+
+        let params = serde_json::json!({"camera": "video2", "index": 0});
+        assert_eq!(CameraSelector::from_params(Some(&params)), CameraSelector::Id("video2".into()));
+    }
+
+    #[test]
+    fn unprobeable_node_falls_back_to_generic_name() {
+        let probe = FakeProbe { devices: HashMap::new() };
+        let cameras = list_cameras_with(&probe);
+        assert_eq!(cameras.len(), 1);
+        assert_eq!(cameras[0].name, "Camera Device 7");
+        assert_eq!(cameras[0].capabilities, CameraCapabilities::default());
+    }
+}
@@ -0,0 +1,261 @@
+// src/camera_id.rs
+//! Stable camera identifiers.
+//!
+//! `/dev/videoN` numbering depends on probe order and changes across reboots
+//! and replugs. We identify a node by, in order of preference:
+//!
+//! 1. its udev `/dev/v4l/by-id` link (vendor, model, serial and interface),
+//! 2. its `/dev/v4l/by-path` link (physical port),
+//! 3. USB vendor/product/serial read from sysfs,
+//! 4. the node name itself, as a last resort.
+//!
+//! The RGB and IR sensors of one camera are separate USB interfaces of the
+//! same device. udev names the by-id link after the device and the node's
+//! index within its interface, so both sensors claim the same
+//! `...-video-index0` link, and which one gets it can change between boots.
+//! IDs from by-id links and sysfs therefore carry the interface number as
+//! `-if<NN>`, the way udev names the links of other multi-interface devices.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const AUTH_CAMERA_PATH: &str = "/etc/ola/auth_camera";
+
+pub struct IdResolver {
+    dev_dir: PathBuf,
+    sys_dir: PathBuf,
+}
+
+impl Default for IdResolver {
+    fn default() -> Self {
+        Self::new(Path::new("/dev"), Path::new("/sys"))
+    }
+}
+
+impl IdResolver {
+    pub fn new(dev_dir: &Path, sys_dir: &Path) -> Self {
+        Self { dev_dir: dev_dir.to_path_buf(), sys_dir: sys_dir.to_path_buf() }
+    }
+
+    pub fn stable_id(&self, node: &Path) -> Option<String> {
+        let target = fs::canonicalize(node).ok()?;
+        self.by_id(node, &target)
+            .or_else(|| self.link_name("by-path", &target))
+            .or_else(|| self.sysfs_id(node))
+    }
+
+    /// The by-id link name, with the USB interface of `node` in it when known.
+    /// A sensor whose sibling got the device's link shares its stem.
+    fn by_id(&self, node: &Path, target: &Path) -> Option<String> {
+        let Some(interface) = self.usb_interface(node) else {
+            return self.link_name("by-id", target);
+        };
+        let device = self.usb_device(node);
+        let mut names: Vec<String> = fs::read_dir(self.dev_dir.join("v4l/by-id"))
+            .ok()?
+            .flatten()
+            .filter(|e| {
+                fs::canonicalize(e.path()).is_ok_and(|t| t == target || (device.is_some() && self.usb_device(&t) == device))
+            })
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect();
+        names.sort();
+        let name = names.into_iter().next()?;
+        let stem = name.rsplit_once("-video-index").map_or(name.as_str(), |(stem, _)| stem);
+        Some(format!("{}-if{}-video-index{}", stem, interface, self.video_index(node)))
+    }
+
+    /// `vid:pid` of the USB device behind `node`, e.g. `04f2:b6d9`.
+    pub fn usb_id(&self, node: &Path) -> Option<String> {
+        let usb_dev = self.usb_device(node)?;
+        let vid = read_attr(&usb_dev.join("idVendor"))?;
+        let pid = read_attr(&usb_dev.join("idProduct"))?;
+        Some(format!("{}:{}", vid, pid))
+    }
+
+    /// Name of the udev symlink in `/dev/v4l/<kind>` that points at `target`.
+    fn link_name(&self, kind: &str, target: &Path) -> Option<String> {
+        let mut names: Vec<String> = fs::read_dir(self.dev_dir.join("v4l").join(kind))
+            .ok()?
+            .flatten()
+            .filter(|e| fs::canonicalize(e.path()).is_ok_and(|t| t == target))
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect();
+        // Deterministic choice if udev created more than one alias
+        names.sort();
+        names.into_iter().next()
+    }
+
+    /// `usb-<vid>_<pid>[_<serial>][-if<NN>]-video-index<N>` from sysfs attributes.
+    fn sysfs_id(&self, node: &Path) -> Option<String> {
+        let index = self.video_index(node);
+        let usb_dev = self.usb_device(node)?;
+        let vid = read_attr(&usb_dev.join("idVendor"))?;
+        let pid = read_attr(&usb_dev.join("idProduct"))?;
+        let mut id = format!("usb-{}_{}", vid, pid);
+        if let Some(serial) = read_attr(&usb_dev.join("serial")) {
+            id.push('_');
+            id.push_str(&sanitize(&serial));
+        }
+        if let Some(interface) = self.usb_interface(node) {
+            id.push_str("-if");
+            id.push_str(&interface);
+        }
+        Some(format!("{}-video-index{}", id, index))
+    }
+
+    /// The node's index among the nodes of its interface, `0` if unknown.
+    fn video_index(&self, node: &Path) -> String {
+        self.class_dir(node).and_then(|d| read_attr(&d.join("index"))).unwrap_or_else(|| "0".to_string())
+    }
+
+    fn class_dir(&self, node: &Path) -> Option<PathBuf> {
+        let name = node.file_name()?.to_str()?;
+        Some(self.sys_dir.join("class/video4linux").join(name))
+    }
+
+    /// The USB interface the node belongs to.
+    fn interface_dir(&self, node: &Path) -> Option<PathBuf> {
+        fs::canonicalize(self.class_dir(node)?.join("device")).ok()
+    }
+
+    /// `bInterfaceNumber` of the node's USB interface, e.g. `02`.
+    fn usb_interface(&self, node: &Path) -> Option<String> {
+        read_attr(&self.interface_dir(node)?.join("bInterfaceNumber"))
+    }
+
+    /// device -> USB interface; its parent holds the device descriptor attributes.
+    fn usb_device(&self, node: &Path) -> Option<PathBuf> {
+        Some(self.interface_dir(node)?.parent()?.to_path_buf())
+    }
+}
+
+fn read_attr(path: &Path) -> Option<String> {
+    let v = fs::read_to_string(path).ok()?;
+    let v = v.trim();
+    (!v.is_empty()).then(|| v.to_string())
+}
+
+fn sanitize(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect()
+}
+
+/// The camera used for authentication: `OLA_AUTH_CAMERA`, else the first
+/// non-comment line of `/etc/ola/auth_camera`.
+pub fn configured_auth_camera() -> Option<String> {
+    if let Ok(id) = std::env::var("OLA_AUTH_CAMERA") {
+        return (!id.trim().is_empty()).then(|| id.trim().to_string());
+    }
+    let content = fs::read_to_string(AUTH_CAMERA_PATH).ok()?;
+    content
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or("").trim())
+        .find(|l| !l.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("ola-camid-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("dev/v4l/by-id")).unwrap();
+            fs::create_dir_all(root.join("dev/v4l/by-path")).unwrap();
+            fs::create_dir_all(root.join("sys/class/video4linux")).unwrap();
+            Tree(root)
+        }
+
+        fn node(&self, name: &str) -> PathBuf {
+            let p = self.0.join("dev").join(name);
+            fs::write(&p, b"").unwrap();
+            p
+        }
+
+        fn resolver(&self) -> IdResolver {
+            IdResolver::new(&self.0.join("dev"), &self.0.join("sys"))
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn prefers_by_id_then_by_path() {
+        let t = Tree::new("links");
+        let v0 = t.node("video0");
+        let v2 = t.node("video2");
+        symlink("../../video0", t.0.join("dev/v4l/by-id/usb-Chicony_Integrated_Camera_0001-video-index0")).unwrap();
+        symlink("../../video0", t.0.join("dev/v4l/by-path/pci-0000:00:14.0-usb-0:8:1.0-video-index0")).unwrap();
+        symlink("../../video2", t.0.join("dev/v4l/by-path/pci-0000:00:14.0-usb-0:8:1.2-video-index0")).unwrap();
+
+        let r = t.resolver();
+        assert_eq!(r.stable_id(&v0).as_deref(), Some("usb-Chicony_Integrated_Camera_0001-video-index0"));
+        assert_eq!(r.stable_id(&v2).as_deref(), Some("pci-0000:00:14.0-usb-0:8:1.2-video-index0"));
+    }
+
+    #[test]
+    fn falls_back_to_usb_sysfs_attributes() {
+        let t = Tree::new("sysfs");
+        let v4 = t.node("video4");
+        let usb = t.0.join("sys/devices/usb1/1-8");
+        fs::create_dir_all(usb.join("1-8:1.0")).unwrap();
+        fs::write(usb.join("idVendor"), "04f2\n").unwrap();
+        fs::write(usb.join("idProduct"), "b6d9\n").unwrap();
+        fs::write(usb.join("serial"), "SN 42\n").unwrap();
+        let class = t.0.join("sys/class/video4linux/video4");
+        fs::create_dir_all(&class).unwrap();
+        fs::write(class.join("index"), "1\n").unwrap();
+        symlink(usb.join("1-8:1.0"), class.join("device")).unwrap();
+
+        assert_eq!(t.resolver().stable_id(&v4).as_deref(), Some("usb-04f2_b6d9_SN_42-video-index1"));
+        assert_eq!(t.resolver().usb_id(&v4).as_deref(), Some("04f2:b6d9"));
+    }
+
+    #[test]
+    fn sensors_of_one_device_keep_their_own_ids() {
+        let t = Tree::new("pair");
+        let usb = t.0.join("sys/devices/usb1/1-8");
+        for (node, interface, number) in [("video0", "1-8:1.0", "00"), ("video2", "1-8:1.2", "02")] {
+            fs::create_dir_all(usb.join(interface)).unwrap();
+            fs::write(usb.join(interface).join("bInterfaceNumber"), format!("{}\n", number)).unwrap();
+            let class = t.0.join("sys/class/video4linux").join(node);
+            fs::create_dir_all(&class).unwrap();
+            fs::write(class.join("index"), "0\n").unwrap();
+            symlink(usb.join(interface), class.join("device")).unwrap();
+        }
+        let (rgb, ir) = (t.node("video0"), t.node("video2"));
+        let link = t.0.join("dev/v4l/by-id/usb-Chicony_Integrated_Camera_0001-video-index0");
+        let r = t.resolver();
+
+        // udev gave the shared link to the IR node this boot, and to the RGB one the next
+        symlink("../../video2", &link).unwrap();
+        let ids = (r.stable_id(&rgb), r.stable_id(&ir));
+        assert_eq!(ids.0.as_deref(), Some("usb-Chicony_Integrated_Camera_0001-if00-video-index0"));
+        assert_eq!(ids.1.as_deref(), Some("usb-Chicony_Integrated_Camera_0001-if02-video-index0"));
+        fs::remove_file(&link).unwrap();
+        symlink("../../video0", &link).unwrap();
+        assert_eq!((r.stable_id(&rgb), r.stable_id(&ir)), ids);
+
+        // Without udev, sysfs tells them apart the same way
+        fs::remove_file(&link).unwrap();
+        fs::write(usb.join("idVendor"), "04f2\n").unwrap();
+        fs::write(usb.join("idProduct"), "b6d9\n").unwrap();
+        assert_eq!(r.stable_id(&rgb).as_deref(), Some("usb-04f2_b6d9-if00-video-index0"));
+        assert_eq!(r.stable_id(&ir).as_deref(), Some("usb-04f2_b6d9-if02-video-index0"));
+    }
+
+    #[test]
+    fn unknown_node_has_no_stable_id() {
+        let t = Tree::new("none");
+        let v9 = t.node("video9");
+        assert_eq!(t.resolver().stable_id(&v9), None);
+    }
+}
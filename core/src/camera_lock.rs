@@ -0,0 +1,221 @@
+// src/camera_lock.rs
+//! Exclusive camera claims and in-use detection.
+//!
+//! While a session is open the daemon holds an exclusive `flock` on the device
+//! node, so a second daemon (or anything else that honours the lock) backs off
+//! instead of fighting over the camera. Browsers and video call apps do not
+//! lock; once they stream, the driver answers our format and buffer setup with
+//! EBUSY. Either way we look through `/proc/*/fd` for whoever has the node open
+//! so the client can tell the user which application to close.
+use anyhow::Context;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use super::frame::Frame;
+use super::frame_source::FrameSource;
+use super::v4l2;
+
+const PROC_DIR: &str = "/proc";
+
+/// A process with the device open.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Holder {
+    pub pid: u32,
+    pub comm: String,
+}
+
+/// The camera is in use elsewhere. Reported to clients as `camera_busy`.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CameraBusy {
+    pub device: String,
+    /// Empty when the holder is not visible to us, e.g. a process of another
+    /// user while the daemon runs without CAP_SYS_PTRACE.
+    pub holders: Vec<Holder>,
+}
+
+impl CameraBusy {
+    pub fn new(device: &Path) -> Self {
+        Self {
+            device: device.display().to_string(),
+            holders: holders(Path::new(PROC_DIR), device, std::process::id()),
+        }
+    }
+}
+
+impl std::fmt::Display for CameraBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "camera_busy: {} is in use", self.device)?;
+        for (i, h) in self.holders.iter().enumerate() {
+            let sep = if i == 0 { " by" } else { "," };
+            write!(f, "{} {} (pid {})", sep, h.comm, h.pid)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CameraBusy {}
+
+/// Processes other than `own_pid` with an fd on `device`, by pid. Processes
+/// whose fd table we may not read are skipped.
+pub fn holders(proc_dir: &Path, device: &Path, own_pid: u32) -> Vec<Holder> {
+    let target = fs::canonicalize(device).unwrap_or_else(|_| device.to_path_buf());
+    let Ok(entries) = fs::read_dir(proc_dir) else { return Vec::new() };
+
+    let mut found = Vec::new();
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse::<u32>().ok()) else { continue };
+        if pid == own_pid {
+            continue;
+        }
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else { continue };
+        let open = fds.flatten().any(|fd| fs::read_link(fd.path()).is_ok_and(|link| link == target));
+        if open {
+            let comm = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            found.push(Holder { pid, comm: comm.trim().to_string() });
+        }
+    }
+    found.sort_by_key(|h| h.pid);
+    found
+}
+
+/// Whether an open or capture error came from the driver refusing a second user.
+pub fn is_busy(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.downcast_ref::<Errno>() == Some(&Errno::EBUSY)
+            || e.downcast_ref::<std::io::Error>().and_then(|e| e.raw_os_error()) == Some(libc::EBUSY)
+    })
+}
+
+/// An exclusive lock on a device node, released when dropped.
+#[derive(Debug)]
+pub struct DeviceClaim {
+    _file: File,
+}
+
+impl DeviceClaim {
+    /// Claims `device`, or fails with `CameraBusy` if someone else holds it.
+    pub fn acquire(device: &Path) -> anyhow::Result<Self> {
+        let file = v4l2::open_device(device).with_context(|| format!("opening {}", device.display()))?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(Errno::EWOULDBLOCK) => Err(CameraBusy::new(device).into()),
+            Err(e) => Err(e).with_context(|| format!("locking {}", device.display())),
+        }
+    }
+
+    /// Ties the claim to `source`; the lock is released after the source is closed.
+    pub fn hold(self, source: Box<dyn FrameSource>) -> ClaimedSource {
+        ClaimedSource { source, _claim: self }
+    }
+}
+
+/// A source that keeps its device claimed while open.
+pub struct ClaimedSource {
+    // Field order matters: the device is closed before the lock goes
+    source: Box<dyn FrameSource>,
+    _claim: DeviceClaim,
+}
+
+impl FrameSource for ClaimedSource {
+    fn backend(&self) -> &'static str {
+        self.source.backend()
+    }
+
+    fn next_frame(&mut self) -> anyhow::Result<Frame<'_>> {
+        self.source.next_frame()
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.source.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    struct Proc(PathBuf);
+
+    impl Proc {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("ola-lock-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Proc(root)
+        }
+
+        fn device(&self) -> PathBuf {
+            let p = self.0.join("video0");
+            fs::write(&p, b"").unwrap();
+            p
+        }
+
+        fn process(&self, pid: u32, comm: &str, fds: &[&Path]) {
+            let dir = self.0.join(pid.to_string());
+            fs::create_dir_all(dir.join("fd")).unwrap();
+            fs::write(dir.join("comm"), format!("{}\n", comm)).unwrap();
+            for (n, target) in fds.iter().enumerate() {
+                symlink(target, dir.join("fd").join(n.to_string())).unwrap();
+            }
+        }
+    }
+
+    impl Drop for Proc {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn finds_other_processes_holding_the_node() {
+        let proc = Proc::new("holders");
+        let dev = proc.device();
+        proc.process(4242, "zoom", &[Path::new("/dev/null"), &dev]);
+        proc.process(77, "firefox", &[&dev]);
+        proc.process(100, "ola-core", &[&dev]);
+        proc.process(12, "bash", &[Path::new("/dev/null")]);
+        fs::create_dir_all(proc.0.join("self")).unwrap();
+
+        let found = holders(&proc.0, &dev, 100);
+        assert_eq!(
+            found,
+            vec![
+                Holder { pid: 77, comm: "firefox".into() },
+                Holder { pid: 4242, comm: "zoom".into() },
+            ]
+        );
+
+        let busy = CameraBusy { device: "/dev/video0".into(), holders: found };
+        assert_eq!(busy.to_string(), "camera_busy: /dev/video0 is in use by firefox (pid 77), zoom (pid 4242)");
+        let busy = CameraBusy { device: "/dev/video0".into(), holders: vec![] };
+        assert_eq!(busy.to_string(), "camera_busy: /dev/video0 is in use");
+    }
+
+    #[test]
+    fn second_claim_is_busy_until_released() {
+        let proc = Proc::new("claim");
+        let dev = proc.device();
+        let claim = DeviceClaim::acquire(&dev).unwrap();
+
+        let err = DeviceClaim::acquire(&dev).unwrap_err();
+        assert!(err.downcast_ref::<CameraBusy>().is_some());
+
+        drop(claim);
+        assert!(DeviceClaim::acquire(&dev).is_ok());
+    }
+
+    #[test]
+    fn ebusy_anywhere_in_the_chain_counts() {
+        let err = anyhow::Error::new(Errno::EBUSY).context("VIDIOC_S_FMT").context("opening camera");
+        assert!(is_busy(&err));
+        let err = anyhow::Error::new(std::io::Error::from_raw_os_error(libc::EBUSY)).context("opening /dev/video0");
+        assert!(is_busy(&err));
+        assert!(!is_busy(&anyhow::Error::new(Errno::ENODEV)));
+    }
+}
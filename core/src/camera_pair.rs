@@ -0,0 +1,183 @@
+// src/camera_pair.rs
+//! RGB+IR camera pairs.
+//!
+//! Dual-sensor modules show up as two capture nodes on the same USB device,
+//! one RGB and one IR. Opened together they give liveness two views of the
+//! same moment, as long as the frames are matched up by capture time.
+use anyhow::bail;
+use std::time::Duration;
+
+use super::camera::CameraInfo;
+use super::frame::Frame;
+use super::frame_source::FrameSource;
+use super::ir::SensorKind;
+
+/// Frames further apart than this are not treated as the same moment.
+/// Comfortably under one frame period at 30 fps.
+pub const DEFAULT_MAX_SKEW: Duration = Duration::from_millis(20);
+
+/// How many extra frames we read while trying to line the two streams up.
+const MAX_SYNC_FRAMES: usize = 8;
+
+/// Links each RGB node to the IR node of the same module through
+/// `paired_with`. A bus with anything other than exactly one RGB and one IR
+/// capture node is left unpaired rather than guessed at.
+pub fn link_pairs(cameras: &mut [CameraInfo]) {
+    for i in 0..cameras.len() {
+        let bus = &cameras[i].bus_info;
+        if bus.is_empty() || cameras[i].sensor.is_none() {
+            continue;
+        }
+        let siblings: Vec<&CameraInfo> = cameras.iter().filter(|c| &c.bus_info == bus && c.sensor.is_some()).collect();
+        let rgb: Vec<_> = siblings.iter().filter(|c| c.sensor == Some(SensorKind::Rgb)).collect();
+        let ir: Vec<_> = siblings.iter().filter(|c| c.sensor == Some(SensorKind::Ir)).collect();
+        let partner = match (rgb.as_slice(), ir.as_slice()) {
+            ([rgb], [ir]) if siblings.len() == 2 => {
+                if cameras[i].sensor == Some(SensorKind::Rgb) { ir.id.clone() } else { rgb.id.clone() }
+            }
+            _ => continue,
+        };
+        cameras[i].paired_with = Some(partner);
+    }
+}
+
+/// An RGB frame and an IR frame captured at (nearly) the same time.
+#[derive(Debug)]
+pub struct PairedFrames {
+    pub rgb: Frame<'static>,
+    pub ir: Frame<'static>,
+    /// Absolute timestamp difference between the two.
+    pub skew: Duration,
+}
+
+/// Two open sources read as one camera.
+pub struct CameraPair {
+    rgb: Box<dyn FrameSource>,
+    ir: Box<dyn FrameSource>,
+    max_skew: Duration,
+}
+
+impl CameraPair {
+    pub fn new(rgb: Box<dyn FrameSource>, ir: Box<dyn FrameSource>) -> Self {
+        Self { rgb, ir, max_skew: DEFAULT_MAX_SKEW }
+    }
+
+    /// Gives the two sources back, RGB first.
+    pub fn into_sources(self) -> (Box<dyn FrameSource>, Box<dyn FrameSource>) {
+        (self.rgb, self.ir)
+    }
+
+    pub fn with_max_skew(mut self, max_skew: Duration) -> Self {
+        self.max_skew = max_skew;
+        self
+    }
+
+    /// Reads one frame from each side, then keeps advancing whichever stream is
+    /// behind until the timestamps are within `max_skew`.
+    pub fn next_pair(&mut self) -> anyhow::Result<PairedFrames> {
+        let mut rgb = self.rgb.next_frame()?.into_owned();
+        let mut ir = self.ir.next_frame()?.into_owned();
+
+        for _ in 0..=MAX_SYNC_FRAMES {
+            let skew = rgb.timestamp.abs_diff(ir.timestamp);
+            if skew <= self.max_skew {
+                return Ok(PairedFrames { rgb, ir, skew });
+            }
+            if rgb.timestamp < ir.timestamp {
+                rgb = self.rgb.next_frame()?.into_owned();
+            } else {
+                ir = self.ir.next_frame()?.into_owned();
+            }
+        }
+        bail!(
+            "RGB and IR streams did not line up within {:?} (last skew {:?})",
+            self.max_skew,
+            rgb.timestamp.abs_diff(ir.timestamp)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::CameraCapabilities;
+    use crate::frame::PixelFormat;
+    use crate::frame_source::SyntheticSource;
+
+    fn source(format: PixelFormat, start_ms: u64, interval_ms: u64) -> Box<dyn FrameSource> {
+        Box::new(
+            SyntheticSource::new(64, 48, format)
+                .unwrap()
+                .with_clock(Duration::from_millis(start_ms), Duration::from_millis(interval_ms)),
+        )
+    }
+
+    #[test]
+    fn aligned_streams_pair_immediately() {
+        let mut pair = CameraPair::new(source(PixelFormat::Yuyv, 0, 33), source(PixelFormat::Grey, 5, 33));
+        let frames = pair.next_pair().unwrap();
+        assert_eq!((frames.rgb.sequence, frames.ir.sequence), (1, 1));
+        assert_eq!(frames.rgb.format, PixelFormat::Yuyv);
+        assert_eq!(frames.ir.format, PixelFormat::Grey);
+        assert_eq!(frames.skew, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn lagging_stream_is_advanced() {
+        // IR started 70ms later: the RGB side has to skip ahead two frames
+        let mut pair = CameraPair::new(source(PixelFormat::Yuyv, 0, 33), source(PixelFormat::Grey, 70, 33));
+        let frames = pair.next_pair().unwrap();
+        assert_eq!((frames.rgb.sequence, frames.ir.sequence), (3, 1));
+        assert_eq!(frames.skew, Duration::from_millis(4));
+
+        // 15 fps IR against 30 fps RGB still lines up every other RGB frame
+        let mut pair = CameraPair::new(source(PixelFormat::Yuyv, 0, 33), source(PixelFormat::Grey, 0, 66));
+        for _ in 0..3 {
+            let frames = pair.next_pair().unwrap();
+            assert!(frames.skew <= DEFAULT_MAX_SKEW);
+        }
+    }
+
+    #[test]
+    fn streams_that_never_meet_are_an_error() {
+        let mut pair = CameraPair::new(source(PixelFormat::Yuyv, 0, 33), source(PixelFormat::Grey, 10_000, 33));
+        assert!(pair.next_pair().is_err());
+
+        let mut pair = CameraPair::new(source(PixelFormat::Yuyv, 0, 33), source(PixelFormat::Grey, 16, 33))
+            .with_max_skew(Duration::from_millis(10));
+        assert!(pair.next_pair().is_err());
+    }
+
+    fn camera(id: &str, bus: &str, sensor: Option<SensorKind>) -> CameraInfo {
+        CameraInfo {
+            id: id.into(),
+            path: format!("/dev/{}", id),
+            name: id.into(),
+            index: 0,
+            driver: "uvcvideo".into(),
+            card: id.into(),
+            bus_info: bus.into(),
+            usb_id: None,
+            capabilities: CameraCapabilities { video_capture: sensor.is_some(), ..Default::default() },
+            formats: vec![],
+            sensor,
+            metadata_node: None,
+            paired_with: None,
+        }
+    }
+
+    #[test]
+    fn pairs_rgb_and_ir_on_the_same_bus() {
+        let mut cams = vec![
+            camera("video0", "usb-1", Some(SensorKind::Rgb)),
+            camera("video1", "usb-1", None),
+            camera("video2", "usb-1", Some(SensorKind::Ir)),
+            camera("video4", "usb-2", Some(SensorKind::Rgb)),
+            camera("video6", "usb-3", Some(SensorKind::Ir)),
+            camera("video8", "usb-3", Some(SensorKind::Ir)),
+        ];
+        link_pairs(&mut cams);
+        let paired: Vec<_> = cams.iter().map(|c| c.paired_with.as_deref()).collect();
+        assert_eq!(paired, vec![Some("video2"), None, Some("video0"), None, None, None]);
+    }
+}
@@ -1,46 +1,917 @@
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use anyhow::Context;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use super::adapt::{self, AdaptConfig, AuditLog};
 use super::camera;
+use super::camera_id;
+use super::camera_lock::{self, CameraBusy, DeviceClaim};
+use super::camera_pair::CameraPair;
+use super::capture_stats::{CaptureReport, CaptureStats, TimedSource};
+use super::convert;
+use super::detector::{Detector, OnnxDetector};
+use super::embedder::{Embedder, OnnxEmbedder};
+use super::enroll;
+use super::exposure::{self, Exposure, ExposureConfig, V4l2Controls};
+use super::frame_source::{Backend, FrameSource, SourceConfig};
+use super::hotplug::{self, CameraEvent, CameraRegistry, HotplugEvent, WatchMode};
+use super::identify::TemplateIndex;
+use super::ir;
+use super::liveness::{self, Challenge, Liveness, LivenessConfig};
+use super::lockout::Lockout;
+use super::matcher::{self, MatchConfig};
+use super::models::{ModelInfo, ModelRegistry, Role};
+use super::quality::{self, Issue, QualityConfig};
+use super::session::{self, SessionPool, SessionStatus};
+use super::templates::{self, Migration, Staleness, Template, TemplateInfo, TemplateStore};
+
+/// Longest a client may ask to wait for a busy camera.
+pub const MAX_QUEUE_WAIT: Duration = Duration::from_secs(30);
+
+/// `verify_once` keeps looking this long unless the client says otherwise.
+pub const DEFAULT_VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default when a head turn is asked for, which takes longer than a glance.
+pub const DEFAULT_CHALLENGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bounds on the `timeout_ms` a client may ask `verify_once` for.
+pub const MIN_VERIFY_TIMEOUT: Duration = Duration::from_millis(500);
+pub const MAX_VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the RPC layer gives a verification before answering for it:
+/// the longest capture, plus opening the camera, loading models and the
+/// detection and embedding that follow the last frame.
+pub const VERIFY_RPC_TIMEOUT: Duration = Duration::from_secs(MAX_VERIFY_TIMEOUT.as_secs() + 5);
+
+/// How often queued requests try the camera again.
+const QUEUE_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Which camera a capture is for, and how long the client is willing to wait
+/// for it if another process has it.
+#[derive(Debug, Clone)]
+pub struct CameraTarget {
+    pub selector: camera::CameraSelector,
+    pub wait: Duration,
+}
+
+impl CameraTarget {
+    /// Reads the camera selector plus `wait_ms` (capped at `MAX_QUEUE_WAIT`).
+    pub fn from_params(params: Option<&serde_json::Value>) -> Self {
+        let wait_ms = params.and_then(|p| p.get("wait_ms")).and_then(|v| v.as_u64()).unwrap_or(0);
+        Self {
+            selector: camera::CameraSelector::from_params(params),
+            wait: Duration::from_millis(wait_ms).min(MAX_QUEUE_WAIT),
+        }
+    }
+}
+
+/// Reads `timeout_ms` for `verify_once`, clamped to the allowed range.
+pub fn verify_timeout(params: Option<&serde_json::Value>, challenge: bool) -> Duration {
+    let default = if challenge { DEFAULT_CHALLENGE_TIMEOUT } else { DEFAULT_VERIFY_TIMEOUT };
+    params
+        .and_then(|p| p.get("timeout_ms"))
+        .and_then(|v| v.as_u64())
+        .map_or(default, Duration::from_millis)
+        .clamp(MIN_VERIFY_TIMEOUT, MAX_VERIFY_TIMEOUT)
+}
+
+/// Where quality issues go while a request captures, for clients that asked
+/// for live guidance.
+pub type Guide = mpsc::UnboundedSender<Issue>;
+
+/// A detector and embedder from one model set, either possibly missing.
+type Models = (Option<Box<dyn Detector>>, Option<Box<dyn Embedder>>);
 
 #[derive(Debug)]
 pub enum CameraRequest {
     ListCameras(oneshot::Sender<Vec<camera::CameraInfo>>),
-    CaptureThumbnail(usize, oneshot::Sender<anyhow::Result<String>>),
-    VerifyOnce(u64, oneshot::Sender<anyhow::Result<camera::VerificationResult>>),
+    CaptureThumbnail(CameraTarget, camera::ThumbnailOptions, oneshot::Sender<anyhow::Result<camera::Thumbnail>>),
+    /// Verifies the user with the given uid; the `u64` is the timeout in ms,
+    /// then the head-turn challenge the client asked for, if any, and where
+    /// to send guidance.
+    VerifyOnce(CameraTarget, u32, u64, Option<Challenge>, Option<Guide>, oneshot::Sender<anyhow::Result<camera::VerificationResult>>),
+    /// Identifies who is in front of the camera for the caller's uid, among
+    /// the given uids or everyone enrolled for `None`; then timeout,
+    /// challenge and guide as above.
+    Identify(CameraTarget, u32, Option<Vec<u32>>, u64, Option<Challenge>, Option<Guide>, oneshot::Sender<anyhow::Result<camera::IdentificationResult>>),
+    /// Captures one enrollment frame and measures the face in it.
+    EnrollFrame(CameraTarget, Option<Guide>, oneshot::Sender<anyhow::Result<enroll::Observation>>),
+    /// Adds templates for a uid, all or nothing.
+    StoreTemplates(u32, Vec<Template>, oneshot::Sender<anyhow::Result<()>>),
+    ListTemplates(u32, oneshot::Sender<anyhow::Result<Vec<TemplateInfo>>>),
+    /// Relabels a uid's template: id, new label.
+    RenameTemplate(u32, String, String, oneshot::Sender<anyhow::Result<TemplateInfo>>),
+    DeleteTemplate(u32, String, oneshot::Sender<anyhow::Result<()>>),
+    /// Users with templates the current embedder cannot use, and its model ID.
+    StaleUsers(oneshot::Sender<anyhow::Result<(String, Vec<Staleness>)>>),
+    /// Re-embeds stale templates from their retained crops, for one uid or
+    /// everyone.
+    MigrateTemplates(Option<u32>, oneshot::Sender<anyhow::Result<Vec<Migration>>>),
+    /// Loads the model set the registry lists now and swaps it in whole, or
+    /// keeps the current one if any model fails.
+    ReloadModels(oneshot::Sender<anyhow::Result<Vec<ModelInfo>>>),
+    Status(oneshot::Sender<WorkerStatus>),
+    /// Sent by the hotplug watcher thread, not by clients.
+    Hotplug(Vec<HotplugEvent>),
+}
+
+/// Worker-side state reported through the `status` RPC.
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerStatus {
+    pub backend: &'static str,
+    pub auth_camera: Option<String>,
+    /// Name of the loaded face detector; `None` until one has loaded.
+    pub detector: Option<String>,
+    /// Name of the loaded face embedder; `None` until one has loaded.
+    pub embedder: Option<String>,
+    /// Where the loaded models came from, their versions and digests.
+    pub models: Vec<ModelInfo>,
+    /// Liveness checks verification runs.
+    pub liveness: Vec<&'static str>,
+    pub capture: CaptureReport,
+    pub sessions: SessionStatus,
+    /// Requests waiting for a busy camera.
+    pub queued: usize,
+}
+
+/// Stable IDs of the RGB and IR sensors a capture used.
+type SensorIds = (Option<String>, Option<String>);
+
+/// A request that found its camera busy and is waiting for it.
+struct Waiting {
+    request: CameraRequest,
+    deadline: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    pub source: SourceConfig,
+    pub watch_mode: WatchMode,
+    pub auth_camera: Option<String>,
+    /// How long a camera stays open after its last use.
+    pub warm_period: Duration,
+    pub models: ModelRegistry,
+    pub matching: MatchConfig,
+    pub liveness: LivenessConfig,
+    pub quality: QualityConfig,
+    pub exposure: ExposureConfig,
+    pub template_dir: PathBuf,
+    pub adapt: AdaptConfig,
+    pub audit_log: PathBuf,
+}
+
+impl WorkerConfig {
+    /// Reads the source, hotplug and auth camera settings, `OLA_CAMERA_WARM_MS`,
+    /// the model registry, the match, liveness, quality, exposure and adaptation configs,
+    /// `OLA_TEMPLATE_DIR` and `OLA_AUDIT_LOG`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let warm_period = match std::env::var("OLA_CAMERA_WARM_MS") {
+            Ok(v) => Duration::from_millis(v.parse().context("invalid OLA_CAMERA_WARM_MS")?),
+            Err(_) => session::DEFAULT_WARM_PERIOD,
+        };
+        Ok(Self {
+            source: SourceConfig::from_env().context("camera backend configuration")?,
+            watch_mode: WatchMode::from_env().context("hotplug configuration")?,
+            auth_camera: camera_id::configured_auth_camera(),
+            warm_period,
+            models: ModelRegistry::from_env(),
+            matching: MatchConfig::load(&matcher::config_path()).context("match configuration")?,
+            liveness: LivenessConfig::load(&liveness::config_path()).context("liveness configuration")?,
+            quality: QualityConfig::load(&quality::config_path()).context("quality configuration")?,
+            exposure: ExposureConfig::load(&exposure::config_path()).context("exposure configuration")?,
+            template_dir: templates::template_dir(),
+            adapt: AdaptConfig::load(&adapt::config_path()).context("adaptation configuration")?,
+            audit_log: adapt::audit_path(),
+        })
+    }
 }
 
 pub struct CameraWorker {
     receiver: mpsc::Receiver<CameraRequest>,
+    // Weak so the hotplug thread never keeps the worker alive past shutdown
+    self_tx: mpsc::WeakSender<CameraRequest>,
+    config: SourceConfig,
+    watch_mode: WatchMode,
+    auth_camera: Option<String>,
+    watching: bool,
+    stats: Arc<Mutex<CaptureStats>>,
+    sessions: SessionPool,
+    models: ModelRegistry,
+    /// Loaded on first use, so a missing model only affects verification.
+    detector: Option<Box<dyn Detector>>,
+    embedder: Option<Box<dyn Embedder>>,
+    /// The registry's record of the models above.
+    loaded: Vec<ModelInfo>,
+    matching: MatchConfig,
+    liveness: Arc<Liveness>,
+    quality: QualityConfig,
+    exposure: ExposureConfig,
+    lockout: Lockout,
+    templates: TemplateStore,
+    /// Every user's templates for `identify`; dropped whenever they change.
+    index: Option<Arc<TemplateIndex>>,
+    adapt: AdaptConfig,
+    audit: AuditLog,
+    waiting: VecDeque<Waiting>,
+    /// Queued requests left in the current retry round.
+    retrying: usize,
+    retry_at: Instant,
+    registry: CameraRegistry,
+    events: broadcast::Sender<CameraEvent>,
 }
 
 impl CameraWorker {
-    pub fn new() -> (Self, mpsc::Sender<CameraRequest>) {
+    pub fn new(config: WorkerConfig) -> (Self, mpsc::Sender<CameraRequest>) {
         // Buffer size 32 is plenty for now
         let (tx, rx) = mpsc::channel(32);
-        (Self { receiver: rx }, tx)
+        let (events, _) = broadcast::channel(16);
+        let worker = Self {
+            receiver: rx,
+            self_tx: tx.downgrade(),
+            config: config.source,
+            watch_mode: config.watch_mode,
+            auth_camera: config.auth_camera,
+            watching: false,
+            stats: Arc::default(),
+            sessions: SessionPool::new(config.warm_period),
+            models: config.models,
+            detector: None,
+            embedder: None,
+            loaded: Vec::new(),
+            matching: config.matching,
+            liveness: Arc::new(Liveness::from_config(&config.liveness)),
+            quality: config.quality,
+            exposure: config.exposure,
+            lockout: Lockout::default(),
+            templates: TemplateStore::new(&config.template_dir),
+            index: None,
+            adapt: config.adapt,
+            audit: AuditLog::new(&config.audit_log),
+            waiting: VecDeque::new(),
+            retrying: 0,
+            retry_at: Instant::now(),
+            registry: CameraRegistry::default(),
+            events,
+        };
+        (worker, tx)
+    }
+
+    /// Handle for connections that subscribe to camera add/remove notifications.
+    pub fn events(&self) -> broadcast::Sender<CameraEvent> {
+        self.events.clone()
     }
 
     pub fn run(mut self) -> thread::JoinHandle<()> {
         // Spawn a dedicated OS thread for blocking camera operations
         thread::spawn(move || {
-            // blocking_recv() waits until a message is available or channel is closed
-            while let Some(req) = self.receiver.blocking_recv() {
+            self.watching = self.start_hotplug();
+            self.registry.refresh(&camera::V4l2Probe::new(self.watch_mode.dev_dir()));
+
+            // Only used to wait for a request with a timeout while a session is warm
+            let timer = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .expect("camera worker timer runtime");
+
+            while let Some((req, deadline)) = self.next_request(&timer) {
                 match req {
                     CameraRequest::ListCameras(tx) => {
-                        let res = camera::list_cameras();
+                        let res = match self.config.virtual_camera() {
+                            Some(cam) => vec![cam],
+                            None => {
+                                // Without a watcher the registry is only as fresh as the last request
+                                if !self.watching {
+                                    self.refresh_registry();
+                                }
+                                self.registry.cameras().to_vec()
+                            }
+                        };
                         let _ = tx.send(res);
                     }
-                    CameraRequest::CaptureThumbnail(idx, tx) => {
-                        let res = camera::capture_thumbnail(idx);
+                    CameraRequest::CaptureThumbnail(target, options, tx) => {
+                        let res = self.capture(&target.selector, |source| camera::capture_thumbnail(source, &options));
+                        self.reply(res, tx, target.wait, deadline, |tx| CameraRequest::CaptureThumbnail(target, options, tx));
+                    }
+                    CameraRequest::VerifyOnce(target, uid, timeout, challenge, guide, tx) => {
+                        let res = self.verify(&target.selector, uid, timeout, challenge, guide.as_ref());
+                        self.reply(res, tx, target.wait, deadline, |tx| CameraRequest::VerifyOnce(target, uid, timeout, challenge, guide, tx));
+                    }
+                    CameraRequest::Identify(target, caller, scope, timeout, challenge, guide, tx) => {
+                        let res = self.identify(&target.selector, caller, scope.as_deref(), timeout, challenge, guide.as_ref());
+                        self.reply(res, tx, target.wait, deadline, |tx| CameraRequest::Identify(target, caller, scope, timeout, challenge, guide, tx));
+                    }
+                    CameraRequest::EnrollFrame(target, guide, tx) => {
+                        let res = self.observe(&target.selector, guide.as_ref());
+                        self.reply(res, tx, target.wait, deadline, |tx| CameraRequest::EnrollFrame(target, guide, tx));
+                    }
+                    // Template changes go through the worker so they never interleave
+                    CameraRequest::StoreTemplates(uid, templates, tx) => {
+                        let res = self.templates.add(uid, &templates);
+                        if res.is_ok() {
+                            self.index = None;
+                        }
                         let _ = tx.send(res);
                     }
-                    CameraRequest::VerifyOnce(timeout, tx) => {
-                        let res = camera::verify_once(timeout);
+                    CameraRequest::ListTemplates(uid, tx) => {
+                        let _ = tx.send(self.templates.list(uid));
+                    }
+                    CameraRequest::RenameTemplate(uid, id, label, tx) => {
+                        let _ = tx.send(self.templates.rename(uid, &id, &label));
+                    }
+                    CameraRequest::DeleteTemplate(uid, id, tx) => {
+                        let res = self.templates.delete(uid, &id);
+                        if res.is_ok() {
+                            self.index = None;
+                        }
                         let _ = tx.send(res);
                     }
+                    CameraRequest::StaleUsers(tx) => {
+                        let _ = tx.send(self.stale_users());
+                    }
+                    CameraRequest::MigrateTemplates(uid, tx) => {
+                        let _ = tx.send(self.migrate_templates(uid));
+                    }
+                    CameraRequest::ReloadModels(tx) => {
+                        let _ = tx.send(self.reload_models());
+                    }
+                    CameraRequest::Status(tx) => {
+                        let _ = tx.send(WorkerStatus {
+                            backend: self.config.backend_name(),
+                            auth_camera: self.auth_camera.clone(),
+                            detector: self.detector.as_ref().map(|d| d.name().to_string()),
+                            embedder: self.embedder.as_ref().map(|e| e.name().to_string()),
+                            models: self.loaded.clone(),
+                            liveness: self.liveness.check_names(),
+                            capture: self.stats.lock().unwrap().report(),
+                            sessions: self.sessions.status(),
+                            queued: self.waiting.len(),
+                        });
+                    }
+                    CameraRequest::Hotplug(events) => {
+                        log::debug!("Hotplug events: {:?}", events);
+                        self.refresh_registry();
+                    }
                 }
             }
             // Loop ends when Sender is dropped (main thread shutdown)
+            self.sessions.close_all();
+        })
+    }
+
+    /// Waits for the next request, closing warm sessions as they expire and
+    /// handing back queued requests when it is their turn to retry. Queued
+    /// requests come with their deadline. `None` once every sender is gone.
+    fn next_request(&mut self, timer: &tokio::runtime::Runtime) -> Option<(CameraRequest, Option<Instant>)> {
+        loop {
+            self.sessions.expire();
+            if let Some(waiting) = self.next_retry() {
+                return Some((waiting.request, Some(waiting.deadline)));
+            }
+            let retry = (!self.waiting.is_empty()).then(|| self.retry_at.saturating_duration_since(Instant::now()));
+            let Some(wait) = [self.sessions.time_to_expiry(), retry].into_iter().flatten().min() else {
+                // blocking_recv() waits until a message is available or channel is closed
+                return self.receiver.blocking_recv().map(|req| (req, None));
+            };
+            // The timeout has to be created inside the runtime it runs on
+            let recv = timer.block_on(async { tokio::time::timeout(wait, self.receiver.recv()).await });
+            if let Ok(req) = recv {
+                return req.map(|req| (req, None));
+            }
+        }
+    }
+
+    /// Every queued request gets one retry per interval, oldest first.
+    fn next_retry(&mut self) -> Option<Waiting> {
+        if self.retrying == 0 && !self.waiting.is_empty() && Instant::now() >= self.retry_at {
+            self.retrying = self.waiting.len();
+            self.retry_at = Instant::now() + QUEUE_RETRY_INTERVAL;
+        }
+        if self.retrying == 0 {
+            return None;
+        }
+        self.retrying -= 1;
+        self.waiting.pop_front()
+    }
+
+    /// Answers a capture request, unless the camera was busy and the client
+    /// asked to wait for it. Then the request is queued until `deadline`,
+    /// which starts counting when the request first found the camera busy.
+    fn reply<T>(
+        &mut self,
+        res: anyhow::Result<T>,
+        tx: oneshot::Sender<anyhow::Result<T>>,
+        wait: Duration,
+        deadline: Option<Instant>,
+        requeue: impl FnOnce(oneshot::Sender<anyhow::Result<T>>) -> CameraRequest,
+    ) {
+        let now = Instant::now();
+        let busy = matches!(&res, Err(e) if e.is::<CameraBusy>());
+        let deadline = deadline.unwrap_or(now + wait);
+        // A client that gave up closes its end; no point retrying for it
+        if !busy || now >= deadline || tx.is_closed() {
+            let _ = tx.send(res);
+            return;
+        }
+        if let Err(e) = &res {
+            log::debug!("Queueing request until the camera is free: {}", e);
+        }
+        if self.waiting.is_empty() {
+            self.retry_at = now + QUEUE_RETRY_INTERVAL;
+        }
+        self.waiting.push_back(Waiting { request: requeue(tx), deadline });
+    }
+
+    /// Starts the hotplug watcher thread. Only real devices can come and go.
+    fn start_hotplug(&self) -> bool {
+        if self.config.backend != Backend::V4l2 {
+            return false;
+        }
+        let watcher = match hotplug::open_watcher(&self.watch_mode) {
+            Ok(Some(w)) => w,
+            Ok(None) => return false,
+            Err(e) => {
+                warn!("Camera hotplug monitoring disabled: {}", e);
+                return false;
+            }
+        };
+        let tx = self.self_tx.clone();
+        hotplug::spawn(watcher, move |events| match tx.upgrade() {
+            Some(tx) => tx.blocking_send(CameraRequest::Hotplug(events)).is_ok(),
+            None => false,
+        });
+        true
+    }
+
+    /// Maps a client's camera selector to a camera. Virtual backends have a
+    /// single camera, so the selector does not matter there.
+    fn resolve_camera(&mut self, selector: &camera::CameraSelector) -> anyhow::Result<camera::CameraInfo> {
+        if let Some(cam) = self.config.virtual_camera() {
+            return Ok(cam);
+        }
+        if !self.watching {
+            self.refresh_registry();
+        }
+        Ok(selector.resolve(self.registry.cameras(), self.auth_camera.as_deref())?.clone())
+    }
+
+    /// Opens the selected camera and runs `f` on it, with the IR emitter on
+    /// for IR sensors.
+    fn capture<T>(
+        &mut self,
+        selector: &camera::CameraSelector,
+        f: impl FnOnce(&mut dyn FrameSource) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let cam = self.resolve_camera(selector)?;
+        self.capture_from(&cam, f)
+    }
+
+    fn capture_from<T>(
+        &mut self,
+        cam: &camera::CameraInfo,
+        f: impl FnOnce(&mut dyn FrameSource) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut source = self.checkout(&cam.path)?;
+        // The emitter is only on for the capture itself, not while the session idles
+        let res = self.ir_emitter(cam).and_then(|_emitter| f(source.as_mut()));
+        self.release(&cam.path, source, res.is_ok());
+        res
+    }
+
+    /// Verifies `uid` on the selected camera, or on both sensors together when
+    /// it is half of an RGB+IR pair.
+    fn verify(
+        &mut self,
+        selector: &camera::CameraSelector,
+        uid: u32,
+        timeout: u64,
+        challenge: Option<Challenge>,
+        guide: Option<&Guide>,
+    ) -> anyhow::Result<camera::VerificationResult> {
+        if let Some(wait) = self.lockout.remaining(uid, Instant::now()) {
+            return Ok(camera::VerificationResult::locked_out(wait));
+        }
+        let (cam, partner, ids) = self.resolve_sensors(selector)?;
+        let mut templates = self.templates.load(uid)?;
+        // Taken out for the duration so captures can borrow the worker
+        let (mut detector, mut embedder) = self.take_models();
+        // After a model upgrade, templates that kept their crop catch up on first use
+        if let Some(e) = embedder.as_mut() {
+            if templates.iter().any(|t| t.crop.is_some() && !t.compatible_with(e.model_id())) {
+                match self.migrate(uid, e.as_mut()).map(|_| self.templates.load(uid)) {
+                    Ok(Ok(migrated)) => templates = migrated,
+                    Ok(Err(e)) => {
+                        // Put the models back, or the next request has to load them again
+                        (self.detector, self.embedder) = (detector, embedder);
+                        return Err(e);
+                    }
+                    Err(e) => warn!("Migrating templates of uid {}: {:#}", uid, e),
+                }
+            }
+        }
+        let liveness = Arc::clone(&self.liveness);
+        let quality = self.quality.clone();
+        let send = guide.map(|guide| move |issue| { let _ = guide.send(issue); });
+        let mut exposure = self.exposure_control(rgb_sensor(&cam, partner.as_ref()));
+        let verifier = camera::Verifier {
+            detector: detector.as_mut().map(|d| d.as_mut() as &mut dyn Detector),
+            embedder: embedder.as_mut().map(|e| e.as_mut() as &mut dyn Embedder),
+            templates: &templates,
+            metric: self.matching.metric,
+            threshold: self.matching.threshold_for(uid),
+            liveness: Some(&liveness),
+            challenge,
+            quality: Some(&quality),
+            guide: send.as_ref().map(|f| f as &dyn Fn(Issue)),
+            exposure: Some(&mut exposure),
+        };
+        let res = match partner {
+            Some(partner) => self.with_pair(cam, partner, |pair| camera::verify_pair(pair, verifier, timeout)),
+            None => self.capture_from(&cam, |source| camera::verify_once(source, verifier, timeout)),
+        };
+        self.detector = detector;
+        self.embedder = embedder;
+        let mut res = res?;
+        (res.diagnostics.camera, res.diagnostics.ir_camera) = ids;
+        self.lockout.record(uid, &res, Instant::now());
+        if res.ok && self.adapt.enabled {
+            // The verification stands whatever happens to the update
+            if let Err(e) = self.adapt(uid, &templates, &mut res) {
+                warn!("Adaptive template update for uid {} failed: {:#}", uid, e);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Stores the best frame of a successful verification as an adaptive
+    /// template, if `adapt.conf` allows it. The audit entry is written first,
+    /// so no change goes unrecorded.
+    fn adapt(&mut self, uid: u32, templates: &[Template], res: &mut camera::VerificationResult) -> anyhow::Result<()> {
+        let (Some(embedding), Some(embedder)) = (res.probe.as_deref(), self.embedder.as_ref()) else { return Ok(()) };
+        let since = SystemTime::now().checked_sub(adapt::CAP_PERIOD).unwrap_or(UNIX_EPOCH);
+        let recent = self.audit.updates_since(uid, since.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0))?;
+        let probe = adapt::Probe { embedding, model: embedder.name(), model_id: embedder.model_id(), camera: res.diagnostics.camera.clone() };
+        let update = match self.adapt.plan(self.matching.metric, self.matching.threshold_for(uid), templates, probe, recent) {
+            Ok(update) => update,
+            Err(why) => {
+                log::debug!("No template update for uid {}: {}", uid, why);
+                return Ok(());
+            }
+        };
+        self.audit.append(&update.audit_entry(uid))?;
+        self.templates.add(uid, std::slice::from_ref(&update.template))?;
+        self.index = None;
+        if let Some(old) = &update.replaces {
+            self.templates.delete(uid, old)?;
+        }
+        info!(
+            "Adaptive template {} stored for uid {} (score {:.3}, needed {:.3}){}",
+            update.template.id,
+            uid,
+            update.score,
+            update.required,
+            update.replaces.map(|old| format!(", replacing {}", old)).unwrap_or_default()
+        );
+        res.diagnostics.template_update = Some(update.template.id);
+        Ok(())
+    }
+
+    /// Identifies who is in front of the selected camera among `scope`, for
+    /// `caller`. A locked-out caller is refused before the camera opens, and
+    /// failures count against them. Someone locked out of verification is
+    /// not identified either.
+    fn identify(
+        &mut self,
+        selector: &camera::CameraSelector,
+        caller: u32,
+        scope: Option<&[u32]>,
+        timeout: u64,
+        challenge: Option<Challenge>,
+        guide: Option<&Guide>,
+    ) -> anyhow::Result<camera::IdentificationResult> {
+        if let Some(wait) = self.lockout.remaining(caller, Instant::now()) {
+            return Ok(camera::IdentificationResult::default().locked_out(wait));
+        }
+        let (cam, partner, ids) = self.resolve_sensors(selector)?;
+        let index = self.template_index()?;
+        let matching = self.matching.clone();
+        let (mut detector, mut embedder) = self.take_models();
+        let liveness = Arc::clone(&self.liveness);
+        let quality = self.quality.clone();
+        let send = guide.map(|guide| move |issue| { let _ = guide.send(issue); });
+        let mut exposure = self.exposure_control(rgb_sensor(&cam, partner.as_ref()));
+        let identifier = camera::Identifier {
+            detector: detector.as_mut().map(|d| d.as_mut() as &mut dyn Detector),
+            embedder: embedder.as_mut().map(|e| e.as_mut() as &mut dyn Embedder),
+            index: &index,
+            config: &matching,
+            scope,
+            liveness: Some(&liveness),
+            challenge,
+            quality: Some(&quality),
+            guide: send.as_ref().map(|f| f as &dyn Fn(Issue)),
+            exposure: Some(&mut exposure),
+        };
+        let res = match partner {
+            Some(partner) => self.with_pair(cam, partner, |pair| camera::identify_pair(pair, identifier, timeout)),
+            None => self.capture_from(&cam, |source| camera::identify_once(source, identifier, timeout)),
+        };
+        self.detector = detector;
+        self.embedder = embedder;
+        let mut res = res?;
+        (res.diagnostics.camera, res.diagnostics.ir_camera) = ids;
+        self.lockout.record_identification(caller, &res, Instant::now());
+        if let Some(wait) = res.uid.and_then(|uid| self.lockout.remaining(uid, Instant::now())) {
+            res = res.locked_out(wait);
+        }
+        Ok(res)
+    }
+
+    /// The selected camera, its RGB+IR partner if it has one, and the stable
+    /// IDs of the RGB and IR sensors between them.
+    fn resolve_sensors(
+        &mut self,
+        selector: &camera::CameraSelector,
+    ) -> anyhow::Result<(camera::CameraInfo, Option<camera::CameraInfo>, SensorIds)> {
+        let cam = self.resolve_camera(selector)?;
+        let partner = cam.paired_with.as_ref()
+            .and_then(|id| self.registry.cameras().iter().find(|c| &c.id == id))
+            .cloned();
+        let ids = match (cam.sensor, &partner) {
+            (Some(ir::SensorKind::Ir), Some(rgb)) => (Some(rgb.id.clone()), Some(cam.id.clone())),
+            (_, partner) => (Some(cam.id.clone()), partner.as_ref().map(|p| p.id.clone())),
+        };
+        Ok((cam, partner, ids))
+    }
+
+    /// Every user's templates, indexed on first use after a change.
+    fn template_index(&mut self) -> anyhow::Result<Arc<TemplateIndex>> {
+        if let Some(index) = &self.index {
+            return Ok(Arc::clone(index));
+        }
+        let started = Instant::now();
+        let index = Arc::new(TemplateIndex::build(&self.templates)?);
+        info!("Indexed {} templates of {} users in {:?}", index.templates(), index.users().len(), started.elapsed());
+        self.index = Some(Arc::clone(&index));
+        Ok(index)
+    }
+
+    /// Re-embeds `uid`'s stale templates from their crops with `embedder`.
+    fn migrate(&mut self, uid: u32, embedder: &mut dyn Embedder) -> anyhow::Result<Migration> {
+        let migrated = self.templates.migrate(uid, embedder)?;
+        if migrated > 0 {
+            self.index = None;
+        }
+        let stale = self.templates.staleness(uid, embedder.model_id())?.stale;
+        Ok(Migration { uid, migrated, stale })
+    }
+
+    /// Runs `f` with the embedder, loading it if need be.
+    fn with_embedder<T>(&mut self, f: impl FnOnce(&mut Self, &mut dyn Embedder) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let Some(mut embedder) = self.take_embedder() else {
+            anyhow::bail!("embedder_unavailable: no face embedder at {}", self.models.describe(Role::Embedder));
+        };
+        let res = f(self, embedder.as_mut());
+        self.embedder = Some(embedder);
+        res
+    }
+
+    fn stale_users(&mut self) -> anyhow::Result<(String, Vec<Staleness>)> {
+        self.with_embedder(|worker, embedder| {
+            let mut stale = Vec::new();
+            for uid in worker.templates.users()? {
+                let user = worker.templates.staleness(uid, embedder.model_id())?;
+                if user.stale > 0 {
+                    stale.push(user);
+                }
+            }
+            Ok((embedder.model_id().to_string(), stale))
+        })
+    }
+
+    /// Migrates `uid`, or everyone; only users that had stale templates are
+    /// reported.
+    fn migrate_templates(&mut self, uid: Option<u32>) -> anyhow::Result<Vec<Migration>> {
+        self.with_embedder(|worker, embedder| {
+            let users = match uid {
+                Some(uid) => vec![uid],
+                None => worker.templates.users()?,
+            };
+            let mut report = Vec::new();
+            for uid in users {
+                let migration = worker.migrate(uid, embedder)?;
+                if migration.migrated + migration.stale > 0 {
+                    report.push(migration);
+                }
+            }
+            Ok(report)
+        })
+    }
+
+    /// Captures a frame for enrollment. For an RGB+IR pair the RGB sensor is
+    /// used, since that is what verification embeds.
+    fn observe(&mut self, selector: &camera::CameraSelector, guide: Option<&Guide>) -> anyhow::Result<enroll::Observation> {
+        let mut cam = self.resolve_camera(selector)?;
+        if cam.sensor == Some(ir::SensorKind::Ir) {
+            if let Some(rgb) = cam.paired_with.as_ref().and_then(|id| self.registry.cameras().iter().find(|c| &c.id == id)) {
+                cam = rgb.clone();
+            }
+        }
+        let (mut detector, mut embedder) = self.take_models();
+        let quality = self.quality.clone();
+        let res = match (detector.as_mut(), embedder.as_mut()) {
+            (Some(detector), Some(embedder)) => self.capture_from(&cam, |source| {
+                let image = convert::to_rgb8(&source.next_frame()?)?;
+                enroll::observe(&image, Some(cam.id.clone()), detector.as_mut(), embedder.as_mut(), &quality)
+            }),
+            (None, _) => Err(anyhow::anyhow!("detector_unavailable: no face detector at {}", self.models.describe(Role::Detector))),
+            (_, None) => Err(anyhow::anyhow!("embedder_unavailable: no face embedder at {}", self.models.describe(Role::Embedder))),
+        };
+        self.detector = detector;
+        self.embedder = embedder;
+        if let (Ok(obs), Some(guide)) = (&res, guide) {
+            if let Some(issue) = obs.quality.as_ref().and_then(|q| q.issues.first()) {
+                let _ = guide.send(*issue);
+            }
+        }
+        res
+    }
+
+    /// Opens both sensors of an RGB+IR pair and runs `f` on them, with the
+    /// IR emitter on.
+    fn with_pair<T>(
+        &mut self,
+        cam: camera::CameraInfo,
+        partner: camera::CameraInfo,
+        f: impl FnOnce(&mut CameraPair) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let (rgb, ir) = match cam.sensor {
+            Some(ir::SensorKind::Ir) => (partner, cam),
+            _ => (cam, partner),
+        };
+        let rgb_source = self.checkout(&rgb.path)?;
+        let ir_source = match self.checkout(&ir.path) {
+            Ok(s) => s,
+            Err(e) => {
+                self.release(&rgb.path, rgb_source, true);
+                return Err(e);
+            }
+        };
+        let mut pair = CameraPair::new(rgb_source, ir_source);
+        let res = self.ir_emitter(&ir).and_then(|_emitter| f(&mut pair));
+        let (rgb_source, ir_source) = pair.into_sources();
+        self.release(&rgb.path, rgb_source, res.is_ok());
+        self.release(&ir.path, ir_source, res.is_ok());
+        res
+    }
+
+    /// The warm session for `path`, or a newly opened one. Real devices are
+    /// claimed exclusively for as long as the session stays open.
+    fn checkout(&mut self, path: &str) -> anyhow::Result<Box<dyn FrameSource>> {
+        let (config, stats) = (&self.config, &self.stats);
+        self.sessions.checkout(path, || {
+            let started = Instant::now();
+            let device = Path::new(path);
+            let source: Box<dyn FrameSource> = if config.backend == Backend::V4l2 {
+                let claim = DeviceClaim::acquire(device)?;
+                let source = config.open(device).map_err(|e| {
+                    if camera_lock::is_busy(&e) { CameraBusy::new(device).into() } else { e }
+                })?;
+                Box::new(claim.hold(source))
+            } else {
+                config.open(device)?
+            };
+            Ok(Box::new(TimedSource::new(source, started, stats.clone())) as Box<dyn FrameSource>)
         })
     }
+
+    /// Keeps a source warm after a successful request. After a failure the
+    /// device is closed, so the next request starts from a clean open.
+    fn release(&mut self, path: &str, source: Box<dyn FrameSource>, ok: bool) {
+        if ok {
+            self.sessions.checkin(path, source);
+        } else {
+            drop(source);
+            self.sessions.discard(path);
+        }
+    }
+
+    /// Takes both models out of the worker, loading them first if either is
+    /// missing. A model installed later is picked up by the next verification.
+    fn take_models(&mut self) -> Models {
+        self.load_models();
+        (self.detector.take(), self.embedder.take())
+    }
+
+    /// Same as `take_models`, for the face embedder alone.
+    fn take_embedder(&mut self) -> Option<Box<dyn Embedder>> {
+        self.load_models();
+        self.embedder.take()
+    }
+
+    /// Loads the model set when a model is missing. Like `reload_models` it
+    /// comes from one reading of the manifest and is installed whole, so a
+    /// manifest replaced in between cannot pair models from different sets.
+    fn load_models(&mut self) {
+        if self.detector.is_some() && self.embedder.is_some() {
+            return;
+        }
+        match self.read_models() {
+            Ok(((detector, embedder), loaded)) => {
+                for model in &loaded {
+                    info!("Face {} loaded: {} (version {})", model.role.name(), model.path.display(), model.version.as_deref().unwrap_or("unknown"));
+                }
+                for role in Role::ALL.into_iter().filter(|role| !loaded.iter().any(|m| m.role == *role)) {
+                    warn!("Face {} unavailable: not listed in the model manifest", role.name());
+                }
+                (self.detector, self.embedder, self.loaded) = (detector, embedder, loaded);
+            }
+            Err(e) => warn!("Face models unavailable: {:#}", e),
+        }
+    }
+
+    /// Builds every model the registry lists, from one reading of the manifest.
+    fn read_models(&self) -> anyhow::Result<(Models, Vec<ModelInfo>)> {
+        let mut detector: Option<Box<dyn Detector>> = None;
+        let mut embedder: Option<Box<dyn Embedder>> = None;
+        let mut loaded = Vec::new();
+        for model in self.models.read_all()? {
+            let context = || format!("loading {}", model.info.path.display());
+            match model.info.role {
+                Role::Detector => detector = Some(Box::new(OnnxDetector::from_registry(&model).with_context(context)?)),
+                Role::Embedder => embedder = Some(Box::new(OnnxEmbedder::from_registry(&model).with_context(context)?)),
+            }
+            loaded.push(model.info);
+        }
+        Ok(((detector, embedder), loaded))
+    }
+
+    /// Loads every model the registry lists and swaps them in together, so
+    /// verification never runs a detector from one set with an embedder from
+    /// another. On any failure the current models stay.
+    fn reload_models(&mut self) -> anyhow::Result<Vec<ModelInfo>> {
+        let ((detector, embedder), loaded) = self.read_models().context("reloading models")?;
+        for model in &loaded {
+            info!("Face {} reloaded: {} (version {})", model.role.name(), model.path.display(), model.version.as_deref().unwrap_or("unknown"));
+        }
+        self.detector = detector;
+        self.embedder = embedder;
+        self.loaded = loaded.clone();
+        Ok(loaded)
+    }
+
+    /// Exposure control for a capture from `cam`: through its V4L2 controls
+    /// when it has any, software enhancement alone otherwise. The controls are
+    /// only read here; nothing changes until frames come in.
+    fn exposure_control(&self, cam: &camera::CameraInfo) -> Exposure {
+        let config = self.exposure.clone();
+        if self.config.backend != Backend::V4l2 || !config.hardware {
+            return Exposure::software(config);
+        }
+        let controls = V4l2Controls::open(Path::new(&cam.path)).map_err(anyhow::Error::from);
+        match controls.and_then(|ctrl| Exposure::take(Box::new(ctrl), config.clone())) {
+            Ok(exposure) => exposure,
+            Err(e) => {
+                warn!("No exposure control on {}, enhancing frames instead: {:#}", cam.id, e);
+                Exposure::software(config)
+            }
+        }
+    }
+
+    fn ir_emitter(&self, cam: &camera::CameraInfo) -> anyhow::Result<Option<ir::Emitter>> {
+        if self.config.backend != Backend::V4l2 || cam.sensor != Some(ir::SensorKind::Ir) {
+            return Ok(None);
+        }
+        let Some(config) = ir::EmitterConfig::lookup(&ir::emitter_dir(), cam)? else {
+            warn!("No IR emitter config for {}; frames may be dark", cam.id);
+            return Ok(None);
+        };
+        let ctrl = ir::UvcXu::open(Path::new(&cam.path))?;
+        ir::Emitter::enable(Box::new(ctrl), config).map(Some)
+    }
+
+    fn refresh_registry(&mut self) {
+        for event in self.registry.refresh(&camera::V4l2Probe::new(self.watch_mode.dev_dir())) {
+            info!("{}: {}", event.method(), event.camera().path);
+            if let CameraEvent::Removed(cam) = &event {
+                self.sessions.close(&cam.path);
+            }
+            // No subscribers is not an error
+            let _ = self.events.send(event);
+        }
+    }
+}
+
+/// The RGB sensor of `cam` and its `partner`: the one frames are matched in,
+/// and so the one whose exposure is steered. A pair may be selected by
+/// either of its nodes.
+fn rgb_sensor<'a>(cam: &'a camera::CameraInfo, partner: Option<&'a camera::CameraInfo>) -> &'a camera::CameraInfo {
+    match (cam.sensor, partner) {
+        (Some(ir::SensorKind::Ir), Some(partner)) => partner,
+        _ => cam,
+    }
 }
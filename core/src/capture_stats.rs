@@ -0,0 +1,153 @@
+// src/capture_stats.rs
+//! Capture latency bookkeeping for the `status` method.
+//!
+//! Two numbers matter for a face unlock: how long it takes from opening a
+//! source to holding its first frame, and how old each frame is when the
+//! pipeline gets it (driver timestamp to delivery).
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::frame::{monotonic_now, Frame};
+use super::frame_source::FrameSource;
+
+#[derive(Debug, Default)]
+pub struct CaptureStats {
+    source: Option<&'static str>,
+    time_to_first_frame: Option<Duration>,
+    frames: u64,
+    last_latency: Duration,
+    total_latency: Duration,
+    max_latency: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LatencyReport {
+    pub last_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Snapshot reported as `capture` by the `status` method.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CaptureReport {
+    /// Backend of the most recently opened source ("v4l2-mmap", "synthetic", ...).
+    pub source: Option<&'static str>,
+    /// Open-to-first-frame time of the most recently opened source.
+    pub time_to_first_frame_ms: Option<f64>,
+    pub frames: u64,
+    pub frame_latency_ms: Option<LatencyReport>,
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl CaptureStats {
+    fn record_open(&mut self, source: &'static str) {
+        self.source = Some(source);
+        self.time_to_first_frame = None;
+    }
+
+    fn record_frame(&mut self, latency: Duration, first_frame: Option<Duration>) {
+        if first_frame.is_some() {
+            self.time_to_first_frame = first_frame;
+        }
+        self.frames += 1;
+        self.last_latency = latency;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+
+    pub fn report(&self) -> CaptureReport {
+        CaptureReport {
+            source: self.source,
+            time_to_first_frame_ms: self.time_to_first_frame.map(ms),
+            frames: self.frames,
+            frame_latency_ms: (self.frames > 0).then(|| LatencyReport {
+                last_ms: ms(self.last_latency),
+                mean_ms: ms(self.total_latency) / self.frames as f64,
+                max_ms: ms(self.max_latency),
+            }),
+        }
+    }
+}
+
+/// Wraps a source and records its latency into shared `CaptureStats`.
+pub struct TimedSource {
+    inner: Box<dyn FrameSource>,
+    opened_at: Instant,
+    seen_first: bool,
+    stats: Arc<Mutex<CaptureStats>>,
+}
+
+impl TimedSource {
+    /// `opened_at` should be taken before the source was opened, so device
+    /// setup counts towards time-to-first-frame.
+    pub fn new(inner: Box<dyn FrameSource>, opened_at: Instant, stats: Arc<Mutex<CaptureStats>>) -> Self {
+        stats.lock().unwrap().record_open(inner.backend());
+        Self { inner, opened_at, seen_first: false, stats }
+    }
+}
+
+impl FrameSource for TimedSource {
+    fn backend(&self) -> &'static str {
+        self.inner.backend()
+    }
+
+    fn next_frame(&mut self) -> anyhow::Result<Frame<'_>> {
+        let frame = self.inner.next_frame()?;
+        let latency = monotonic_now().saturating_sub(frame.timestamp);
+        let first_frame = (!self.seen_first).then(|| self.opened_at.elapsed());
+        self.seen_first = true;
+        self.stats.lock().unwrap().record_frame(latency, first_frame);
+        Ok(frame)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+    use crate::frame_source::SyntheticSource;
+
+    #[test]
+    fn records_first_frame_and_latency() {
+        let stats = Arc::new(Mutex::new(CaptureStats::default()));
+        assert_eq!(stats.lock().unwrap().report().frame_latency_ms, None);
+
+        let source = SyntheticSource::new(64, 48, PixelFormat::Yuyv).unwrap();
+        let mut timed = TimedSource::new(Box::new(source), Instant::now(), stats.clone());
+        for _ in 0..3 {
+            timed.next_frame().unwrap();
+        }
+
+        let report = stats.lock().unwrap().report();
+        assert_eq!(report.source, Some("synthetic"));
+        assert_eq!(report.frames, 3);
+        assert!(report.time_to_first_frame_ms.is_some());
+        let latency = report.frame_latency_ms.unwrap();
+        assert!(latency.max_ms >= latency.mean_ms && latency.mean_ms >= 0.0);
+    }
+
+    #[test]
+    fn latency_is_measured_from_the_frame_timestamp() {
+        let mut stats = CaptureStats::default();
+        stats.record_open("v4l2-mmap");
+        stats.record_frame(Duration::from_millis(4), Some(Duration::from_millis(120)));
+        stats.record_frame(Duration::from_millis(8), None);
+
+        let report = stats.report();
+        assert_eq!(report.time_to_first_frame_ms, Some(120.0));
+        assert_eq!(report.frame_latency_ms, Some(LatencyReport { last_ms: 8.0, mean_ms: 6.0, max_ms: 8.0 }));
+
+        // Reopening forgets the old time-to-first-frame until a frame arrives
+        stats.record_open("v4l2-read");
+        assert_eq!(stats.report().time_to_first_frame_ms, None);
+        assert_eq!(stats.report().frames, 2);
+    }
+}
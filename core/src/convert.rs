@@ -0,0 +1,344 @@
+// src/convert.rs
+//! Converts captured frames into the RGB8 / Luma8 buffers that thumbnails and
+//! verification consume.
+//!
+//! YUV formats are treated as BT.601 limited range, which is what V4L2 drivers
+//! report by default for webcams. GREY and Y16 are full-range intensity.
+//! This runs on every frame of every `verify_once`, so the hot loops work a row
+//! at a time and avoid per-pixel bounds checks where they can.
+use image::{GrayImage, RgbImage};
+
+use super::frame::{Frame, PixelFormat};
+
+pub fn to_rgb8(frame: &Frame) -> anyhow::Result<RgbImage> {
+    if frame.format == PixelFormat::Mjpeg {
+        return Ok(decode_mjpeg(frame)?.into_rgb8());
+    }
+    let (w, h, stride) = check_geometry(frame)?;
+    let data = &frame.data[..];
+    let mut out = vec![0u8; w * h * 3];
+    let rows = out.chunks_exact_mut(w * 3);
+
+    match frame.format {
+        PixelFormat::Yuyv | PixelFormat::Uyvy => {
+            // Byte offsets of Y0, U, Y1, V within each 4-byte macropixel
+            let [y0, u, y1, v] = if frame.format == PixelFormat::Yuyv { [0, 1, 2, 3] } else { [1, 0, 3, 2] };
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                let mut pairs = dst.chunks_exact_mut(6);
+                let mut macros = row[..w * 2].chunks_exact(4);
+                for (src, px) in (&mut macros).zip(&mut pairs) {
+                    px[..3].copy_from_slice(&yuv_to_rgb(src[y0], src[u], src[v]));
+                    px[3..].copy_from_slice(&yuv_to_rgb(src[y1], src[u], src[v]));
+                }
+                // Odd widths leave a final half macropixel
+                if let Some(src) = row[..w * 2].chunks_exact(4).remainder().get(..2) {
+                    let last = &mut dst[(w - 1) * 3..];
+                    let (yy, uu) = if frame.format == PixelFormat::Yuyv { (src[0], src[1]) } else { (src[1], src[0]) };
+                    last.copy_from_slice(&yuv_to_rgb(yy, uu, 128));
+                }
+            }
+        }
+        PixelFormat::Nv12 | PixelFormat::I420 => {
+            let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+            let (y_plane, chroma) = data.split_at(stride * h);
+            for (y, dst) in rows.enumerate() {
+                let y_row = &y_plane[y * stride..][..w];
+                let cy = y / 2;
+                for (x, px) in dst.chunks_exact_mut(3).enumerate() {
+                    let (u, v) = if frame.format == PixelFormat::Nv12 {
+                        // Interleaved CbCr plane at the luma stride
+                        let i = cy * stride + (x / 2) * 2;
+                        (chroma[i], chroma[i + 1])
+                    } else {
+                        let i = cy * cw + x / 2;
+                        (chroma[i], chroma[cw * ch + i])
+                    };
+                    px.copy_from_slice(&yuv_to_rgb(y_row[x], u, v));
+                }
+            }
+        }
+        PixelFormat::Grey => {
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                for (&l, px) in row[..w].iter().zip(dst.chunks_exact_mut(3)) {
+                    px.fill(l);
+                }
+            }
+        }
+        PixelFormat::Y16 => {
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                for (src, px) in row[..w * 2].chunks_exact(2).zip(dst.chunks_exact_mut(3)) {
+                    px.fill(src[1]);
+                }
+            }
+        }
+        PixelFormat::Rgb24 => {
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                dst.copy_from_slice(&row[..w * 3]);
+            }
+        }
+        PixelFormat::Mjpeg => unreachable!(),
+    }
+
+    RgbImage::from_raw(frame.width, frame.height, out)
+        .ok_or_else(|| anyhow::anyhow!("frame buffer size mismatch"))
+}
+
+/// Extracts full-range luma. For YUV formats this reads the Y plane directly
+/// instead of going through RGB.
+pub fn to_luma8(frame: &Frame) -> anyhow::Result<GrayImage> {
+    if frame.format == PixelFormat::Mjpeg {
+        return Ok(decode_mjpeg(frame)?.into_luma8());
+    }
+    let (w, h, stride) = check_geometry(frame)?;
+    let data = &frame.data[..];
+    let mut out = vec![0u8; w * h];
+    let rows = out.chunks_exact_mut(w);
+
+    match frame.format {
+        PixelFormat::Yuyv | PixelFormat::Uyvy => {
+            let offset = if frame.format == PixelFormat::Yuyv { 0 } else { 1 };
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                for (src, l) in row[offset..].iter().step_by(2).zip(dst.iter_mut()) {
+                    *l = LIMITED_TO_FULL[*src as usize];
+                }
+            }
+        }
+        PixelFormat::Nv12 | PixelFormat::I420 => {
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                for (src, l) in row[..w].iter().zip(dst.iter_mut()) {
+                    *l = LIMITED_TO_FULL[*src as usize];
+                }
+            }
+        }
+        PixelFormat::Grey => {
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                dst.copy_from_slice(&row[..w]);
+            }
+        }
+        PixelFormat::Y16 => {
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                for (src, l) in row[..w * 2].chunks_exact(2).zip(dst.iter_mut()) {
+                    *l = src[1];
+                }
+            }
+        }
+        PixelFormat::Rgb24 => {
+            for (row, dst) in data.chunks(stride).zip(rows) {
+                for (px, l) in row[..w * 3].chunks_exact(3).zip(dst.iter_mut()) {
+                    *l = ((77 * px[0] as u32 + 150 * px[1] as u32 + 29 * px[2] as u32 + 128) >> 8) as u8;
+                }
+            }
+        }
+        PixelFormat::Mjpeg => unreachable!(),
+    }
+
+    GrayImage::from_raw(frame.width, frame.height, out)
+        .ok_or_else(|| anyhow::anyhow!("frame buffer size mismatch"))
+}
+
+fn decode_mjpeg(frame: &Frame) -> anyhow::Result<image::DynamicImage> {
+    // jpeg-decoder fills in the default Huffman tables many UVC cameras omit
+    Ok(image::load_from_memory_with_format(&frame.data, image::ImageFormat::Jpeg)?)
+}
+
+/// Validates stride and buffer length, returning (width, height, stride).
+fn check_geometry(frame: &Frame) -> anyhow::Result<(usize, usize, usize)> {
+    let (w, h) = (frame.width as usize, frame.height as usize);
+    let stride = frame.stride as usize;
+    if w == 0 || h == 0 {
+        anyhow::bail!("empty frame");
+    }
+    let min = frame.format.min_stride(frame.width).unwrap_or(0) as usize;
+    if stride < min {
+        anyhow::bail!("stride {} too small for {}px wide {:?}", stride, w, frame.format);
+    }
+
+    let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+    let needed = match frame.format {
+        // NV12 chroma rows share the luma stride; I420 chroma planes are packed
+        PixelFormat::Nv12 => stride * h + stride * (ch - 1) + cw * 2,
+        PixelFormat::I420 => stride * h + 2 * cw * ch,
+        _ => stride * (h - 1) + min,
+    };
+    if frame.data.len() < needed {
+        anyhow::bail!("short frame: {} bytes, expected {}", frame.data.len(), needed);
+    }
+    Ok((w, h, stride))
+}
+
+/// Limited-range (16-235) luma expanded to 0-255.
+static LIMITED_TO_FULL: [u8; 256] = {
+    let mut lut = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        let v = (i as i32 - 16) * 255 / 219;
+        lut[i] = if v < 0 { 0 } else if v > 255 { 255 } else { v as u8 };
+        i += 1;
+    }
+    lut
+};
+
+/// BT.601 limited-range YCbCr to RGB in 8.8 fixed point.
+#[inline]
+pub fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    [
+        clamp((c + 409 * e + 128) >> 8),
+        clamp((c - 100 * d - 208 * e + 128) >> 8),
+        clamp((c + 516 * d + 128) >> 8),
+    ]
+}
+
+/// Inverse of `yuv_to_rgb`; used by the synthetic source.
+#[inline]
+pub fn rgb_to_yuv([r, g, b]: [u8; 3]) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (clamp(y), clamp(u), clamp(v))
+}
+
+#[inline]
+fn clamp(v: i32) -> u8 {
+    v.clamp(0, 255) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{ColorType, ImageEncoder};
+    use std::borrow::Cow;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const W: u32 = 16;
+    const H: u32 = 8;
+
+    /// Eight vertical colour bars, the reference every format is built from.
+    fn reference_rgb() -> Vec<[u8; 3]> {
+        const BARS: [[u8; 3]; 8] = [
+            [255, 255, 255], [255, 255, 0], [0, 255, 255], [0, 255, 0],
+            [255, 0, 255], [255, 0, 0], [0, 0, 255], [0, 0, 0],
+        ];
+        (0..H).flat_map(|_| (0..W).map(|x| BARS[(x * 8 / W) as usize])).collect()
+    }
+
+    fn frame(format: PixelFormat, stride: u32, data: Vec<u8>) -> Frame<'static> {
+        Frame { format, width: W, height: H, stride, sequence: 1, timestamp: Duration::ZERO, data: Cow::Owned(data) }
+    }
+
+    fn encode(format: PixelFormat) -> Frame<'static> {
+        let rgb = reference_rgb();
+        let yuv: Vec<_> = rgb.iter().map(|&p| rgb_to_yuv(p)).collect();
+        let (w, h) = (W as usize, H as usize);
+        match format {
+            PixelFormat::Yuyv | PixelFormat::Uyvy => {
+                let mut d = Vec::new();
+                for pair in yuv.chunks(2) {
+                    let (y0, u, v) = pair[0];
+                    let y1 = pair[1].0;
+                    if format == PixelFormat::Yuyv { d.extend([y0, u, y1, v]) } else { d.extend([u, y0, v, y1]) }
+                }
+                frame(format, W * 2, d)
+            }
+            PixelFormat::Nv12 => {
+                let mut d: Vec<u8> = yuv.iter().map(|p| p.0).collect();
+                for cy in 0..h / 2 {
+                    for cx in 0..w / 2 {
+                        let (_, u, v) = yuv[cy * 2 * w + cx * 2];
+                        d.extend([u, v]);
+                    }
+                }
+                frame(format, W, d)
+            }
+            PixelFormat::Grey => frame(format, W, rgb.iter().map(|p| luma(*p)).collect()),
+            PixelFormat::Y16 => frame(format, W * 2, rgb.iter().flat_map(|p| [0x80, luma(*p)]).collect()),
+            PixelFormat::Mjpeg => {
+                let raw: Vec<u8> = rgb.concat();
+                let mut d = Vec::new();
+                JpegEncoder::new_with_quality(&mut d, 95).write_image(&raw, W, H, ColorType::Rgb8).unwrap();
+                frame(format, 0, d)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn luma([r, g, b]: [u8; 3]) -> u8 {
+        ((77 * r as u32 + 150 * g as u32 + 29 * b as u32 + 128) >> 8) as u8
+    }
+
+    fn golden_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name)
+    }
+
+    /// Compares against a checked-in golden PNG. Set `OLA_BLESS=1` to regenerate.
+    fn assert_golden(name: &str, actual: &image::DynamicImage, tolerance: u8) {
+        let path = golden_path(name);
+        if std::env::var("OLA_BLESS").is_ok() {
+            actual.save(&path).unwrap();
+        }
+        let expected = image::open(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        assert_eq!(expected.color(), actual.color(), "{}", name);
+        let diff = expected.as_bytes().iter().zip(actual.as_bytes())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap_or(0);
+        assert!(diff <= tolerance, "{} differs from golden by {}", name, diff);
+    }
+
+    #[test]
+    fn converts_every_format_to_golden_rgb_and_luma() {
+        let cases = [
+            (PixelFormat::Yuyv, "yuyv", 0),
+            (PixelFormat::Uyvy, "uyvy", 0),
+            (PixelFormat::Nv12, "nv12", 0),
+            (PixelFormat::Grey, "grey", 0),
+            (PixelFormat::Y16, "y16", 0),
+            // Lossy: allow for decoder rounding differences
+            (PixelFormat::Mjpeg, "mjpeg", 8),
+        ];
+        for (format, name, tol) in cases {
+            let f = encode(format);
+            let rgb = to_rgb8(&f).unwrap();
+            let luma = to_luma8(&f).unwrap();
+            assert_golden(&format!("{}_rgb.png", name), &image::DynamicImage::ImageRgb8(rgb), tol);
+            assert_golden(&format!("{}_luma.png", name), &image::DynamicImage::ImageLuma8(luma), tol);
+        }
+    }
+
+    #[test]
+    fn yuv_round_trip_is_close_to_reference() {
+        for format in [PixelFormat::Yuyv, PixelFormat::Uyvy, PixelFormat::Nv12] {
+            let rgb = to_rgb8(&encode(format)).unwrap();
+            for (actual, expected) in rgb.pixels().zip(reference_rgb()) {
+                for c in 0..3 {
+                    assert!(actual.0[c].abs_diff(expected[c]) <= 3, "{:?}: {:?} vs {:?}", format, actual.0, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn honours_padded_stride() {
+        let packed = encode(PixelFormat::Grey);
+        let mut padded = Vec::new();
+        for row in packed.data.chunks(W as usize) {
+            padded.extend_from_slice(row);
+            padded.extend_from_slice(&[0xAA; 4]);
+        }
+        let f = frame(PixelFormat::Grey, W + 4, padded);
+        assert_eq!(to_luma8(&f).unwrap().into_raw(), packed.data.to_vec());
+    }
+
+    #[test]
+    fn rejects_short_buffers() {
+        let mut f = encode(PixelFormat::Nv12);
+        f.data.to_mut().truncate(W as usize * H as usize);
+        assert!(to_rgb8(&f).is_err());
+        assert!(to_luma8(&frame(PixelFormat::Yuyv, W, vec![0; 512])).is_err());
+    }
+}
@@ -0,0 +1,492 @@
+// src/detector.rs
+//! Face detection, the first stage of verification.
+//!
+//! A `Detector` finds faces in an RGB image and reports each one as a box, a
+//! few landmarks and a confidence. `OnnxDetector` runs a BlazeFace-style model
+//! on the CPU through tract, so detection works without a GPU or any native
+//! inference runtime.
+use anyhow::{bail, Context};
+use image::imageops::{self, FilterType};
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tract_onnx::prelude::*;
+
+use super::models;
+
+/// Where the detector model is installed unless `OLA_DETECTOR_MODEL` says otherwise.
+pub const DEFAULT_MODEL: &str = "/etc/ola/models/face_detector.onnx";
+
+/// Candidates scoring below this are dropped before suppression.
+pub const DEFAULT_MIN_CONFIDENCE: f32 = 0.5;
+
+/// Candidates overlapping a stronger one by more than this are merged into it.
+const NMS_IOU: f32 = 0.3;
+
+pub fn model_path() -> PathBuf {
+    std::env::var_os("OLA_DETECTOR_MODEL").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_MODEL))
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct BoundingBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BoundingBox {
+    pub fn area(&self) -> f32 {
+        self.width.max(0.0) * self.height.max(0.0)
+    }
+
+    /// Intersection over union.
+    pub fn iou(&self, other: &BoundingBox) -> f32 {
+        let w = (self.x + self.width).min(other.x + other.width) - self.x.max(other.x);
+        let h = (self.y + self.height).min(other.y + other.height) - self.y.max(other.y);
+        if w <= 0.0 || h <= 0.0 {
+            return 0.0;
+        }
+        let inter = w * h;
+        inter / (self.area() + other.area() - inter)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// One face, in pixel coordinates of the image it was found in.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Detection {
+    pub bbox: BoundingBox,
+    /// Model-defined keypoints. For BlazeFace: right eye, left eye, nose tip,
+    /// mouth centre, right ear, left ear.
+    pub landmarks: Vec<Point>,
+    pub confidence: f32,
+}
+
+pub trait Detector: Send {
+    /// Short name for logs and `status`.
+    fn name(&self) -> &str;
+
+    /// Faces in `image`, most confident first.
+    fn detect(&mut self, image: &RgbImage) -> anyhow::Result<Vec<Detection>>;
+}
+
+/// Centre of an SSD anchor, normalised to the model input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Anchor {
+    x: f32,
+    y: f32,
+}
+
+/// BlazeFace front-camera anchors: two per cell on the stride-8 grid, then six
+/// per cell on the stride-16 grid, row by row. 896 for a 128x128 input.
+fn anchors(input_size: usize) -> Vec<Anchor> {
+    let mut out = Vec::new();
+    for (stride, per_cell) in [(8, 2), (16, 6)] {
+        let grid = input_size / stride;
+        for y in 0..grid {
+            for x in 0..grid {
+                let anchor = Anchor { x: (x as f32 + 0.5) / grid as f32, y: (y as f32 + 0.5) / grid as f32 };
+                out.extend(std::iter::repeat_n(anchor, per_cell));
+            }
+        }
+    }
+    out
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x.clamp(-100.0, 100.0)).exp())
+}
+
+/// Turns raw model outputs into candidates in normalised input coordinates.
+/// `boxes` holds `values` floats per anchor: centre offset, size, then
+/// landmark offsets, all in input pixels.
+fn decode(boxes: &[f32], values: usize, scores: &[f32], anchors: &[Anchor], input_size: usize, min_confidence: f32) -> Vec<Detection> {
+    let scale = input_size as f32;
+    let mut out = Vec::new();
+    for (i, (anchor, raw)) in anchors.iter().zip(boxes.chunks_exact(values)).enumerate() {
+        let confidence = sigmoid(scores[i]);
+        if confidence < min_confidence {
+            continue;
+        }
+        let (cx, cy) = (raw[0] / scale + anchor.x, raw[1] / scale + anchor.y);
+        let (w, h) = (raw[2] / scale, raw[3] / scale);
+        let landmarks = raw[4..]
+            .chunks_exact(2)
+            .map(|p| Point { x: p[0] / scale + anchor.x, y: p[1] / scale + anchor.y })
+            .collect();
+        out.push(Detection { bbox: BoundingBox { x: cx - w / 2.0, y: cy - h / 2.0, width: w, height: h }, landmarks, confidence });
+    }
+    out
+}
+
+/// Weighted non-maximum suppression: each cluster of overlapping candidates
+/// becomes one detection, its box and landmarks averaged by confidence. That
+/// is steadier from frame to frame than keeping only the strongest candidate.
+fn weighted_nms(mut candidates: Vec<Detection>, iou: f32) -> Vec<Detection> {
+    candidates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    let mut out = Vec::new();
+    while !candidates.is_empty() {
+        let top = candidates[0].bbox;
+        let (cluster, rest): (Vec<_>, Vec<_>) = candidates.into_iter().partition(|c| c.bbox.iou(&top) > iou || c.bbox == top);
+        candidates = rest;
+
+        let total: f32 = cluster.iter().map(|c| c.confidence).sum();
+        let avg = |f: &dyn Fn(&Detection) -> f32| cluster.iter().map(|c| f(c) * c.confidence).sum::<f32>() / total;
+        let landmarks = (0..cluster[0].landmarks.len())
+            .map(|k| Point { x: avg(&|c| c.landmarks[k].x), y: avg(&|c| c.landmarks[k].y) })
+            .collect();
+        out.push(Detection {
+            bbox: BoundingBox {
+                x: avg(&|c| c.bbox.x),
+                y: avg(&|c| c.bbox.y),
+                width: avg(&|c| c.bbox.width),
+                height: avg(&|c| c.bbox.height),
+            },
+            landmarks,
+            confidence: cluster[0].confidence,
+        });
+    }
+    out
+}
+
+/// Aspect-preserving fit of an image into the square model input, padded
+/// equally on both sides.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Letterbox {
+    scale: f32,
+    width: u32,
+    height: u32,
+    pad_x: u32,
+    pad_y: u32,
+}
+
+impl Letterbox {
+    fn new(width: u32, height: u32, input_size: usize) -> Self {
+        let size = input_size as u32;
+        let scale = size as f32 / width.max(height) as f32;
+        let w = ((width as f32 * scale).round() as u32).clamp(1, size);
+        let h = ((height as f32 * scale).round() as u32).clamp(1, size);
+        Self { scale, width: w, height: h, pad_x: (size - w) / 2, pad_y: (size - h) / 2 }
+    }
+
+    /// Maps a detection from normalised input coordinates back onto the image.
+    fn map_back(&self, det: Detection, input_size: usize) -> Detection {
+        let s = input_size as f32;
+        let point = |x: f32, y: f32| Point {
+            x: (x * s - self.pad_x as f32) / self.scale,
+            y: (y * s - self.pad_y as f32) / self.scale,
+        };
+        let origin = point(det.bbox.x, det.bbox.y);
+        Detection {
+            bbox: BoundingBox {
+                x: origin.x,
+                y: origin.y,
+                width: det.bbox.width * s / self.scale,
+                height: det.bbox.height * s / self.scale,
+            },
+            landmarks: det.landmarks.iter().map(|p| point(p.x, p.y)).collect(),
+            confidence: det.confidence,
+        }
+    }
+}
+
+/// BlazeFace-style ONNX model on tract.
+///
+/// The model takes one square RGB image scaled to [-1, 1], NHWC or NCHW, and
+/// returns per-anchor regressors `[1, N, 4 + 2 * landmarks]` and scores
+/// `[1, N, 1]` for the anchors described by `anchors`.
+pub struct OnnxDetector {
+    name: String,
+    model: TypedRunnableModel<TypedModel>,
+    input_size: usize,
+    channels_first: bool,
+    anchors: Vec<Anchor>,
+    min_confidence: f32,
+}
+
+impl OnnxDetector {
+    /// Loads a model the registry has already read and checked. This is the
+    /// only way to build one, so no model skips the manifest.
+    pub fn from_registry(model: &models::Model) -> anyhow::Result<Self> {
+        let onnx = tract_onnx::onnx()
+            .model_for_read(&mut &model.bytes[..])
+            .with_context(|| format!("loading face detector {}", model.info.path.display()))?;
+        Self::from_model(onnx, model.name())
+    }
+
+    fn from_model(model: InferenceModel, name: String) -> anyhow::Result<Self> {
+        let model = model.into_typed()?;
+        let (channels_first, input_size) = square_image_input(&model, "face detector")?;
+        Ok(Self {
+            name,
+            model: model.into_optimized()?.into_runnable()?,
+            input_size,
+            channels_first,
+            anchors: anchors(input_size),
+            min_confidence: DEFAULT_MIN_CONFIDENCE,
+        })
+    }
+
+    pub fn with_min_confidence(mut self, min_confidence: f32) -> Self {
+        self.min_confidence = min_confidence;
+        self
+    }
+
+    fn input(&self, image: &RgbImage, fit: &Letterbox) -> Tensor {
+        let resized = imageops::resize(image, fit.width, fit.height, FilterType::Triangle);
+        image_tensor(&resized, self.input_size, self.channels_first, fit.pad_x, fit.pad_y)
+    }
+}
+
+/// Layout and side of a model's square RGB input: `(channels_first, size)`.
+pub(crate) fn square_image_input(model: &TypedModel, what: &str) -> anyhow::Result<(bool, usize)> {
+    let shape = model
+        .input_fact(0)?
+        .shape
+        .as_concrete()
+        .with_context(|| format!("{} input shape must be fixed", what))?
+        .to_vec();
+    match shape[..] {
+        [1, 3, h, w] if h == w => Ok((true, h)),
+        [1, h, w, 3] if h == w => Ok((false, h)),
+        _ => bail!("unsupported {} input shape {:?}", what, shape),
+    }
+}
+
+/// A `size`x`size` input tensor with `image` placed at `(pad_x, pad_y)` and
+/// scaled to [-1, 1]. Padding is black, which is -1 after scaling.
+pub(crate) fn image_tensor(image: &RgbImage, size: usize, channels_first: bool, pad_x: u32, pad_y: u32) -> Tensor {
+    let shape = if channels_first { [1, 3, size, size] } else { [1, size, size, 3] };
+    let mut input = tract_ndarray::Array4::<f32>::from_elem(shape, -1.0);
+    for (x, y, px) in image.enumerate_pixels() {
+        let (x, y) = ((x + pad_x) as usize, (y + pad_y) as usize);
+        for c in 0..3 {
+            let v = px[c] as f32 / 127.5 - 1.0;
+            if channels_first {
+                input[[0, c, y, x]] = v;
+            } else {
+                input[[0, y, x, c]] = v;
+            }
+        }
+    }
+    input.into()
+}
+
+impl Detector for OnnxDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detect(&mut self, image: &RgbImage) -> anyhow::Result<Vec<Detection>> {
+        let fit = Letterbox::new(image.width(), image.height(), self.input_size);
+        let outputs = self.model.run(tvec!(self.input(image, &fit).into()))?;
+
+        // Outputs are told apart by shape, since exporters name and order them freely
+        let n = self.anchors.len();
+        let (mut boxes, mut scores) = (None, None);
+        for output in &outputs {
+            let view = output.to_array_view::<f32>()?;
+            match *view.shape() {
+                [1, rows, 1] if rows == n => scores = Some(view.iter().copied().collect::<Vec<_>>()),
+                [1, rows, values] if rows == n && values >= 4 && values % 2 == 0 => {
+                    boxes = Some((view.iter().copied().collect::<Vec<_>>(), values))
+                }
+                _ => {}
+            }
+        }
+        let (Some((boxes, values)), Some(scores)) = (boxes, scores) else {
+            bail!("face detector outputs do not match its {} anchors", n);
+        };
+
+        let candidates = decode(&boxes, values, &scores, &self.anchors, self.input_size, self.min_confidence);
+        Ok(weighted_nms(candidates, NMS_IOU)
+            .into_iter()
+            .map(|d| fit.map_back(d, self.input_size))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::test_models::{floats, int, ints, model, node, relu_model, shape, value_info};
+    use tract_onnx::pb;
+
+    #[test]
+    fn anchor_layout_matches_blazeface_front() {
+        let a = anchors(128);
+        assert_eq!(a.len(), 896);
+        assert_eq!((a[0], a[1]), (Anchor { x: 0.5 / 16.0, y: 0.5 / 16.0 }, Anchor { x: 0.5 / 16.0, y: 0.5 / 16.0 }));
+        assert_eq!(a[2], Anchor { x: 1.5 / 16.0, y: 0.5 / 16.0 });
+        assert_eq!(a[512], Anchor { x: 0.5 / 8.0, y: 0.5 / 8.0 });
+        assert_eq!(a[895], Anchor { x: 7.5 / 8.0, y: 7.5 / 8.0 });
+    }
+
+    #[test]
+    fn overlapping_candidates_merge_by_confidence() {
+        let anchors = [Anchor { x: 0.5, y: 0.5 }, Anchor { x: 0.5, y: 0.5 }, Anchor { x: 0.1, y: 0.1 }];
+        // Two candidates 4px apart around the centre, one weak one in the corner
+        #[rustfmt::skip]
+        let boxes = [
+            -2.0, 0.0, 32.0, 32.0,   0.0, 0.0,
+             2.0, 0.0, 32.0, 32.0,   4.0, 0.0,
+             0.0, 0.0, 16.0, 16.0,   0.0, 0.0,
+        ];
+        let scores = [3.0, 3.0, -3.0];
+        let candidates = decode(&boxes, 6, &scores, &anchors, 128, 0.5);
+        assert_eq!(candidates.len(), 2);
+
+        let merged = weighted_nms(candidates, NMS_IOU);
+        assert_eq!(merged.len(), 1);
+        let face = &merged[0];
+        assert!((face.bbox.x - (0.5 - 16.0 / 128.0)).abs() < 1e-6);
+        assert!((face.bbox.width - 0.25).abs() < 1e-6);
+        assert!((face.landmarks[0].x - (0.5 + 2.0 / 128.0)).abs() < 1e-6);
+        assert!((face.confidence - sigmoid(3.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn separate_faces_stay_separate() {
+        let a = Detection { bbox: BoundingBox { x: 0.0, y: 0.0, width: 10.0, height: 10.0 }, landmarks: vec![], confidence: 0.9 };
+        let b = Detection { bbox: BoundingBox { x: 20.0, ..a.bbox }, confidence: 0.8, ..a.clone() };
+        let out = weighted_nms(vec![b.clone(), a.clone()], NMS_IOU);
+        assert_eq!(out, vec![a, b]);
+    }
+
+    #[test]
+    fn letterbox_maps_back_to_image_pixels() {
+        // 640x480 fills the width; 16px of padding above and below at 128
+        let fit = Letterbox::new(640, 480, 128);
+        assert_eq!((fit.width, fit.height, fit.pad_x, fit.pad_y), (128, 96, 0, 16));
+        let det = Detection {
+            bbox: BoundingBox { x: 0.25, y: 0.125 + 0.25, width: 0.25, height: 0.25 },
+            landmarks: vec![Point { x: 0.5, y: 0.5 }],
+            confidence: 1.0,
+        };
+        let det = fit.map_back(det, 128);
+        assert_eq!(det.bbox, BoundingBox { x: 160.0, y: 160.0, width: 160.0, height: 160.0 });
+        assert_eq!(det.landmarks, vec![Point { x: 320.0, y: 240.0 }]);
+    }
+
+    // --- Fixture regression tests ---
+    //
+    // Real face models are not checked in, so the fixtures run against a tiny
+    // stand-in network with BlazeFace's inputs and outputs: each anchor on the
+    // stride-16 grid scores the mean brightness of its cell, and every box is a
+    // fixed 16x16 square with five fixed landmarks. A white square on black is a
+    // "face". That exercises everything around the model: letterboxing, input
+    // layout, output matching, anchor decoding, suppression and mapping back.
+
+    const FIXTURE_LANDMARKS: [(f32, f32); 5] = [(-4.0, -3.0), (4.0, -3.0), (0.0, 0.0), (0.0, 4.0), (0.0, 6.0)];
+
+    fn fixture_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/detector")
+    }
+
+    /// The stand-in network described above, NHWC 128x128 in.
+    fn fixture_model() -> OnnxDetector {
+        let mut regressor = vec![0.0, 0.0, 16.0, 16.0];
+        regressor.extend(FIXTURE_LANDMARKS.iter().flat_map(|&(x, y)| [x, y]));
+        // Only the stride-16 anchors may fire, and only for cells brighter than 75% grey
+        let bias: Vec<f32> = (0..896).map(|i| if i < 512 { -100.0 } else { -5.0 }).collect();
+
+        let graph = pb::GraphProto {
+            name: "fixture-detector".into(),
+            node: vec![
+                node("ReduceMean", &["input"], "luma", vec![ints("axes", &[3]), int("keepdims", 1)]),
+                node("Transpose", &["luma"], "luma_nchw", vec![ints("perm", &[0, 3, 1, 2])]),
+                node("AveragePool", &["luma_nchw"], "cells8", vec![ints("kernel_shape", &[8, 8]), ints("strides", &[8, 8])]),
+                node("AveragePool", &["luma_nchw"], "cells16", vec![ints("kernel_shape", &[16, 16]), ints("strides", &[16, 16])]),
+                node("Reshape", &["cells8", "shape8"], "col8", vec![]),
+                node("Concat", &["col8", "col8"], "rep8", vec![int("axis", 2)]),
+                node("Reshape", &["rep8", "shape_a8"], "anchors8", vec![]),
+                node("Reshape", &["cells16", "shape16"], "col16", vec![]),
+                node("Concat", &["col16"; 6], "rep16", vec![int("axis", 2)]),
+                node("Reshape", &["rep16", "shape_a16"], "anchors16", vec![]),
+                node("Concat", &["anchors8", "anchors16"], "brightness", vec![int("axis", 1)]),
+                node("Mul", &["brightness", "gain"], "logits", vec![]),
+                node("Add", &["logits", "bias"], "classificators", vec![]),
+                node("Mul", &["brightness", "zero"], "zeros", vec![]),
+                node("Add", &["zeros", "regressor"], "regressors", vec![]),
+            ],
+            initializer: vec![
+                shape("shape8", &[1, 256, 1]),
+                shape("shape_a8", &[1, 512, 1]),
+                shape("shape16", &[1, 64, 1]),
+                shape("shape_a16", &[1, 384, 1]),
+                floats("gain", &[], vec![10.0]),
+                floats("zero", &[], vec![0.0]),
+                floats("bias", &[1, 896, 1], bias),
+                floats("regressor", &[1, 1, 14], regressor),
+            ],
+            input: vec![value_info("input", &[1, 128, 128, 3])],
+            output: vec![value_info("regressors", &[1, 896, 14]), value_info("classificators", &[1, 896, 1])],
+            ..Default::default()
+        };
+        OnnxDetector::from_model(model(graph), "fixture".into()).unwrap()
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() <= 0.5
+    }
+
+    /// Runs every fixture image through the stand-in model and compares with
+    /// `expected.json`. Set `OLA_BLESS=1` to regenerate it.
+    #[test]
+    fn fixture_detections_match_expected() {
+        let mut detector = fixture_model();
+        let dir = fixture_dir();
+        let mut images: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "png"))
+            .collect();
+        images.sort();
+        assert!(!images.is_empty(), "no fixtures in {}", dir.display());
+
+        let mut actual = BTreeMap::new();
+        for path in &images {
+            let image = image::open(path).unwrap().into_rgb8();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            actual.insert(name, detector.detect(&image).unwrap());
+        }
+
+        let expected_path = dir.join("expected.json");
+        if std::env::var_os("OLA_BLESS").is_some() {
+            std::fs::write(&expected_path, serde_json::to_string_pretty(&actual).unwrap() + "\n").unwrap();
+        }
+        let expected: BTreeMap<String, Vec<Detection>> =
+            serde_json::from_str(&std::fs::read_to_string(&expected_path).unwrap()).unwrap();
+        assert_eq!(actual.keys().collect::<Vec<_>>(), expected.keys().collect::<Vec<_>>());
+
+        for (name, want) in &expected {
+            let got = &actual[name];
+            assert_eq!(got.len(), want.len(), "{}: {:?}", name, got);
+            for (g, w) in got.iter().zip(want) {
+                let matches = close(g.bbox.x, w.bbox.x)
+                    && close(g.bbox.y, w.bbox.y)
+                    && close(g.bbox.width, w.bbox.width)
+                    && close(g.bbox.height, w.bbox.height)
+                    && (g.confidence - w.confidence).abs() <= 0.01
+                    && g.landmarks.iter().zip(&w.landmarks).all(|(a, b)| close(a.x, b.x) && close(a.y, b.y));
+                assert!(matches, "{}: got {:?}, expected {:?}", name, g, w);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_models_it_cannot_drive() {
+        assert!(OnnxDetector::from_model(relu_model(&[1, 96, 128, 3]), "wide".into()).is_err());
+
+        // Square input, but nothing shaped like regressors and scores
+        let mut detector = OnnxDetector::from_model(relu_model(&[1, 128, 128, 3]), "relu".into()).unwrap();
+        assert!(detector.detect(&RgbImage::new(64, 64)).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+// src/embedder.rs
+//! Face embeddings, the second stage of verification.
+//!
+//! An `Embedder` turns a detected face into a fixed-length vector such that
+//! two captures of the same person land close together and different people
+//! far apart. `OnnxEmbedder` runs an ArcFace-style recognition model on the
+//! CPU through tract, on a square crop of the face rotated so the eyes are
+//! level.
+//!
+//! Embeddings from different models, or from the same model fed differently
+//! prepared crops, live in unrelated spaces. `Embedder::model_id` and
+//! `EMBEDDING_SCHEMA` identify both, so templates can be checked against the
+//! model that would be compared with them.
+use anyhow::{bail, Context};
+use image::{Rgb, RgbImage};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use tract_onnx::prelude::*;
+
+use super::detector::{self, Detection};
+use super::models;
+
+/// Where the embedding model is installed unless `OLA_EMBEDDER_MODEL` says otherwise.
+pub const DEFAULT_MODEL: &str = "/etc/ola/models/face_embedder.onnx";
+
+/// Side of the crop relative to the detected box. Recognition models are
+/// trained on loose crops that include some forehead and chin.
+const CROP_MARGIN: f32 = 1.3;
+
+/// Version of the preprocessing between a detected face and the model input:
+/// alignment, crop margin and pixel scaling. Bump it whenever `align` or the
+/// input scaling changes, since that invalidates every stored embedding.
+pub const EMBEDDING_SCHEMA: u32 = 1;
+
+pub fn model_path() -> PathBuf {
+    std::env::var_os("OLA_EMBEDDER_MODEL").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_MODEL))
+}
+
+pub trait Embedder: Send {
+    /// Short name for logs and `status`.
+    fn name(&self) -> &str;
+
+    /// Identifies the exact weights. Templates are only compared with
+    /// probes from the model whose ID they carry.
+    fn model_id(&self) -> &str {
+        self.name()
+    }
+
+    /// Unit-length embedding of `face`, found in `image`.
+    fn embed(&mut self, image: &RgbImage, face: &Detection) -> anyhow::Result<Vec<f32>>;
+}
+
+/// Scales `v` to unit length.
+pub fn normalize(v: &mut [f32]) -> anyhow::Result<()> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if !norm.is_normal() {
+        bail!("embedding has no direction");
+    }
+    v.iter_mut().for_each(|x| *x /= norm);
+    Ok(())
+}
+
+/// A `size`x`size` crop centred on the face, rotated so the first two
+/// landmarks (the eyes) lie on a horizontal line. Faces without landmarks are
+/// cropped upright. Pixels outside the image are black.
+pub fn align(image: &RgbImage, face: &Detection, size: u32) -> RgbImage {
+    let b = face.bbox;
+    let (cx, cy) = (b.x + b.width / 2.0, b.y + b.height / 2.0);
+    let angle = match face.landmarks[..] {
+        // Measured left to right in the image, whichever eye the model lists first
+        [p, q, ..] => {
+            let (left, right) = if p.x <= q.x { (p, q) } else { (q, p) };
+            (right.y - left.y).atan2(right.x - left.x)
+        }
+        _ => 0.0,
+    };
+    let (sin, cos) = angle.sin_cos();
+    let step = b.width.max(b.height) * CROP_MARGIN / size as f32;
+    let half = size as f32 / 2.0;
+
+    RgbImage::from_fn(size, size, |u, v| {
+        let dx = (u as f32 + 0.5 - half) * step;
+        let dy = (v as f32 + 0.5 - half) * step;
+        sample(image, cx + dx * cos - dy * sin, cy + dx * sin + dy * cos)
+    })
+}
+
+/// Bilinear sample at `(x, y)`, where pixel centres sit at half-integers.
+fn sample(image: &RgbImage, x: f32, y: f32) -> Rgb<u8> {
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let px = |xi: f32, yi: f32| -> [f32; 3] {
+        if xi < 0.0 || yi < 0.0 || xi >= image.width() as f32 || yi >= image.height() as f32 {
+            return [0.0; 3];
+        }
+        image.get_pixel(xi as u32, yi as u32).0.map(f32::from)
+    };
+    let (a, b, c, d) = (px(x0, y0), px(x0 + 1.0, y0), px(x0, y0 + 1.0), px(x0 + 1.0, y0 + 1.0));
+    Rgb(std::array::from_fn(|i| {
+        let top = a[i] + (b[i] - a[i]) * fx;
+        let bottom = c[i] + (d[i] - c[i]) * fx;
+        (top + (bottom - top) * fy).round().clamp(0.0, 255.0) as u8
+    }))
+}
+
+/// ArcFace-style ONNX model on tract.
+///
+/// The model takes one square RGB face crop scaled to [-1, 1], NHWC or NCHW,
+/// and returns the embedding as its first output, in any shape.
+pub struct OnnxEmbedder {
+    name: String,
+    /// Name and a digest of the model file.
+    model_id: String,
+    model: TypedRunnableModel<TypedModel>,
+    input_size: usize,
+    channels_first: bool,
+}
+
+impl OnnxEmbedder {
+    /// Loads a model the registry has already read and checked. This is the
+    /// only way to build one, so no model skips the manifest.
+    pub fn from_registry(model: &models::Model) -> anyhow::Result<Self> {
+        let onnx = tract_onnx::onnx()
+            .model_for_read(&mut &model.bytes[..])
+            .with_context(|| format!("loading face embedder {}", model.info.path.display()))?;
+        let name = model.name();
+        let model_id = model_id(&name, &model.bytes);
+        Self::from_model(onnx, name, model_id)
+    }
+
+    fn from_model(model: InferenceModel, name: String, model_id: String) -> anyhow::Result<Self> {
+        let model = model.into_typed()?;
+        let (channels_first, input_size) = detector::square_image_input(&model, "face embedder")?;
+        Ok(Self { name, model_id, model: model.into_optimized()?.into_runnable()?, input_size, channels_first })
+    }
+}
+
+/// `<name>-<first 12 hex digits of the SHA-256 of the model file>`: readable
+/// in logs, and different for retrained weights installed under the same name.
+pub fn model_id(name: &str, model: &[u8]) -> String {
+    let digest: String = Sha256::digest(model).iter().take(6).map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", name, digest)
+}
+
+impl Embedder for OnnxEmbedder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn embed(&mut self, image: &RgbImage, face: &Detection) -> anyhow::Result<Vec<f32>> {
+        let crop = align(image, face, self.input_size as u32);
+        let input = detector::image_tensor(&crop, self.input_size, self.channels_first, 0, 0);
+        let outputs = self.model.run(tvec!(input.into()))?;
+        let mut embedding: Vec<f32> = outputs[0].to_array_view::<f32>()?.iter().copied().collect();
+        normalize(&mut embedding)?;
+        Ok(embedding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::{BoundingBox, Point};
+    use crate::test_models::{int, ints, model, node, value_info};
+    use tract_onnx::pb;
+
+    fn face(x: f32, y: f32, side: f32, landmarks: Vec<Point>) -> Detection {
+        Detection { bbox: BoundingBox { x, y, width: side, height: side }, landmarks, confidence: 0.9 }
+    }
+
+    #[test]
+    fn crop_follows_the_face_and_levels_the_eyes() {
+        // Left half dark, right half bright
+        let image = RgbImage::from_fn(200, 200, |x, _| if x < 100 { Rgb([0, 0, 0]) } else { Rgb([200, 200, 200]) });
+
+        // Upright: the split runs down the middle of the crop
+        let crop = align(&image, &face(60.0, 60.0, 80.0, vec![]), 16);
+        assert_eq!(crop.get_pixel(2, 8), &Rgb([0, 0, 0]));
+        assert_eq!(crop.get_pixel(13, 8), &Rgb([200, 200, 200]));
+
+        // Eyes (nearly) stacked vertically means the head lies on its side, so
+        // the crop turns a quarter and the split runs across it instead. The
+        // order the eyes are listed in must not turn it upside down.
+        let eyes = vec![Point { x: 99.0, y: 120.0 }, Point { x: 101.0, y: 80.0 }];
+        for landmarks in [eyes.clone(), eyes.into_iter().rev().collect()] {
+            let crop = align(&image, &face(60.0, 60.0, 80.0, landmarks), 16);
+            assert_eq!(crop.get_pixel(8, 2), &Rgb([0, 0, 0]));
+            assert_eq!(crop.get_pixel(8, 13), &Rgb([200, 200, 200]));
+        }
+
+        // Crops hanging off the image are padded with black
+        let corner = align(&image, &face(180.0, 0.0, 40.0, vec![]), 16);
+        assert_eq!(corner.get_pixel(15, 0), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn embeddings_are_unit_length() {
+        // Stand-in model: the embedding is the mean colour of an NCHW 32x32 crop
+        let graph = pb::GraphProto {
+            node: vec![node("ReduceMean", &["input"], "mean", vec![ints("axes", &[2, 3]), int("keepdims", 0)])],
+            input: vec![value_info("input", &[1, 3, 32, 32])],
+            output: vec![value_info("mean", &[1, 3])],
+            ..Default::default()
+        };
+        let mut embedder = OnnxEmbedder::from_model(model(graph), "mean".into(), "mean-0".into()).unwrap();
+        assert_eq!(embedder.model_id(), "mean-0");
+
+        let image = RgbImage::from_pixel(64, 64, Rgb([255, 0, 0]));
+        let v = embedder.embed(&image, &face(16.0, 16.0, 24.0, vec![])).unwrap();
+        let third = 1.0 / 3f32.sqrt();
+        for (got, want) in v.iter().zip([third, -third, -third]) {
+            assert!((got - want).abs() < 1e-4, "{:?}", v);
+        }
+
+        assert!(normalize(&mut [0.0; 4]).is_err());
+    }
+
+    #[test]
+    fn model_ids_change_with_the_weights() {
+        let id = model_id("arcface", b"weights");
+        assert!(id.starts_with("arcface-") && id.len() == "arcface-".len() + 12, "{}", id);
+        assert_eq!(id, model_id("arcface", b"weights"));
+        assert_ne!(id, model_id("arcface", b"retrained"));
+    }
+}
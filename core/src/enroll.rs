@@ -0,0 +1,392 @@
+// src/enroll.rs
+//! Face enrollment sessions.
+//!
+//! A client opens a session with `enroll_begin` and calls `enroll_frame`
+//! until it reports `complete`. Each call captures one frame; the session
+//! keeps the face if it passes the `quality` checks, is consistent with the faces
+//! already kept and not a near-duplicate of one of them, and answers with
+//! guidance for the next frame. Kept samples live only in memory until
+//! `enroll_commit` writes them as templates in one go, so a session that is
+//! cancelled, expires or loses its connection leaves nothing behind.
+//!
+//! A session begun with `retain_crops` also stores each sample's face crop
+//! with its template, so the template can be migrated to a future model
+//! instead of enrolling again.
+use image::{imageops, RgbImage};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use super::detector::{Detection, Detector};
+use super::embedder::Embedder;
+use super::matcher::Metric;
+use super::quality::{Assessment, Issue, QualityConfig};
+use super::templates::{self, FaceCrop, Template};
+
+/// Samples a session collects before it can be committed.
+pub const SAMPLES_NEEDED: usize = 5;
+
+/// Sessions are dropped this long after `enroll_begin`.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A sample this similar (cosine) to one already kept adds nothing.
+const DUPLICATE_SIMILARITY: f32 = 0.97;
+
+/// Nose offset from the eye midpoint, in eye distances, that counts as turned.
+const TURN_YAW: f32 = 0.15;
+
+/// Rough head pose. Directions are the user's own, facing the camera.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pose {
+    Front,
+    Left,
+    Right,
+}
+
+impl Pose {
+    /// Estimated from where the nose sits between the eyes. Faces without
+    /// eye and nose landmarks count as frontal.
+    pub fn of(face: &Detection) -> Self {
+        let [a, b, nose, ..] = face.landmarks[..] else { return Pose::Front };
+        let eye_distance = (b.x - a.x).hypot(b.y - a.y);
+        if eye_distance <= f32::EPSILON {
+            return Pose::Front;
+        }
+        // Turning to your left moves your nose towards the right of the image
+        let yaw = (nose.x - (a.x + b.x) / 2.0) / eye_distance;
+        if yaw > TURN_YAW {
+            Pose::Left
+        } else if yaw < -TURN_YAW {
+            Pose::Right
+        } else {
+            Pose::Front
+        }
+    }
+
+    fn guidance(self) -> &'static str {
+        match self {
+            Pose::Front => "look straight at the camera",
+            Pose::Left => "turn slightly left",
+            Pose::Right => "turn slightly right",
+        }
+    }
+}
+
+/// Poses every enrollment needs at least one sample of, in the order they are asked for.
+const POSES: [Pose; 3] = [Pose::Front, Pose::Left, Pose::Right];
+
+/// What one frame showed, measured on the camera worker.
+#[derive(Debug, Clone)]
+pub struct Observation {
+    pub faces: Vec<Detection>,
+    /// Quality of the first face.
+    pub quality: Option<Assessment>,
+    /// Embedding of the face, when there is exactly one.
+    pub embedding: Option<Vec<f32>>,
+    /// Embedding model and camera, recorded with the template.
+    pub model: String,
+    pub model_id: String,
+    pub camera: Option<String>,
+    /// The face cut from the frame, when there is exactly one.
+    pub crop: Option<(RgbImage, Detection)>,
+}
+
+/// Detects, measures and (for a single face) embeds. `camera` is the stable
+/// ID of the camera `image` came from.
+pub fn observe(
+    image: &RgbImage,
+    camera: Option<String>,
+    detector: &mut dyn Detector,
+    embedder: &mut dyn Embedder,
+    gate: &QualityConfig,
+) -> anyhow::Result<Observation> {
+    let faces = detector.detect(image)?;
+    let quality = faces.first().map(|f| gate.assess(&imageops::grayscale(image), f));
+    let (embedding, crop) = match &faces[..] {
+        [face] => (Some(embedder.embed(image, face)?), Some(templates::cut_face(image, face))),
+        _ => (None, None),
+    };
+    Ok(Observation {
+        faces,
+        quality,
+        embedding,
+        model: embedder.name().to_string(),
+        model_id: embedder.model_id().to_string(),
+        camera,
+        crop,
+    })
+}
+
+/// Answer to one `enroll_frame`.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Progress {
+    pub accepted: bool,
+    /// Why the frame was not kept.
+    pub reason: Option<&'static str>,
+    /// 0-1, from detection confidence, face size and sharpness.
+    pub quality: Option<f32>,
+    pub pose: Option<Pose>,
+    pub samples: usize,
+    pub needed: usize,
+    pub complete: bool,
+    /// What the user should do for the next frame; `None` once complete.
+    pub guidance: Option<&'static str>,
+}
+
+struct Sample {
+    embedding: Vec<f32>,
+    pose: Pose,
+    model: String,
+    model_id: String,
+    camera: Option<String>,
+    crop: Option<(RgbImage, Detection)>,
+}
+
+pub struct Enrollment {
+    /// Given to every template the session stores.
+    label: String,
+    /// Whether templates keep the face crop they came from.
+    retain_crops: bool,
+    samples: Vec<Sample>,
+    started: Instant,
+}
+
+impl Enrollment {
+    pub fn new(label: String) -> Self {
+        Self { label, retain_crops: false, samples: Vec::new(), started: Instant::now() }
+    }
+
+    pub fn with_retained_crops(mut self, retain: bool) -> Self {
+        self.retain_crops = retain;
+        self
+    }
+
+    pub fn expired(&self) -> bool {
+        self.started.elapsed() >= SESSION_TIMEOUT
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.samples.len() >= SAMPLES_NEEDED
+    }
+
+    /// Poses still without a sample.
+    fn missing(&self) -> Vec<Pose> {
+        POSES.into_iter().filter(|p| !self.samples.iter().any(|s| s.pose == *p)).collect()
+    }
+
+    /// What to ask for next.
+    pub fn guidance(&self) -> Option<&'static str> {
+        if self.is_complete() {
+            return None;
+        }
+        Some(self.missing().first().map(|p| p.guidance()).unwrap_or("hold still"))
+    }
+
+    fn progress(&self, accepted: bool, reason: Option<&'static str>, quality: Option<f32>, pose: Option<Pose>) -> Progress {
+        // A rejected frame is retried with advice on what was wrong with it
+        let guidance = match reason {
+            Some("no_face") => Some("move into view of the camera"),
+            Some("multiple_faces") => Some("make sure only your face is in view"),
+            Some("different_face") => Some("only the person enrolling should be in view"),
+            Some(reason) => Issue::from_code(reason).map(Issue::guidance).or_else(|| self.guidance()),
+            None => self.guidance(),
+        };
+        Progress {
+            accepted,
+            reason,
+            quality,
+            pose,
+            samples: self.samples.len(),
+            needed: SAMPLES_NEEDED,
+            complete: self.is_complete(),
+            guidance,
+        }
+    }
+
+    /// Scores one frame and keeps its face if it helps the enrollment.
+    pub fn add(&mut self, obs: Observation) -> Progress {
+        let (face, embedding) = match (&obs.faces[..], obs.embedding) {
+            ([], _) => return self.progress(false, Some("no_face"), None, None),
+            ([face], Some(embedding)) => (face, embedding),
+            _ => return self.progress(false, Some("multiple_faces"), None, None),
+        };
+        let pose = Pose::of(face);
+        let quality = obs.quality.as_ref().map(|q| q.score);
+        let reject = |this: &Self, reason| this.progress(false, Some(reason), quality, Some(pose));
+
+        if self.is_complete() {
+            return reject(self, "complete");
+        }
+        if let Some(issue) = obs.quality.as_ref().and_then(|q| q.issues.first()) {
+            return reject(self, issue.code());
+        }
+        if !self.samples.is_empty() {
+            // Everything kept has to be the same person
+            let scores: Vec<f32> = self
+                .samples
+                .iter()
+                .filter_map(|s| Metric::Cosine.score(&embedding, &s.embedding))
+                .collect();
+            let mean = scores.iter().sum::<f32>() / scores.len().max(1) as f32;
+            if scores.len() != self.samples.len() || !Metric::Cosine.accepts(mean, Metric::Cosine.default_threshold()) {
+                return reject(self, "different_face");
+            }
+            if scores.iter().any(|&s| s >= DUPLICATE_SIMILARITY) {
+                return reject(self, "too_similar");
+            }
+        }
+        // The last free slots are reserved for poses not seen yet
+        let missing = self.missing();
+        if !missing.contains(&pose) && SAMPLES_NEEDED - self.samples.len() <= missing.len() {
+            return reject(self, "pose_needed");
+        }
+
+        let crop = if self.retain_crops { obs.crop } else { None };
+        self.samples.push(Sample { embedding, pose, model: obs.model, model_id: obs.model_id, camera: obs.camera, crop });
+        self.progress(true, None, quality, Some(pose))
+    }
+
+    /// The kept samples as new templates.
+    pub fn into_templates(self) -> anyhow::Result<Vec<Template>> {
+        let label = self.label;
+        self.samples
+            .into_iter()
+            .map(|s| {
+                let mut template = Template::new(&label, &s.model, &s.model_id, s.camera, s.embedding);
+                template.crop = s.crop.map(|(image, face)| FaceCrop::encode(&image, face)).transpose()?;
+                Ok(template)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::{BoundingBox, Point};
+
+    /// A 200px face in a 480px frame, nose offset `yaw` eye distances.
+    fn face(yaw: f32) -> Detection {
+        Detection {
+            bbox: BoundingBox { x: 200.0, y: 100.0, width: 200.0, height: 200.0 },
+            landmarks: vec![
+                Point { x: 250.0, y: 160.0 },
+                Point { x: 350.0, y: 160.0 },
+                Point { x: 300.0 + yaw * 100.0, y: 200.0 },
+            ],
+            confidence: 0.95,
+        }
+    }
+
+    /// Unit vector close to `base`, nudged along axis `k`.
+    fn embedding(base: usize, k: usize) -> Vec<f32> {
+        let mut v = vec![0.0; 16];
+        v[base] = 1.0;
+        v[k] += 0.5;
+        crate::embedder::normalize(&mut v).unwrap();
+        v
+    }
+
+    fn assessment(issues: Vec<Issue>) -> Assessment {
+        Assessment {
+            brightness: 120.0,
+            contrast: 40.0,
+            sharpness: 100.0,
+            face_size: 0.4,
+            yaw: Some(0.0),
+            pitch: Some(0.0),
+            occlusion: Some(0.0),
+            score: 0.95,
+            issues,
+        }
+    }
+
+    fn obs(face: Detection, embedding: Vec<f32>) -> Observation {
+        let crop = Some((RgbImage::new(8, 8), face.clone()));
+        Observation { faces: vec![face], embedding: Some(embedding), crop, ..blank() }
+    }
+
+    fn blank() -> Observation {
+        Observation {
+            faces: vec![],
+            quality: Some(assessment(vec![])),
+            embedding: None,
+            model: "arcface".into(),
+            model_id: "arcface-1".into(),
+            camera: Some("usb-cam".into()),
+            crop: None,
+        }
+    }
+
+    #[test]
+    fn pose_follows_the_nose() {
+        assert_eq!(Pose::of(&face(0.0)), Pose::Front);
+        assert_eq!(Pose::of(&face(0.3)), Pose::Left);
+        assert_eq!(Pose::of(&face(-0.3)), Pose::Right);
+        assert_eq!(Pose::of(&Detection { landmarks: vec![], ..face(0.3) }), Pose::Front);
+    }
+
+    #[test]
+    fn session_collects_varied_samples_of_one_face() {
+        let mut e = Enrollment::new("face".into());
+        assert_eq!(e.guidance(), Some("look straight at the camera"));
+
+        let p = e.add(obs(face(0.0), embedding(0, 1)));
+        assert!(p.accepted);
+        assert_eq!((p.samples, p.pose, p.guidance), (1, Some(Pose::Front), Some("turn slightly left")));
+        assert!(p.quality.unwrap() > 0.9);
+
+        // The same frame again adds nothing
+        let p = e.add(obs(face(0.0), embedding(0, 1)));
+        assert_eq!((p.accepted, p.reason), (false, Some("too_similar")));
+
+        // Somebody else
+        let p = e.add(obs(face(0.3), embedding(5, 6)));
+        assert_eq!((p.accepted, p.reason), (false, Some("different_face")));
+
+        let p = e.add(obs(face(0.0), embedding(0, 2)));
+        assert!(p.accepted);
+        let p = e.add(obs(face(0.0), embedding(0, 3)));
+        assert!(p.accepted);
+
+        // Two slots left, both needed for the turned poses
+        let p = e.add(obs(face(0.0), embedding(0, 4)));
+        assert_eq!((p.accepted, p.reason, p.guidance), (false, Some("pose_needed"), Some("turn slightly left")));
+        assert!(e.add(obs(face(0.3), embedding(0, 4))).accepted);
+        let p = e.add(obs(face(-0.3), embedding(0, 5)));
+        assert!(p.accepted && p.complete);
+        assert_eq!((p.samples, p.guidance), (SAMPLES_NEEDED, None));
+
+        assert_eq!(e.add(obs(face(0.0), embedding(0, 6))).reason, Some("complete"));
+        let templates = e.into_templates().unwrap();
+        assert_eq!(templates.len(), SAMPLES_NEEDED);
+        assert!(templates.iter().all(|t| t.label == "face" && t.model == "arcface" && t.camera.as_deref() == Some("usb-cam")));
+        assert!(templates.iter().all(|t| t.compatible_with("arcface-1") && t.crop.is_none()));
+
+        // Crops are only kept when asked for
+        let mut e = Enrollment::new("face".into()).with_retained_crops(true);
+        e.add(obs(face(0.0), embedding(0, 1)));
+        let templates = e.into_templates().unwrap();
+        assert_eq!(templates[0].crop.as_ref().unwrap().decode().unwrap().dimensions(), (8, 8));
+    }
+
+    #[test]
+    fn bad_frames_are_rejected_with_advice() {
+        let mut e = Enrollment::new("face".into());
+        assert_eq!(e.add(blank()).guidance, Some("move into view of the camera"));
+
+        let two = Observation { faces: vec![face(0.0), face(0.0)], ..blank() };
+        assert_eq!(e.add(two).reason, Some("multiple_faces"));
+
+        // The first quality issue is the one the user hears about
+        let far = Some(assessment(vec![Issue::TooFar, Issue::Blurry]));
+        let p = e.add(Observation { quality: far, ..obs(face(0.0), embedding(0, 1)) });
+        assert_eq!((p.reason, p.guidance), (Some("too_far"), Some("move closer to the camera")));
+
+        let p = e.add(Observation { quality: Some(assessment(vec![Issue::Blurry])), ..obs(face(0.0), embedding(0, 1)) });
+        assert_eq!((p.reason, p.guidance), (Some("blurry"), Some("hold still")));
+        let p = e.add(Observation { quality: Some(assessment(vec![Issue::TooDark])), ..obs(face(0.0), embedding(0, 1)) });
+        assert_eq!((p.reason, p.guidance, p.quality), (Some("too_dark"), Some("too dark, turn on a light"), Some(0.95)));
+        assert_eq!(p.samples, 0);
+    }
+}
@@ -0,0 +1,443 @@
+// src/eval.rs
+//! Offline evaluation of the recognition pipeline on a labelled dataset.
+//!
+//! The dataset is laid out like LFW: one directory per person, holding that
+//! person's images (PNG or JPEG).
+//!
+//! ```text
+//! dataset/
+//!   Ada_Lovelace/Ada_Lovelace_0001.jpg
+//!   Ada_Lovelace/Ada_Lovelace_0002.jpg
+//!   Alan_Turing/Alan_Turing_0001.jpg
+//! ```
+//!
+//! Each image goes through exposure enhancement, the detector, the face
+//! choice, the quality gate and the embedder exactly as a camera frame does
+//! in `verify_once`. Every pair of images is
+//! then scored with the configured metric: pairs of the same person are
+//! genuine, the rest impostors. From those scores come the false accept and
+//! false reject rates at each threshold, the equal error rate, and the
+//! thresholds that keep false accepts under given targets.
+use anyhow::{bail, Context};
+use image::{imageops, RgbImage};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::camera::{self, ReasonCode};
+use super::detector::{Detection, Detector};
+use super::embedder::Embedder;
+use super::exposure::{self, Exposure, ExposureConfig};
+use super::matcher::Metric;
+use super::quality::QualityConfig;
+
+/// False accept rates `recommended` reports thresholds for unless told otherwise.
+pub const DEFAULT_TARGET_FARS: [f64; 3] = [1e-2, 1e-3, 1e-4];
+
+/// Pairs scored per class unless told otherwise; above it pairs are sampled.
+pub const DEFAULT_MAX_PAIRS: usize = 2_000_000;
+
+/// Evenly spaced thresholds the ROC curve is reported at.
+const ROC_POINTS: usize = 51;
+
+/// Images of each person, by directory name. Both are sorted, so runs over
+/// the same dataset compare the same pairs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dataset {
+    pub identities: Vec<(String, Vec<PathBuf>)>,
+}
+
+impl Dataset {
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let mut identities = Vec::new();
+        for entry in fs::read_dir(root).with_context(|| format!("reading {}", root.display()))? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let mut images: Vec<PathBuf> = fs::read_dir(entry.path())?
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| {
+                    p.extension()
+                        .and_then(|e| e.to_str())
+                        .is_some_and(|e| ["png", "jpg", "jpeg"].contains(&e.to_ascii_lowercase().as_str()))
+                })
+                .collect();
+            if images.is_empty() {
+                continue;
+            }
+            images.sort();
+            identities.push((entry.file_name().to_string_lossy().to_string(), images));
+        }
+        if identities.is_empty() {
+            bail!("{} has no person directories with images", root.display());
+        }
+        identities.sort();
+        Ok(Self { identities })
+    }
+
+    pub fn images(&self) -> usize {
+        self.identities.iter().map(|(_, images)| images.len()).sum()
+    }
+}
+
+/// An image the pipeline produced an embedding for.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// Index into `Dataset::identities`.
+    pub identity: usize,
+    pub embedding: Vec<f32>,
+}
+
+/// An image the pipeline found nothing to match in, or only a face too poor
+/// to match, with the code `verify_once` would have given.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Failure {
+    pub image: String,
+    pub code: ReasonCode,
+}
+
+/// Embeds every image of `dataset` the way `verify_once` embeds a frame,
+/// with the daemon's `quality` limits and `exposure` settings. Faces the
+/// quality gate rejects are failures, not samples.
+pub fn embed_dataset(
+    dataset: &Dataset,
+    detector: &mut dyn Detector,
+    embedder: &mut dyn Embedder,
+    quality: &QualityConfig,
+    exposure: &ExposureConfig,
+) -> anyhow::Result<(Vec<Sample>, Vec<Failure>)> {
+    let mut samples = Vec::new();
+    let mut failures = Vec::new();
+    let total = dataset.images();
+    let mut done = 0;
+    for (identity, (_, images)) in dataset.identities.iter().enumerate() {
+        for path in images {
+            let image = image::open(path).with_context(|| format!("reading {}", path.display()))?.to_rgb8();
+            let (image, detected) = enhance(detector, exposure, image)?;
+            let code = match detected.first() {
+                None => Some(ReasonCode::NoFace),
+                Some(_) if camera::is_ambiguous(&detected) => Some(ReasonCode::MultipleFaces),
+                Some(face) if !quality.assess(&imageops::grayscale(&image), face).issues.is_empty() => Some(ReasonCode::LowQuality),
+                Some(face) => {
+                    samples.push(Sample { identity, embedding: embedder.embed(&image, face)? });
+                    None
+                }
+            };
+            if let Some(code) = code {
+                failures.push(Failure { image: path.display().to_string(), code });
+            }
+            done += 1;
+            if done % 100 == 0 {
+                log::info!("Embedded {}/{} images", done, total);
+            }
+        }
+    }
+    Ok((samples, failures))
+}
+
+/// `image` as a camera without controls would have delivered it by the next
+/// frame: enhanced toward the target when the face (or the middle of the
+/// image) is off it, with the faces found in it.
+fn enhance(detector: &mut dyn Detector, config: &ExposureConfig, image: RgbImage) -> anyhow::Result<(RgbImage, Vec<Detection>)> {
+    let detected = detector.detect(&image)?;
+    let mut exposure = Exposure::software(config.clone());
+    exposure.observe(exposure::luminance(&imageops::grayscale(&image), detected.first().map(|d| &d.bbox)));
+    if !exposure.report().enhanced {
+        return Ok((image, detected));
+    }
+    let image = exposure.prepare(image);
+    let detected = detector.detect(&image)?;
+    Ok((image, detected))
+}
+
+/// Scores of genuine and impostor pairs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scores {
+    pub genuine: Vec<f32>,
+    pub impostor: Vec<f32>,
+}
+
+/// Scores every pair of samples, or an evenly spread subset of at most
+/// `max_pairs` per class when there are more.
+pub fn score_pairs(metric: Metric, samples: &[Sample], max_pairs: usize) -> Scores {
+    let pairs = |n: usize| n * n.saturating_sub(1) / 2;
+    let mut per_identity = std::collections::HashMap::new();
+    for sample in samples {
+        *per_identity.entry(sample.identity).or_insert(0) += 1;
+    }
+    let genuine_total: usize = per_identity.values().map(|&n| pairs(n)).sum();
+    let impostor_total = pairs(samples.len()) - genuine_total;
+    // Every k-th pair of a class, so sampling does not favour any one person
+    let step = |total: usize| total.div_ceil(max_pairs.max(1)).max(1);
+    let (genuine_step, impostor_step) = (step(genuine_total), step(impostor_total));
+
+    let mut scores = Scores::default();
+    let (mut genuine_seen, mut impostor_seen) = (0, 0);
+    for (i, a) in samples.iter().enumerate() {
+        for b in &samples[i + 1..] {
+            let (seen, step, out) = match a.identity == b.identity {
+                true => (&mut genuine_seen, genuine_step, &mut scores.genuine),
+                false => (&mut impostor_seen, impostor_step, &mut scores.impostor),
+            };
+            if *seen % step == 0 {
+                // Samples of another embedding size cannot come from one pipeline run
+                if let Some(score) = metric.score(&a.embedding, &b.embedding) {
+                    out.push(score);
+                }
+            }
+            *seen += 1;
+        }
+    }
+    scores
+}
+
+/// Error rates at one threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RocPoint {
+    pub threshold: f32,
+    /// Impostor pairs accepted.
+    pub far: f64,
+    /// Genuine pairs rejected.
+    pub frr: f64,
+}
+
+/// The threshold where false accepts and false rejects are closest to equal.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Eer {
+    /// Mean of the two rates there.
+    pub rate: f64,
+    pub threshold: f32,
+}
+
+/// The threshold with the fewest false rejects that keeps false accepts at or
+/// under `target_far`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Recommendation {
+    pub target_far: f64,
+    pub threshold: f32,
+    pub far: f64,
+    pub frr: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub metric: Metric,
+    pub detector: String,
+    pub embedder: String,
+    pub identities: usize,
+    pub images: usize,
+    /// Images that were left out, and why.
+    pub failures: Vec<Failure>,
+    pub genuine_pairs: usize,
+    pub impostor_pairs: usize,
+    #[serde(flatten)]
+    pub rates: ErrorRates,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorRates {
+    /// Rates at the threshold `match.conf` configures now.
+    pub configured: RocPoint,
+    pub eer: Option<Eer>,
+    pub recommended: Vec<Recommendation>,
+    pub roc: Vec<RocPoint>,
+}
+
+/// Scores sorted so a threshold's accepts can be counted by bisection.
+struct Rates<'a> {
+    metric: Metric,
+    genuine: &'a [f32],
+    impostor: &'a [f32],
+}
+
+impl Rates<'_> {
+    fn accepted(&self, sorted: &[f32], threshold: f32) -> usize {
+        // Ascending order: similarity accepts the top, distance the bottom
+        match self.metric {
+            Metric::Cosine => sorted.len() - sorted.partition_point(|&s| s < threshold),
+            Metric::L2 => sorted.partition_point(|&s| s <= threshold),
+        }
+    }
+
+    fn at(&self, threshold: f32) -> RocPoint {
+        let rate = |n: usize, total: usize| if total == 0 { 0.0 } else { n as f64 / total as f64 };
+        RocPoint {
+            threshold,
+            far: rate(self.accepted(self.impostor, threshold), self.impostor.len()),
+            frr: rate(self.genuine.len() - self.accepted(self.genuine, threshold), self.genuine.len()),
+        }
+    }
+}
+
+/// Error rates of `scores`, with the rates at `configured` for comparison.
+pub fn evaluate(metric: Metric, mut scores: Scores, configured: f32, target_fars: &[f64]) -> ErrorRates {
+    scores.genuine.sort_by(f32::total_cmp);
+    scores.impostor.sort_by(f32::total_cmp);
+    let rates = Rates { metric, genuine: &scores.genuine, impostor: &scores.impostor };
+
+    // Every score is a threshold where a rate can change
+    let mut thresholds: Vec<f32> = scores.genuine.iter().chain(&scores.impostor).copied().collect();
+    thresholds.sort_by(f32::total_cmp);
+    thresholds.dedup();
+    let points: Vec<RocPoint> = thresholds.iter().map(|&t| rates.at(t)).collect();
+
+    let eer = (!scores.genuine.is_empty() && !scores.impostor.is_empty())
+        .then(|| {
+            points
+                .iter()
+                .min_by(|a, b| (a.far - a.frr).abs().total_cmp(&(b.far - b.frr).abs()))
+                .map(|p| Eer { rate: (p.far + p.frr) / 2.0, threshold: p.threshold })
+        })
+        .flatten();
+    let recommended = target_fars
+        .iter()
+        .filter_map(|&target| {
+            points
+                .iter()
+                .filter(|p| p.far <= target)
+                .min_by(|a, b| (a.frr, a.far).partial_cmp(&(b.frr, b.far)).unwrap())
+                .map(|p| Recommendation { target_far: target, threshold: p.threshold, far: p.far, frr: p.frr })
+        })
+        .collect();
+    let roc = match (thresholds.first(), thresholds.last()) {
+        (Some(&lo), Some(&hi)) => (0..ROC_POINTS)
+            .map(|i| {
+                let f = i as f32 / (ROC_POINTS - 1) as f32;
+                // Exactly `lo` and `hi` at the ends
+                rates.at(lo * (1.0 - f) + hi * f)
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    ErrorRates { configured: rates.at(configured), eer, recommended, roc }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::{BoundingBox, Detection};
+    use crate::exposure::ExposureConfig;
+    use crate::quality::QualityConfig;
+    use crate::test_models::embeddings;
+    use image::RgbImage;
+
+    /// The shared embedding fixture as samples: five people, four captures each.
+    fn samples() -> Vec<Sample> {
+        embeddings()
+            .identities
+            .into_values()
+            .enumerate()
+            .flat_map(|(identity, captures)| captures.into_iter().map(move |embedding| Sample { identity, embedding }))
+            .collect()
+    }
+
+    #[test]
+    fn separable_scores_have_no_errors_at_a_sensible_threshold() {
+        for metric in [Metric::Cosine, Metric::L2] {
+            let scores = score_pairs(metric, &samples(), DEFAULT_MAX_PAIRS);
+            // 5 people x 6 pairs of their own, the rest of the 190 pairs impostors
+            assert_eq!((scores.genuine.len(), scores.impostor.len()), (30, 160));
+            let rates = evaluate(metric, scores, metric.default_threshold(), &DEFAULT_TARGET_FARS);
+            assert_eq!((rates.configured.far, rates.configured.frr), (0.0, 0.0));
+            let eer = rates.eer.unwrap();
+            assert_eq!(eer.rate, 0.0);
+            assert_eq!(rates.recommended.len(), 3);
+            for r in &rates.recommended {
+                assert_eq!((r.far, r.frr), (0.0, 0.0), "{:?}", r);
+            }
+            // The curve runs from accepting everything to rejecting all but the closest
+            assert_eq!(rates.roc.len(), ROC_POINTS);
+            let (first, last) = (rates.roc[0], rates.roc[ROC_POINTS - 1]);
+            match metric {
+                Metric::Cosine => assert!(first.far == 1.0 && first.frr == 0.0 && last.far == 0.0, "{:?}", rates.roc),
+                Metric::L2 => assert!(first.far == 0.0 && first.frr > 0.0 && last.far == 1.0 && last.frr == 0.0, "{:?}", rates.roc),
+            }
+        }
+
+        // Too many pairs are sampled evenly, not cut off
+        let scores = score_pairs(Metric::Cosine, &samples(), 10);
+        assert_eq!((scores.genuine.len(), scores.impostor.len()), (10, 10));
+    }
+
+    #[test]
+    fn overlapping_scores_trade_false_accepts_for_false_rejects() {
+        let scores = Scores { genuine: vec![0.3, 0.5, 0.6, 0.7], impostor: vec![0.1, 0.2, 0.4, 0.55] };
+        let rates = evaluate(Metric::Cosine, scores, 0.45, &[0.25, 0.0]);
+        assert_eq!((rates.configured.far, rates.configured.frr), (0.25, 0.25));
+        assert_eq!(rates.eer, Some(Eer { rate: 0.25, threshold: 0.5 }));
+        let thresholds: Vec<_> = rates.recommended.iter().map(|r| (r.threshold, r.frr)).collect();
+        assert_eq!(thresholds, vec![(0.5, 0.25), (0.6, 0.5)]);
+    }
+
+    /// Finds a face in any image that is not black.
+    struct BrightDetector;
+
+    impl Detector for BrightDetector {
+        fn name(&self) -> &str {
+            "bright"
+        }
+
+        fn detect(&mut self, image: &RgbImage) -> anyhow::Result<Vec<Detection>> {
+            let face = Detection {
+                bbox: BoundingBox { x: 0.0, y: 0.0, width: 4.0, height: 4.0 },
+                landmarks: vec![],
+                confidence: 0.9,
+            };
+            Ok(if image.get_pixel(0, 0)[0] > 0 { vec![face] } else { vec![] })
+        }
+    }
+
+    /// Embeds a face as the colour of its image.
+    struct ColourEmbedder;
+
+    impl Embedder for ColourEmbedder {
+        fn name(&self) -> &str {
+            "colour"
+        }
+
+        fn embed(&mut self, image: &RgbImage, _face: &Detection) -> anyhow::Result<Vec<f32>> {
+            Ok(image.get_pixel(0, 0).0.iter().map(|&c| c as f32).collect())
+        }
+    }
+
+    #[test]
+    fn dataset_images_go_through_the_pipeline() {
+        let root = std::env::temp_dir().join(format!("ola-eval-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (person, images) in [("ada", vec![[200, 10, 10], [0, 0, 0]]), ("bo", vec![[10, 200, 10], [20, 190, 10]])] {
+            fs::create_dir_all(root.join(person)).unwrap();
+            for (i, colour) in images.into_iter().enumerate() {
+                RgbImage::from_pixel(4, 4, image::Rgb(colour)).save(root.join(person).join(format!("{}.png", i))).unwrap();
+            }
+        }
+        fs::write(root.join("ada/notes.txt"), "not an image").unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+
+        let dataset = Dataset::load(&root).unwrap();
+        let names: Vec<_> = dataset.identities.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!((names, dataset.images()), (vec!["ada", "bo"], 4));
+        let image = |name: &str| root.join(name).display().to_string();
+        let (ungated, as_taken) = (QualityConfig::parse("disable all").unwrap(), ExposureConfig::parse("enhance off").unwrap());
+        let (samples, failures) = embed_dataset(&dataset, &mut BrightDetector, &mut ColourEmbedder, &ungated, &as_taken).unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(failures, vec![Failure { image: image("ada/1.png"), code: ReasonCode::NoFace }]);
+        let scores = score_pairs(Metric::Cosine, &samples, DEFAULT_MAX_PAIRS);
+        assert_eq!((scores.genuine.len(), scores.impostor.len()), (1, 2));
+
+        // Faces the daemon would not match are failures, not scored samples
+        let checks = ["contrast", "sharpness", "face_size", "pose", "occlusion"].map(|c| format!("disable {}\n", c)).concat();
+        let gate = QualityConfig::parse(&format!("brightness 90 255\n{}", checks)).unwrap();
+        let (samples, failures) = embed_dataset(&dataset, &mut BrightDetector, &mut ColourEmbedder, &gate, &as_taken).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(failures[0], Failure { image: image("ada/0.png"), code: ReasonCode::LowQuality });
+        // Unless enhancement brings them up to it, as it would a camera's
+        let (samples, failures) = embed_dataset(&dataset, &mut BrightDetector, &mut ColourEmbedder, &gate, &ExposureConfig::default()).unwrap();
+        assert_eq!((samples.len(), failures.len()), (3, 1));
+        assert_eq!(samples[0].embedding, vec![255.0, 50.0, 50.0]);
+
+        assert!(Dataset::load(&root.join("empty")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
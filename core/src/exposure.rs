@@ -0,0 +1,683 @@
+// src/exposure.rs
+//! Capture-time exposure control and low-light enhancement.
+//!
+//! A dim office is where face unlock fails most: the sensor's own auto
+//! exposure meters the whole scene, so a face lit only by a monitor comes out
+//! too dark to detect or match. While a camera verifies, the mean luma of the
+//! face (of the frame centre until a face is found) is steered toward a
+//! target through the V4L2 exposure, gain and brightness controls, in that
+//! order when brightening since exposure adds the least noise. Whatever was
+//! changed is put back when the capture ends. When the controls run out, or
+//! the camera has none, frames are enhanced in software before detection: a
+//! gamma curve lifts the face to the target and CLAHE brings back local
+//! contrast, or plain histogram equalisation does both. Settings come from
+//! `/etc/ola/exposure.conf` (or `OLA_EXPOSURE_CONFIG`):
+//!
+//! ```text
+//! # mean face luma to aim for, and how far off is close enough
+//! target 110
+//! tolerance 25
+//! # control changes to try before enhancing in software
+//! steps 6
+//! # clahe, equalize or off
+//! enhance clahe
+//! clip_limit 2.5
+//! tiles 8
+//! # leave the camera's controls alone
+//! disable hardware
+//! ```
+use anyhow::{bail, Context};
+use image::{imageops, GrayImage, RgbImage};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::detector::BoundingBox;
+use super::liveness::{region, stats};
+use super::v4l2;
+
+pub const DEFAULT_CONFIG: &str = "/etc/ola/exposure.conf";
+
+/// Frames to skip after a control change; sensors apply them a frame or two late.
+const SETTLE_FRAMES: u32 = 2;
+
+/// Most a single change may scale the signal by, so one bad frame cannot
+/// slam the controls to their limits.
+const MAX_RATIO: f32 = 4.0;
+
+pub fn config_path() -> PathBuf {
+    std::env::var_os("OLA_EXPOSURE_CONFIG").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG))
+}
+
+/// Software fallback once the hardware cannot reach the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enhance {
+    /// A gamma curve toward the target, then contrast-limited adaptive
+    /// histogram equalisation, which brings out a dim face's detail without
+    /// stretching sensor noise in flat areas.
+    Clahe,
+    /// Global histogram equalisation.
+    Equalize,
+    Off,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExposureConfig {
+    /// Mean face luma to aim for, 0-255.
+    pub target: f32,
+    pub tolerance: f32,
+    /// Control changes per capture before falling back to software.
+    pub steps: u32,
+    pub enhance: Enhance,
+    /// CLAHE histogram clip, as a multiple of the mean bin height.
+    pub clip_limit: f32,
+    /// CLAHE tiles across and down.
+    pub tiles: u32,
+    /// Whether the camera's controls may be changed.
+    pub hardware: bool,
+}
+
+impl Default for ExposureConfig {
+    fn default() -> Self {
+        Self { target: 110.0, tolerance: 25.0, steps: 6, enhance: Enhance::Clahe, clip_limit: 2.5, tiles: 8, hardware: true }
+    }
+}
+
+impl ExposureConfig {
+    /// Reads `path`; a missing file means defaults.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |s: &str, min: f32, max: f32| -> anyhow::Result<f32> {
+                match s.parse::<f32>() {
+                    Ok(v) if (min..=max).contains(&v) => Ok(v),
+                    _ => bail!("line {}: {} must be a number between {} and {}", n + 1, words[0], min, max),
+                }
+            };
+            match words[..] {
+                [] => {}
+                ["target", v] => config.target = number(v, 1.0, 254.0)?,
+                ["tolerance", v] => config.tolerance = number(v, 0.0, 255.0)?,
+                ["steps", v] => config.steps = number(v, 0.0, 100.0)? as u32,
+                ["enhance", "clahe"] => config.enhance = Enhance::Clahe,
+                ["enhance", "equalize"] => config.enhance = Enhance::Equalize,
+                ["enhance", "off"] => config.enhance = Enhance::Off,
+                ["enhance", other] => bail!("line {}: unknown enhancement '{}' (expected clahe, equalize or off)", n + 1, other),
+                ["clip_limit", v] => config.clip_limit = number(v, 1.0, 256.0)?,
+                ["tiles", v] => config.tiles = number(v, 1.0, 64.0)? as u32,
+                ["disable", "hardware"] => config.hardware = false,
+                _ => bail!("line {}: expected an exposure setting or 'disable hardware': {}", n + 1, line),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The camera controls exposure is steered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Exposure,
+    Gain,
+    Brightness,
+}
+
+impl Control {
+    /// In the order they brighten a frame.
+    const ALL: [Control; 3] = [Control::Exposure, Control::Gain, Control::Brightness];
+
+    fn id(self) -> u32 {
+        match self {
+            Control::Exposure => v4l2::V4L2_CID_EXPOSURE_ABSOLUTE,
+            Control::Gain => v4l2::V4L2_CID_GAIN,
+            Control::Brightness => v4l2::V4L2_CID_BRIGHTNESS,
+        }
+    }
+
+    /// The automatic mode that overrides the control, and its manual value.
+    fn auto(self) -> Option<(u32, i32)> {
+        match self {
+            Control::Exposure => Some((v4l2::V4L2_CID_EXPOSURE_AUTO, v4l2::V4L2_EXPOSURE_MANUAL)),
+            Control::Gain => Some((v4l2::V4L2_CID_AUTOGAIN, 0)),
+            Control::Brightness => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub min: i32,
+    pub max: i32,
+    pub step: i32,
+}
+
+impl Range {
+    fn snap(&self, value: f32) -> i32 {
+        let value = (value.round() as i64).clamp(self.min as i64, self.max as i64) as i32;
+        let step = self.step.max(1);
+        self.min + (value - self.min) / step * step
+    }
+}
+
+/// The V4L2 control queries exposure needs. Split out so the controller can
+/// be tested without hardware.
+pub trait CameraControls: Send {
+    /// `None` when the camera lacks the control or it cannot be written.
+    fn query(&mut self, id: u32) -> io::Result<Option<Range>>;
+    fn get(&mut self, id: u32) -> io::Result<i32>;
+    fn set(&mut self, id: u32, value: i32) -> io::Result<()>;
+}
+
+/// Controls through `VIDIOC_QUERYCTRL`, `G_CTRL` and `S_CTRL` on a device node.
+pub struct V4l2Controls {
+    file: File,
+}
+
+impl V4l2Controls {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self { file: v4l2::open_device(path)? })
+    }
+}
+
+impl CameraControls for V4l2Controls {
+    fn query(&mut self, id: u32) -> io::Result<Option<Range>> {
+        match v4l2::query_control(&self.file, id) {
+            Ok(q) if q.flags & (v4l2::V4L2_CTRL_FLAG_DISABLED | v4l2::V4L2_CTRL_FLAG_READ_ONLY) == 0 => {
+                Ok(Some(Range { min: q.minimum, max: q.maximum, step: q.step }))
+            }
+            Ok(_) | Err(nix::errno::Errno::EINVAL) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get(&mut self, id: u32) -> io::Result<i32> {
+        v4l2::get_control(&self.file, id).map_err(io::Error::from)
+    }
+
+    fn set(&mut self, id: u32, value: i32) -> io::Result<()> {
+        v4l2::set_control(&self.file, id, value).map_err(io::Error::from)
+    }
+}
+
+/// What exposure control did during one capture, for diagnostics.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExposureReport {
+    /// Mean face luma of the last frame, 0-255.
+    pub luminance: Option<f32>,
+    /// Camera control changes made.
+    pub adjustments: u32,
+    /// Whether frames were enhanced in software.
+    pub enhanced: bool,
+}
+
+struct Knob {
+    control: Control,
+    range: Range,
+    original: i32,
+    value: i32,
+    /// Automatic mode still to be switched off before the first change.
+    auto: Option<(u32, i32)>,
+}
+
+/// Steers one capture's exposure and puts the camera's controls back when
+/// dropped.
+pub struct Exposure {
+    config: ExposureConfig,
+    ctrl: Option<Box<dyn CameraControls>>,
+    knobs: Vec<Knob>,
+    /// Automatic modes switched off, and the values to restore.
+    autos: Vec<(u32, i32)>,
+    settling: u32,
+    /// The hardware can do no more.
+    exhausted: bool,
+    /// Software curve from the luminance the hardware left us with to the target.
+    gamma: f32,
+    report: ExposureReport,
+}
+
+impl std::fmt::Debug for Exposure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Exposure").field("config", &self.config).field("report", &self.report).finish()
+    }
+}
+
+impl Exposure {
+    /// Enhancement only, for cameras without usable controls.
+    pub fn software(config: ExposureConfig) -> Self {
+        Self { config, ctrl: None, knobs: Vec::new(), autos: Vec::new(), settling: 0, exhausted: false, gamma: 1.0, report: ExposureReport::default() }
+    }
+
+    /// Takes over whichever of exposure, gain and brightness the camera has.
+    /// Automatic modes stay on until a control actually has to change.
+    pub fn take(mut ctrl: Box<dyn CameraControls>, config: ExposureConfig) -> anyhow::Result<Self> {
+        let mut knobs = Vec::new();
+        for control in Control::ALL {
+            let Some(range) = ctrl.query(control.id()).with_context(|| format!("querying {:?} control", control))? else {
+                continue;
+            };
+            let value = ctrl.get(control.id()).with_context(|| format!("reading {:?} control", control))?;
+            let auto = match control.auto() {
+                Some((id, manual)) => ctrl.query(id)?.map(|_| (id, manual)),
+                None => None,
+            };
+            knobs.push(Knob { control, range, original: value, value, auto });
+        }
+        let mut exposure = Self::software(config);
+        (exposure.ctrl, exposure.knobs) = (Some(ctrl), knobs);
+        Ok(exposure)
+    }
+
+    pub fn report(&self) -> ExposureReport {
+        self.report.clone()
+    }
+
+    /// Feeds back the mean luma of the face in the latest frame (see
+    /// `luminance`). Adjusts one control when it is off target, and turns
+    /// software enhancement on once the controls cannot get there.
+    pub fn observe(&mut self, luminance: f32) {
+        self.report.luminance = Some(luminance);
+        if self.exhausted {
+            return;
+        }
+        if self.settling > 0 {
+            self.settling -= 1;
+            return;
+        }
+        if (luminance - self.config.target).abs() <= self.config.tolerance {
+            return;
+        }
+        if self.report.adjustments < self.config.steps {
+            match self.adjust(luminance) {
+                Ok(true) => {
+                    self.report.adjustments += 1;
+                    self.settling = SETTLE_FRAMES;
+                    return;
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Exposure control failed, enhancing frames instead: {}", e),
+            }
+        }
+        self.exhausted = true;
+        self.report.enhanced = self.config.enhance != Enhance::Off;
+        // Maps the face's luma onto the target: (l/255)^gamma = target/255
+        let level = |v: f32| (v / 255.0).clamp(1.0 / 255.0, 254.0 / 255.0).ln();
+        self.gamma = (level(self.config.target) / level(luminance)).clamp(0.2, 5.0);
+    }
+
+    /// Whether frames come out the way they will stay: the face is on target,
+    /// or as close as the controls get it, and no change is still settling.
+    pub fn steady(&self) -> bool {
+        let on_target = self.report.luminance.is_some_and(|l| (l - self.config.target).abs() <= self.config.tolerance);
+        self.settling == 0 && (self.exhausted || on_target)
+    }
+
+    /// Moves the first control that still has room toward the target.
+    /// Brightening starts with exposure, darkening undoes in reverse.
+    fn adjust(&mut self, luminance: f32) -> io::Result<bool> {
+        let Some(ctrl) = self.ctrl.as_mut() else { return Ok(false) };
+        let target = self.config.target;
+        let ratio = (target / luminance.max(1.0)).clamp(1.0 / MAX_RATIO, MAX_RATIO);
+        let order: Vec<usize> = match ratio > 1.0 {
+            true => (0..self.knobs.len()).collect(),
+            false => (0..self.knobs.len()).rev().collect(),
+        };
+        for i in order {
+            let knob = &mut self.knobs[i];
+            let Range { min, max, .. } = knob.range;
+            let span = (max - min) as f32;
+            let wanted = match knob.control {
+                // An offset on the signal rather than a multiplier
+                Control::Brightness => knob.value as f32 + (target - luminance) * span / 255.0,
+                _ => {
+                    // A control at its minimum still needs somewhere to scale from
+                    let base = (knob.value - min) as f32;
+                    let base = if ratio > 1.0 { base.max(span / 16.0) } else { base };
+                    min as f32 + base * ratio
+                }
+            };
+            let next = knob.range.snap(wanted);
+            if next == knob.value {
+                continue;
+            }
+            if let Some((id, manual)) = knob.auto.take() {
+                let original = ctrl.get(id)?;
+                if original != manual {
+                    ctrl.set(id, manual)?;
+                    self.autos.push((id, original));
+                }
+            }
+            ctrl.set(knob.control.id(), next)?;
+            knob.value = next;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// `image` as detection should see it: enhanced once the hardware has
+    /// given up, untouched before.
+    pub fn prepare(&self, image: RgbImage) -> RgbImage {
+        if !self.report.enhanced {
+            return image;
+        }
+        let luma = imageops::grayscale(&image);
+        let enhanced = match self.config.enhance {
+            Enhance::Clahe => clahe(&curve(&luma, self.gamma), self.config.tiles, self.config.clip_limit),
+            Enhance::Equalize => equalize(&luma),
+            Enhance::Off => return image,
+        };
+        // Scale each pixel by its luma gain so colours keep their hue
+        let mut image = image;
+        for ((p, before), after) in image.pixels_mut().zip(luma.pixels()).zip(enhanced.pixels()) {
+            let gain = (after[0] as f32 + 1.0) / (before[0] as f32 + 1.0);
+            for c in p.0.iter_mut() {
+                *c = (*c as f32 * gain).round().min(255.0) as u8;
+            }
+        }
+        image
+    }
+}
+
+impl Drop for Exposure {
+    fn drop(&mut self) {
+        let Some(ctrl) = self.ctrl.as_mut() else { return };
+        for knob in self.knobs.iter().filter(|k| k.value != k.original) {
+            if let Err(e) = ctrl.set(knob.control.id(), knob.original) {
+                warn!("Failed to restore {:?} control: {}", knob.control, e);
+            }
+        }
+        for &(id, value) in &self.autos {
+            if let Err(e) = ctrl.set(id, value) {
+                warn!("Failed to restore automatic mode {:#x}: {}", id, e);
+            }
+        }
+    }
+}
+
+/// Mean luma of `face`, or of the middle of the frame when there is none:
+/// that is where a face usually is.
+pub fn luminance(luma: &GrayImage, face: Option<&BoundingBox>) -> f32 {
+    let (w, h) = (luma.width() as f32, luma.height() as f32);
+    let centre = BoundingBox { x: w / 4.0, y: h / 4.0, width: w / 2.0, height: h / 2.0 };
+    region(luma, face.unwrap_or(&centre), 1.0, 1.0).map(|r| stats(luma, r).0).unwrap_or(0.0)
+}
+
+/// Applies `v' = 255 (v / 255)^gamma`.
+pub fn curve(luma: &GrayImage, gamma: f32) -> GrayImage {
+    let lut: Vec<u8> = (0..256).map(|v| (255.0 * (v as f32 / 255.0).powf(gamma)).round() as u8).collect();
+    let mut out = luma.clone();
+    for p in out.pixels_mut() {
+        p[0] = lut[p[0] as usize];
+    }
+    out
+}
+
+/// Maps `hist` through its clipped, normalised cumulative sum.
+fn equalizer(hist: &mut [u32; 256], clip: Option<u32>) -> [u8; 256] {
+    if let Some(limit) = clip {
+        // Spread what sticks out above the limit evenly over all bins
+        let excess: u32 = hist.iter().map(|&n| n.saturating_sub(limit)).sum();
+        for n in hist.iter_mut() {
+            *n = (*n).min(limit) + excess / 256;
+        }
+        let rest = (excess % 256) as usize;
+        if let Some(step) = 256usize.checked_div(rest) {
+            for n in hist.iter_mut().step_by(step).take(rest) {
+                *n += 1;
+            }
+        }
+    }
+    let total: u32 = hist.iter().sum();
+    let mut lut = [0u8; 256];
+    let mut sum = 0;
+    for (v, n) in hist.iter().enumerate() {
+        sum += n;
+        lut[v] = (sum as f32 * 255.0 / total.max(1) as f32).round() as u8;
+    }
+    lut
+}
+
+/// Global histogram equalisation.
+pub fn equalize(luma: &GrayImage) -> GrayImage {
+    let mut hist = [0u32; 256];
+    for p in luma.pixels() {
+        hist[p[0] as usize] += 1;
+    }
+    // Start the ramp at the darkest pixel rather than above it
+    let first = hist.iter().position(|&n| n > 0).unwrap_or(0);
+    let skip = hist[first];
+    let total = luma.pixels().len() as u32 - skip;
+    let mut lut = [0u8; 256];
+    let mut sum = 0;
+    for v in first..256 {
+        sum += hist[v];
+        lut[v] = ((sum - skip) as f32 * 255.0 / total.max(1) as f32).round() as u8;
+    }
+    let mut out = luma.clone();
+    for p in out.pixels_mut() {
+        p[0] = lut[p[0] as usize];
+    }
+    out
+}
+
+/// Contrast-limited adaptive histogram equalisation over `tiles` x `tiles`
+/// regions, interpolating between neighbouring tiles so no seams show.
+/// Clipping each histogram at `clip_limit` times its mean bin keeps flat
+/// areas (and their sensor noise) from being stretched.
+pub fn clahe(luma: &GrayImage, tiles: u32, clip_limit: f32) -> GrayImage {
+    let (w, h) = luma.dimensions();
+    let (tx, ty) = (tiles.clamp(1, w.max(1)), tiles.clamp(1, h.max(1)));
+    let mut luts = Vec::with_capacity((tx * ty) as usize);
+    for j in 0..ty {
+        for i in 0..tx {
+            let (x0, x1) = (i * w / tx, (i + 1) * w / tx);
+            let (y0, y1) = (j * h / ty, (j + 1) * h / ty);
+            let mut hist = [0u32; 256];
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    hist[luma.get_pixel(x, y)[0] as usize] += 1;
+                }
+            }
+            let limit = (clip_limit * ((x1 - x0) * (y1 - y0)) as f32 / 256.0).max(1.0) as u32;
+            luts.push(equalizer(&mut hist, Some(limit)));
+        }
+    }
+
+    // Each pixel blends the LUTs of the four tile centres around it
+    let neighbours = |pos: u32, size: u32, count: u32| {
+        let f = ((pos as f32 + 0.5) * count as f32 / size as f32 - 0.5).max(0.0);
+        let lo = (f as u32).min(count - 1);
+        (lo, (lo + 1).min(count - 1), (f - lo as f32).clamp(0.0, 1.0))
+    };
+    let mut out = luma.clone();
+    for (x, y, p) in out.enumerate_pixels_mut() {
+        let v = p[0] as usize;
+        let (i0, i1, ax) = neighbours(x, w, tx);
+        let (j0, j1, ay) = neighbours(y, h, ty);
+        let at = |i: u32, j: u32| luts[(j * tx + i) as usize][v] as f32;
+        let top = at(i0, j0) * (1.0 - ax) + at(i1, j0) * ax;
+        let bottom = at(i0, j1) * (1.0 - ax) + at(i1, j1) * ax;
+        p[0] = (top * (1.0 - ay) + bottom * ay).round() as u8;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, Rgb};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// A sensor in front of a textured scene of the given reflectance: the
+    /// frame's luma scales with exposure and gain, brightness adds to it.
+    #[derive(Clone)]
+    struct FakeSensor {
+        ranges: HashMap<u32, Range>,
+        values: Arc<Mutex<HashMap<u32, i32>>>,
+    }
+
+    impl FakeSensor {
+        fn new(exposure_max: i32, brightness: i32) -> Self {
+            let ranges = HashMap::from([
+                (v4l2::V4L2_CID_EXPOSURE_AUTO, Range { min: 0, max: 3, step: 1 }),
+                (v4l2::V4L2_CID_EXPOSURE_ABSOLUTE, Range { min: 1, max: exposure_max, step: 1 }),
+                (v4l2::V4L2_CID_GAIN, Range { min: 0, max: 100, step: 1 }),
+                (v4l2::V4L2_CID_BRIGHTNESS, Range { min: -brightness, max: brightness, step: 1 }),
+            ]);
+            let values = HashMap::from([
+                (v4l2::V4L2_CID_EXPOSURE_AUTO, 3),
+                (v4l2::V4L2_CID_EXPOSURE_ABSOLUTE, 100),
+                (v4l2::V4L2_CID_GAIN, 0),
+                (v4l2::V4L2_CID_BRIGHTNESS, 0),
+            ]);
+            Self { ranges, values: Arc::new(Mutex::new(values)) }
+        }
+
+        fn value(&self, id: u32) -> i32 {
+            self.values.lock().unwrap()[&id]
+        }
+
+        fn frame(&self, scene: f32) -> RgbImage {
+            let exposure = self.value(v4l2::V4L2_CID_EXPOSURE_ABSOLUTE) as f32 / 100.0;
+            let gain = 1.0 + self.value(v4l2::V4L2_CID_GAIN) as f32 / 25.0;
+            let offset = self.value(v4l2::V4L2_CID_BRIGHTNESS) as f32;
+            RgbImage::from_fn(64, 48, |x, y| {
+                let texture = if (x / 4 + y / 4) % 2 == 0 { 1.2 } else { 0.8 };
+                let v = (scene * texture * exposure * gain + offset).clamp(0.0, 255.0) as u8;
+                Rgb([v, v, v])
+            })
+        }
+    }
+
+    impl CameraControls for FakeSensor {
+        fn query(&mut self, id: u32) -> io::Result<Option<Range>> {
+            Ok(self.ranges.get(&id).copied())
+        }
+
+        fn get(&mut self, id: u32) -> io::Result<i32> {
+            Ok(self.value(id))
+        }
+
+        fn set(&mut self, id: u32, value: i32) -> io::Result<()> {
+            self.values.lock().unwrap().insert(id, value);
+            Ok(())
+        }
+    }
+
+    /// Runs `frames` frames of `scene` through the controller, as a scan would.
+    fn run(exposure: &mut Exposure, sensor: &FakeSensor, scene: f32, frames: usize) -> f32 {
+        let mut last = 0.0;
+        for _ in 0..frames {
+            let image = exposure.prepare(sensor.frame(scene));
+            last = luminance(&imageops::grayscale(&image), None);
+            exposure.observe(last);
+        }
+        last
+    }
+
+    #[test]
+    fn steers_a_dim_face_to_the_target() {
+        let sensor = FakeSensor::new(1000, 64);
+        let mut exposure = Exposure::take(Box::new(sensor.clone()), ExposureConfig::default()).unwrap();
+        assert!(!exposure.steady());
+        let lit = run(&mut exposure, &sensor, 30.0, 10);
+        assert!((lit - 110.0).abs() <= 25.0, "{}", lit);
+        assert!(exposure.steady());
+        let report = exposure.report();
+        assert!(report.adjustments > 0 && !report.enhanced, "{:?}", report);
+        assert_eq!(sensor.value(v4l2::V4L2_CID_EXPOSURE_AUTO), v4l2::V4L2_EXPOSURE_MANUAL);
+        // Exposure alone got there
+        assert_eq!(sensor.value(v4l2::V4L2_CID_GAIN), 0);
+
+        drop(exposure);
+        assert_eq!(sensor.value(v4l2::V4L2_CID_EXPOSURE_AUTO), 3);
+        assert_eq!(sensor.value(v4l2::V4L2_CID_EXPOSURE_ABSOLUTE), 100);
+    }
+
+    #[test]
+    fn well_lit_faces_are_left_alone() {
+        let sensor = FakeSensor::new(1000, 64);
+        let mut exposure = Exposure::take(Box::new(sensor.clone()), ExposureConfig::default()).unwrap();
+        run(&mut exposure, &sensor, 100.0, 5);
+        assert_eq!(exposure.report().adjustments, 0);
+        // Automatic modes are only taken over when needed
+        assert_eq!(sensor.value(v4l2::V4L2_CID_EXPOSURE_AUTO), 3);
+
+        // Too bright is pulled down too
+        let glare = run(&mut exposure, &sensor, 240.0, 15);
+        assert!((glare - 110.0).abs() <= 25.0, "{}", glare);
+    }
+
+    #[test]
+    fn enhances_once_the_controls_run_out() {
+        let sensor = FakeSensor::new(300, 16);
+        let mut exposure = Exposure::take(Box::new(sensor.clone()), ExposureConfig::default()).unwrap();
+        let raw = luminance(&imageops::grayscale(&sensor.frame(1.0)), None);
+        let lifted = run(&mut exposure, &sensor, 1.0, 30);
+        let report = exposure.report();
+        assert!(report.enhanced, "{:?}", report);
+        // Every control was pushed to its limit first
+        assert_eq!(sensor.value(v4l2::V4L2_CID_EXPOSURE_ABSOLUTE), 300);
+        assert_eq!(sensor.value(v4l2::V4L2_CID_GAIN), 100);
+        assert_eq!(sensor.value(v4l2::V4L2_CID_BRIGHTNESS), 16);
+        assert!(lifted > 80.0 && raw < 10.0, "{} -> {}", raw, lifted);
+
+        // Without controls, enhancement starts on the first dark frame
+        let mut software = Exposure::software(ExposureConfig::default());
+        software.observe(20.0);
+        assert!(software.report().enhanced);
+        let mut off = Exposure::software(ExposureConfig::parse("enhance off").unwrap());
+        off.observe(20.0);
+        let dark = sensor.frame(1.0);
+        assert_eq!(off.prepare(dark.clone()), dark);
+    }
+
+    #[test]
+    fn clahe_stretches_detail_but_not_flat_areas() {
+        // A dim, low-contrast face on a flat background
+        let dim = GrayImage::from_fn(64, 64, |x, y| {
+            let face = (16..48).contains(&x) && (16..48).contains(&y);
+            Luma([if face { 16 + ((x * 7 + y * 3) % 16) as u8 } else { 12 }])
+        });
+        let face = BoundingBox { x: 16.0, y: 16.0, width: 32.0, height: 32.0 };
+        let measure = |img: &GrayImage| stats(img, region(img, &face, 1.0, 1.0).unwrap());
+        let (_, before) = measure(&dim);
+        let (mean, after) = measure(&clahe(&dim, 4, 4.0));
+        assert!(after > 3.0 * before && mean > measure(&dim).0, "{} -> {} (mean {})", before, after, mean);
+        // The curve takes the face where it should be
+        let (lifted, _) = measure(&curve(&dim, 0.4));
+        assert!(lifted > 90.0, "{}", lifted);
+
+        let flat = GrayImage::from_pixel(32, 32, Luma([40]));
+        let out = clahe(&flat, 4, 2.5);
+        assert!(out.pixels().all(|p| p == out.get_pixel(0, 0)));
+
+        let ramp = GrayImage::from_fn(64, 1, |x, _| Luma([20 + x as u8 / 4]));
+        let out = equalize(&ramp);
+        assert_eq!((out.get_pixel(0, 0)[0], out.get_pixel(63, 0)[0]), (0, 255));
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config = ExposureConfig::parse("# dim office\ntarget 100\ntolerance 15\nenhance equalize\ntiles 4\ndisable hardware\n").unwrap();
+        assert_eq!((config.target, config.tolerance, config.tiles), (100.0, 15.0, 4));
+        assert_eq!(config.enhance, Enhance::Equalize);
+        assert!(!config.hardware);
+        assert_eq!(ExposureConfig::parse("").unwrap(), ExposureConfig::default());
+        assert!(ExposureConfig::parse("enhance retinex").is_err());
+        assert!(ExposureConfig::parse("target 300").is_err());
+        assert!(ExposureConfig::parse("clip_limit 0.5").is_err());
+        assert!(ExposureConfig::parse("tiles").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+// src/frame.rs
+use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
+use std::time::Duration;
+
+use super::v4l2::fourcc;
+
+/// Pixel layouts a frame source may hand us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PixelFormat {
+    Yuyv,
+    Uyvy,
+    Nv12,
+    /// Planar 4:2:0 (Y, then U, then V) as produced by Y4M files.
+    I420,
+    Mjpeg,
+    Grey,
+    /// 16-bit little-endian luma, common on IR sensors.
+    Y16,
+    Rgb24,
+}
+
+impl PixelFormat {
+    pub fn fourcc(self) -> u32 {
+        match self {
+            PixelFormat::Yuyv => fourcc(b"YUYV"),
+            PixelFormat::Uyvy => fourcc(b"UYVY"),
+            PixelFormat::Nv12 => fourcc(b"NV12"),
+            PixelFormat::I420 => fourcc(b"YU12"),
+            PixelFormat::Mjpeg => fourcc(b"MJPG"),
+            PixelFormat::Grey => fourcc(b"GREY"),
+            PixelFormat::Y16 => fourcc(b"Y16 "),
+            PixelFormat::Rgb24 => fourcc(b"RGB3"),
+        }
+    }
+
+    pub fn from_fourcc(code: u32) -> Option<Self> {
+        [
+            PixelFormat::Yuyv,
+            PixelFormat::Uyvy,
+            PixelFormat::Nv12,
+            PixelFormat::I420,
+            PixelFormat::Mjpeg,
+            PixelFormat::Grey,
+            PixelFormat::Y16,
+            PixelFormat::Rgb24,
+        ]
+        .into_iter()
+        .find(|f| f.fourcc() == code)
+    }
+
+    /// Default line stride in bytes for the first plane, `None` for compressed formats.
+    pub fn min_stride(self, width: u32) -> Option<u32> {
+        match self {
+            PixelFormat::Yuyv | PixelFormat::Uyvy | PixelFormat::Y16 => Some(width * 2),
+            PixelFormat::Nv12 | PixelFormat::I420 | PixelFormat::Grey => Some(width),
+            PixelFormat::Rgb24 => Some(width * 3),
+            PixelFormat::Mjpeg => None,
+        }
+    }
+
+    /// Size in bytes of a tightly packed frame, `None` for compressed formats.
+    pub fn frame_size(self, width: u32, height: u32) -> Option<usize> {
+        let (w, h) = (width as usize, height as usize);
+        match self {
+            PixelFormat::Nv12 | PixelFormat::I420 => Some(w * h + 2 * w.div_ceil(2) * h.div_ceil(2)),
+            PixelFormat::Mjpeg => None,
+            _ => self.min_stride(width).map(|s| s as usize * h),
+        }
+    }
+}
+
+/// A single captured frame.
+///
+/// Sources lend their internal buffer where they can; call `into_owned` to keep a
+/// frame beyond the next `next_frame` call.
+#[derive(Debug, Clone)]
+pub struct Frame<'a> {
+    pub format: PixelFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per line of the first plane.
+    pub stride: u32,
+    pub sequence: u64,
+    /// CLOCK_MONOTONIC capture time, the same clock V4L2 stamps buffers with.
+    pub timestamp: Duration,
+    pub data: Cow<'a, [u8]>,
+}
+
+impl Frame<'_> {
+    pub fn into_owned(self) -> Frame<'static> {
+        Frame {
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            data: Cow::Owned(self.data.into_owned()),
+        }
+    }
+}
+
+pub fn monotonic_now() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // CLOCK_MONOTONIC cannot fail with a valid pointer
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
@@ -0,0 +1,352 @@
+// src/hotplug.rs
+//! Camera hotplug detection.
+//!
+//! The preferred watcher listens for kernel uevents on a netlink socket. Where
+//! that is unavailable (containers, restricted sandboxes) we fall back to
+//! inotify on the device directory, which is also what the tests point at a
+//! temporary directory.
+use log::{info, warn};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use super::camera::{list_cameras_with, CameraInfo, DeviceProbe};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HotplugEvent {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchMode {
+    Udev,
+    Inotify(PathBuf),
+    Off,
+}
+
+impl WatchMode {
+    /// Reads `OLA_HOTPLUG` (`udev`, `inotify` or `off`) and `OLA_HOTPLUG_DIR`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let dir = std::env::var("OLA_HOTPLUG_DIR").unwrap_or_else(|_| "/dev".to_string());
+        match std::env::var("OLA_HOTPLUG").as_deref().unwrap_or("udev") {
+            "udev" => Ok(WatchMode::Udev),
+            "inotify" => Ok(WatchMode::Inotify(PathBuf::from(dir))),
+            "off" => Ok(WatchMode::Off),
+            other => anyhow::bail!("Unknown OLA_HOTPLUG '{}'", other),
+        }
+    }
+}
+
+impl WatchMode {
+    /// Directory whose `videoN` nodes make up the camera registry.
+    pub fn dev_dir(&self) -> &Path {
+        match self {
+            WatchMode::Inotify(dir) => dir,
+            _ => Path::new("/dev"),
+        }
+    }
+}
+
+/// A blocking source of hotplug events.
+pub trait HotplugWatcher: Send {
+    /// Blocks until at least one relevant event is available.
+    fn next_events(&mut self) -> io::Result<Vec<HotplugEvent>>;
+}
+
+/// Opens the watcher for `mode`, falling back from netlink to inotify on /dev.
+pub fn open_watcher(mode: &WatchMode) -> anyhow::Result<Option<Box<dyn HotplugWatcher>>> {
+    match mode {
+        WatchMode::Off => Ok(None),
+        WatchMode::Inotify(dir) => Ok(Some(Box::new(InotifyWatcher::new(dir)?))),
+        WatchMode::Udev => match UeventWatcher::new(Path::new("/dev")) {
+            Ok(w) => Ok(Some(Box::new(w))),
+            Err(e) => {
+                warn!("Netlink uevent socket unavailable ({}), falling back to inotify on /dev", e);
+                Ok(Some(Box::new(InotifyWatcher::new(Path::new("/dev"))?)))
+            }
+        },
+    }
+}
+
+/// Runs a watcher on its own thread, handing each batch to `sink` until it
+/// returns `false` or the watcher fails.
+pub fn spawn(mut watcher: Box<dyn HotplugWatcher>, mut sink: impl FnMut(Vec<HotplugEvent>) -> bool + Send + 'static) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        match watcher.next_events() {
+            Ok(events) if events.is_empty() => continue,
+            Ok(events) => {
+                if !sink(events) {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Hotplug watcher stopped: {}", e);
+                break;
+            }
+        }
+    })
+}
+
+fn is_video_node(name: &str) -> bool {
+    name.strip_prefix("video").is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Kernel uevents over NETLINK_KOBJECT_UEVENT.
+pub struct UeventWatcher {
+    fd: OwnedFd,
+    dev_dir: PathBuf,
+}
+
+impl UeventWatcher {
+    pub fn new(dev_dir: &Path) -> io::Result<Self> {
+        let raw = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT)
+        };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // Group 1 carries raw kernel events; group 2 is udev's re-broadcast
+        addr.nl_groups = 1;
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        info!("Watching kernel uevents for camera hotplug");
+        Ok(Self { fd, dev_dir: dev_dir.to_path_buf() })
+    }
+}
+
+impl HotplugWatcher for UeventWatcher {
+    fn next_events(&mut self) -> io::Result<Vec<HotplugEvent>> {
+        let mut buf = [0u8; 8192];
+        let n = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(parse_uevent(&buf[..n as usize], &self.dev_dir).into_iter().collect())
+    }
+}
+
+/// Parses a kernel uevent datagram ("add@/devices/...\0ACTION=add\0...").
+pub fn parse_uevent(msg: &[u8], dev_dir: &Path) -> Option<HotplugEvent> {
+    let (mut action, mut subsystem, mut devname) = (None, None, None);
+    for field in msg.split(|&b| b == 0).skip(1) {
+        let field = std::str::from_utf8(field).ok()?;
+        if let Some(v) = field.strip_prefix("ACTION=") {
+            action = Some(v);
+        } else if let Some(v) = field.strip_prefix("SUBSYSTEM=") {
+            subsystem = Some(v);
+        } else if let Some(v) = field.strip_prefix("DEVNAME=") {
+            devname = Some(v);
+        }
+    }
+
+    if subsystem != Some("video4linux") {
+        return None;
+    }
+    // DEVNAME is relative to /dev, e.g. "video2"
+    let name = devname?;
+    if !is_video_node(name) {
+        return None;
+    }
+    let path = dev_dir.join(name);
+    match action? {
+        "add" => Some(HotplugEvent::Added(path)),
+        "remove" => Some(HotplugEvent::Removed(path)),
+        _ => None,
+    }
+}
+
+/// Watches a directory for `videoN` entries appearing and disappearing.
+pub struct InotifyWatcher {
+    inotify: Inotify,
+    dir: PathBuf,
+}
+
+impl InotifyWatcher {
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            dir,
+            AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_MOVED_FROM,
+        )?;
+        info!("Watching {} for camera hotplug (inotify)", dir.display());
+        Ok(Self { inotify, dir: dir.to_path_buf() })
+    }
+}
+
+impl HotplugWatcher for InotifyWatcher {
+    fn next_events(&mut self) -> io::Result<Vec<HotplugEvent>> {
+        let events = self.inotify.read_events().map_err(io::Error::from)?;
+        Ok(events
+            .into_iter()
+            .filter_map(|ev| {
+                let name = ev.name?.into_string().ok()?;
+                if !is_video_node(&name) {
+                    return None;
+                }
+                let path = self.dir.join(name);
+                if ev.mask.intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO) {
+                    Some(HotplugEvent::Added(path))
+                } else {
+                    Some(HotplugEvent::Removed(path))
+                }
+            })
+            .collect())
+    }
+}
+
+/// Change pushed to subscribed clients.
+#[derive(Debug, Clone)]
+pub enum CameraEvent {
+    Added(CameraInfo),
+    Removed(CameraInfo),
+}
+
+impl CameraEvent {
+    /// JSON-RPC notification method name.
+    pub fn method(&self) -> &'static str {
+        match self {
+            CameraEvent::Added(_) => "camera_added",
+            CameraEvent::Removed(_) => "camera_removed",
+        }
+    }
+
+    pub fn camera(&self) -> &CameraInfo {
+        match self {
+            CameraEvent::Added(c) | CameraEvent::Removed(c) => c,
+        }
+    }
+}
+
+/// The worker's current view of attached cameras.
+#[derive(Debug, Default)]
+pub struct CameraRegistry {
+    cameras: Vec<CameraInfo>,
+}
+
+impl CameraRegistry {
+    pub fn cameras(&self) -> &[CameraInfo] {
+        &self.cameras
+    }
+
+    /// Re-probes every node and returns what changed since the last refresh.
+    ///
+    /// We rescan rather than trusting individual events: nodes for one device
+    /// come and go in bursts, and a full diff is cheap next to a probe.
+    pub fn refresh(&mut self, probe: &dyn DeviceProbe) -> Vec<CameraEvent> {
+        let current = list_cameras_with(probe);
+        let same = |a: &CameraInfo, b: &CameraInfo| a.path == b.path && a.bus_info == b.bus_info && a.card == b.card;
+
+        let mut events: Vec<CameraEvent> = self.cameras
+            .iter()
+            .filter(|old| !current.iter().any(|c| same(old, c)))
+            .cloned()
+            .map(CameraEvent::Removed)
+            .collect();
+        events.extend(
+            current
+                .iter()
+                .filter(|c| !self.cameras.iter().any(|old| same(old, c)))
+                .cloned()
+                .map(CameraEvent::Added),
+        );
+
+        self.cameras = current;
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::DeviceDescription;
+    use std::cell::RefCell;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ola-hotplug-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn inotify_reports_video_nodes_only() {
+        let dir = temp_dir("inotify");
+        let mut watcher = InotifyWatcher::new(&dir).unwrap();
+
+        std::fs::write(dir.join("video3"), b"").unwrap();
+        std::fs::write(dir.join("media0"), b"").unwrap();
+        std::fs::remove_file(dir.join("video3")).unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            events.extend(watcher.next_events().unwrap());
+        }
+        assert_eq!(events, vec![
+            HotplugEvent::Added(dir.join("video3")),
+            HotplugEvent::Removed(dir.join("video3")),
+        ]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn parses_kernel_uevents() {
+        let dev = Path::new("/dev");
+        let add = b"add@/devices/pci0000:00/usb1/1-8/video4linux/video2\0ACTION=add\0DEVPATH=/devices/x\0SUBSYSTEM=video4linux\0MAJOR=81\0DEVNAME=video2\0";
+        assert_eq!(parse_uevent(add, dev), Some(HotplugEvent::Added(PathBuf::from("/dev/video2"))));
+
+        let remove = b"remove@/x\0ACTION=remove\0SUBSYSTEM=video4linux\0DEVNAME=video2\0";
+        assert_eq!(parse_uevent(remove, dev), Some(HotplugEvent::Removed(PathBuf::from("/dev/video2"))));
+
+        let other = b"add@/x\0ACTION=add\0SUBSYSTEM=input\0DEVNAME=input/event3\0";
+        assert_eq!(parse_uevent(other, dev), None);
+    }
+
+    struct TableProbe(RefCell<Vec<(&'static str, &'static str)>>);
+
+    impl DeviceProbe for TableProbe {
+        fn nodes(&self) -> Vec<PathBuf> {
+            self.0.borrow().iter().map(|(p, _)| PathBuf::from(p)).collect()
+        }
+
+        fn probe(&self, path: &Path) -> anyhow::Result<DeviceDescription> {
+            let table = self.0.borrow();
+            let (_, bus) = table.iter().find(|(p, _)| Path::new(p) == path).unwrap();
+            Ok(DeviceDescription { bus_info: bus.to_string(), card: "Cam".into(), ..Default::default() })
+        }
+    }
+
+    #[test]
+    fn registry_diffs_successive_scans() {
+        let probe = TableProbe(RefCell::new(vec![("/dev/video0", "usb-1")]));
+        let mut registry = CameraRegistry::default();
+
+        let events = registry.refresh(&probe);
+        assert!(matches!(&events[..], [CameraEvent::Added(c)] if c.path == "/dev/video0"));
+        assert!(registry.refresh(&probe).is_empty());
+
+        // Replugged into another port: same node name, different device
+        *probe.0.borrow_mut() = vec![("/dev/video0", "usb-2"), ("/dev/video1", "usb-2")];
+        let methods: Vec<_> = registry.refresh(&probe).iter().map(|e| (e.method(), e.camera().path.clone())).collect();
+        assert_eq!(methods, vec![
+            ("camera_removed", "/dev/video0".to_string()),
+            ("camera_added", "/dev/video0".to_string()),
+            ("camera_added", "/dev/video1".to_string()),
+        ]);
+        assert_eq!(registry.cameras().len(), 2);
+    }
+}
@@ -0,0 +1,351 @@
+// src/identify.rs
+//! 1:N identification: which enrolled user, if any, is in front of the camera.
+//!
+//! Every template of every user is held in a `TemplateIndex`, one flat matrix
+//! per embedding model with the norms precomputed, so scoring a probe against
+//! all of them is a single pass of dot products. The worker builds it from
+//! the template store on first use and drops it whenever enrollments change.
+//! Only the matrix of the model that produced the probe is scored; see
+//! `Template::compatible_with`.
+//!
+//! Each user is scored by their best template, and the scores of successive
+//! frames are averaged (`Ranking`). The best user is only identified when
+//! they clear their own threshold and lead the runner-up by the configured
+//! margin; two similar-looking users are rejected rather than guessed between.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::camera::{Enrolled, ReasonCode};
+use super::embedder::EMBEDDING_SCHEMA;
+use super::matcher::{self, MatchConfig, Metric};
+use super::templates::{Template, TemplateStore};
+
+/// Candidates reported with an identification, best first.
+pub const MAX_CANDIDATES: usize = 5;
+
+/// Templates of one embedding model and size, row by row.
+#[derive(Debug, Default)]
+struct Block {
+    model_id: String,
+    dimension: usize,
+    rows: Vec<f32>,
+    norms: Vec<f32>,
+    /// Owner and template ID of each row.
+    owners: Vec<(u32, String)>,
+}
+
+/// Every enrolled template, ready to be scored against a probe.
+#[derive(Debug, Default)]
+pub struct TemplateIndex {
+    blocks: Vec<Block>,
+    /// Users with any template, usable or stale.
+    users: Vec<u32>,
+}
+
+impl TemplateIndex {
+    /// Loads every user's templates from `store`.
+    pub fn build(store: &TemplateStore) -> anyhow::Result<Self> {
+        let mut templates = Vec::new();
+        for uid in store.users()? {
+            templates.extend(store.load(uid)?.into_iter().map(|t| (uid, t)));
+        }
+        Ok(Self::from_templates(templates))
+    }
+
+    pub fn from_templates(templates: impl IntoIterator<Item = (u32, Template)>) -> Self {
+        let mut index = Self::default();
+        for (uid, t) in templates {
+            if !index.users.contains(&uid) {
+                index.users.push(uid);
+            }
+            // Stale templates still make their owner count as enrolled
+            if t.embedding.is_empty() || t.model_id.is_empty() || t.schema != EMBEDDING_SCHEMA {
+                continue;
+            }
+            let dimension = t.embedding.len();
+            let i = match index.blocks.iter().position(|b| b.model_id == t.model_id && b.dimension == dimension) {
+                Some(i) => i,
+                None => {
+                    index.blocks.push(Block { model_id: t.model_id.clone(), dimension, ..Default::default() });
+                    index.blocks.len() - 1
+                }
+            };
+            let block = &mut index.blocks[i];
+            block.norms.push(t.embedding.iter().map(|x| x * x).sum::<f32>().sqrt());
+            block.rows.extend_from_slice(&t.embedding);
+            block.owners.push((uid, t.id));
+        }
+        index.users.sort_unstable();
+        index
+    }
+
+    /// Users with at least one template.
+    pub fn users(&self) -> &[u32] {
+        &self.users
+    }
+
+    pub fn templates(&self) -> usize {
+        self.blocks.iter().map(|b| b.owners.len()).sum()
+    }
+
+    fn block(&self, model_id: &str, dimension: usize) -> Option<&Block> {
+        self.blocks.iter().find(|b| b.model_id == model_id && b.dimension == dimension)
+    }
+
+    /// Each user's best template against `probe`, from the model `model_id`,
+    /// best first. Users outside `scope`, when given, and templates of another
+    /// model or size are left out.
+    pub fn rank(&self, metric: Metric, model_id: &str, probe: &[f32], scope: Option<&[u32]>) -> Vec<(u32, matcher::Match)> {
+        let Some(block) = self.block(model_id, probe.len()) else {
+            return Vec::new();
+        };
+        let probe_norm = probe.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let mut best: BTreeMap<u32, matcher::Match> = BTreeMap::new();
+        for ((row, norm), (uid, id)) in block.rows.chunks_exact(block.dimension).zip(&block.norms).zip(&block.owners) {
+            if scope.is_some_and(|s| !s.contains(uid)) {
+                continue;
+            }
+            let dot: f32 = row.iter().zip(probe).map(|(x, y)| x * y).sum();
+            // Same values `Metric::score` gives, from the one dot product
+            let score = match metric {
+                Metric::Cosine if norm * probe_norm == 0.0 => 0.0,
+                Metric::Cosine => dot / (norm * probe_norm),
+                Metric::L2 => (norm * norm + probe_norm * probe_norm - 2.0 * dot).max(0.0).sqrt(),
+            };
+            if best.get(uid).is_none_or(|b| metric.better(score, b.score)) {
+                best.insert(*uid, matcher::Match { template_id: id.clone(), score });
+            }
+        }
+        let mut ranked: Vec<_> = best.into_iter().collect();
+        // Stable, so equal scores stay in uid order
+        ranked.sort_by(|(_, a), (_, b)| metric.best_first(a.score, b.score));
+        ranked
+    }
+
+    /// Whether anyone in `scope` has a template to compare with probes from
+    /// the model `model_id`, or only ones from other models.
+    pub fn covers(&self, model_id: &str, scope: Option<&[u32]>) -> Enrolled {
+        let in_scope = |u: &u32| scope.is_none_or(|s| s.contains(u));
+        if self.blocks.iter().any(|b| b.model_id == model_id && b.owners.iter().any(|(u, _)| in_scope(u))) {
+            Enrolled::Yes
+        } else if self.users.iter().any(in_scope) {
+            Enrolled::Stale
+        } else {
+            Enrolled::No
+        }
+    }
+}
+
+/// One user an identification considered.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub uid: u32,
+    /// Best-template score, averaged over the frames.
+    pub score: f32,
+    /// The user's own threshold.
+    pub threshold: f32,
+    /// The template that scored best in the latest frame.
+    pub template_id: String,
+}
+
+/// Per-user scores of successive frames, combined into a ranking.
+pub struct Ranking<'a> {
+    index: &'a TemplateIndex,
+    config: &'a MatchConfig,
+    scope: Option<&'a [u32]>,
+    /// Model the probes come from.
+    model_id: String,
+    frames: usize,
+    /// Score sums and latest best template per user.
+    totals: BTreeMap<u32, (f32, String)>,
+}
+
+impl<'a> Ranking<'a> {
+    pub fn new(index: &'a TemplateIndex, config: &'a MatchConfig, scope: Option<&'a [u32]>) -> Self {
+        Self { index, config, scope, model_id: String::new(), frames: 0, totals: BTreeMap::new() }
+    }
+
+    /// Scores probes from the model `model_id` from now on.
+    pub fn select(&mut self, model_id: &str) -> Enrolled {
+        self.model_id = model_id.to_string();
+        self.index.covers(model_id, self.scope)
+    }
+
+    /// Scores one frame's probe; `false` when no template has its size.
+    pub fn add(&mut self, probe: &[f32]) -> bool {
+        let ranked = self.index.rank(self.config.metric, &self.model_id, probe, self.scope);
+        if ranked.is_empty() {
+            return false;
+        }
+        // Everyone in scope is scored on every frame, so the sums stay comparable
+        for (uid, m) in ranked {
+            let total = self.totals.entry(uid).or_insert((0.0, String::new()));
+            total.0 += m.score;
+            total.1 = m.template_id;
+        }
+        self.frames += 1;
+        true
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Mean scores per user, best first, at most `MAX_CANDIDATES`.
+    pub fn candidates(&self) -> Vec<Candidate> {
+        let metric = self.config.metric;
+        let mut candidates: Vec<Candidate> = self
+            .totals
+            .iter()
+            .map(|(&uid, (sum, id))| Candidate {
+                uid,
+                score: sum / self.frames as f32,
+                threshold: self.config.threshold_for(uid),
+                template_id: id.clone(),
+            })
+            .collect();
+        candidates.sort_by(|a, b| metric.best_first(a.score, b.score));
+        candidates.truncate(MAX_CANDIDATES);
+        candidates
+    }
+
+    /// How far the best user's score is ahead of the runner-up's; `None`
+    /// with fewer than two candidates.
+    pub fn lead(&self) -> Option<f32> {
+        match &self.candidates()[..] {
+            [a, b, ..] => Some((a.score - b.score).abs()),
+            _ => None,
+        }
+    }
+
+    /// The identified user, or the code and reason why there is none.
+    pub fn decide(&self) -> Result<u32, (ReasonCode, &'static str)> {
+        let candidates = self.candidates();
+        let no_match = Err((ReasonCode::NoMatch, "no_match"));
+        let Some(best) = candidates.first() else { return no_match };
+        if !self.config.metric.accepts(best.score, best.threshold) {
+            return no_match;
+        }
+        if self.lead().is_some_and(|lead| lead < self.config.identify_margin()) {
+            return Err((ReasonCode::AmbiguousMatch, "ambiguous_match"));
+        }
+        Ok(best.uid)
+    }
+
+    /// Whether more frames are unlikely to change the outcome: the best user
+    /// is well clear of their threshold, one way or the other, and well ahead
+    /// of the runner-up when they pass.
+    pub fn confident(&self) -> bool {
+        let candidates = self.candidates();
+        let Some(best) = candidates.first() else { return false };
+        let margin = self.config.metric.margin(best.score, best.threshold);
+        let clear = margin.abs() >= matcher::CONFIDENT_MARGIN;
+        let ahead = margin < 0.0 || self.lead().is_none_or(|lead| lead >= self.config.identify_margin() + matcher::CONFIDENT_MARGIN);
+        self.frames >= matcher::CONFIDENT_FRAMES && clear && ahead
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_models::embeddings;
+
+    const MODEL: &str = "arcface-1";
+
+    fn template(id: &str, embedding: Vec<f32>) -> Template {
+        Template { id: id.into(), model_id: MODEL.into(), schema: EMBEDDING_SCHEMA, embedding, ..Default::default() }
+    }
+
+    /// The shared embedding fixture, the first capture of person `i` enrolled
+    /// for uid `1000 + i`.
+    fn enrolled() -> (TemplateIndex, Vec<Vec<Vec<f32>>>) {
+        let people: Vec<_> = embeddings().identities.into_values().collect();
+        let templates = people.iter().enumerate().map(|(i, captures)| {
+            (1000 + i as u32, template(&format!("t{}", i), captures[0].clone()))
+        });
+        (TemplateIndex::from_templates(templates), people)
+    }
+
+    #[test]
+    fn index_scores_like_the_matcher() {
+        let (index, people) = enrolled();
+        assert_eq!((index.users().len(), index.templates()), (5, 5));
+        for metric in [Metric::Cosine, Metric::L2] {
+            let probe = &people[2][1];
+            let ranked = index.rank(metric, MODEL, probe, None);
+            assert_eq!(ranked.len(), 5);
+            assert_eq!((ranked[0].0, ranked[0].1.template_id.as_str()), (1002, "t2"));
+            for (uid, m) in &ranked {
+                let i = (uid - 1000) as usize;
+                let expected = metric.score(probe, &people[i][0]).unwrap();
+                assert!((m.score - expected).abs() < 1e-4, "{:?} {:?} vs {}", metric, m, expected);
+            }
+            // Out of scope users, and probes of another size or model, are not compared
+            assert_eq!(index.rank(metric, MODEL, probe, Some(&[1000, 1001])).len(), 2);
+            assert!(index.rank(metric, MODEL, &[1.0; 64], None).is_empty());
+            assert!(index.rank(metric, "arcface-2", probe, None).is_empty());
+        }
+        assert_eq!(index.covers(MODEL, Some(&[1004, 4242])), Enrolled::Yes);
+        assert_eq!(index.covers(MODEL, Some(&[4242])), Enrolled::No);
+        assert_eq!(index.covers("arcface-2", None), Enrolled::Stale);
+        assert!(TemplateIndex::default().rank(Metric::Cosine, MODEL, &people[0][0], None).is_empty());
+
+        // Untagged templates from before model IDs index nothing but their owner
+        let legacy = TemplateIndex::from_templates([(7, Template { id: "old".into(), embedding: vec![1.0, 0.0], ..Default::default() })]);
+        assert_eq!((legacy.users(), legacy.templates()), (&[7][..], 0));
+        assert_eq!(legacy.covers("", None), Enrolled::Stale);
+    }
+
+    #[test]
+    fn ranking_identifies_enrolled_users_and_rejects_strangers() {
+        let (index, people) = enrolled();
+        let config = MatchConfig::default();
+        for (i, captures) in people.iter().enumerate() {
+            let mut ranking = Ranking::new(&index, &config, None);
+            assert_eq!(ranking.select(MODEL), Enrolled::Yes);
+            for probe in &captures[1..] {
+                assert!(ranking.add(probe));
+            }
+            assert_eq!(ranking.decide(), Ok(1000 + i as u32));
+            assert!(ranking.confident());
+            assert_eq!(ranking.candidates().len(), 5);
+            assert!(ranking.lead().unwrap() >= config.identify_margin());
+
+            // Someone who is not enrolled matches nobody
+            let only = [1000 + i as u32];
+            let mut ranking = Ranking::new(&index, &config, Some(&only));
+            ranking.select(MODEL);
+            let stranger = &people[(i + 1) % people.len()];
+            for probe in stranger {
+                ranking.add(probe);
+            }
+            assert_eq!(ranking.decide(), Err((ReasonCode::NoMatch, "no_match")));
+            assert!(ranking.confident());
+        }
+    }
+
+    #[test]
+    fn close_runner_up_is_ambiguous() {
+        // Two users enrolled with nearly the same face
+        let index = TemplateIndex::from_templates([
+            (1000, template("a", vec![1.0, 0.0])),
+            (1001, template("b", vec![0.99, 0.14])),
+            (1001, template("c", vec![0.0, 1.0])),
+        ]);
+        let config = MatchConfig::default();
+        let mut ranking = Ranking::new(&index, &config, None);
+        ranking.select(MODEL);
+        assert!(ranking.add(&[1.0, 0.05]));
+        let candidates = ranking.candidates();
+        assert_eq!((candidates[0].uid, candidates[1].uid, candidates[1].template_id.as_str()), (1000, 1001, "b"));
+        assert_eq!(ranking.decide(), Err((ReasonCode::AmbiguousMatch, "ambiguous_match")));
+        assert!(!ranking.confident());
+
+        // Narrowed to one of them there is nobody to confuse them with
+        let mut ranking = Ranking::new(&index, &config, Some(&[1001]));
+        ranking.select(MODEL);
+        assert!(ranking.add(&[1.0, 0.05]));
+        assert_eq!(ranking.decide(), Ok(1001));
+        assert_eq!(Ranking::new(&index, &config, Some(&[7])).select(MODEL), Enrolled::No);
+    }
+}
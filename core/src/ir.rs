@@ -0,0 +1,424 @@
+// src/ir.rs
+//! IR sensor classification and IR emitter control.
+//!
+//! Face-auth laptops expose the IR sensor as its own capture node next to the
+//! RGB one, usually mono-only and followed by a UVC metadata node. The IR
+//! emitter is often off unless a vendor-specific UVC extension unit (XU)
+//! control is set, so captures from an IR node switch it on for the duration
+//! of the capture and off again afterwards.
+use anyhow::{bail, Context};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::camera::CameraInfo;
+use super::v4l2;
+
+const EMITTER_DIR: &str = "/etc/ola/ir_emitters";
+
+/// Formats only an IR (or otherwise mono) sensor delivers.
+const MONO_FOURCCS: &[&str] = &["GREY", "Y8", "Y8I", "Y10", "Y12", "Y16"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorKind {
+    Rgb,
+    Ir,
+}
+
+/// A device whose IR node cannot be recognised from its formats alone, and the
+/// XU setting that drives its emitter.
+struct IrQuirk {
+    usb_id: &'static str,
+    /// Substring of the card name that marks the IR node on this device.
+    ir_card: &'static str,
+    emitter: Option<XuSetting>,
+}
+
+struct XuSetting {
+    unit: u8,
+    selector: u8,
+    on: &'static [u8],
+    off: &'static [u8],
+}
+
+// Values as reported by linux-enable-ir-emitter users. A per-device file in
+// the emitter directory always wins over this table.
+const QUIRKS: &[IrQuirk] = &[
+    // Chicony integrated RGB+IR camera (ThinkPad); the IR node advertises YUYV
+    IrQuirk {
+        usb_id: "04f2:b6d9",
+        ir_card: "Integrated I",
+        emitter: Some(XuSetting {
+            unit: 14,
+            selector: 6,
+            on: &[1, 3, 2, 0, 0, 0, 0, 0, 0],
+            off: &[1, 3, 0, 0, 0, 0, 0, 0, 0],
+        }),
+    },
+];
+
+fn quirk_for(cam: &CameraInfo) -> Option<&'static IrQuirk> {
+    let usb_id = cam.usb_id.as_deref()?;
+    QUIRKS.iter().find(|q| q.usb_id == usb_id && cam.card.contains(q.ir_card))
+}
+
+fn is_ir(cam: &CameraInfo) -> bool {
+    if quirk_for(cam).is_some() {
+        return true;
+    }
+    let mono_only = !cam.formats.is_empty()
+        && cam.formats.iter().all(|f| MONO_FOURCCS.contains(&f.fourcc.as_str()));
+    let ir_name = cam.card.split(|c: char| !c.is_ascii_alphanumeric()).any(|w| w == "IR");
+    mono_only || ir_name
+}
+
+/// Fills in `sensor` and `metadata_node` for a sorted camera list.
+///
+/// UVC creates a metadata node right after each streaming node of the same
+/// device, so the pairing is the next node on the same bus if it is metadata.
+pub fn classify(cameras: &mut [CameraInfo]) {
+    for i in 0..cameras.len() {
+        if !cameras[i].capabilities.video_capture {
+            continue;
+        }
+        cameras[i].sensor = Some(if is_ir(&cameras[i]) { SensorKind::Ir } else { SensorKind::Rgb });
+        cameras[i].metadata_node = cameras.get(i + 1)
+            .filter(|next| !next.bus_info.is_empty() && next.bus_info == cameras[i].bus_info)
+            .filter(|next| next.capabilities.metadata_capture && !next.capabilities.video_capture)
+            .map(|next| next.path.clone());
+    }
+}
+
+/// The XU control that switches one device's emitter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmitterConfig {
+    pub unit: u8,
+    pub selector: u8,
+    pub on: Vec<u8>,
+    pub off: Vec<u8>,
+}
+
+impl EmitterConfig {
+    /// Emitter settings for `cam`: `<dir>/<camera id>` if present, else the
+    /// built-in quirk table. `None` means the camera has no known emitter.
+    pub fn lookup(dir: &Path, cam: &CameraInfo) -> anyhow::Result<Option<Self>> {
+        let path = dir.join(&cam.id);
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                return Self::parse(&content).with_context(|| format!("{}", path.display())).map(Some)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("{}", path.display())),
+        }
+        Ok(quirk_for(cam).and_then(|q| q.emitter.as_ref()).map(|x| Self {
+            unit: x.unit,
+            selector: x.selector,
+            on: x.on.to_vec(),
+            off: x.off.to_vec(),
+        }))
+    }
+
+    /// Parses the per-device file format:
+    ///
+    /// ```text
+    /// unit 14
+    /// selector 6
+    /// on 1 3 2 0 0 0 0 0 0
+    /// off 1 3 0 0 0 0 0 0 0
+    /// ```
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let (mut unit, mut selector, mut on, mut off) = (None, None, None, None);
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut words = line.split_whitespace();
+            let Some(key) = words.next() else { continue };
+            let values = words
+                .map(|w| w.parse::<u8>().with_context(|| format!("bad byte '{}' for {}", w, key)))
+                .collect::<anyhow::Result<Vec<u8>>>()?;
+            let single = || match values.as_slice() {
+                [v] => Ok(*v),
+                _ => Err(anyhow::anyhow!("{} takes exactly one value", key)),
+            };
+            match key {
+                "unit" => unit = Some(single()?),
+                "selector" => selector = Some(single()?),
+                "on" => on = Some(values),
+                "off" => off = Some(values),
+                _ => bail!("unknown key '{}'", key),
+            }
+        }
+        let (Some(unit), Some(selector), Some(on), Some(off)) = (unit, selector, on, off) else {
+            bail!("unit, selector, on and off are all required");
+        };
+        if on.is_empty() || on.len() != off.len() {
+            bail!("on and off must be the same non-zero length");
+        }
+        Ok(Self { unit, selector, on, off })
+    }
+}
+
+/// Directory holding per-device emitter files (`OLA_IR_EMITTER_DIR`).
+pub fn emitter_dir() -> PathBuf {
+    std::env::var_os("OLA_IR_EMITTER_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(EMITTER_DIR))
+}
+
+/// The UVC XU queries emitter control needs. Split out so the sequencing can be
+/// tested without hardware.
+pub trait XuControl: Send {
+    fn get_len(&mut self, unit: u8, selector: u8) -> io::Result<u16>;
+    fn get_cur(&mut self, unit: u8, selector: u8, data: &mut [u8]) -> io::Result<()>;
+    fn set_cur(&mut self, unit: u8, selector: u8, data: &[u8]) -> io::Result<()>;
+}
+
+/// XU queries through `UVCIOC_CTRL_QUERY` on a device node.
+pub struct UvcXu {
+    file: File,
+}
+
+impl UvcXu {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self { file: v4l2::open_device(path)? })
+    }
+
+    fn query(&self, unit: u8, selector: u8, query: u8, data: &mut [u8]) -> io::Result<()> {
+        v4l2::uvc_xu_query(&self.file, unit, selector, query, data).map_err(io::Error::from)
+    }
+}
+
+impl XuControl for UvcXu {
+    fn get_len(&mut self, unit: u8, selector: u8) -> io::Result<u16> {
+        let mut len = [0u8; 2];
+        self.query(unit, selector, v4l2::UVC_GET_LEN, &mut len)?;
+        Ok(u16::from_le_bytes(len))
+    }
+
+    fn get_cur(&mut self, unit: u8, selector: u8, data: &mut [u8]) -> io::Result<()> {
+        self.query(unit, selector, v4l2::UVC_GET_CUR, data)
+    }
+
+    fn set_cur(&mut self, unit: u8, selector: u8, data: &[u8]) -> io::Result<()> {
+        let mut buf = data.to_vec();
+        self.query(unit, selector, v4l2::UVC_SET_CUR, &mut buf)
+    }
+}
+
+/// Keeps the emitter on while alive and switches it off when dropped.
+pub struct Emitter {
+    ctrl: Box<dyn XuControl>,
+    config: EmitterConfig,
+}
+
+impl std::fmt::Debug for Emitter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Emitter").field("config", &self.config).finish()
+    }
+}
+
+impl Emitter {
+    /// Checks the control size, writes the `on` value and reads it back.
+    /// Firmware that ignores the write would otherwise give us dark frames.
+    pub fn enable(mut ctrl: Box<dyn XuControl>, config: EmitterConfig) -> anyhow::Result<Self> {
+        let len = ctrl.get_len(config.unit, config.selector).context("IR emitter GET_LEN")?;
+        if len as usize != config.on.len() {
+            bail!(
+                "IR emitter control {}:{} is {} bytes, config has {}",
+                config.unit,
+                config.selector,
+                len,
+                config.on.len()
+            );
+        }
+        ctrl.set_cur(config.unit, config.selector, &config.on).context("IR emitter SET_CUR")?;
+
+        // From here on the guard owns switching it back off
+        let mut emitter = Self { ctrl, config };
+        let mut current = vec![0u8; emitter.config.on.len()];
+        emitter.ctrl
+            .get_cur(emitter.config.unit, emitter.config.selector, &mut current)
+            .context("IR emitter GET_CUR")?;
+        if current != emitter.config.on {
+            bail!("IR emitter did not accept the on value (reads back {:?})", current);
+        }
+        Ok(emitter)
+    }
+}
+
+impl Drop for Emitter {
+    fn drop(&mut self) {
+        if let Err(e) = self.ctrl.set_cur(self.config.unit, self.config.selector, &self.config.off) {
+            warn!("Failed to switch IR emitter off: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{CameraCapabilities, FormatInfo};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Call {
+        GetLen,
+        GetCur,
+        SetCur(Vec<u8>),
+    }
+
+    /// Records queries against a single control and behaves like firmware that
+    /// may ignore writes.
+    #[derive(Clone)]
+    struct MockXu {
+        calls: Arc<Mutex<Vec<Call>>>,
+        len: u16,
+        value: Arc<Mutex<Vec<u8>>>,
+        ignore_writes: bool,
+        fail_set: bool,
+    }
+
+    impl MockXu {
+        fn new(len: u16) -> Self {
+            Self {
+                calls: Arc::default(),
+                len,
+                value: Arc::new(Mutex::new(vec![0; len as usize])),
+                ignore_writes: false,
+                fail_set: false,
+            }
+        }
+
+        fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl XuControl for MockXu {
+        fn get_len(&mut self, unit: u8, selector: u8) -> io::Result<u16> {
+            assert_eq!((unit, selector), (14, 6));
+            self.calls.lock().unwrap().push(Call::GetLen);
+            Ok(self.len)
+        }
+
+        fn get_cur(&mut self, _unit: u8, _selector: u8, data: &mut [u8]) -> io::Result<()> {
+            self.calls.lock().unwrap().push(Call::GetCur);
+            data.copy_from_slice(&self.value.lock().unwrap());
+            Ok(())
+        }
+
+        fn set_cur(&mut self, _unit: u8, _selector: u8, data: &[u8]) -> io::Result<()> {
+            self.calls.lock().unwrap().push(Call::SetCur(data.to_vec()));
+            if self.fail_set {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            if !self.ignore_writes {
+                *self.value.lock().unwrap() = data.to_vec();
+            }
+            Ok(())
+        }
+    }
+
+    fn config() -> EmitterConfig {
+        EmitterConfig { unit: 14, selector: 6, on: vec![1, 3, 2], off: vec![1, 3, 0] }
+    }
+
+    #[test]
+    fn emitter_is_switched_on_then_off() {
+        let mock = MockXu::new(3);
+        let emitter = Emitter::enable(Box::new(mock.clone()), config()).unwrap();
+        assert_eq!(mock.calls(), vec![Call::GetLen, Call::SetCur(vec![1, 3, 2]), Call::GetCur]);
+
+        drop(emitter);
+        assert_eq!(mock.calls().last(), Some(&Call::SetCur(vec![1, 3, 0])));
+        assert_eq!(*mock.value.lock().unwrap(), vec![1, 3, 0]);
+    }
+
+    #[test]
+    fn size_mismatch_never_writes() {
+        let mock = MockXu::new(9);
+        assert!(Emitter::enable(Box::new(mock.clone()), config()).is_err());
+        assert_eq!(mock.calls(), vec![Call::GetLen]);
+    }
+
+    #[test]
+    fn ignored_write_fails_and_still_switches_off() {
+        let mut mock = MockXu::new(3);
+        mock.ignore_writes = true;
+        assert!(Emitter::enable(Box::new(mock.clone()), config()).is_err());
+        assert_eq!(
+            mock.calls(),
+            vec![Call::GetLen, Call::SetCur(vec![1, 3, 2]), Call::GetCur, Call::SetCur(vec![1, 3, 0])]
+        );
+    }
+
+    #[test]
+    fn failed_write_is_not_undone() {
+        let mut mock = MockXu::new(3);
+        mock.fail_set = true;
+        assert!(Emitter::enable(Box::new(mock.clone()), config()).is_err());
+        assert_eq!(mock.calls(), vec![Call::GetLen, Call::SetCur(vec![1, 3, 2])]);
+    }
+
+    #[test]
+    fn parses_emitter_file() {
+        let cfg = EmitterConfig::parse("# Dell\nunit 14\nselector 6\non 1 3 2\noff 1 3 0  # idle\n").unwrap();
+        assert_eq!(cfg, config());
+        assert!(EmitterConfig::parse("unit 14\nselector 6\non 1 3 2\noff 1 3\n").is_err());
+        assert!(EmitterConfig::parse("unit 14 15\nselector 6\non 1\noff 0\n").is_err());
+        assert!(EmitterConfig::parse("unit 300\nselector 6\non 1\noff 0\n").is_err());
+    }
+
+    fn camera(id: &str, card: &str, usb_id: Option<&str>, fourccs: &[&str]) -> CameraInfo {
+        CameraInfo {
+            id: id.into(),
+            path: format!("/dev/{}", id),
+            name: card.into(),
+            index: 0,
+            driver: "uvcvideo".into(),
+            card: card.into(),
+            bus_info: "usb-0000:00:14.0-8".into(),
+            usb_id: usb_id.map(str::to_string),
+            capabilities: CameraCapabilities { video_capture: true, ..Default::default() },
+            formats: fourccs
+                .iter()
+                .map(|f| FormatInfo { fourcc: f.to_string(), description: String::new(), sizes: vec![] })
+                .collect(),
+            sensor: None,
+            metadata_node: None,
+            paired_with: None,
+        }
+    }
+
+    #[test]
+    fn per_device_file_overrides_quirk_table() {
+        let dir = std::env::temp_dir().join(format!("ola-ir-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let quirky = camera("video2", "Integrated Camera: Integrated I", Some("04f2:b6d9"), &["YUYV"]);
+        let plain = camera("video4", "USB IR Camera", Some("1234:5678"), &["GREY"]);
+
+        let builtin = EmitterConfig::lookup(&dir, &quirky).unwrap().unwrap();
+        assert_eq!((builtin.unit, builtin.selector, builtin.on.len()), (14, 6, 9));
+        assert_eq!(EmitterConfig::lookup(&dir, &plain).unwrap(), None);
+
+        fs::write(dir.join("video2"), "unit 10\nselector 2\non 1\noff 0\n").unwrap();
+        assert_eq!(EmitterConfig::lookup(&dir, &quirky).unwrap().unwrap().unit, 10);
+        fs::write(dir.join("video4"), "unit 10\n").unwrap();
+        assert!(EmitterConfig::lookup(&dir, &plain).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quirk_marks_yuyv_ir_node() {
+        let mut cams = vec![
+            camera("video0", "Integrated Camera: Integrated C", Some("04f2:b6d9"), &["YUYV", "MJPG"]),
+            camera("video2", "Integrated Camera: Integrated I", Some("04f2:b6d9"), &["YUYV"]),
+        ];
+        classify(&mut cams);
+        assert_eq!(cams[0].sensor, Some(SensorKind::Rgb));
+        assert_eq!(cams[1].sensor, Some(SensorKind::Ir));
+    }
+}
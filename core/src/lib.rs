@@ -0,0 +1,34 @@
+// src/lib.rs
+//! Core library behind the `ola-core` daemon. The binary owns the socket and
+//! client handling; everything that touches cameras or frames lives here so
+//! benches and tools can reuse it.
+pub mod camera_worker;
+pub mod camera;
+pub mod camera_id;
+pub mod camera_lock;
+pub mod camera_pair;
+mod secure_store;
+mod v4l2;
+pub mod v4l2_stream;
+pub mod frame;
+pub mod frame_source;
+pub mod capture_stats;
+pub mod session;
+pub mod convert;
+pub mod detector;
+pub mod embedder;
+pub mod enroll;
+pub mod eval;
+pub mod identify;
+pub mod matcher;
+pub mod models;
+pub mod liveness;
+pub mod lockout;
+pub mod quality;
+pub mod exposure;
+pub mod adapt;
+pub mod templates;
+pub mod hotplug;
+pub mod ir;
+#[cfg(test)]
+mod test_models;
@@ -0,0 +1,578 @@
+// src/liveness.rs
+//! Liveness: is the face in front of the camera a person, or a photo, a
+//! screen or a mask held up to it?
+//!
+//! Each `LivenessCheck` looks at a short burst of frames and scores it from
+//! 0 (spoof) to 1 (live), or abstains when it has nothing to go on (no IR
+//! frames, too few faces). The scores are fused into a weighted mean that has
+//! to reach the configured threshold; on top of that any single check scoring
+//! below `VETO` fails the attempt on its own, since one clear sign of a spoof
+//! should not be outvoted. Settings come from `/etc/ola/liveness.conf` (or
+//! `OLA_LIVENESS_CONFIG`):
+//!
+//! ```text
+//! threshold 0.5
+//! frames 5
+//! challenge_frames 60
+//! # no usable IR on this machine
+//! disable ir_reflectance
+//! ```
+use anyhow::{bail, Context};
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use super::detector::{BoundingBox, Detection};
+use super::enroll::Pose;
+
+pub const DEFAULT_CONFIG: &str = "/etc/ola/liveness.conf";
+
+/// A check scoring below this fails liveness whatever the others say.
+const VETO: f32 = 0.25;
+
+/// Fewest frames with a face the motion check will judge.
+const MIN_MOTION_FRAMES: usize = 3;
+
+/// Side of an eye patch, as a fraction of the face width.
+const EYE_PATCH: f32 = 0.15;
+
+pub fn config_path() -> PathBuf {
+    std::env::var_os("OLA_LIVENESS_CONFIG").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG))
+}
+
+/// One captured frame as the checks see it.
+#[derive(Debug, Clone)]
+pub struct LiveFrame {
+    pub luma: GrayImage,
+    /// The IR partner's frame, for cameras that have one.
+    pub ir: Option<GrayImage>,
+    /// Most confident face, in `luma` coordinates.
+    pub face: Option<Detection>,
+}
+
+/// Checks are shared with the camera worker, so they keep no state between calls.
+pub trait LivenessCheck: Send + Sync {
+    /// Short name, used in `liveness_<name>` reasons and the config file.
+    fn name(&self) -> &'static str;
+
+    /// Share of the fused score.
+    fn weight(&self) -> f32 {
+        1.0
+    }
+
+    /// 0 (spoof) to 1 (live), or `None` when the frames say nothing either way.
+    fn score(&self, frames: &[LiveFrame]) -> Option<f32>;
+}
+
+/// Head-turn challenge the client may ask for. Directions are the user's own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Challenge {
+    TurnLeft,
+    TurnRight,
+}
+
+impl Challenge {
+    fn pose(self) -> Pose {
+        match self {
+            Challenge::TurnLeft => Pose::Left,
+            Challenge::TurnRight => Pose::Right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckScore {
+    pub check: String,
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LivenessResult {
+    pub live: bool,
+    /// Fused score; `None` when no check had anything to say.
+    pub score: Option<f32>,
+    pub threshold: f32,
+    /// Scores of the checks that did not abstain.
+    pub checks: Vec<CheckScore>,
+    /// The check that failed the attempt.
+    pub failed: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LivenessConfig {
+    pub threshold: f32,
+    /// Frames captured for the checks.
+    pub frames: usize,
+    /// Frames captured when a head turn is asked for, long enough to make one.
+    pub challenge_frames: usize,
+    pub disabled: Vec<String>,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self { threshold: 0.5, frames: 5, challenge_frames: 60, disabled: Vec::new() }
+    }
+}
+
+/// Names the config file may disable, `all` aside.
+const CHECKS: [&str; 3] = ["ir_reflectance", "motion", "texture"];
+
+impl LivenessConfig {
+    /// Reads `path`; a missing file means defaults.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let count = |s: &str| -> anyhow::Result<usize> {
+                match s.parse() {
+                    Ok(c) if c > 0 => Ok(c),
+                    _ => bail!("line {}: frame count must be a positive integer", n + 1),
+                }
+            };
+            match words[..] {
+                [] => {}
+                ["threshold", t] => {
+                    config.threshold = match t.parse::<f32>() {
+                        Ok(t) if (0.0..=1.0).contains(&t) => t,
+                        _ => bail!("line {}: threshold must be between 0 and 1", n + 1),
+                    }
+                }
+                ["frames", c] => config.frames = count(c)?,
+                ["challenge_frames", c] => config.challenge_frames = count(c)?,
+                ["disable", "all"] => config.disabled = CHECKS.iter().map(|c| c.to_string()).collect(),
+                ["disable", check] if CHECKS.contains(&check) => config.disabled.push(check.to_string()),
+                ["disable", check] => bail!("line {}: unknown check '{}' (expected {} or all)", n + 1, check, CHECKS.join(", ")),
+                _ => bail!("line {}: expected 'threshold', 'frames', 'challenge_frames' or 'disable': {}", n + 1, line),
+            }
+        }
+        Ok(config)
+    }
+}
+
+pub struct Liveness {
+    checks: Vec<Box<dyn LivenessCheck>>,
+    threshold: f32,
+    frames: usize,
+    challenge_frames: usize,
+}
+
+impl Liveness {
+    pub fn new(checks: Vec<Box<dyn LivenessCheck>>, config: &LivenessConfig) -> Self {
+        Self { checks, threshold: config.threshold, frames: config.frames, challenge_frames: config.challenge_frames }
+    }
+
+    /// The built-in checks the config leaves enabled.
+    pub fn from_config(config: &LivenessConfig) -> Self {
+        let checks: Vec<Box<dyn LivenessCheck>> = vec![Box::new(IrReflectance), Box::new(Motion), Box::new(Texture)];
+        let checks = checks.into_iter().filter(|c| !config.disabled.iter().any(|d| d == c.name())).collect();
+        Self::new(checks, config)
+    }
+
+    pub fn check_names(&self) -> Vec<&'static str> {
+        self.checks.iter().map(|c| c.name()).collect()
+    }
+
+    /// Frames to capture for one attempt.
+    pub fn frames_needed(&self, challenge: Option<Challenge>) -> usize {
+        match challenge {
+            Some(_) => self.challenge_frames.max(self.frames),
+            None => self.frames,
+        }
+    }
+
+    pub fn assess(&self, frames: &[LiveFrame], challenge: Option<Challenge>) -> LivenessResult {
+        let head_turn = challenge.map(HeadTurn);
+        let checks = self.checks.iter().map(|c| c.as_ref()).chain(head_turn.as_ref().map(|c| c as &dyn LivenessCheck));
+
+        let mut scores = Vec::new();
+        let (mut total, mut weights) = (0.0, 0.0);
+        for check in checks {
+            let Some(score) = check.score(frames) else {
+                log::debug!("liveness: {} abstained", check.name());
+                continue;
+            };
+            log::debug!("liveness: {} scored {:.3}", check.name(), score);
+            total += check.weight() * score;
+            weights += check.weight();
+            scores.push(CheckScore { check: check.name().to_string(), score });
+        }
+        let threshold = self.threshold;
+        if scores.is_empty() {
+            // Nothing to judge by is only fine when nothing was asked for
+            let live = self.checks.is_empty() && challenge.is_none();
+            let failed = (!live).then(|| "insufficient".to_string());
+            return LivenessResult { live, score: None, threshold, checks: scores, failed };
+        }
+
+        let fused = total / weights;
+        // The first of the weakest checks is the one to blame
+        let weakest = scores.iter().fold(None::<&CheckScore>, |w, s| match w {
+            Some(w) if w.score <= s.score => Some(w),
+            _ => Some(s),
+        });
+        let live = fused >= threshold && scores.iter().all(|s| s.score >= VETO);
+        let failed = if live { None } else { weakest.map(|s| s.check.clone()) };
+        LivenessResult { live, score: Some(fused), threshold, checks: scores, failed }
+    }
+}
+
+/// `(v - lo) / (hi - lo)`, clamped to 0-1.
+fn ramp(v: f32, lo: f32, hi: f32) -> f32 {
+    ((v - lo) / (hi - lo)).clamp(0.0, 1.0)
+}
+
+/// The middle 60% of a face box, which keeps the face's outline and the
+/// background out.
+fn inner(b: &BoundingBox) -> BoundingBox {
+    BoundingBox { x: b.x + 0.2 * b.width, y: b.y + 0.2 * b.height, width: 0.6 * b.width, height: 0.6 * b.height }
+}
+
+/// Pixel bounds of `bbox` scaled by `sx, sy` and clipped to `image`.
+pub(crate) fn region(image: &GrayImage, bbox: &BoundingBox, sx: f32, sy: f32) -> Option<(u32, u32, u32, u32)> {
+    let clamp = |v: f32, max: u32| (v.max(0.0) as u32).min(max);
+    let (x0, y0) = (clamp(bbox.x * sx, image.width()), clamp(bbox.y * sy, image.height()));
+    let (x1, y1) = (clamp((bbox.x + bbox.width) * sx, image.width()), clamp((bbox.y + bbox.height) * sy, image.height()));
+    (x1 > x0 && y1 > y0).then_some((x0, y0, x1, y1))
+}
+
+/// Mean and standard deviation of the pixels in `(x0, y0, x1, y1)`.
+pub(crate) fn stats(image: &GrayImage, (x0, y0, x1, y1): (u32, u32, u32, u32)) -> (f32, f32) {
+    let values: Vec<f32> = (y0..y1).flat_map(|y| (x0..x1).map(move |x| (x, y))).map(|(x, y)| image.get_pixel(x, y)[0] as f32).collect();
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let var = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / values.len() as f32;
+    (mean, var.sqrt())
+}
+
+/// Skin lit by the IR emitter comes back bright and full of detail (eyes,
+/// brows, nostrils). Screens emit no IR and show up dark; most print inks are
+/// transparent to it, so a photo shows up as blank paper.
+pub struct IrReflectance;
+
+impl LivenessCheck for IrReflectance {
+    fn name(&self) -> &'static str {
+        "ir_reflectance"
+    }
+
+    fn score(&self, frames: &[LiveFrame]) -> Option<f32> {
+        let scores: Vec<f32> = frames
+            .iter()
+            .filter_map(|f| {
+                let (ir, face) = (f.ir.as_ref()?, f.face.as_ref()?);
+                // The sensors of a pair share a field of view, if not a resolution
+                let sx = ir.width() as f32 / f.luma.width() as f32;
+                let sy = ir.height() as f32 / f.luma.height() as f32;
+                let (mean, std) = stats(ir, region(ir, &inner(&face.bbox), sx, sy)?);
+                let bright = ramp(mean, 20.0, 50.0);
+                let detail = ramp(std / mean.max(1.0), 0.04, 0.12);
+                Some(bright.min(detail))
+            })
+            .collect();
+        (!scores.is_empty()).then(|| scores.iter().sum::<f32>() / scores.len() as f32)
+    }
+}
+
+/// A live face is never quite still: landmarks shift against each other and
+/// eyes blink. A photo moved in front of the camera keeps its landmarks in
+/// place relative to the face box and its eyes open.
+pub struct Motion;
+
+impl Motion {
+    /// Mean per-landmark standard deviation of positions relative to the box,
+    /// in face widths.
+    fn jitter(faces: &[&Detection]) -> f32 {
+        let landmarks = faces.iter().map(|f| f.landmarks.len()).min().unwrap_or(0);
+        if landmarks == 0 {
+            return 0.0;
+        }
+        let n = faces.len() as f32;
+        let mut total = 0.0;
+        for i in 0..landmarks {
+            let rel: Vec<(f32, f32)> = faces
+                .iter()
+                .map(|f| ((f.landmarks[i].x - f.bbox.x) / f.bbox.width, (f.landmarks[i].y - f.bbox.y) / f.bbox.height))
+                .collect();
+            let (mx, my) = rel.iter().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+            let (mx, my) = (mx / n, my / n);
+            let var = rel.iter().map(|(x, y)| (x - mx).powi(2) + (y - my).powi(2)).sum::<f32>() / n;
+            total += var.sqrt();
+        }
+        total / landmarks as f32
+    }
+
+    /// Brightness of the eyes relative to the whole face. Closing them swaps
+    /// dark pupils for eyelid skin.
+    fn eye_brightness(luma: &GrayImage, face: &Detection) -> Option<f32> {
+        let [a, b, ..] = face.landmarks[..] else { return None };
+        let (face_mean, _) = stats(luma, region(luma, &face.bbox, 1.0, 1.0)?);
+        let side = face.bbox.width * EYE_PATCH;
+        let mut eyes = 0.0;
+        for p in [a, b] {
+            let patch = BoundingBox { x: p.x - side / 2.0, y: p.y - side / 2.0, width: side, height: side };
+            eyes += stats(luma, region(luma, &patch, 1.0, 1.0)?).0;
+        }
+        Some(eyes / 2.0 / face_mean.max(1.0))
+    }
+}
+
+impl LivenessCheck for Motion {
+    fn name(&self) -> &'static str {
+        "motion"
+    }
+
+    fn score(&self, frames: &[LiveFrame]) -> Option<f32> {
+        let with_face: Vec<(&GrayImage, &Detection)> = frames.iter().filter_map(|f| Some((&f.luma, f.face.as_ref()?))).collect();
+        if with_face.len() < MIN_MOTION_FRAMES {
+            return None;
+        }
+        let faces: Vec<&Detection> = with_face.iter().map(|(_, f)| *f).collect();
+        let jitter = ramp(Self::jitter(&faces), 0.002, 0.01);
+        let eyes: Vec<f32> = with_face.iter().filter_map(|(luma, face)| Self::eye_brightness(luma, face)).collect();
+        let blink = match (eyes.iter().copied().reduce(f32::min), eyes.iter().copied().reduce(f32::max)) {
+            (Some(lo), Some(hi)) => ramp(hi - lo, 0.05, 0.2),
+            _ => 0.0,
+        };
+        Some(jitter.max(blink))
+    }
+}
+
+/// Side of the square the texture check resamples the face to, at most.
+const TEXTURE_SIZE: usize = 64;
+
+/// Filming a screen lays the camera's pixel grid over the screen's, which
+/// makes moiré: a few sharp peaks in the high frequencies of an otherwise
+/// smoothly falling spectrum. Skin texture spreads its energy out.
+pub struct Texture;
+
+impl Texture {
+    /// Strongest high-frequency component of the middle of the face, over the
+    /// median one.
+    fn peakiness(luma: &GrayImage, face: &Detection) -> Option<f32> {
+        let (x0, y0, x1, y1) = region(luma, &inner(&face.bbox), 1.0, 1.0)?;
+        let n = ((x1 - x0).min(y1 - y0) as usize).min(TEXTURE_SIZE);
+        if n < 8 {
+            return None;
+        }
+        // Nearest neighbour, so no smoothing hides the pattern
+        let step = (x1 - x0).min(y1 - y0) as f32 / n as f32;
+        let mut samples: Vec<f32> = (0..n * n)
+            .map(|i| {
+                let (u, v) = ((i % n) as f32, (i / n) as f32);
+                luma.get_pixel(x0 + (u * step) as u32, y0 + (v * step) as u32)[0] as f32
+            })
+            .collect();
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        let hann: Vec<f32> = (0..n).map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * (i as f32 + 0.5) / n as f32).cos()).collect();
+        for (i, s) in samples.iter_mut().enumerate() {
+            *s = (*s - mean) * hann[i % n] * hann[i / n];
+        }
+
+        let spectrum = dft2(&samples, n);
+        let mut band: Vec<f32> = Vec::new();
+        for v in 0..n {
+            for u in 0..n {
+                // Signed frequencies, in cycles per crop
+                let fu = if u <= n / 2 { u as f32 } else { u as f32 - n as f32 };
+                let fv = if v <= n / 2 { v as f32 } else { v as f32 - n as f32 };
+                if fu.hypot(fv) >= n as f32 / 4.0 {
+                    band.push(spectrum[v * n + u]);
+                }
+            }
+        }
+        band.sort_by(f32::total_cmp);
+        let median = band[band.len() / 2];
+        let peak = *band.last()?;
+        Some(if median > 0.0 { peak / median } else { 0.0 })
+    }
+}
+
+/// Magnitudes of the 2-D DFT of an `n`x`n` row-major block. The crops are
+/// small enough that a direct transform, rows then columns, is plenty fast.
+fn dft2(samples: &[f32], n: usize) -> Vec<f32> {
+    let (cos, sin): (Vec<f32>, Vec<f32>) = (0..n)
+        .map(|k| (2.0 * std::f32::consts::PI * k as f32 / n as f32).sin_cos())
+        .map(|(s, c)| (c, s))
+        .unzip();
+    let mut rows = vec![(0.0f32, 0.0f32); n * n];
+    for y in 0..n {
+        for u in 0..n {
+            let (mut re, mut im) = (0.0, 0.0);
+            for x in 0..n {
+                let k = (u * x) % n;
+                re += samples[y * n + x] * cos[k];
+                im -= samples[y * n + x] * sin[k];
+            }
+            rows[y * n + u] = (re, im);
+        }
+    }
+    let mut out = vec![0.0; n * n];
+    for u in 0..n {
+        for v in 0..n {
+            let (mut re, mut im) = (0.0, 0.0);
+            for y in 0..n {
+                let k = (v * y) % n;
+                let (r, i) = rows[y * n + u];
+                re += r * cos[k] + i * sin[k];
+                im += i * cos[k] - r * sin[k];
+            }
+            out[v * n + u] = re.hypot(im);
+        }
+    }
+    out
+}
+
+impl LivenessCheck for Texture {
+    fn name(&self) -> &'static str {
+        "texture"
+    }
+
+    fn score(&self, frames: &[LiveFrame]) -> Option<f32> {
+        // The pattern is there in every frame; the first face is enough
+        let frame = frames.iter().find(|f| f.face.is_some())?;
+        let peakiness = Self::peakiness(&frame.luma, frame.face.as_ref()?)?;
+        Some(1.0 - ramp(peakiness, 5.0, 10.0))
+    }
+}
+
+/// The user was asked to turn their head and did: the burst starts facing
+/// the camera and later shows the requested pose. A replayed video would have
+/// to guess the direction.
+pub struct HeadTurn(pub Challenge);
+
+impl LivenessCheck for HeadTurn {
+    fn name(&self) -> &'static str {
+        "head_turn"
+    }
+
+    fn weight(&self) -> f32 {
+        // It was asked for; not doing it is not outweighed by the rest
+        2.0
+    }
+
+    fn score(&self, frames: &[LiveFrame]) -> Option<f32> {
+        let mut poses = frames.iter().filter_map(|f| f.face.as_ref()).map(Pose::of);
+        let turned = poses.next() == Some(Pose::Front) && poses.any(|p| p == self.0.pose());
+        Some(if turned { 1.0 } else { 0.0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert;
+    use crate::frame_source::{FileSource, FrameSource};
+    use std::collections::BTreeMap;
+
+    fn fixture_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/liveness")
+    }
+
+    /// A recorded sequence: RGB (and IR, if recorded) clips replayed through
+    /// `FileSource`, with what the detector found in each frame.
+    fn sequence(name: &str, with_ir: bool) -> Vec<LiveFrame> {
+        let dir = fixture_dir().join(name);
+        let faces: Vec<Option<Detection>> = serde_json::from_str(&std::fs::read_to_string(dir.join("faces.json")).unwrap()).unwrap();
+        let mut rgb = FileSource::open(&dir.join("rgb.y4m")).unwrap();
+        let mut ir = with_ir.then(|| FileSource::open(&dir.join("ir.y4m")).unwrap());
+        faces
+            .into_iter()
+            .map(|face| LiveFrame {
+                luma: image::imageops::grayscale(&convert::to_rgb8(&rgb.next_frame().unwrap()).unwrap()),
+                ir: ir.as_mut().map(|s| convert::to_luma8(&s.next_frame().unwrap()).unwrap()),
+                face,
+            })
+            .collect()
+    }
+
+    fn liveness() -> Liveness {
+        Liveness::from_config(&LivenessConfig::default())
+    }
+
+    /// Every sequence's result with and without its IR clip, compared with
+    /// `expected.json`. Set `OLA_BLESS=1` to regenerate it.
+    #[test]
+    fn recorded_sequences_match_expected() {
+        let cases = [("live", true), ("live", false), ("photo", true), ("photo", false), ("screen", true), ("screen", false)];
+        let mut actual = BTreeMap::new();
+        for (name, with_ir) in cases {
+            let key = format!("{}{}", name, if with_ir { "+ir" } else { "" });
+            actual.insert(key, liveness().assess(&sequence(name, with_ir), None));
+        }
+        let path = fixture_dir().join("expected.json");
+        if std::env::var_os("OLA_BLESS").is_some() {
+            std::fs::write(&path, serde_json::to_string_pretty(&actual).unwrap() + "\n").unwrap();
+        }
+        let expected: BTreeMap<String, LivenessResult> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(actual.keys().collect::<Vec<_>>(), expected.keys().collect::<Vec<_>>());
+        for (key, want) in &expected {
+            let got = &actual[key];
+            assert_eq!((got.live, &got.failed), (want.live, &want.failed), "{}: {:?}", key, got);
+            let names = |r: &LivenessResult| r.checks.iter().map(|c| c.check.clone()).collect::<Vec<_>>();
+            assert_eq!(names(got), names(want), "{}", key);
+            for (g, w) in got.checks.iter().zip(&want.checks) {
+                assert!((g.score - w.score).abs() < 1e-3, "{} {}: {} vs {}", key, g.check, g.score, w.score);
+            }
+        }
+
+        // Whatever the blessed numbers, the outcomes must be these
+        let failed = |key: &str| actual[key].failed.as_deref();
+        assert!(actual["live+ir"].live && actual["live"].live);
+        assert_eq!(failed("photo+ir"), Some("ir_reflectance"));
+        assert_eq!(failed("photo"), Some("motion"));
+        assert_eq!(failed("screen+ir"), Some("ir_reflectance"));
+        assert_eq!(failed("screen"), Some("texture"));
+        assert!(actual["live"].checks.iter().all(|c| c.check != "ir_reflectance"));
+    }
+
+    #[test]
+    fn head_turn_challenge_needs_the_requested_direction() {
+        let frames = sequence("turn_left", false);
+        let res = liveness().assess(&frames, Some(Challenge::TurnLeft));
+        assert!(res.live, "{:?}", res);
+        assert!(res.checks.iter().any(|c| c.check == "head_turn" && c.score == 1.0));
+
+        let res = liveness().assess(&frames, Some(Challenge::TurnRight));
+        assert_eq!((res.live, res.failed.as_deref()), (false, Some("head_turn")));
+
+        // Looking straight ahead throughout is no answer either
+        let res = liveness().assess(&sequence("live", false), Some(Challenge::TurnLeft));
+        assert_eq!(res.failed.as_deref(), Some("head_turn"));
+    }
+
+    #[test]
+    fn too_little_to_go_on_fails_closed() {
+        let mut frames = sequence("live", false);
+        frames.iter_mut().for_each(|f| f.face = None);
+        let res = liveness().assess(&frames, None);
+        assert_eq!((res.live, res.failed.as_deref()), (false, Some("insufficient")));
+
+        // With every check disabled there is nothing to fail
+        let off = Liveness::from_config(&LivenessConfig::parse("disable all\n").unwrap());
+        assert!(off.check_names().is_empty());
+        assert!(off.assess(&frames, None).live);
+    }
+
+    #[test]
+    fn config_parses_and_rejects_unknown_checks() {
+        let config = LivenessConfig::parse("threshold 0.6 # stricter\nframes 8\nchallenge_frames 90\ndisable texture\n").unwrap();
+        assert_eq!(config.threshold, 0.6);
+        assert_eq!((config.frames, config.challenge_frames), (8, 90));
+        let live = Liveness::from_config(&config);
+        assert_eq!(live.check_names(), vec!["ir_reflectance", "motion"]);
+        assert_eq!((live.frames_needed(None), live.frames_needed(Some(Challenge::TurnLeft))), (8, 90));
+
+        assert!(LivenessConfig::parse("disable depth\n").is_err());
+        assert!(LivenessConfig::parse("threshold 2\n").is_err());
+        assert!(LivenessConfig::parse("frames 0\n").is_err());
+        assert_eq!(LivenessConfig::load(Path::new("/nonexistent/liveness.conf")).unwrap(), LivenessConfig::default());
+    }
+}
@@ -0,0 +1,133 @@
+// src/lockout.rs
+//! Backs off a user after repeated failed verifications, so photos or masks
+//! cannot be tried at camera speed.
+//!
+//! Only attempts that reached a verdict count: a face that did not match or
+//! did not pass liveness. No face, a busy camera or a timeout say nothing
+//! about who is trying. A success clears the count. State is kept in memory,
+//! so a daemon restart clears it too; PAM's own faillock covers the rest.
+//!
+//! Identification counts against the caller: otherwise it would be a way to
+//! try faces against everyone, and around verification's lockout.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::camera::{IdentificationResult, ReasonCode, VerificationResult};
+
+/// Failures in a row that lock a user out.
+pub const MAX_FAILURES: u32 = 5;
+
+/// How long a lockout lasts.
+pub const LOCKOUT_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct Record {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct Lockout {
+    users: HashMap<u32, Record>,
+}
+
+impl Lockout {
+    /// Time left before `uid` may try again, if locked out.
+    pub fn remaining(&self, uid: u32, now: Instant) -> Option<Duration> {
+        let until = self.users.get(&uid)?.locked_until?;
+        (until > now).then(|| until - now)
+    }
+
+    pub fn record(&mut self, uid: u32, result: &VerificationResult, now: Instant) {
+        if result.ok {
+            self.users.remove(&uid);
+        } else if matches!(result.code, Some(ReasonCode::NoMatch | ReasonCode::LivenessFailed)) {
+            self.fail(uid, now);
+        }
+    }
+
+    /// Records an identification run by `caller`. Only identifying the caller
+    /// themselves clears their count; finding nobody, or nobody for sure, is
+    /// a failure like a verification that did not match.
+    pub fn record_identification(&mut self, caller: u32, result: &IdentificationResult, now: Instant) {
+        if result.ok && result.uid == Some(caller) {
+            self.users.remove(&caller);
+        } else if matches!(result.code, Some(ReasonCode::NoMatch | ReasonCode::AmbiguousMatch | ReasonCode::LivenessFailed)) {
+            self.fail(caller, now);
+        }
+    }
+
+    fn fail(&mut self, uid: u32, now: Instant) {
+        let record = self.users.entry(uid).or_default();
+        record.failures += 1;
+        if record.failures >= MAX_FAILURES {
+            log::warn!("uid {} locked out for {:?} after {} failed verifications", uid, LOCKOUT_PERIOD, record.failures);
+            // Another failure after the lockout starts a new one straight away
+            record.failures = MAX_FAILURES - 1;
+            record.locked_until = Some(now + LOCKOUT_PERIOD);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_failures_lock_out_until_the_period_ends() {
+        let mut lockout = Lockout::default();
+        let now = Instant::now();
+        let no_match = VerificationResult::rejected(ReasonCode::NoMatch, "no_match", vec![]);
+        let no_face = VerificationResult::rejected(ReasonCode::NoFace, "no_face", vec![]);
+
+        for _ in 0..MAX_FAILURES - 1 {
+            lockout.record(1000, &no_match, now);
+            lockout.record(1000, &no_face, now);
+        }
+        assert_eq!(lockout.remaining(1000, now), None);
+        lockout.record(1000, &no_match, now);
+        assert_eq!(lockout.remaining(1000, now), Some(LOCKOUT_PERIOD));
+        assert_eq!(lockout.remaining(1001, now), None);
+
+        // Once it has passed, one more failure is enough to lock again
+        let later = now + LOCKOUT_PERIOD;
+        assert_eq!(lockout.remaining(1000, later), None);
+        lockout.record(1000, &no_match, later);
+        assert_eq!(lockout.remaining(1000, later), Some(LOCKOUT_PERIOD));
+
+        // A success starts over
+        let ok = VerificationResult { ok: true, ..Default::default() };
+        lockout.record(1000, &ok, later);
+        assert_eq!(lockout.remaining(1000, later), None);
+        lockout.record(1000, &no_match, later);
+        assert_eq!(lockout.remaining(1000, later), None);
+    }
+
+    #[test]
+    fn failed_identifications_count_against_the_caller() {
+        let mut lockout = Lockout::default();
+        let now = Instant::now();
+        let rejected = |code| IdentificationResult { code: Some(code), ..Default::default() };
+        let found = |uid| IdentificationResult { ok: true, uid: Some(uid), ..Default::default() };
+
+        for _ in 0..MAX_FAILURES - 1 {
+            lockout.record_identification(0, &rejected(ReasonCode::AmbiguousMatch), now);
+            lockout.record_identification(0, &rejected(ReasonCode::NoFace), now);
+        }
+        // Finding someone else does not clear the caller's failures
+        lockout.record_identification(0, &found(1000), now);
+        assert_eq!(lockout.remaining(0, now), None);
+        lockout.record_identification(0, &rejected(ReasonCode::NoMatch), now);
+        assert_eq!(lockout.remaining(0, now), Some(LOCKOUT_PERIOD));
+        assert_eq!(lockout.remaining(1000, now), None);
+
+        let later = now + LOCKOUT_PERIOD;
+        lockout.record_identification(1000, &rejected(ReasonCode::LivenessFailed), later);
+        lockout.record_identification(1000, &found(1000), later);
+        lockout.record_identification(0, &found(0), later);
+        for _ in 0..MAX_FAILURES - 1 {
+            lockout.record_identification(0, &rejected(ReasonCode::NoMatch), later);
+        }
+        assert_eq!(lockout.remaining(0, later), None);
+    }
+}
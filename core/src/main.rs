@@ -1,10 +1,13 @@
 // src/main.rs
-pub mod camera_worker;
-pub mod camera;
-mod secure_store;
-
-use camera_worker::{CameraWorker, CameraRequest};
-use tokio::sync::{mpsc, oneshot};
+use ola_core::camera;
+use ola_core::camera_lock::CameraBusy;
+use ola_core::camera_worker::{self, CameraTarget, CameraWorker, CameraRequest, WorkerConfig};
+use ola_core::enroll::{self, Enrollment};
+use ola_core::liveness::Challenge;
+use ola_core::quality::Issue;
+use ola_core::templates;
+use ola_core::hotplug::CameraEvent;
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 use tokio::net::{UnixListener, UnixStream};
 use serde::{Deserialize, Serialize};
@@ -27,6 +30,9 @@ use nix::sys::socket::{getsockopt, sockopt::PeerCredentials, UnixCredentials};
 
 const SOCKET_PATH: &str = "/run/ola/ola.sock"; // systemd /run path
 
+// Connections that are not subscribed to notifications are closed after this long without a request
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(20);
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Request {
     id: Option<u64>,
@@ -41,6 +47,13 @@ struct Response {
     error: Option<String>,
 }
 
+/// Unsolicited server-to-client message (no `id`).
+#[derive(Serialize, Debug)]
+struct Notification {
+    method: String,
+    params: serde_json::Value,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
@@ -49,9 +62,35 @@ async fn main() -> anyhow::Result<()> {
     // Start Camera Worker
     // This spawns a dedicated thread for blocking camera operations.
     // We clone the sender (worker_tx) for each client connection.
-    let (worker, worker_tx) = CameraWorker::new();
+    let worker_config = WorkerConfig::from_env()?;
+    info!("Camera backend: {}", worker_config.source.backend_name());
+    if let Some(id) = &worker_config.auth_camera {
+        info!("Auth camera pinned to {}", id);
+    }
+    let (worker, worker_tx) = CameraWorker::new(worker_config);
+    let camera_events = worker.events();
     let worker_handle = worker.run();
 
+    // SIGHUP swaps in the model set the registry lists now. Weak, so it does
+    // not keep the worker alive through shutdown.
+    let reload_tx = worker_tx.downgrade();
+    let mut sighup = signal(SignalKind::hangup()).context("installing SIGHUP handler")?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("SIGHUP received, reloading models");
+            let Some(worker_tx) = reload_tx.upgrade() else { break };
+            let (tx, rx) = oneshot::channel();
+            if worker_tx.send(CameraRequest::ReloadModels(tx)).await.is_err() {
+                break;
+            }
+            match rx.await {
+                Ok(Ok(models)) => info!("Reloaded {} models", models.len()),
+                Ok(Err(e)) => error!("Model reload failed, keeping the current models: {:#}", e),
+                Err(_) => error!("Worker dropped model reload response"),
+            }
+        }
+    });
+
     // Allow overriding socket path (useful for dev/testing without root)
     let socket_path_str = std::env::var("OLA_SOCKET_PATH").unwrap_or_else(|_| SOCKET_PATH.to_string());
     let socket_path = Path::new(&socket_path_str);
@@ -136,11 +175,12 @@ async fn main() -> anyhow::Result<()> {
                 let permit = max_conns.clone().acquire_owned().await.unwrap();
                 let socket_path_clone = socket_path_str.clone();
                 let worker_tx = worker_tx.clone();
+                let camera_events = camera_events.clone();
 
                 tokio::spawn(async move {
                     let _permit = permit; // release when task finishes
-                    if let Err(e) = timeout(Duration::from_secs(20), handle_client(stream, socket_path_clone, worker_tx)).await {
-                        error!("Client handling timed out or errored: {:?}", e);
+                    if let Err(e) = handle_client(stream, socket_path_clone, worker_tx, camera_events).await {
+                        error!("Client handling errored: {:?}", e);
                     }
                 });
             }
@@ -241,7 +281,77 @@ fn check_allowlist(creds: &UnixCredentials) -> bool {
     false
 }
 
-async fn handle_client(stream: UnixStream, socket_path: String, worker_tx: mpsc::Sender<CameraRequest>) -> anyhow::Result<()> {
+/// Waits for the next camera event on a subscription; never resolves when unsubscribed.
+/// Returns `None` once the worker has gone away.
+async fn next_camera_event(subscription: &mut Option<broadcast::Receiver<CameraEvent>>) -> Option<CameraEvent> {
+    let Some(rx) = subscription else {
+        return std::future::pending().await;
+    };
+    loop {
+        match rx.recv().await {
+            Ok(event) => return Some(event),
+            Err(broadcast::error::RecvError::Lagged(n)) => warn!("Subscriber lagged, dropped {} camera events", n),
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Error response for a failed capture. A busy camera is reported as
+/// `camera_busy`, with the device and the processes holding it as the result.
+fn camera_error(id: Option<u64>, context: &str, e: anyhow::Error) -> Response {
+    match e.downcast_ref::<CameraBusy>() {
+        Some(busy) => Response { id, result: Some(serde_json::json!(busy)), error: Some(busy.to_string()) },
+        None => Response { id, result: None, error: Some(format!("{}: {}", context, e)) },
+    }
+}
+
+/// A string param, if present.
+fn str_param<'a>(params: Option<&'a serde_json::Value>, name: &str) -> Option<&'a str> {
+    params.and_then(|p| p.get(name)).and_then(|v| v.as_str())
+}
+
+/// The uid a template RPC acts on: the caller's own unless a `uid` param
+/// names someone else, which only root may do.
+fn template_owner(creds: &UnixCredentials, params: Option<&serde_json::Value>) -> Result<u32, String> {
+    let Some(v) = params.and_then(|p| p.get("uid")) else { return Ok(creds.uid()) };
+    let uid = v.as_u64().and_then(|u| u32::try_from(u).ok()).ok_or("Invalid uid")?;
+    if uid != creds.uid() && creds.uid() != 0 {
+        return Err("permission_denied: only root can manage other users' templates".into());
+    }
+    Ok(uid)
+}
+
+/// The uids `identify` picks from. Root and the daemon's own user pick from
+/// everyone enrolled, or the `uids` param if given; anyone else only from
+/// themselves.
+fn identify_scope(creds: &UnixCredentials, params: Option<&serde_json::Value>) -> Result<Option<Vec<u32>>, String> {
+    let uids: Option<Vec<u32>> = match params.and_then(|p| p.get("uids")) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(v) => Some(serde_json::from_value(v.clone()).map_err(|_| "Invalid params: uids must be a list of uids")?),
+    };
+    if creds.uid() == 0 || creds.uid() == nix::unistd::getuid().as_raw() {
+        return Ok(uids);
+    }
+    if uids.as_ref().is_some_and(|uids| uids.iter().any(|&u| u != creds.uid())) {
+        return Err("permission_denied: only root can identify other users".into());
+    }
+    Ok(Some(vec![creds.uid()]))
+}
+
+/// Live advice on a request's frames, tagged with the request's `id`.
+fn guidance_note(id: Option<u64>, issue: Issue) -> Notification {
+    Notification {
+        method: "guidance".into(),
+        params: serde_json::json!({ "id": id, "issue": issue, "guidance": issue.guidance() }),
+    }
+}
+
+async fn handle_client(
+    stream: UnixStream,
+    socket_path: String,
+    worker_tx: mpsc::Sender<CameraRequest>,
+    camera_events: broadcast::Sender<CameraEvent>,
+) -> anyhow::Result<()> {
     // Security: Check Peer Credentials (SO_PEERCRED)
     // Note: getsockopt expects a type implementing AsFd. UnixStream implements AsFd.
     // Defensive: Handle getsockopt errors gracefully (e.g. abstract sockets, activation quirks)
@@ -263,8 +373,40 @@ async fn handle_client(stream: UnixStream, socket_path: String, worker_tx: mpsc:
     }
 
     let mut framed = Framed::new(stream, LinesCodec::new());
+    let mut subscription: Option<broadcast::Receiver<CameraEvent>> = None;
+    // Samples stay in memory until committed, so dropping the connection discards them
+    let mut enrollment: Option<(camera::CameraSelector, Enrollment)> = None;
+
+    loop {
+        // Subscribed connections stay open indefinitely to receive notifications
+        let idle_limit = if subscription.is_some() { Duration::MAX } else { CLIENT_IDLE_TIMEOUT };
+
+        let line_result = tokio::select! {
+            line = framed.next() => match line {
+                Some(l) => l,
+                None => break,
+            },
+            event = next_camera_event(&mut subscription) => {
+                let Some(event) = event else {
+                    subscription = None;
+                    continue;
+                };
+                let note = Notification {
+                    method: event.method().to_string(),
+                    params: serde_json::json!(event.camera()),
+                };
+                if let Err(e) = framed.send(serde_json::to_string(&note)?).await {
+                    error!("Failed to send notification: {}", e);
+                    break;
+                }
+                continue;
+            },
+            _ = tokio::time::sleep(idle_limit) => {
+                info!("Closing idle connection (uid={})", creds.uid());
+                break;
+            },
+        };
 
-    while let Some(line_result) = framed.next().await {
         match line_result {
             Ok(line) => {
                 // Defensive: reject excessively large lines
@@ -300,13 +442,42 @@ async fn handle_client(stream: UnixStream, socket_path: String, worker_tx: mpsc:
                     }
                 };
 
-                // 2. Determine Timeout based on method
+                // Subscriptions are per-connection state, handled outside the worker
+                if req.method == "subscribe" || req.method == "unsubscribe" {
+                    subscription = (req.method == "subscribe").then(|| camera_events.subscribe());
+                    let resp = Response {
+                        id: req.id,
+                        result: Some(serde_json::json!({ "subscribed": subscription.is_some() })),
+                        error: None,
+                    };
+                    if let Err(e) = framed.send(serde_json::to_string(&resp)?).await {
+                        error!("Failed to send response to client: {}", e);
+                        break;
+                    }
+                    continue;
+                }
+
+                // 2. Determine Timeout based on method, plus any time the client
+                // is willing to wait for a busy camera
+                let queue_wait = CameraTarget::from_params(req.params.as_ref()).wait;
                 let timeout_duration = match req.method.as_str() {
-                    "capture_thumbnail" => Duration::from_secs(15), // Camera ops need more time
-                    "verify_once" => Duration::from_secs(10),       // Verification might take time
-                    _ => Duration::from_secs(5),                    // Fast ops (ping, status)
+                    "capture_thumbnail" => Duration::from_secs(15) + queue_wait, // Camera ops need more time
+                    "verify_once" | "identify" => camera_worker::VERIFY_RPC_TIMEOUT + queue_wait, // Verification might take time
+                    "enroll_frame" => Duration::from_secs(15) + queue_wait,
+                    // May load the embedder, then embed every retained crop
+                    "list_stale_users" | "migrate_templates" => Duration::from_secs(60),
+                    // Reads, hashes and optimizes every model
+                    "reload_models" => Duration::from_secs(60),
+                    _ => Duration::from_secs(5),                                 // Fast ops (ping, status)
                 };
 
+                // Clients that ask for `guidance` hear about frame quality
+                // issues while their verification or enrollment frame runs
+                let (guide_tx, mut guide_rx) = mpsc::unbounded_channel();
+                let wants_guidance = req.params.as_ref().and_then(|p| p.get("guidance")).and_then(|v| v.as_bool()).unwrap_or(false);
+                let guide = wants_guidance.then_some(guide_tx);
+                let request_id = req.id;
+
                 // 3. Process with dynamic timeout
                 let process = async {
                     match req.method.as_str() {
@@ -334,26 +505,66 @@ async fn handle_client(stream: UnixStream, socket_path: String, worker_tx: mpsc:
                             }
                         },
                         "capture_thumbnail" => {
-                            let index = req.params
-                                .and_then(|p| p.get("index").and_then(|i| i.as_u64()))
-                                .map(|i| i as usize)
-                                .unwrap_or(0);
+                            let params = req.params.unwrap_or_else(|| serde_json::json!({}));
+                            let target = CameraTarget::from_params(Some(&params));
+                            let options: camera::ThumbnailOptions = match serde_json::from_value(params) {
+                                Ok(o) => o,
+                                Err(e) => return Response { id: req.id, result: None, error: Some(format!("Invalid params: {}", e)) },
+                            };
+                            if let Err(e) = options.validate() {
+                                return Response { id: req.id, result: None, error: Some(format!("Invalid params: {}", e)) };
+                            }
 
                             let (tx, rx) = oneshot::channel();
-                            if let Err(e) = worker_tx.send(CameraRequest::CaptureThumbnail(index, tx)).await {
+                            if let Err(e) = worker_tx.send(CameraRequest::CaptureThumbnail(target, options, tx)).await {
                                 return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
                             }
 
                             match rx.await {
-                                Ok(Ok(base64_img)) => Response {
+                                Ok(Ok(thumbnail)) => Response {
                                     id: req.id,
-                                    result: Some(serde_json::json!({ "image": base64_img })),
+                                    result: Some(serde_json::json!(thumbnail)),
                                     error: None,
                                 },
-                                Ok(Err(e)) => Response {
+                                Ok(Err(e)) => camera_error(req.id, "Capture error", e),
+                                Err(_) => Response {
                                     id: req.id,
                                     result: None,
-                                    error: Some(format!("Capture error: {}", e)),
+                                    error: Some("Worker dropped response".into()),
+                                }
+                            }
+                        },
+                        "verify_once" => {
+                            let target = CameraTarget::from_params(req.params.as_ref());
+                            let challenge: Option<Challenge> = match req.params.as_ref().and_then(|p| p.get("challenge")) {
+                                None | Some(serde_json::Value::Null) => None,
+                                Some(v) => match serde_json::from_value(v.clone()) {
+                                    Ok(c) => Some(c),
+                                    Err(_) => return Response { id: req.id, result: None, error: Some("Invalid params: challenge must be turn_left or turn_right".into()) },
+                                },
+                            };
+                            let timeout = camera_worker::verify_timeout(req.params.as_ref(), challenge.is_some());
+                            let (tx, rx) = oneshot::channel();
+                            // Callers are matched against their own templates
+                            let request = CameraRequest::VerifyOnce(target, creds.uid(), timeout.as_millis() as u64, challenge, guide.clone(), tx);
+                            if let Err(e) = worker_tx.send(request).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+
+                            match rx.await {
+                                Ok(Ok(res)) => Response {
+                                    id: req.id,
+                                    result: Some(serde_json::json!(res)),
+                                    error: None,
+                                },
+                                // A busy camera is an outcome clients branch on like any other
+                                Ok(Err(e)) => match e.downcast_ref::<CameraBusy>() {
+                                    Some(busy) => Response {
+                                        id: req.id,
+                                        result: Some(serde_json::json!(camera::VerificationResult::camera_busy(busy))),
+                                        error: None,
+                                    },
+                                    None => camera_error(req.id, "Verification error", e),
                                 },
                                 Err(_) => Response {
                                     id: req.id,
@@ -362,9 +573,23 @@ async fn handle_client(stream: UnixStream, socket_path: String, worker_tx: mpsc:
                                 }
                             }
                         },
-                        "verify_once" => {
+                        "identify" => {
+                            let target = CameraTarget::from_params(req.params.as_ref());
+                            let scope = match identify_scope(&creds, req.params.as_ref()) {
+                                Ok(scope) => scope,
+                                Err(e) => return Response { id: req.id, result: None, error: Some(e) },
+                            };
+                            let challenge: Option<Challenge> = match req.params.as_ref().and_then(|p| p.get("challenge")) {
+                                None | Some(serde_json::Value::Null) => None,
+                                Some(v) => match serde_json::from_value(v.clone()) {
+                                    Ok(c) => Some(c),
+                                    Err(_) => return Response { id: req.id, result: None, error: Some("Invalid params: challenge must be turn_left or turn_right".into()) },
+                                },
+                            };
+                            let timeout = camera_worker::verify_timeout(req.params.as_ref(), challenge.is_some());
                             let (tx, rx) = oneshot::channel();
-                            if let Err(e) = worker_tx.send(CameraRequest::VerifyOnce(2000, tx)).await {
+                            let request = CameraRequest::Identify(target, creds.uid(), scope, timeout.as_millis() as u64, challenge, guide.clone(), tx);
+                            if let Err(e) = worker_tx.send(request).await {
                                 return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
                             }
 
@@ -374,11 +599,96 @@ async fn handle_client(stream: UnixStream, socket_path: String, worker_tx: mpsc:
                                     result: Some(serde_json::json!(res)),
                                     error: None,
                                 },
-                                Ok(Err(e)) => Response {
+                                Ok(Err(e)) => match e.downcast_ref::<CameraBusy>() {
+                                    Some(busy) => Response {
+                                        id: req.id,
+                                        result: Some(serde_json::json!(camera::IdentificationResult::camera_busy(busy))),
+                                        error: None,
+                                    },
+                                    None => camera_error(req.id, "Identification error", e),
+                                },
+                                Err(_) => Response {
+                                    id: req.id,
+                                    result: None,
+                                    error: Some("Worker dropped response".into()),
+                                }
+                            }
+                        },
+                        "enroll_begin" => {
+                            let target = CameraTarget::from_params(req.params.as_ref());
+                            let label = match templates::validate_label(str_param(req.params.as_ref(), "label").unwrap_or("face")) {
+                                Ok(label) => label,
+                                Err(e) => return Response { id: req.id, result: None, error: Some(e.to_string()) },
+                            };
+                            // Opt-in: keeping face crops lets templates survive a model upgrade
+                            let retain_crops = req.params.as_ref().and_then(|p| p.get("retain_crops")).and_then(|v| v.as_bool()).unwrap_or(false);
+                            let session = Enrollment::new(label).with_retained_crops(retain_crops);
+                            let result = serde_json::json!({
+                                "needed": enroll::SAMPLES_NEEDED,
+                                "guidance": session.guidance(),
+                                "expires_in_ms": enroll::SESSION_TIMEOUT.as_millis() as u64,
+                            });
+                            // Beginning again discards a session in progress
+                            enrollment = Some((target.selector, session));
+                            Response { id: req.id, result: Some(result), error: None }
+                        },
+                        "enroll_frame" => {
+                            if enrollment.as_ref().is_some_and(|(_, session)| session.expired()) {
+                                enrollment = None;
+                                return Response { id: req.id, result: None, error: Some("enrollment_expired: call enroll_begin again".into()) };
+                            }
+                            let Some((selector, session)) = enrollment.as_mut() else {
+                                return Response { id: req.id, result: None, error: Some("No enrollment in progress".into()) };
+                            };
+                            // The camera is fixed by enroll_begin; each frame may wait for it
+                            let target = CameraTarget { selector: selector.clone(), wait: CameraTarget::from_params(req.params.as_ref()).wait };
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::EnrollFrame(target, guide.clone(), tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+
+                            match rx.await {
+                                Ok(Ok(observation)) => Response {
+                                    id: req.id,
+                                    result: Some(serde_json::json!(session.add(observation))),
+                                    error: None,
+                                },
+                                Ok(Err(e)) => camera_error(req.id, "Enrollment error", e),
+                                Err(_) => Response {
                                     id: req.id,
                                     result: None,
-                                    error: Some(format!("Verification error: {}", e)),
+                                    error: Some("Worker dropped response".into()),
+                                }
+                            }
+                        },
+                        "enroll_commit" => {
+                            let Some((selector, session)) = enrollment.take() else {
+                                return Response { id: req.id, result: None, error: Some("No enrollment in progress".into()) };
+                            };
+                            if session.expired() {
+                                return Response { id: req.id, result: None, error: Some("enrollment_expired: call enroll_begin again".into()) };
+                            }
+                            if !session.is_complete() {
+                                enrollment = Some((selector, session));
+                                return Response { id: req.id, result: None, error: Some("enrollment_incomplete: keep calling enroll_frame".into()) };
+                            }
+                            let new_templates = match session.into_templates() {
+                                Ok(templates) => templates,
+                                Err(e) => return Response { id: req.id, result: None, error: Some(format!("Enrollment error: {:#}", e)) },
+                            };
+                            let infos: Vec<_> = new_templates.iter().map(|t| t.info()).collect();
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::StoreTemplates(creds.uid(), new_templates, tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+
+                            match rx.await {
+                                Ok(Ok(())) => Response {
+                                    id: req.id,
+                                    result: Some(serde_json::json!({ "templates": infos })),
+                                    error: None,
                                 },
+                                Ok(Err(e)) => Response { id: req.id, result: None, error: Some(format!("Enrollment error: {:#}", e)) },
                                 Err(_) => Response {
                                     id: req.id,
                                     result: None,
@@ -386,16 +696,144 @@ async fn handle_client(stream: UnixStream, socket_path: String, worker_tx: mpsc:
                                 }
                             }
                         },
-                        "status" => Response {
+                        "enroll_cancel" => Response {
                             id: req.id,
-                            result: Some(serde_json::json!({
-                                "status": "running",
-                                "version": env!("CARGO_PKG_VERSION"),
-                                "backend": "stubbed",
-                                    "socket": socket_path
-                            })),
+                            result: Some(serde_json::json!({ "cancelled": enrollment.take().is_some() })),
                             error: None,
                         },
+                        "list_templates" => {
+                            let uid = match template_owner(&creds, req.params.as_ref()) {
+                                Ok(uid) => uid,
+                                Err(e) => return Response { id: req.id, result: None, error: Some(e) },
+                            };
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::ListTemplates(uid, tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+                            match rx.await {
+                                Ok(Ok(list)) => Response { id: req.id, result: Some(serde_json::json!(list)), error: None },
+                                Ok(Err(e)) => Response { id: req.id, result: None, error: Some(format!("{:#}", e)) },
+                                Err(_) => Response { id: req.id, result: None, error: Some("Worker dropped response".into()) },
+                            }
+                        },
+                        "rename_template" => {
+                            let uid = match template_owner(&creds, req.params.as_ref()) {
+                                Ok(uid) => uid,
+                                Err(e) => return Response { id: req.id, result: None, error: Some(e) },
+                            };
+                            let id = str_param(req.params.as_ref(), "id").unwrap_or_default().to_string();
+                            let label = str_param(req.params.as_ref(), "label").unwrap_or_default().to_string();
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::RenameTemplate(uid, id, label, tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+                            match rx.await {
+                                Ok(Ok(info)) => Response { id: req.id, result: Some(serde_json::json!(info)), error: None },
+                                Ok(Err(e)) => Response { id: req.id, result: None, error: Some(format!("{:#}", e)) },
+                                Err(_) => Response { id: req.id, result: None, error: Some("Worker dropped response".into()) },
+                            }
+                        },
+                        "delete_template" => {
+                            let uid = match template_owner(&creds, req.params.as_ref()) {
+                                Ok(uid) => uid,
+                                Err(e) => return Response { id: req.id, result: None, error: Some(e) },
+                            };
+                            let id = str_param(req.params.as_ref(), "id").unwrap_or_default().to_string();
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::DeleteTemplate(uid, id.clone(), tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+                            match rx.await {
+                                Ok(Ok(())) => Response { id: req.id, result: Some(serde_json::json!({ "deleted": id })), error: None },
+                                Ok(Err(e)) => Response { id: req.id, result: None, error: Some(format!("{:#}", e)) },
+                                Err(_) => Response { id: req.id, result: None, error: Some("Worker dropped response".into()) },
+                            }
+                        },
+                        "list_stale_users" => {
+                            if creds.uid() != 0 {
+                                return Response { id: req.id, result: None, error: Some("permission_denied: only root can list stale templates".into()) };
+                            }
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::StaleUsers(tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+                            match rx.await {
+                                Ok(Ok((model_id, users))) => Response {
+                                    id: req.id,
+                                    result: Some(serde_json::json!({ "model_id": model_id, "users": users })),
+                                    error: None,
+                                },
+                                Ok(Err(e)) => Response { id: req.id, result: None, error: Some(format!("{:#}", e)) },
+                                Err(_) => Response { id: req.id, result: None, error: Some("Worker dropped response".into()) },
+                            }
+                        },
+                        "migrate_templates" => {
+                            if creds.uid() != 0 {
+                                return Response { id: req.id, result: None, error: Some("permission_denied: only root can migrate templates".into()) };
+                            }
+                            let uid = match req.params.as_ref().and_then(|p| p.get("uid")) {
+                                None | Some(serde_json::Value::Null) => None,
+                                Some(v) => match v.as_u64().and_then(|u| u32::try_from(u).ok()) {
+                                    Some(uid) => Some(uid),
+                                    None => return Response { id: req.id, result: None, error: Some("Invalid uid".into()) },
+                                },
+                            };
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::MigrateTemplates(uid, tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+                            match rx.await {
+                                Ok(Ok(users)) => Response { id: req.id, result: Some(serde_json::json!({ "users": users })), error: None },
+                                Ok(Err(e)) => Response { id: req.id, result: None, error: Some(format!("{:#}", e)) },
+                                Err(_) => Response { id: req.id, result: None, error: Some("Worker dropped response".into()) },
+                            }
+                        },
+                        "reload_models" => {
+                            if creds.uid() != 0 {
+                                return Response { id: req.id, result: None, error: Some("permission_denied: only root can reload models".into()) };
+                            }
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::ReloadModels(tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+                            match rx.await {
+                                Ok(Ok(models)) => Response { id: req.id, result: Some(serde_json::json!({ "models": models })), error: None },
+                                Ok(Err(e)) => Response { id: req.id, result: None, error: Some(format!("{:#}", e)) },
+                                Err(_) => Response { id: req.id, result: None, error: Some("Worker dropped response".into()) },
+                            }
+                        },
+                        "status" => {
+                            let (tx, rx) = oneshot::channel();
+                            if let Err(e) = worker_tx.send(CameraRequest::Status(tx)).await {
+                                return Response { id: req.id, result: None, error: Some(format!("Worker died: {}", e)) };
+                            }
+
+                            match rx.await {
+                                Ok(worker_status) => Response {
+                                    id: req.id,
+                                    result: Some(serde_json::json!({
+                                        "status": "running",
+                                        "version": env!("CARGO_PKG_VERSION"),
+                                        "backend": worker_status.backend,
+                                        "auth_camera": worker_status.auth_camera,
+                                        "detector": worker_status.detector,
+                                        "embedder": worker_status.embedder,
+                                        "models": worker_status.models,
+                                        "liveness": worker_status.liveness,
+                                        "capture": worker_status.capture,
+                                        "sessions": worker_status.sessions,
+                                        "queued": worker_status.queued,
+                                        "socket": socket_path
+                                    })),
+                                    error: None,
+                                },
+                                Err(_) => Response {
+                                    id: req.id,
+                                    result: None,
+                                    error: Some("Worker dropped response".into()),
+                                }
+                            }
+                        },
                         _ => Response {
                             id: req.id,
                             result: None,
@@ -404,7 +842,25 @@ async fn handle_client(stream: UnixStream, socket_path: String, worker_tx: mpsc:
                     }
                 };
 
-                match timeout(timeout_duration, process).await {
+                let process = timeout(timeout_duration, process);
+                tokio::pin!(process);
+                let outcome = loop {
+                    tokio::select! {
+                        outcome = &mut process => break outcome,
+                        Some(issue) = guide_rx.recv() => {
+                            if let Err(e) = framed.send(serde_json::to_string(&guidance_note(request_id, issue))?).await {
+                                error!("Failed to send guidance: {}", e);
+                            }
+                        }
+                    }
+                };
+                // Guidance queued as the request finished still goes ahead of its response
+                while let Ok(issue) = guide_rx.try_recv() {
+                    if let Err(e) = framed.send(serde_json::to_string(&guidance_note(request_id, issue))?).await {
+                        error!("Failed to send guidance: {}", e);
+                    }
+                }
+                match outcome {
                     Ok(response) => {
                         let resp_str = serde_json::to_string(&response)?;
                         if let Err(e) = framed.send(resp_str).await {
@@ -0,0 +1,371 @@
+// src/matcher.rs
+//! Compares a probe embedding with a user's enrolled templates.
+//!
+//! Scores are cosine similarity (higher is closer) or Euclidean distance
+//! (lower is closer). Embeddings are unit length, so the two rank templates
+//! the same way and a cosine threshold `t` matches an L2 threshold of
+//! `sqrt(2 - 2t)`. The threshold comes from `/etc/ola/match.conf` (or
+//! `OLA_MATCH_CONFIG`), globally and optionally per user:
+//!
+//! ```text
+//! metric cosine
+//! threshold 0.45
+//! # stricter for uid 1000
+//! user 1000 0.55
+//! # identify: how far ahead of the runner-up the best user must be
+//! margin 0.05
+//! ```
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::templates::Template;
+
+pub const DEFAULT_CONFIG: &str = "/etc/ola/match.conf";
+
+/// Lead over the runner-up that `identify` needs when the config names none.
+pub const DEFAULT_IDENTIFY_MARGIN: f32 = 0.05;
+
+pub fn config_path() -> PathBuf {
+    std::env::var_os("OLA_MATCH_CONFIG").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    #[default]
+    Cosine,
+    L2,
+}
+
+impl Metric {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "cosine" => Ok(Metric::Cosine),
+            "l2" => Ok(Metric::L2),
+            _ => bail!("unknown metric '{}' (expected cosine or l2)", s),
+        }
+    }
+
+    /// Threshold used when the config names none.
+    pub fn default_threshold(self) -> f32 {
+        match self {
+            Metric::Cosine => 0.45,
+            // sqrt(2 - 2 * 0.45)
+            Metric::L2 => 1.05,
+        }
+    }
+
+    /// `None` when the embeddings have different lengths.
+    pub fn score(self, a: &[f32], b: &[f32]) -> Option<f32> {
+        if a.len() != b.len() || a.is_empty() {
+            return None;
+        }
+        let pairs = a.iter().zip(b);
+        Some(match self {
+            Metric::Cosine => {
+                let dot: f32 = pairs.map(|(x, y)| x * y).sum();
+                let norms = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|y| y * y).sum::<f32>().sqrt();
+                if norms == 0.0 { 0.0 } else { dot / norms }
+            }
+            Metric::L2 => pairs.map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt(),
+        })
+    }
+
+    /// Similarity has to reach the threshold; distance has to stay within it.
+    pub fn accepts(self, score: f32, threshold: f32) -> bool {
+        match self {
+            Metric::Cosine => score >= threshold,
+            Metric::L2 => score <= threshold,
+        }
+    }
+
+    /// How far `score` is on the accepting side of `threshold`; negative when it misses.
+    pub fn margin(self, score: f32, threshold: f32) -> f32 {
+        match self {
+            Metric::Cosine => score - threshold,
+            Metric::L2 => threshold - score,
+        }
+    }
+
+    /// Whether `a` is a closer match than `b`.
+    pub fn better(self, a: f32, b: f32) -> bool {
+        match self {
+            Metric::Cosine => a > b,
+            Metric::L2 => a < b,
+        }
+    }
+
+    /// Orders scores closest match first.
+    pub fn best_first(self, a: f32, b: f32) -> std::cmp::Ordering {
+        match self {
+            Metric::Cosine => b.total_cmp(&a),
+            Metric::L2 => a.total_cmp(&b),
+        }
+    }
+}
+
+/// The closest template to a probe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub template_id: String,
+    pub score: f32,
+}
+
+/// The template closest to `probe`. Templates of another dimension, left over
+/// from a different model, are skipped.
+pub fn best_match<'a>(metric: Metric, probe: &[f32], templates: impl IntoIterator<Item = &'a Template>) -> Option<Match> {
+    let mut best: Option<Match> = None;
+    for t in templates {
+        let Some(score) = metric.score(probe, &t.embedding) else {
+            log::debug!("Skipping template {}: {} values, probe has {}", t.id, t.embedding.len(), probe.len());
+            continue;
+        };
+        if best.as_ref().is_none_or(|b| metric.better(score, b.score)) {
+            best = Some(Match { template_id: t.id.clone(), score });
+        }
+    }
+    best
+}
+
+/// Frames that must have been scored before verification may stop early.
+pub const CONFIDENT_FRAMES: usize = 3;
+
+/// How far the mean score must clear, or miss, the threshold to stop early.
+pub const CONFIDENT_MARGIN: f32 = 0.1;
+
+/// Best matches of successive frames, combined by voting and averaging.
+///
+/// The attempt is accepted when most frames accept and so does their mean
+/// score. It is settled early, before the deadline, once `CONFIDENT_FRAMES`
+/// have been scored, the mean is at least `CONFIDENT_MARGIN` from the
+/// threshold and most frames agree with it; near the threshold every frame
+/// until the deadline gets a say.
+#[derive(Debug, Clone)]
+pub struct Fusion {
+    metric: Metric,
+    threshold: f32,
+    matches: Vec<Match>,
+}
+
+impl Fusion {
+    pub fn new(metric: Metric, threshold: f32) -> Self {
+        Self { metric, threshold, matches: Vec::new() }
+    }
+
+    pub fn add(&mut self, m: Match) {
+        self.matches.push(m);
+    }
+
+    pub fn frames(&self) -> usize {
+        self.matches.len()
+    }
+
+    pub fn scores(&self) -> Vec<f32> {
+        self.matches.iter().map(|m| m.score).collect()
+    }
+
+    pub fn mean(&self) -> Option<f32> {
+        (!self.matches.is_empty()).then(|| self.matches.iter().map(|m| m.score).sum::<f32>() / self.matches.len() as f32)
+    }
+
+    fn accept_votes(&self) -> usize {
+        self.matches.iter().filter(|m| self.metric.accepts(m.score, self.threshold)).count()
+    }
+
+    pub fn accepted(&self) -> bool {
+        self.mean().is_some_and(|mean| self.metric.accepts(mean, self.threshold)) && self.accept_votes() * 2 > self.frames()
+    }
+
+    /// Whether more frames are unlikely to change the outcome.
+    pub fn confident(&self) -> bool {
+        let Some(mean) = self.mean() else { return false };
+        let n = self.frames();
+        let margin = self.metric.margin(mean, self.threshold);
+        let agreeing = if margin > 0.0 { self.accept_votes() } else { n - self.accept_votes() };
+        n >= CONFIDENT_FRAMES && margin.abs() >= CONFIDENT_MARGIN && agreeing * 2 > n
+    }
+
+    /// The template that matched best in the most frames, the earliest on a tie.
+    pub fn template_id(&self) -> Option<&str> {
+        let mut best: Option<(&str, usize)> = None;
+        for m in &self.matches {
+            let wins = self.matches.iter().filter(|o| o.template_id == m.template_id).count();
+            if best.is_none_or(|(_, w)| wins > w) {
+                best = Some((&m.template_id, wins));
+            }
+        }
+        best.map(|(id, _)| id)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MatchConfig {
+    pub metric: Metric,
+    /// Global threshold; the metric's default when unset.
+    pub threshold: Option<f32>,
+    pub per_user: HashMap<u32, f32>,
+    /// Identification margin; `DEFAULT_IDENTIFY_MARGIN` when unset.
+    pub margin: Option<f32>,
+}
+
+impl MatchConfig {
+    /// Reads `path`; a missing file means defaults.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let threshold = |s: &str| -> anyhow::Result<f32> {
+                let t: f32 = s.parse().with_context(|| format!("line {}: invalid threshold '{}'", n + 1, s))?;
+                if !t.is_finite() || t < 0.0 {
+                    bail!("line {}: threshold must be a non-negative number", n + 1);
+                }
+                Ok(t)
+            };
+            match words[..] {
+                [] => {}
+                ["metric", m] => config.metric = Metric::parse(m).with_context(|| format!("line {}", n + 1))?,
+                ["threshold", t] => config.threshold = Some(threshold(t)?),
+                ["margin", m] => config.margin = Some(threshold(m)?),
+                ["user", uid, t] => {
+                    let uid = uid.parse().with_context(|| format!("line {}: invalid uid '{}'", n + 1, uid))?;
+                    config.per_user.insert(uid, threshold(t)?);
+                }
+                _ => bail!("line {}: expected 'metric', 'threshold', 'margin' or 'user': {}", n + 1, line),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The user's own threshold, else the global one, else the metric's default.
+    pub fn threshold_for(&self, uid: u32) -> f32 {
+        self.per_user
+            .get(&uid)
+            .copied()
+            .or(self.threshold)
+            .unwrap_or_else(|| self.metric.default_threshold())
+    }
+
+    pub fn identify_margin(&self) -> f32 {
+        self.margin.unwrap_or(DEFAULT_IDENTIFY_MARGIN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_models::{embeddings, Embeddings};
+
+    /// The first capture of each person enrolled as templates `<name>-0`.
+    fn enrolled(f: &Embeddings, name: &str) -> Vec<Template> {
+        vec![Template { id: format!("{}-0", name), embedding: f.identities[name][0].clone(), ..Default::default() }]
+    }
+
+    #[test]
+    fn genuine_captures_match_and_impostors_do_not() {
+        let f = embeddings();
+        for metric in [Metric::Cosine, Metric::L2] {
+            let threshold = metric.default_threshold();
+            for (name, captures) in &f.identities {
+                assert_eq!(captures[0].len(), f.dimension);
+                let own = enrolled(&f, name);
+                for probe in &captures[1..] {
+                    let m = best_match(metric, probe, &own).unwrap();
+                    assert!(metric.accepts(m.score, threshold), "{:?} {}: {:?}", metric, name, m);
+                }
+                for (other, probes) in &f.identities {
+                    if other == name {
+                        continue;
+                    }
+                    for probe in probes {
+                        let m = best_match(metric, probe, &own).unwrap();
+                        assert!(!metric.accepts(m.score, threshold), "{:?} {} as {}: {:?}", metric, other, name, m);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn best_template_wins() {
+        let f = embeddings();
+        let all: Vec<Template> = f.identities.keys().flat_map(|name| enrolled(&f, name)).collect();
+        for metric in [Metric::Cosine, Metric::L2] {
+            let m = best_match(metric, &f.identities["chen"][2], &all).unwrap();
+            assert_eq!(m.template_id, "chen-0");
+        }
+
+        // A template from another model is ignored, not compared
+        let short = Template { id: "old".into(), embedding: vec![1.0; 64], ..Default::default() };
+        assert_eq!(best_match(Metric::Cosine, &f.identities["ada"][1], std::slice::from_ref(&short)), None);
+        let mixed = [short, enrolled(&f, "ada").remove(0)];
+        assert_eq!(best_match(Metric::Cosine, &f.identities["ada"][1], &mixed).unwrap().template_id, "ada-0");
+    }
+
+    #[test]
+    fn fusion_settles_early_only_when_frames_agree() {
+        let f = embeddings();
+        let own = enrolled(&f, "bo");
+        let fuse = |probes: &[Vec<f32>]| {
+            let mut fusion = Fusion::new(Metric::Cosine, Metric::Cosine.default_threshold());
+            let mut settled_after = None;
+            for probe in probes {
+                fusion.add(best_match(Metric::Cosine, probe, &own).unwrap());
+                if settled_after.is_none() && fusion.confident() {
+                    settled_after = Some(fusion.frames());
+                }
+            }
+            (fusion, settled_after)
+        };
+
+        let (genuine, settled) = fuse(&f.identities["bo"][1..]);
+        assert!(genuine.accepted());
+        assert_eq!(settled, Some(CONFIDENT_FRAMES));
+        assert_eq!(genuine.template_id(), Some("bo-0"));
+        assert_eq!(genuine.scores().len(), 3);
+
+        let (impostor, settled) = fuse(&f.identities["dara"]);
+        assert!(!impostor.accepted());
+        assert_eq!(settled, Some(CONFIDENT_FRAMES));
+
+        // One lucky frame among impostor frames is outvoted, and a mixed bag
+        // close to the threshold never counts as confident
+        let mut fusion = Fusion::new(Metric::Cosine, 0.5);
+        for (id, score) in [("a", 0.9), ("b", 0.3), ("b", 0.35)] {
+            fusion.add(Match { template_id: id.into(), score });
+        }
+        assert!(!fusion.accepted());
+        assert!(!fusion.confident());
+        assert_eq!(fusion.template_id(), Some("b"));
+        assert!(!Fusion::new(Metric::L2, 1.0).accepted());
+    }
+
+    #[test]
+    fn config_sets_metric_and_thresholds() {
+        let config = MatchConfig::parse("# comment\nmetric l2\n\nthreshold 0.9\nuser 1000 0.8  # stricter\nmargin 0.1\n").unwrap();
+        assert_eq!(config.metric, Metric::L2);
+        assert_eq!(config.identify_margin(), 0.1);
+        assert_eq!(config.threshold_for(1000), 0.8);
+        assert_eq!(config.threshold_for(1001), 0.9);
+
+        let config = MatchConfig::parse("user 1000 0.6\n").unwrap();
+        assert_eq!(config.threshold_for(1000), 0.6);
+        assert_eq!(config.threshold_for(0), Metric::Cosine.default_threshold());
+        assert_eq!(config.identify_margin(), DEFAULT_IDENTIFY_MARGIN);
+
+        assert!(MatchConfig::parse("metric hamming\n").is_err());
+        assert!(MatchConfig::parse("threshold -1\n").is_err());
+        assert!(MatchConfig::parse("user alice 0.5\n").is_err());
+        assert_eq!(MatchConfig::load(Path::new("/nonexistent/match.conf")).unwrap(), MatchConfig::default());
+    }
+}
@@ -0,0 +1,448 @@
+// src/models.rs
+//! Model registry: which inference models the daemon runs, and proof that
+//! they are the files the administrator installed.
+//!
+//! A swapped model file is an authentication bypass, so models are listed in
+//! `/etc/ola/models/manifest` (or `OLA_MODEL_MANIFEST`) with their SHA-256
+//! and checked against it every time they are loaded:
+//!
+//! ```text
+//! # role      file               sha256            version  [signature]
+//! detector    blazeface.onnx     3f5c...e1         1.2
+//! embedder    arcface-r100.onnx  9a0b...44         2024.03  Zm9v...
+//! ```
+//!
+//! Files are relative to the manifest's directory. The optional signature is
+//! a base64 ed25519 signature over the model file, as made by
+//! `openssl pkeyutl -sign -rawin`. Once `/etc/ola/model_keys` (or
+//! `OLA_MODEL_KEYS`) lists any trusted public keys, one base64 32-byte key
+//! per line, every listed model must carry a signature from one of them and
+//! the manifest itself becomes mandatory.
+//!
+//! Without a manifest the daemon falls back to `OLA_DETECTOR_MODEL` and
+//! `OLA_EMBEDDER_MODEL`, unverified, and says so in the log and `status`.
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::detector;
+use super::embedder;
+
+pub const DEFAULT_MANIFEST: &str = "/etc/ola/models/manifest";
+
+pub const DEFAULT_KEYS: &str = "/etc/ola/model_keys";
+
+pub fn manifest_path() -> PathBuf {
+    std::env::var_os("OLA_MODEL_MANIFEST").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_MANIFEST))
+}
+
+pub fn keys_path() -> PathBuf {
+    std::env::var_os("OLA_MODEL_KEYS").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_KEYS))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Detector,
+    Embedder,
+}
+
+impl Role {
+    pub const ALL: [Role; 2] = [Role::Detector, Role::Embedder];
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "detector" => Some(Role::Detector),
+            "embedder" => Some(Role::Embedder),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Detector => "detector",
+            Role::Embedder => "embedder",
+        }
+    }
+}
+
+/// One manifest line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub role: Role,
+    pub path: PathBuf,
+    pub sha256: [u8; 32],
+    pub version: String,
+    pub signature: Option<Signature>,
+}
+
+impl Entry {
+    /// Reads the model file and checks it against this entry. Errors start
+    /// with `model_integrity:` when the file is not the one listed.
+    pub fn read(&self, keys: &TrustedKeys) -> anyhow::Result<Model> {
+        let bytes = fs::read(&self.path).with_context(|| format!("reading {}", self.path.display()))?;
+        let sha256: [u8; 32] = Sha256::digest(&bytes).into();
+        if sha256 != self.sha256 {
+            bail!("model_integrity: {} has SHA-256 {}, the manifest lists {}", self.path.display(), hex(&sha256), hex(&self.sha256));
+        }
+        let signed = match &self.signature {
+            _ if keys.is_empty() => false,
+            Some(signature) if keys.verify(&bytes, signature) => true,
+            Some(_) => bail!("model_integrity: {} is not signed by a trusted key", self.path.display()),
+            None => bail!("model_integrity: {} is unsigned but trusted keys are configured", self.path.display()),
+        };
+        let info = ModelInfo {
+            role: self.role,
+            path: self.path.clone(),
+            version: Some(self.version.clone()),
+            sha256: hex(&sha256),
+            verified: true,
+            signed,
+        };
+        Ok(Model { bytes, info })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Reads `path`; `None` when there is no manifest.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                let dir = path.parent().unwrap_or(Path::new("/"));
+                Self::parse(&text, dir).with_context(|| format!("parsing {}", path.display())).map(Some)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// Parses manifest lines, resolving relative files against `dir`.
+    pub fn parse(text: &str, dir: &Path) -> anyhow::Result<Self> {
+        let mut entries: Vec<Entry> = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (role, file, sha256, version, signature) = match words.as_slice() {
+                [] => continue,
+                [role, file, sha256, version] => (role, file, sha256, version, None),
+                [role, file, sha256, version, signature] => (role, file, sha256, version, Some(signature)),
+                _ => bail!("line {}: expected `<role> <file> <sha256> <version> [signature]`", n + 1),
+            };
+            let Some(role) = Role::parse(role) else {
+                bail!("line {}: unknown model role {}", n + 1, role);
+            };
+            if entries.iter().any(|e| e.role == role) {
+                bail!("line {}: {} listed twice", n + 1, role.name());
+            }
+            let Some(sha256) = parse_sha256(sha256) else {
+                bail!("line {}: SHA-256 must be 64 hex digits", n + 1);
+            };
+            let signature = match signature {
+                Some(s) => match STANDARD.decode(s).ok().and_then(|b| Signature::from_slice(&b).ok()) {
+                    Some(signature) => Some(signature),
+                    None => bail!("line {}: signature must be a base64 ed25519 signature", n + 1),
+                },
+                None => None,
+            };
+            entries.push(Entry { role, path: dir.join(file), sha256, version: version.to_string(), signature });
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn entry(&self, role: Role) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.role == role)
+    }
+}
+
+/// Public keys model signatures are checked against.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys(Vec<VerifyingKey>);
+
+impl TrustedKeys {
+    /// Reads `path`; a missing file means no keys, and signatures go unchecked.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut keys = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let bytes: Option<[u8; 32]> = STANDARD.decode(line).ok().and_then(|b| b.try_into().ok());
+            match bytes.and_then(|b| VerifyingKey::from_bytes(&b).ok()) {
+                Some(key) => keys.push(key),
+                None => bail!("line {}: expected a base64 ed25519 public key", n + 1),
+            }
+        }
+        Ok(Self(keys))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        self.0.iter().any(|key| key.verify_strict(message, signature).is_ok())
+    }
+}
+
+/// What `status` reports about a loaded model.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelInfo {
+    pub role: Role,
+    pub path: PathBuf,
+    /// From the manifest; `None` for a model loaded without one.
+    pub version: Option<String>,
+    pub sha256: String,
+    /// Checked against a manifest SHA-256.
+    pub verified: bool,
+    /// Checked against a trusted signature too.
+    pub signed: bool,
+}
+
+/// A model file's contents, checked as far as the configuration asks.
+pub struct Model {
+    pub bytes: Vec<u8>,
+    pub info: ModelInfo,
+}
+
+impl Model {
+    /// File stem, used as the model's name in logs and `status`.
+    pub fn name(&self) -> String {
+        self.info.path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+    }
+}
+
+/// Finds the model for each role, through the manifest when there is one.
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    manifest: PathBuf,
+    keys: PathBuf,
+    detector: PathBuf,
+    embedder: PathBuf,
+}
+
+impl ModelRegistry {
+    /// `detector` and `embedder` are the unverified fallbacks for when there is
+    /// no manifest.
+    pub fn new(manifest: &Path, keys: &Path, detector: &Path, embedder: &Path) -> Self {
+        Self { manifest: manifest.into(), keys: keys.into(), detector: detector.into(), embedder: embedder.into() }
+    }
+
+    /// Paths from `OLA_MODEL_MANIFEST`, `OLA_MODEL_KEYS` and the model path
+    /// variables, or their defaults.
+    pub fn from_env() -> Self {
+        Self::new(&manifest_path(), &keys_path(), &detector::model_path(), &embedder::model_path())
+    }
+
+    /// Where the model for `role` comes from, for error messages.
+    pub fn describe(&self, role: Role) -> String {
+        if self.manifest.exists() {
+            format!("{} in {}", role.name(), self.manifest.display())
+        } else {
+            self.fallback(role).display().to_string()
+        }
+    }
+
+    /// Reads and checks the model for `role`; `None` when the manifest does
+    /// not list one.
+    pub fn read(&self, role: Role) -> anyhow::Result<Option<Model>> {
+        match self.source()? {
+            Some((manifest, keys)) => manifest.entry(role).map(|e| e.read(&keys)).transpose(),
+            None => self.read_unverified(role).map(Some),
+        }
+    }
+
+    /// Reads every model from one reading of the manifest, failing if any
+    /// listed model does. Without a manifest, fallback files that do not
+    /// exist are left out.
+    pub fn read_all(&self) -> anyhow::Result<Vec<Model>> {
+        let mut models = Vec::new();
+        match self.source()? {
+            Some((manifest, keys)) => {
+                for entry in &manifest.entries {
+                    models.push(entry.read(&keys)?);
+                }
+            }
+            None => {
+                for role in Role::ALL {
+                    if self.fallback(role).exists() {
+                        models.push(self.read_unverified(role)?);
+                    }
+                }
+            }
+        }
+        Ok(models)
+    }
+
+    fn source(&self) -> anyhow::Result<Option<(Manifest, TrustedKeys)>> {
+        let keys = TrustedKeys::load(&self.keys)?;
+        match Manifest::load(&self.manifest)? {
+            Some(manifest) => Ok(Some((manifest, keys))),
+            None if !keys.is_empty() => {
+                bail!("model_integrity: trusted model keys are configured but {} is missing", self.manifest.display())
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn fallback(&self, role: Role) -> &Path {
+        match role {
+            Role::Detector => &self.detector,
+            Role::Embedder => &self.embedder,
+        }
+    }
+
+    fn read_unverified(&self, role: Role) -> anyhow::Result<Model> {
+        let path = self.fallback(role);
+        let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        log::warn!("No model manifest at {}; loading {} unverified", self.manifest.display(), path.display());
+        let info = ModelInfo {
+            role,
+            path: path.to_path_buf(),
+            version: None,
+            sha256: hex(&Sha256::digest(&bytes)),
+            verified: false,
+            signed: false,
+        };
+        Ok(Model { bytes, info })
+    }
+}
+
+fn parse_sha256(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn install(dir: &Path, name: &str, bytes: &[u8]) -> String {
+        fs::write(dir.join(name), bytes).unwrap();
+        hex(&Sha256::digest(bytes))
+    }
+
+    fn registry(dir: &Path) -> ModelRegistry {
+        ModelRegistry::new(&dir.join("manifest"), &dir.join("keys"), &dir.join("det.onnx"), &dir.join("emb.onnx"))
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ola-models-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_manifest_lines() {
+        let sha = "ab".repeat(32);
+        let manifest = Manifest::parse(&format!("# models\ndetector det.onnx {} 1.2\n\nembedder /opt/emb.onnx {} 7 # current\n", sha, sha), Path::new("/etc/ola/models")).unwrap();
+        assert_eq!(manifest.entries.len(), 2);
+        let detector = manifest.entry(Role::Detector).unwrap();
+        assert_eq!(detector.path, Path::new("/etc/ola/models/det.onnx"));
+        assert_eq!(detector.sha256, [0xab; 32]);
+        assert_eq!(detector.version, "1.2");
+        assert_eq!(manifest.entry(Role::Embedder).unwrap().path, Path::new("/opt/emb.onnx"));
+
+        assert!(Manifest::parse(&format!("liveness l.onnx {} 1", sha), Path::new("/")).is_err());
+        assert!(Manifest::parse("detector det.onnx abcd 1", Path::new("/")).is_err());
+        assert!(Manifest::parse(&format!("detector det.onnx {} 1 !!!", sha), Path::new("/")).is_err());
+        assert!(Manifest::parse(&format!("detector a {} 1\ndetector b {} 2", sha, sha), Path::new("/")).is_err());
+        assert!(Manifest::parse("detector det.onnx", Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn models_must_match_their_digest() {
+        let dir = temp_dir("digest");
+        let sha = install(&dir, "det.onnx", b"detector weights");
+        fs::write(dir.join("manifest"), format!("detector det.onnx {} 1.0\n", sha)).unwrap();
+        let models = registry(&dir);
+
+        let model = models.read(Role::Detector).unwrap().unwrap();
+        assert_eq!(model.bytes, b"detector weights");
+        assert_eq!(model.info.version.as_deref(), Some("1.0"));
+        assert!(model.info.verified && !model.info.signed);
+        // Listed nowhere, so there is no embedder even though a fallback path is set
+        assert!(models.read(Role::Embedder).unwrap().is_none());
+
+        fs::write(dir.join("det.onnx"), b"tampered weights").unwrap();
+        let err = models.read(Role::Detector).err().unwrap();
+        assert!(format!("{:#}", err).starts_with("model_integrity:"));
+        assert!(models.read_all().is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn trusted_keys_require_signatures() {
+        let dir = temp_dir("signed");
+        let trusted = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[9; 32]);
+        fs::write(dir.join("keys"), format!("# release key\n{}\n", STANDARD.encode(trusted.verifying_key().to_bytes()))).unwrap();
+        let sha = install(&dir, "det.onnx", b"detector weights");
+        let manifest = |signer: Option<&SigningKey>| {
+            let signature = signer.map(|k| STANDARD.encode(k.sign(b"detector weights").to_bytes())).unwrap_or_default();
+            fs::write(dir.join("manifest"), format!("detector det.onnx {} 1.0 {}\n", sha, signature)).unwrap();
+        };
+        let models = registry(&dir);
+
+        manifest(Some(&trusted));
+        let model = models.read(Role::Detector).unwrap().unwrap();
+        assert!(model.info.verified && model.info.signed);
+
+        manifest(Some(&other));
+        assert!(format!("{:#}", models.read(Role::Detector).err().unwrap()).contains("not signed by a trusted key"));
+        manifest(None);
+        assert!(format!("{:#}", models.read(Role::Detector).err().unwrap()).contains("unsigned"));
+
+        // Keys without a manifest refuse the unverified fallback
+        fs::remove_file(dir.join("manifest")).unwrap();
+        install(&dir, "det.onnx", b"anything");
+        assert!(models.read(Role::Detector).is_err());
+        assert!(TrustedKeys::parse("not a key").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn falls_back_to_unverified_paths_without_a_manifest() {
+        let dir = temp_dir("fallback");
+        install(&dir, "emb.onnx", b"embedder weights");
+        let models = registry(&dir);
+
+        let all = models.read_all().unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].info.role, Role::Embedder);
+        assert_eq!(all[0].name(), "emb");
+        assert!(!all[0].info.verified && all[0].info.version.is_none());
+        assert!(models.read(Role::Detector).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
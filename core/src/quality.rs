@@ -0,0 +1,444 @@
+// src/quality.rs
+//! Frame quality: is a face good enough to match at all?
+//!
+//! A dark, blurred or turned-away face scores low against everyone's
+//! templates, so matching it makes a bad frame look like the wrong person.
+//! Each face is measured before it is embedded: brightness and contrast of
+//! the face, sharpness (variance of the Laplacian), size in the frame, head
+//! pose from the landmarks, and how much of the eyes and mouth is covered.
+//! Frames failing any check are left out of matching, and the user is told
+//! what to change. Enrollment applies the same checks. Limits come from
+//! `/etc/ola/quality.conf` (or `OLA_QUALITY_CONFIG`):
+//!
+//! ```text
+//! # mean face luma, 0-255
+//! brightness 40 220
+//! contrast 12
+//! sharpness 15
+//! # face height as a fraction of the frame
+//! face_size 0.2 0.9
+//! # degrees
+//! yaw 40
+//! pitch 30
+//! # share of the eyes and mouth that may be covered
+//! occlusion 0.25
+//! disable occlusion
+//! ```
+use anyhow::{bail, Context};
+use image::GrayImage;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use super::detector::{BoundingBox, Detection, Point};
+use super::liveness::{region, stats};
+
+pub const DEFAULT_CONFIG: &str = "/etc/ola/quality.conf";
+
+/// Nose tip's depth in front of the eyes, in eye distances, for turning
+/// landmark offsets into rough angles.
+const NOSE_DEPTH: f32 = 0.5;
+
+/// Where the nose tip sits between the eyes and the mouth, facing the camera.
+const FRONTAL_NOSE: f32 = 0.6;
+
+/// Side of the patch around an eye or the mouth, as a fraction of the face width.
+const FEATURE_PATCH: f32 = 0.2;
+
+/// A feature patch with less texture than this share of the face's is covered.
+const COVERED_TEXTURE: f32 = 0.25;
+
+pub fn config_path() -> PathBuf {
+    std::env::var_os("OLA_QUALITY_CONFIG").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG))
+}
+
+/// What is wrong with a face, in the order the checks run: a face that is
+/// too small or badly lit fails the later checks too, so its first issue is
+/// the one worth fixing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Issue {
+    TooFar,
+    TooClose,
+    TooDark,
+    TooBright,
+    LowContrast,
+    Blurry,
+    TurnedAway,
+    Occluded,
+}
+
+impl Issue {
+    const ALL: [Issue; 8] = [
+        Issue::TooFar,
+        Issue::TooClose,
+        Issue::TooDark,
+        Issue::TooBright,
+        Issue::LowContrast,
+        Issue::Blurry,
+        Issue::TurnedAway,
+        Issue::Occluded,
+    ];
+
+    /// Used as the `reason` of results that fail on it.
+    pub fn code(self) -> &'static str {
+        match self {
+            Issue::TooFar => "too_far",
+            Issue::TooClose => "too_close",
+            Issue::TooDark => "too_dark",
+            Issue::TooBright => "too_bright",
+            Issue::LowContrast => "low_contrast",
+            Issue::Blurry => "blurry",
+            Issue::TurnedAway => "turned_away",
+            Issue::Occluded => "occluded",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|i| i.code() == code)
+    }
+
+    /// What the user should do about it.
+    pub fn guidance(self) -> &'static str {
+        match self {
+            Issue::TooFar => "move closer to the camera",
+            Issue::TooClose => "move back from the camera",
+            Issue::TooDark => "too dark, turn on a light",
+            Issue::TooBright => "too bright, move out of direct light",
+            Issue::LowContrast => "avoid bright light behind you",
+            Issue::Blurry => "hold still",
+            Issue::TurnedAway => "face the camera",
+            Issue::Occluded => "uncover your face",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityConfig {
+    /// Mean face luma.
+    pub min_brightness: f32,
+    pub max_brightness: f32,
+    /// Standard deviation of the face luma.
+    pub min_contrast: f32,
+    /// Variance of the Laplacian over the face.
+    pub min_sharpness: f32,
+    /// Face height over frame height.
+    pub min_face: f32,
+    pub max_face: f32,
+    /// Head turn and tilt, in degrees.
+    pub max_yaw: f32,
+    pub max_pitch: f32,
+    /// Share of the eyes and mouth covered.
+    pub max_occlusion: f32,
+    pub disabled: Vec<String>,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            min_brightness: 40.0,
+            max_brightness: 220.0,
+            min_contrast: 12.0,
+            min_sharpness: 15.0,
+            min_face: 0.2,
+            max_face: 0.9,
+            max_yaw: 40.0,
+            max_pitch: 30.0,
+            max_occlusion: 0.25,
+            disabled: Vec::new(),
+        }
+    }
+}
+
+/// Names the config file may disable, `all` aside.
+const CHECKS: [&str; 6] = ["brightness", "contrast", "sharpness", "face_size", "pose", "occlusion"];
+
+/// One face's measurements, and the checks it failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Assessment {
+    /// Mean luma of the face, 0-255.
+    pub brightness: f32,
+    /// Standard deviation of the face luma.
+    pub contrast: f32,
+    pub sharpness: f32,
+    /// Face height over frame height.
+    pub face_size: f32,
+    /// Rough head turn (positive to the user's left) and tilt (positive
+    /// down) in degrees; `None` without the landmarks to tell.
+    pub yaw: Option<f32>,
+    pub pitch: Option<f32>,
+    /// Share of the eyes and mouth that look covered.
+    pub occlusion: Option<f32>,
+    /// 0-1, from detection confidence, face size and sharpness.
+    pub score: f32,
+    pub issues: Vec<Issue>,
+}
+
+impl Assessment {
+    pub fn passed(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl QualityConfig {
+    /// Reads `path`; a missing file means defaults.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |s: &str, max: f32| -> anyhow::Result<f32> {
+                match s.parse::<f32>() {
+                    Ok(v) if (0.0..=max).contains(&v) => Ok(v),
+                    _ => bail!("line {}: {} must be a number between 0 and {}", n + 1, words[0], max),
+                }
+            };
+            match words[..] {
+                [] => {}
+                ["brightness", min, max] => (config.min_brightness, config.max_brightness) = (number(min, 255.0)?, number(max, 255.0)?),
+                ["contrast", min] => config.min_contrast = number(min, 255.0)?,
+                ["sharpness", min] => config.min_sharpness = number(min, f32::MAX)?,
+                ["face_size", min, max] => (config.min_face, config.max_face) = (number(min, 1.0)?, number(max, 1.0)?),
+                ["yaw", max] => config.max_yaw = number(max, 90.0)?,
+                ["pitch", max] => config.max_pitch = number(max, 90.0)?,
+                ["occlusion", max] => config.max_occlusion = number(max, 1.0)?,
+                ["disable", "all"] => config.disabled = CHECKS.iter().map(|c| c.to_string()).collect(),
+                ["disable", check] if CHECKS.contains(&check) => config.disabled.push(check.to_string()),
+                ["disable", check] => bail!("line {}: unknown check '{}' (expected {} or all)", n + 1, check, CHECKS.join(", ")),
+                _ => bail!("line {}: expected a quality limit or 'disable': {}", n + 1, line),
+            }
+            if config.min_brightness > config.max_brightness || config.min_face > config.max_face {
+                bail!("line {}: the minimum is above the maximum", n + 1);
+            }
+        }
+        Ok(config)
+    }
+
+    fn enabled(&self, check: &str) -> bool {
+        !self.disabled.iter().any(|d| d == check)
+    }
+
+    /// Measures `face` in `luma`, the grayscale of the frame it was found in.
+    pub fn assess(&self, luma: &GrayImage, face: &Detection) -> Assessment {
+        let (brightness, contrast) = region(luma, &face.bbox, 1.0, 1.0).map(|r| stats(luma, r)).unwrap_or((0.0, 0.0));
+        let sharpness = sharpness(luma, &face.bbox);
+        let face_size = face.bbox.height / luma.height().max(1) as f32;
+        let (yaw, pitch) = pose(face);
+        let occlusion = occlusion(luma, face, contrast);
+
+        let mut issues = Vec::new();
+        if self.enabled("face_size") {
+            if face_size < self.min_face {
+                issues.push(Issue::TooFar);
+            } else if face_size > self.max_face {
+                issues.push(Issue::TooClose);
+            }
+        }
+        if self.enabled("brightness") {
+            if brightness < self.min_brightness {
+                issues.push(Issue::TooDark);
+            } else if brightness > self.max_brightness {
+                issues.push(Issue::TooBright);
+            }
+        }
+        if self.enabled("contrast") && contrast < self.min_contrast {
+            issues.push(Issue::LowContrast);
+        }
+        if self.enabled("sharpness") && sharpness < self.min_sharpness {
+            issues.push(Issue::Blurry);
+        }
+        let turned = yaw.is_some_and(|y| y.abs() > self.max_yaw) || pitch.is_some_and(|p| p.abs() > self.max_pitch);
+        if self.enabled("pose") && turned {
+            issues.push(Issue::TurnedAway);
+        }
+        if self.enabled("occlusion") && occlusion.is_some_and(|o| o > self.max_occlusion) {
+            issues.push(Issue::Occluded);
+        }
+
+        let score = face
+            .confidence
+            .min(face_size / (2.0 * self.min_face))
+            .min(sharpness / (4.0 * self.min_sharpness))
+            .clamp(0.0, 1.0);
+        Assessment { brightness, contrast, sharpness, face_size, yaw, pitch, occlusion, score, issues }
+    }
+}
+
+/// Variance of the 4-neighbour Laplacian inside `bbox`. Blurred or badly
+/// exposed faces have little fine detail and score low.
+pub fn sharpness(luma: &GrayImage, bbox: &BoundingBox) -> f32 {
+    let Some((x0, y0, x1, y1)) = region(luma, bbox, 1.0, 1.0) else { return 0.0 };
+    if x1 < x0 + 3 || y1 < y0 + 3 {
+        return 0.0;
+    }
+    let at = |x: u32, y: u32| luma.get_pixel(x, y)[0] as f32;
+    let mut values = Vec::new();
+    for y in y0 + 1..y1 - 1 {
+        for x in x0 + 1..x1 - 1 {
+            values.push(at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y));
+        }
+    }
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / values.len() as f32
+}
+
+/// Yaw from the nose's offset along the eye line, pitch from its height
+/// between the eyes and the mouth, both measured square to the eye line so
+/// a tilted head is not taken for a turned one.
+fn pose(face: &Detection) -> (Option<f32>, Option<f32>) {
+    let [a, b, nose, rest @ ..] = &face.landmarks[..] else { return (None, None) };
+    let eye_distance = (b.x - a.x).hypot(b.y - a.y);
+    if eye_distance <= f32::EPSILON {
+        return (None, None);
+    }
+    let across = Point { x: (b.x - a.x) / eye_distance, y: (b.y - a.y) / eye_distance };
+    let down = Point { x: -across.y, y: across.x };
+    let middle = Point { x: (a.x + b.x) / 2.0, y: (a.y + b.y) / 2.0 };
+    let along = |p: &Point, axis: &Point| (p.x - middle.x) * axis.x + (p.y - middle.y) * axis.y;
+    let angle = |offset: f32| (offset / NOSE_DEPTH).clamp(-1.0, 1.0).asin().to_degrees();
+
+    let yaw = angle(along(nose, &across) / eye_distance);
+    let pitch = rest.first().map(|mouth| (along(nose, &down), along(mouth, &down))).and_then(|(nose, mouth)| {
+        (mouth > f32::EPSILON).then(|| angle((nose - FRONTAL_NOSE * mouth) / eye_distance))
+    });
+    (Some(yaw), pitch)
+}
+
+/// Share of the eyes and mouth that are outside the face box or show almost
+/// none of the face's texture, as under sunglasses, a mask or a hand.
+fn occlusion(luma: &GrayImage, face: &Detection, contrast: f32) -> Option<f32> {
+    let features: Vec<&Point> = match &face.landmarks[..] {
+        [a, b, _, mouth, ..] => vec![a, b, mouth],
+        _ => return None,
+    };
+    let side = face.bbox.width * FEATURE_PATCH;
+    let bbox = &face.bbox;
+    let covered = features
+        .iter()
+        .filter(|p| {
+            let inside = p.x >= bbox.x && p.x <= bbox.x + bbox.width && p.y >= bbox.y && p.y <= bbox.y + bbox.height;
+            let patch = BoundingBox { x: p.x - side / 2.0, y: p.y - side / 2.0, width: side, height: side };
+            let texture = region(luma, &patch, 1.0, 1.0).map(|r| stats(luma, r).1).unwrap_or(0.0);
+            !inside || texture < COVERED_TEXTURE * contrast
+        })
+        .count();
+    Some(covered as f32 / features.len() as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    /// A 200px face in a 480px frame, with eyes, nose and mouth where a
+    /// frontal face has them, or the nose moved by `yaw` eye distances.
+    fn face(yaw: f32) -> Detection {
+        Detection {
+            bbox: BoundingBox { x: 200.0, y: 100.0, width: 200.0, height: 200.0 },
+            landmarks: vec![
+                Point { x: 250.0, y: 160.0 },
+                Point { x: 350.0, y: 160.0 },
+                Point { x: 300.0 + yaw * 100.0, y: 220.0 },
+                Point { x: 300.0, y: 260.0 },
+            ],
+            confidence: 0.95,
+        }
+    }
+
+    /// Mid-grey checks of the given amplitude, lit to `level`.
+    fn frame(level: u8, amplitude: u8) -> GrayImage {
+        GrayImage::from_fn(640, 480, |x, y| {
+            let up = (x / 2 + y / 2) % 2 == 0;
+            Luma([if up { level.saturating_add(amplitude) } else { level.saturating_sub(amplitude) }])
+        })
+    }
+
+    #[test]
+    fn good_faces_pass() {
+        let config = QualityConfig::default();
+        let q = config.assess(&frame(120, 40), &face(0.0));
+        assert!(q.passed(), "{:?}", q);
+        assert!((q.face_size - 200.0 / 480.0).abs() < 1e-6);
+        assert!(q.yaw.unwrap().abs() < 1.0 && q.pitch.unwrap().abs() < 1.0, "{:?}", q);
+        assert_eq!(q.occlusion, Some(0.0));
+        assert!(q.score > 0.9);
+    }
+
+    #[test]
+    fn each_check_names_its_issue() {
+        let config = QualityConfig::default();
+        let issues = |luma: &GrayImage, face: &Detection| config.assess(luma, face).issues;
+        assert_eq!(issues(&frame(15, 8), &face(0.0)), vec![Issue::TooDark, Issue::LowContrast]);
+        assert_eq!(issues(&frame(240, 10), &face(0.0)), vec![Issue::TooBright, Issue::LowContrast]);
+        assert_eq!(issues(&GrayImage::from_pixel(640, 480, Luma([128])), &face(0.0)), vec![Issue::LowContrast, Issue::Blurry]);
+        assert_eq!(issues(&frame(120, 40), &face(0.4)), vec![Issue::TurnedAway]);
+
+        let mut small = face(0.0);
+        small.bbox.height = 60.0;
+        assert_eq!(issues(&frame(120, 40), &small)[0], Issue::TooFar);
+
+        // Sunglasses: both eyes flat
+        let mut shaded = frame(120, 40);
+        for (x, y, p) in shaded.enumerate_pixels_mut() {
+            if (130..=190).contains(&y) && (210..=390).contains(&x) {
+                *p = Luma([30]);
+            }
+        }
+        let q = config.assess(&shaded, &face(0.0));
+        assert!((q.occlusion.unwrap() - 2.0 / 3.0).abs() < 1e-6);
+        assert_eq!(q.issues, vec![Issue::Occluded]);
+        let lenient = QualityConfig::parse("disable occlusion").unwrap();
+        assert!(lenient.assess(&shaded, &face(0.0)).passed());
+
+        // Without landmarks pose and occlusion are not judged
+        let bare = Detection { landmarks: vec![], ..face(0.4) };
+        let q = config.assess(&frame(120, 40), &bare);
+        assert!(q.passed() && q.yaw.is_none() && q.occlusion.is_none());
+    }
+
+    #[test]
+    fn pose_ignores_head_tilt() {
+        // The frontal face rolled 30 degrees about the eye midpoint
+        let (sin, cos) = 30f32.to_radians().sin_cos();
+        let mut tilted = face(0.0);
+        for p in &mut tilted.landmarks {
+            let (x, y) = (p.x - 300.0, p.y - 160.0);
+            (p.x, p.y) = (300.0 + x * cos - y * sin, 160.0 + x * sin + y * cos);
+        }
+        let (yaw, pitch) = pose(&tilted);
+        assert!(yaw.unwrap().abs() < 1e-3 && pitch.unwrap().abs() < 1e-3, "{:?}", (yaw, pitch));
+        let (yaw, _) = pose(&face(0.2));
+        assert!((yaw.unwrap() - 23.58).abs() < 0.1, "{:?}", yaw);
+    }
+
+    #[test]
+    fn sharpness_sees_detail() {
+        let flat = GrayImage::from_pixel(64, 64, Luma([128]));
+        let checks = GrayImage::from_fn(64, 64, |x, y| if (x + y) % 2 == 0 { Luma([0]) } else { Luma([255]) });
+        let bbox = BoundingBox { x: 8.0, y: 8.0, width: 48.0, height: 48.0 };
+        assert_eq!(sharpness(&flat, &bbox), 0.0);
+        assert!(sharpness(&checks, &bbox) > 1000.0);
+        // Boxes off the image measure nothing
+        assert_eq!(sharpness(&checks, &BoundingBox { x: 70.0, ..bbox }), 0.0);
+    }
+
+    #[test]
+    fn parses_limits() {
+        let config = QualityConfig::parse("# dim room\nbrightness 25 230\nsharpness 8\nface_size 0.1 1\nyaw 30\ndisable pose\n").unwrap();
+        assert_eq!((config.min_brightness, config.max_brightness, config.min_sharpness), (25.0, 230.0, 8.0));
+        assert_eq!((config.min_face, config.max_face, config.max_yaw), (0.1, 1.0, 30.0));
+        assert_eq!(config.disabled, vec!["pose"]);
+        assert_eq!(QualityConfig::parse("").unwrap(), QualityConfig::default());
+        assert!(QualityConfig::parse("brightness 200 100").is_err());
+        assert!(QualityConfig::parse("face_size 0.1 2").is_err());
+        assert!(QualityConfig::parse("disable texture").is_err());
+        assert!(QualityConfig::parse("sharpness").is_err());
+    }
+}
@@ -1,4 +1,3 @@
-#![allow(dead_code)]
 use sodiumoxide::crypto::secretbox;
 use std::fs;
 use std::path::Path;
@@ -17,34 +16,48 @@ pub fn ensure_key() -> anyhow::Result<secretbox::Key> {
     }
 }
 
-pub fn save_secure(path: &str, data: &[u8]) -> anyhow::Result<()> {
-    let key = ensure_key()?;
+/// Encrypts `data` as nonce + secretbox.
+fn seal(key: &secretbox::Key, data: &[u8]) -> Vec<u8> {
     let nonce = secretbox::gen_nonce();
-    let cipher = secretbox::seal(data, &nonce, &key);
-    // store nonce + ciphertext
+    let cipher = secretbox::seal(data, &nonce, key);
     let mut out = nonce.0.to_vec();
     out.extend_from_slice(&cipher);
-    fs::write(path, &out).context("writing secure file")?;
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        if let Ok(metadata) = fs::metadata(path) {
-            let mut perm = metadata.permissions();
-            perm.set_mode(0o600);
-            fs::set_permissions(path, perm).ok();
-        }
+    out
+}
+
+fn open(key: &secretbox::Key, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if sealed.len() < secretbox::NONCEBYTES { return Err(anyhow::anyhow!("file too short")); }
+    let (nonce_bytes, cipher) = sealed.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce_bytes).unwrap();
+    secretbox::open(cipher, &nonce, key).map_err(|_| anyhow::anyhow!("decryption failed"))
+}
+
+/// Replaces `path` with `data`, mode 0600. Readers see the old or the new
+/// contents, never a partial write.
+fn write_private(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut tmp_name = path.file_name().context("no file name")?.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+    let res = (|| {
+        let mut f = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
     }
-    Ok(())
+    res.context("writing secure file")
+}
+
+/// Seals `data` with `key` into `path`.
+pub fn save_secure(key: &secretbox::Key, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    write_private(path, &seal(key, data))
 }
 
-pub fn load_secure(path: &str) -> anyhow::Result<Vec<u8>> {
-    let key = ensure_key()?;
+pub fn load_secure(key: &secretbox::Key, path: &Path) -> anyhow::Result<Vec<u8>> {
     let b = fs::read(path).context("reading secure file")?;
-    if b.len() < secretbox::NONCEBYTES { return Err(anyhow::anyhow!("file too short")); }
-    let (nonce_bytes, cipher) = b.split_at(secretbox::NONCEBYTES);
-    let mut nonce_arr = [0u8; secretbox::NONCEBYTES];
-    nonce_arr.copy_from_slice(nonce_bytes);
-    let nonce = secretbox::Nonce(nonce_arr);
-    let plain = secretbox::open(&cipher, &nonce, &key).map_err(|_| anyhow::anyhow!("decryption failed"))?;
-    Ok(plain)
+    open(key, &b)
 }
@@ -0,0 +1,324 @@
+// src/session.rs
+//! Keep-warm camera sessions.
+//!
+//! Opening a camera and getting it streaming costs hundreds of milliseconds,
+//! and a preview is usually followed by a verification on the same device. The
+//! worker checks a source out of the pool for each request and checks it back
+//! in afterwards. A checked-in source stays open for the warm period, then it
+//! is closed so the privacy LED goes off.
+use log::info;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use super::frame_source::FrameSource;
+
+/// Default keep-warm period after a camera was last used.
+pub const DEFAULT_WARM_PERIOD: Duration = Duration::from_secs(5);
+
+/// Time source for expiry, replaceable in tests.
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+struct Session {
+    path: String,
+    source: Box<dyn FrameSource>,
+    opened_at: Instant,
+    last_used: Instant,
+    uses: u64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SessionInfo {
+    pub camera: String,
+    pub open_ms: u64,
+    pub idle_ms: u64,
+    pub uses: u64,
+}
+
+/// Reported as `sessions` by the `status` method.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SessionStatus {
+    pub warm_period_ms: u64,
+    /// Sessions that are open and idle right now.
+    pub open: Vec<SessionInfo>,
+    pub opened: u64,
+    pub reused: u64,
+    pub closed: u64,
+}
+
+pub struct SessionPool {
+    clock: Box<dyn Clock>,
+    warm_period: Duration,
+    sessions: Vec<Session>,
+    /// Open time and use count of sessions that are checked out.
+    checked_out: Vec<(String, Instant, u64)>,
+    opened: u64,
+    reused: u64,
+    closed: u64,
+}
+
+impl SessionPool {
+    pub fn new(warm_period: Duration) -> Self {
+        Self::with_clock(warm_period, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(warm_period: Duration, clock: Box<dyn Clock>) -> Self {
+        Self {
+            clock,
+            warm_period,
+            sessions: Vec::new(),
+            checked_out: Vec::new(),
+            opened: 0,
+            reused: 0,
+            closed: 0,
+        }
+    }
+
+    /// Hands out the warm source for `path`, or opens one with `open`.
+    /// Frames that queued up while the session sat idle are discarded first.
+    pub fn checkout(
+        &mut self,
+        path: &str,
+        open: impl FnOnce() -> anyhow::Result<Box<dyn FrameSource>>,
+    ) -> anyhow::Result<Box<dyn FrameSource>> {
+        self.expire();
+        if let Some(pos) = self.sessions.iter().position(|s| s.path == path) {
+            let mut session = self.sessions.remove(pos);
+            match session.source.flush() {
+                Ok(()) => {
+                    self.reused += 1;
+                    self.checked_out.push((session.path, session.opened_at, session.uses));
+                    return Ok(session.source);
+                }
+                Err(e) => {
+                    // A device that went bad while idle is reopened below
+                    info!("Dropping camera session {}: {:#}", path, e);
+                    self.closed += 1;
+                }
+            }
+        }
+        let source = open()?;
+        info!("Camera session opened: {}", path);
+        self.opened += 1;
+        self.checked_out.push((path.to_string(), self.clock.now(), 0));
+        Ok(source)
+    }
+
+    /// Returns a source after a successful request. Sources from failed requests
+    /// should just be dropped, which closes the device.
+    pub fn checkin(&mut self, path: &str, source: Box<dyn FrameSource>) {
+        let now = self.clock.now();
+        let (opened_at, uses) = match self.checked_out.iter().position(|(p, _, _)| p == path) {
+            Some(pos) => {
+                let (_, opened_at, uses) = self.checked_out.remove(pos);
+                (opened_at, uses)
+            }
+            None => (now, 0),
+        };
+        self.sessions.push(Session { path: path.to_string(), source, opened_at, last_used: now, uses: uses + 1 });
+        self.expire();
+    }
+
+    /// Forgets a checked-out session whose source was dropped after an error.
+    pub fn discard(&mut self, path: &str) {
+        if let Some(pos) = self.checked_out.iter().position(|(p, _, _)| p == path) {
+            self.checked_out.remove(pos);
+            info!("Camera session closed after error: {}", path);
+            self.closed += 1;
+        }
+    }
+
+    /// Closes sessions idle for at least the warm period.
+    pub fn expire(&mut self) {
+        let now = self.clock.now();
+        let warm = self.warm_period;
+        self.close_where(|s| now.saturating_duration_since(s.last_used) >= warm);
+    }
+
+    /// Closes the session for a device that went away.
+    pub fn close(&mut self, path: &str) {
+        self.close_where(|s| s.path == path);
+    }
+
+    pub fn close_all(&mut self) {
+        self.close_where(|_| true);
+    }
+
+    fn close_where(&mut self, mut pred: impl FnMut(&Session) -> bool) {
+        let mut i = 0;
+        while i < self.sessions.len() {
+            if pred(&self.sessions[i]) {
+                let session = self.sessions.remove(i);
+                info!("Camera session closed: {} ({} uses)", session.path, session.uses);
+                self.closed += 1;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// How long until the next idle session expires.
+    pub fn time_to_expiry(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.sessions
+            .iter()
+            .map(|s| (s.last_used + self.warm_period).saturating_duration_since(now))
+            .min()
+    }
+
+    pub fn status(&self) -> SessionStatus {
+        let now = self.clock.now();
+        SessionStatus {
+            warm_period_ms: self.warm_period.as_millis() as u64,
+            open: self.sessions
+                .iter()
+                .map(|s| SessionInfo {
+                    camera: s.path.clone(),
+                    open_ms: now.saturating_duration_since(s.opened_at).as_millis() as u64,
+                    idle_ms: now.saturating_duration_since(s.last_used).as_millis() as u64,
+                    uses: s.uses,
+                })
+                .collect(),
+            opened: self.opened,
+            reused: self.reused,
+            closed: self.closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Frame, PixelFormat};
+    use crate::frame_source::SyntheticSource;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct FakeClock(Arc<Mutex<Instant>>);
+
+    impl FakeClock {
+        fn advance(&self, d: Duration) {
+            *self.0.lock().unwrap() += d;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Counts flushes so we can tell a warm reuse from a fresh open.
+    struct Tracked {
+        inner: SyntheticSource,
+        flushes: Arc<Mutex<u32>>,
+        fail_flush: bool,
+    }
+
+    impl FrameSource for Tracked {
+        fn backend(&self) -> &'static str {
+            "tracked"
+        }
+
+        fn next_frame(&mut self) -> anyhow::Result<Frame<'_>> {
+            self.inner.next_frame()
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            *self.flushes.lock().unwrap() += 1;
+            if self.fail_flush {
+                anyhow::bail!("device gone");
+            }
+            Ok(())
+        }
+    }
+
+    fn pool(warm_ms: u64) -> (SessionPool, FakeClock) {
+        let clock = FakeClock(Arc::new(Mutex::new(Instant::now())));
+        (SessionPool::with_clock(Duration::from_millis(warm_ms), Box::new(clock.clone())), clock)
+    }
+
+    fn opener(opens: &Arc<Mutex<u32>>, flushes: &Arc<Mutex<u32>>, fail_flush: bool) -> impl FnOnce() -> anyhow::Result<Box<dyn FrameSource>> {
+        let (opens, flushes) = (opens.clone(), flushes.clone());
+        move || {
+            *opens.lock().unwrap() += 1;
+            let inner = SyntheticSource::new(64, 48, PixelFormat::Yuyv)?;
+            Ok(Box::new(Tracked { inner, flushes, fail_flush }))
+        }
+    }
+
+    #[test]
+    fn session_stays_warm_then_closes() {
+        let (mut pool, clock) = pool(3000);
+        let (opens, flushes) = (Arc::default(), Arc::default());
+
+        let mut src = pool.checkout("/dev/video0", opener(&opens, &flushes, false)).unwrap();
+        src.next_frame().unwrap();
+        pool.checkin("/dev/video0", src);
+        assert_eq!(pool.time_to_expiry(), Some(Duration::from_millis(3000)));
+
+        // A verify shortly after the preview reuses the open device
+        clock.advance(Duration::from_millis(1200));
+        let src = pool.checkout("/dev/video0", opener(&opens, &flushes, false)).unwrap();
+        pool.checkin("/dev/video0", src);
+        assert_eq!((*opens.lock().unwrap(), *flushes.lock().unwrap()), (1, 1));
+
+        let status = pool.status();
+        assert_eq!(status.open.len(), 1);
+        assert_eq!(status.open[0].uses, 2);
+        assert_eq!(status.open[0].open_ms, 1200);
+        assert_eq!((status.opened, status.reused, status.closed), (1, 1, 0));
+
+        // The warm period restarts from the last use
+        clock.advance(Duration::from_millis(2999));
+        pool.expire();
+        assert_eq!(pool.status().open.len(), 1);
+        clock.advance(Duration::from_millis(1));
+        pool.expire();
+        assert!(pool.status().open.is_empty());
+        assert_eq!(pool.status().closed, 1);
+        assert_eq!(pool.time_to_expiry(), None);
+
+        pool.checkout("/dev/video0", opener(&opens, &flushes, false)).unwrap();
+        assert_eq!(*opens.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn zero_warm_period_closes_on_checkin() {
+        let (mut pool, _clock) = pool(0);
+        let (opens, flushes) = (Arc::default(), Arc::default());
+        let src = pool.checkout("synthetic", opener(&opens, &flushes, false)).unwrap();
+        pool.checkin("synthetic", src);
+        assert!(pool.status().open.is_empty());
+        assert_eq!(pool.status().closed, 1);
+    }
+
+    #[test]
+    fn broken_or_removed_sessions_are_not_reused() {
+        let (mut pool, _clock) = pool(3000);
+        let (opens, flushes) = (Arc::default(), Arc::default());
+
+        let src = pool.checkout("/dev/video0", opener(&opens, &flushes, true)).unwrap();
+        pool.checkin("/dev/video0", src);
+        // Flush fails, so the pool opens a fresh source
+        let src = pool.checkout("/dev/video0", opener(&opens, &flushes, false)).unwrap();
+        assert_eq!(*opens.lock().unwrap(), 2);
+        drop(src);
+        pool.discard("/dev/video0");
+        assert_eq!(pool.status().closed, 2);
+
+        let src = pool.checkout("/dev/video2", opener(&opens, &flushes, false)).unwrap();
+        pool.checkin("/dev/video2", src);
+        pool.close("/dev/video2");
+        assert!(pool.status().open.is_empty());
+    }
+}
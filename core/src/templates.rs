@@ -0,0 +1,524 @@
+// src/templates.rs
+//! Enrolled face templates, one sealed record per template.
+//!
+//! Records live under a directory owned by the daemon, not in the user's
+//! home: a user must not be able to swap in someone else's face. Each user
+//! has a `<uid>/` directory (0700) of `<id>.tpl` records (0600), each sealed
+//! with the installation key through `secure_store` and written atomically.
+//!
+//! Every template records the model and embedding schema that produced it.
+//! After a model upgrade, old templates no longer match anything; those that
+//! kept the face crop they came from (an opt-in at enrollment) can be embedded
+//! again with `migrate`, the rest need the user to enroll again.
+use anyhow::{bail, Context};
+use base64::Engine;
+use image::codecs::png::PngEncoder;
+use image::{ColorType, ImageEncoder, RgbImage};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::secretbox;
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::detector::{BoundingBox, Detection, Point};
+use super::embedder::{Embedder, EMBEDDING_SCHEMA};
+use super::secure_store;
+
+/// Where templates are kept unless `OLA_TEMPLATE_DIR` says otherwise.
+pub const DEFAULT_DIR: &str = "/var/lib/ola/templates";
+
+/// Longest label a template may have, in characters.
+const MAX_LABEL_CHARS: usize = 64;
+
+/// Side of a retained crop relative to the detected box: enough for `align`
+/// to cut a rotated, loosened crop from it.
+const RETAINED_MARGIN: f32 = 2.0;
+
+pub fn template_dir() -> PathBuf {
+    std::env::var_os("OLA_TEMPLATE_DIR").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_DIR))
+}
+
+/// One enrolled embedding and where it came from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Template {
+    pub id: String,
+    pub label: String,
+    pub created_at_ms: u64,
+    /// Embedding model that produced it.
+    pub model: String,
+    /// `Embedder::model_id` and `EMBEDDING_SCHEMA` at the time. Records from
+    /// before these were tagged have neither and match nothing.
+    #[serde(default)]
+    pub model_id: String,
+    #[serde(default)]
+    pub schema: u32,
+    /// Stable ID of the camera it was captured with.
+    pub camera: Option<String>,
+    pub embedding: Vec<f32>,
+    /// The face it was embedded from, kept if the user opted in.
+    #[serde(default)]
+    pub crop: Option<FaceCrop>,
+    #[serde(default)]
+    pub origin: Origin,
+}
+
+/// How a template came to be.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    /// Captured in an enrollment session. Only the user or root removes it.
+    #[default]
+    Enrolled,
+    /// Added after a high-confidence verification; see `adapt`.
+    Adaptive,
+}
+
+/// A face cut from a frame with room around it, so another model can embed
+/// it again.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FaceCrop {
+    /// PNG, base64.
+    pub image: String,
+    /// The face, in crop coordinates.
+    pub face: Detection,
+}
+
+impl FaceCrop {
+    pub fn encode(image: &RgbImage, face: Detection) -> anyhow::Result<Self> {
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png).write_image(image.as_raw(), image.width(), image.height(), ColorType::Rgb8)?;
+        Ok(Self { image: base64::engine::general_purpose::STANDARD.encode(&png), face })
+    }
+
+    pub fn decode(&self) -> anyhow::Result<RgbImage> {
+        let png = base64::engine::general_purpose::STANDARD.decode(&self.image).context("decoding face crop")?;
+        Ok(image::load_from_memory(&png).context("decoding face crop")?.to_rgb8())
+    }
+}
+
+/// Cuts `face` out of `image`, centred, `RETAINED_MARGIN` times its size and
+/// clipped to the frame, with the face moved into the crop's coordinates.
+pub fn cut_face(image: &RgbImage, face: &Detection) -> (RgbImage, Detection) {
+    let b = face.bbox;
+    let side = b.width.max(b.height) * RETAINED_MARGIN;
+    let clamp = |v: f32, max: u32| (v.max(0.0) as u32).min(max);
+    let (x0, y0) = (clamp(b.x + b.width / 2.0 - side / 2.0, image.width()), clamp(b.y + b.height / 2.0 - side / 2.0, image.height()));
+    let (x1, y1) = (clamp(b.x + b.width / 2.0 + side / 2.0, image.width()), clamp(b.y + b.height / 2.0 + side / 2.0, image.height()));
+    let crop = image::imageops::crop_imm(image, x0, y0, x1 - x0, y1 - y0).to_image();
+    let (dx, dy) = (x0 as f32, y0 as f32);
+    let moved = Detection {
+        bbox: BoundingBox { x: b.x - dx, y: b.y - dy, ..b },
+        landmarks: face.landmarks.iter().map(|p| Point { x: p.x - dx, y: p.y - dy }).collect(),
+        confidence: face.confidence,
+    };
+    (crop, moved)
+}
+
+/// What `list_templates` shows: everything but the embedding.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TemplateInfo {
+    pub id: String,
+    pub label: String,
+    pub created_at_ms: u64,
+    pub model: String,
+    pub model_id: String,
+    pub schema: u32,
+    pub camera: Option<String>,
+    /// Whether the face crop was kept for migration.
+    pub crop: bool,
+    pub origin: Origin,
+}
+
+impl Template {
+    /// A new template with a fresh random ID, created now, embedded by
+    /// `model` (`model_id`) under the current embedding schema.
+    pub fn new(label: &str, model: &str, model_id: &str, camera: Option<String>, embedding: Vec<f32>) -> Self {
+        let created_at_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        Self {
+            id: new_id(),
+            label: label.to_string(),
+            created_at_ms,
+            model: model.to_string(),
+            model_id: model_id.to_string(),
+            schema: EMBEDDING_SCHEMA,
+            camera,
+            embedding,
+            crop: None,
+            origin: Origin::Enrolled,
+        }
+    }
+
+    /// Whether probes from the model `model_id` can be compared with it.
+    pub fn compatible_with(&self, model_id: &str) -> bool {
+        !self.model_id.is_empty() && self.model_id == model_id && self.schema == EMBEDDING_SCHEMA
+    }
+
+    pub fn info(&self) -> TemplateInfo {
+        TemplateInfo {
+            id: self.id.clone(),
+            label: self.label.clone(),
+            created_at_ms: self.created_at_ms,
+            model: self.model.clone(),
+            model_id: self.model_id.clone(),
+            schema: self.schema,
+            camera: self.camera.clone(),
+            crop: self.crop.is_some(),
+            origin: self.origin,
+        }
+    }
+}
+
+fn new_id() -> String {
+    // randombytes needs sodium initialised; a second init is a no-op
+    let _ = sodiumoxide::init();
+    sodiumoxide::randombytes::randombytes(6).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// IDs come from clients and become file names, so only ours are accepted.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Labels are shown to users; keep them to one short line.
+pub fn validate_label(label: &str) -> anyhow::Result<String> {
+    let label = label.trim();
+    if label.is_empty() || label.chars().count() > MAX_LABEL_CHARS || label.chars().any(char::is_control) {
+        bail!("invalid_label: labels are 1-{} printable characters", MAX_LABEL_CHARS);
+    }
+    Ok(label.to_string())
+}
+
+/// How many of a user's templates the current model cannot use.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Staleness {
+    pub uid: u32,
+    pub templates: usize,
+    pub stale: usize,
+    /// Stale templates with a face crop, which `migrate` can bring back.
+    pub migratable: usize,
+}
+
+/// Outcome of migrating one user's templates.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Migration {
+    pub uid: u32,
+    pub migrated: usize,
+    /// Still stale: no crop was kept, or it could not be embedded.
+    pub stale: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TemplateStore {
+    dir: PathBuf,
+    /// `None` means the installation key, read when needed.
+    key: Option<secretbox::Key>,
+}
+
+impl TemplateStore {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.to_path_buf(), key: None }
+    }
+
+    #[cfg(test)]
+    fn with_key(dir: &Path, key: secretbox::Key) -> Self {
+        Self { dir: dir.to_path_buf(), key: Some(key) }
+    }
+
+    fn key(&self) -> anyhow::Result<secretbox::Key> {
+        match &self.key {
+            Some(key) => Ok(key.clone()),
+            None => secure_store::ensure_key(),
+        }
+    }
+
+    fn user_dir(&self, uid: u32) -> PathBuf {
+        self.dir.join(uid.to_string())
+    }
+
+    fn record(&self, uid: u32, id: &str) -> anyhow::Result<PathBuf> {
+        if !valid_id(id) {
+            bail!("template_not_found: {}", id);
+        }
+        let path = self.user_dir(uid).join(format!("{}.tpl", id));
+        if !path.exists() {
+            bail!("template_not_found: {}", id);
+        }
+        Ok(path)
+    }
+
+    fn read(&self, key: &secretbox::Key, path: &Path) -> anyhow::Result<Template> {
+        let plain = secure_store::load_secure(key, path)?;
+        serde_json::from_slice(&plain).context("parsing template")
+    }
+
+    fn write(&self, key: &secretbox::Key, uid: u32, template: &Template) -> anyhow::Result<()> {
+        let path = self.user_dir(uid).join(format!("{}.tpl", template.id));
+        secure_store::save_secure(key, &path, &serde_json::to_vec(template)?)
+            .with_context(|| format!("saving template {} for uid {}", template.id, uid))
+    }
+
+    /// The templates enrolled for `uid`, oldest first. A record that cannot
+    /// be read is skipped with a warning rather than locking the user out of
+    /// the others.
+    pub fn load(&self, uid: u32) -> anyhow::Result<Vec<Template>> {
+        let dir = self.user_dir(uid);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+        };
+        let key = self.key()?;
+        let mut templates = Vec::new();
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.extension().is_none_or(|e| e != "tpl") {
+                continue;
+            }
+            match self.read(&key, &path) {
+                Ok(t) => templates.push(t),
+                Err(e) => warn!("Skipping template {}: {:#}", path.display(), e),
+            }
+        }
+        templates.sort_by(|a, b| (a.created_at_ms, &a.id).cmp(&(b.created_at_ms, &b.id)));
+        Ok(templates)
+    }
+
+    /// Users with a template directory, in uid order. Anything else under the
+    /// store is ignored.
+    pub fn users(&self) -> anyhow::Result<Vec<u32>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.dir.display())),
+        };
+        let mut users: Vec<u32> = entries
+            .flatten()
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .filter_map(|e| e.file_name().to_str()?.parse().ok())
+            .collect();
+        users.sort_unstable();
+        Ok(users)
+    }
+
+    pub fn list(&self, uid: u32) -> anyhow::Result<Vec<TemplateInfo>> {
+        Ok(self.load(uid)?.iter().map(Template::info).collect())
+    }
+
+    /// Stores `templates` for `uid`, all or nothing.
+    pub fn add(&self, uid: u32, templates: &[Template]) -> anyhow::Result<()> {
+        let key = self.key()?;
+        let dir = self.user_dir(uid);
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("creating {}", dir.display()))?;
+        for (i, t) in templates.iter().enumerate() {
+            if let Err(e) = self.write(&key, uid, t) {
+                for written in &templates[..i] {
+                    let _ = fs::remove_file(dir.join(format!("{}.tpl", written.id)));
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rename(&self, uid: u32, id: &str, label: &str) -> anyhow::Result<TemplateInfo> {
+        let label = validate_label(label)?;
+        let path = self.record(uid, id)?;
+        let key = self.key()?;
+        let mut template = self.read(&key, &path)?;
+        template.label = label;
+        self.write(&key, uid, &template)?;
+        Ok(template.info())
+    }
+
+    pub fn delete(&self, uid: u32, id: &str) -> anyhow::Result<()> {
+        let path = self.record(uid, id)?;
+        fs::remove_file(&path).with_context(|| format!("deleting {}", path.display()))
+    }
+
+    pub fn staleness(&self, uid: u32, model_id: &str) -> anyhow::Result<Staleness> {
+        let templates = self.load(uid)?;
+        let stale: Vec<_> = templates.iter().filter(|t| !t.compatible_with(model_id)).collect();
+        Ok(Staleness {
+            uid,
+            templates: templates.len(),
+            stale: stale.len(),
+            migratable: stale.iter().filter(|t| t.crop.is_some()).count(),
+        })
+    }
+
+    /// Embeds `uid`'s stale templates that kept their face crop again with
+    /// `embedder`, rewriting each record in place with the same ID, label and
+    /// creation time. Returns how many were migrated; a crop that cannot be
+    /// embedded stays stale, with a warning.
+    pub fn migrate(&self, uid: u32, embedder: &mut dyn Embedder) -> anyhow::Result<usize> {
+        let key = self.key()?;
+        let mut migrated = 0;
+        for mut template in self.load(uid)? {
+            if template.compatible_with(embedder.model_id()) {
+                continue;
+            }
+            let Some(crop) = &template.crop else { continue };
+            let embedding = match crop.decode().and_then(|image| embedder.embed(&image, &crop.face)) {
+                Ok(embedding) => embedding,
+                Err(e) => {
+                    warn!("Cannot migrate template {} of uid {}: {:#}", template.id, uid, e);
+                    continue;
+                }
+            };
+            info!("Migrating template {} of uid {} from {:?} to {}", template.id, uid, template.model_id, embedder.model_id());
+            template.embedding = embedding;
+            template.model = embedder.name().to_string();
+            template.model_id = embedder.model_id().to_string();
+            template.schema = EMBEDDING_SCHEMA;
+            self.write(&key, uid, &template)?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("ola-templates-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn store(dir: &TempDir) -> TemplateStore {
+        sodiumoxide::init().unwrap();
+        TemplateStore::with_key(&dir.0, secretbox::gen_key())
+    }
+
+    #[test]
+    fn records_round_trip_sealed_and_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("round-trip");
+        let store = store(&dir);
+        assert_eq!(store.load(1000).unwrap(), vec![]);
+
+        let mut first = Template::new("glasses", "arcface", "arcface-1", Some("usb-cam".into()), vec![0.6, 0.8]);
+        first.created_at_ms -= 1;
+        let second = Template::new("glasses", "arcface", "arcface-1", None, vec![1.0, 0.0]);
+        store.add(1000, std::slice::from_ref(&first)).unwrap();
+        store.add(1000, std::slice::from_ref(&second)).unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(store.load(1000).unwrap(), vec![first.clone(), second.clone()]);
+        assert_eq!(store.list(1000).unwrap(), vec![first.info(), second.info()]);
+        assert_eq!(store.load(1001).unwrap(), vec![]);
+        fs::create_dir_all(dir.0.join("not-a-uid")).unwrap();
+        assert_eq!(store.users().unwrap(), vec![1000]);
+
+        let user = dir.0.join("1000");
+        assert_eq!(fs::metadata(&user).unwrap().permissions().mode() & 0o777, 0o700);
+        let record = user.join(format!("{}.tpl", first.id));
+        assert_eq!(fs::metadata(&record).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!String::from_utf8_lossy(&fs::read(&record).unwrap()).contains("glasses"));
+        // Nothing left over from the atomic writes
+        assert_eq!(fs::read_dir(&user).unwrap().count(), 2);
+
+        // Another key reads nothing; unreadable records are skipped, not fatal
+        let other = TemplateStore::with_key(&dir.0, secretbox::gen_key());
+        assert_eq!(other.load(1000).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn rename_and_delete_stay_within_the_user() {
+        let dir = TempDir::new("manage");
+        let store = store(&dir);
+        let t = Template::new("face", "arcface", "arcface-1", None, vec![1.0, 0.0]);
+        store.add(1000, std::slice::from_ref(&t)).unwrap();
+
+        let info = store.rename(1000, &t.id, "  with glasses ").unwrap();
+        assert_eq!(info.label, "with glasses");
+        assert_eq!(store.load(1000).unwrap()[0].embedding, t.embedding);
+        assert!(store.rename(1000, &t.id, "").is_err());
+        assert!(store.rename(1000, &t.id, "line\nbreak").is_err());
+
+        // Someone else's ID, made-up IDs and paths are all just not found
+        for (uid, id) in [(1001, t.id.as_str()), (1000, "abcdef"), (1000, "../1001/x"), (1000, "")] {
+            let err = store.delete(uid, id).unwrap_err().to_string();
+            assert!(err.starts_with("template_not_found"), "{}", err);
+        }
+
+        store.delete(1000, &t.id).unwrap();
+        assert_eq!(store.list(1000).unwrap(), vec![]);
+        assert!(store.delete(1000, &t.id).is_err());
+    }
+
+    /// Embeds a crop as the mean colour of its face box.
+    struct MeanColour;
+
+    impl Embedder for MeanColour {
+        fn name(&self) -> &str {
+            "mean"
+        }
+
+        fn model_id(&self) -> &str {
+            "mean-2"
+        }
+
+        fn embed(&mut self, image: &RgbImage, face: &Detection) -> anyhow::Result<Vec<f32>> {
+            let b = face.bbox;
+            let p = image.get_pixel((b.x + b.width / 2.0) as u32, (b.y + b.height / 2.0) as u32);
+            let mut v: Vec<f32> = p.0.iter().map(|&c| c as f32).collect();
+            crate::embedder::normalize(&mut v)?;
+            Ok(v)
+        }
+    }
+
+    #[test]
+    fn stale_templates_migrate_from_their_crops() {
+        use image::Rgb;
+        let dir = TempDir::new("migrate");
+        let store = store(&dir);
+
+        // A red face in the corner of a grey frame
+        let frame = RgbImage::from_fn(200, 100, |x, y| if x < 40 && y < 40 { Rgb([200, 0, 0]) } else { Rgb([90, 90, 90]) });
+        let face = Detection {
+            bbox: BoundingBox { x: 10.0, y: 10.0, width: 20.0, height: 20.0 },
+            landmarks: vec![Point { x: 15.0, y: 15.0 }],
+            confidence: 0.9,
+        };
+        let (image, moved) = cut_face(&frame, &face);
+        assert_eq!(image.dimensions(), (40, 40));
+        assert_eq!((moved.bbox.x, moved.landmarks[0].x), (10.0, 15.0));
+
+        let mut kept = Template::new("face", "arcface", "arcface-1", None, vec![0.0, 1.0, 0.0]);
+        kept.crop = Some(FaceCrop::encode(&image, moved).unwrap());
+        // From before templates were tagged
+        let legacy = Template { model_id: String::new(), schema: 0, ..Template::new("old", "arcface", "", None, vec![0.0, 0.0, 1.0]) };
+        let current = Template::new("new", "mean", "mean-2", None, vec![0.0, 0.0, 1.0]);
+        store.add(1000, &[kept.clone(), legacy, current]).unwrap();
+        assert!(!kept.compatible_with("mean-2") && kept.compatible_with("arcface-1"));
+
+        let before = store.staleness(1000, "mean-2").unwrap();
+        assert_eq!((before.templates, before.stale, before.migratable), (3, 2, 1));
+        assert_eq!(store.migrate(1000, &mut MeanColour).unwrap(), 1);
+        assert_eq!(store.migrate(1000, &mut MeanColour).unwrap(), 0);
+        let after = store.staleness(1000, "mean-2").unwrap();
+        assert_eq!((after.stale, after.migratable), (1, 0));
+
+        let migrated = store.load(1000).unwrap().into_iter().find(|t| t.id == kept.id).unwrap();
+        assert_eq!((migrated.label.as_str(), migrated.created_at_ms), ("face", kept.created_at_ms));
+        assert!(migrated.compatible_with("mean-2"));
+        assert_eq!(migrated.embedding, vec![1.0, 0.0, 0.0]);
+    }
+}
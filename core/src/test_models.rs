@@ -0,0 +1,112 @@
+// src/test_models.rs
+//! Builders for the tiny ONNX graphs that stand in for real models in tests,
+//! and the embeddings a real model would have produced.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tract_onnx::pb;
+use tract_onnx::prelude::*;
+
+/// Precomputed 128-D unit embeddings, four captures each of five people.
+/// Same-person pairs score 0.6-0.75 cosine, different people within ±0.25,
+/// which is where a working ArcFace model puts them.
+#[derive(serde::Deserialize)]
+pub struct Embeddings {
+    pub dimension: usize,
+    pub identities: BTreeMap<String, Vec<Vec<f32>>>,
+}
+
+/// Loads `tests/fixtures/embeddings/embeddings.json`.
+pub fn embeddings() -> Embeddings {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/embeddings/embeddings.json");
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+pub fn node(op: &str, inputs: &[&str], output: &str, attributes: Vec<pb::AttributeProto>) -> pb::NodeProto {
+    pb::NodeProto {
+        input: inputs.iter().map(|s| s.to_string()).collect(),
+        output: vec![output.to_string()],
+        name: output.to_string(),
+        op_type: op.to_string(),
+        attribute: attributes,
+        ..Default::default()
+    }
+}
+
+pub fn ints(name: &str, values: &[i64]) -> pb::AttributeProto {
+    pb::AttributeProto {
+        name: name.to_string(),
+        r#type: pb::attribute_proto::AttributeType::Ints as i32,
+        ints: values.to_vec(),
+        ..Default::default()
+    }
+}
+
+pub fn int(name: &str, value: i64) -> pb::AttributeProto {
+    pb::AttributeProto {
+        name: name.to_string(),
+        r#type: pb::attribute_proto::AttributeType::Int as i32,
+        i: value,
+        ..Default::default()
+    }
+}
+
+pub fn floats(name: &str, dims: &[i64], values: Vec<f32>) -> pb::TensorProto {
+    pb::TensorProto {
+        name: name.to_string(),
+        dims: dims.to_vec(),
+        data_type: pb::tensor_proto::DataType::Float as i32,
+        float_data: values,
+        ..Default::default()
+    }
+}
+
+pub fn shape(name: &str, dims: &[i64]) -> pb::TensorProto {
+    pb::TensorProto {
+        name: name.to_string(),
+        dims: vec![dims.len() as i64],
+        data_type: pb::tensor_proto::DataType::Int64 as i32,
+        int64_data: dims.to_vec(),
+        ..Default::default()
+    }
+}
+
+pub fn value_info(name: &str, dims: &[i64]) -> pb::ValueInfoProto {
+    use pb::tensor_shape_proto::{dimension, Dimension};
+    pb::ValueInfoProto {
+        name: name.to_string(),
+        r#type: Some(pb::TypeProto {
+            value: Some(pb::type_proto::Value::TensorType(pb::type_proto::Tensor {
+                elem_type: pb::tensor_proto::DataType::Float as i32,
+                shape: Some(pb::TensorShapeProto {
+                    dim: dims
+                        .iter()
+                        .map(|d| Dimension { value: Some(dimension::Value::DimValue(*d)), ..Default::default() })
+                        .collect(),
+                }),
+            })),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Wraps a graph in an opset-13 model and loads it with tract.
+pub fn model(graph: pb::GraphProto) -> InferenceModel {
+    let proto = pb::ModelProto {
+        ir_version: 8,
+        opset_import: vec![pb::OperatorSetIdProto { domain: String::new(), version: 13 }],
+        graph: Some(graph),
+        ..Default::default()
+    };
+    tract_onnx::onnx().model_for_proto_model(&proto).unwrap()
+}
+
+/// A pass-through network with the given input shape.
+pub fn relu_model(dims: &[i64]) -> InferenceModel {
+    model(pb::GraphProto {
+        node: vec![node("Relu", &["input"], "out", vec![])],
+        input: vec![value_info("input", dims)],
+        output: vec![value_info("out", dims)],
+        ..Default::default()
+    })
+}
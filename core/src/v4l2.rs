@@ -0,0 +1,515 @@
+// src/v4l2.rs
+//! Minimal hand-written V4L2 bindings.
+//!
+//! We only bind the handful of ioctls the daemon actually needs so the core keeps
+//! building without libv4l or bindgen. Struct layouts mirror `linux/videodev2.h`.
+#![allow(non_camel_case_types)]
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+// Capability flags (v4l2_capability.capabilities / device_caps)
+pub const V4L2_CAP_VIDEO_CAPTURE: u32 = 0x0000_0001;
+pub const V4L2_CAP_VIDEO_CAPTURE_MPLANE: u32 = 0x0000_1000;
+pub const V4L2_CAP_META_CAPTURE: u32 = 0x0080_0000;
+pub const V4L2_CAP_READWRITE: u32 = 0x0100_0000;
+pub const V4L2_CAP_STREAMING: u32 = 0x0400_0000;
+pub const V4L2_CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+
+// Buffer types
+pub const V4L2_BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+
+pub const V4L2_FIELD_NONE: u32 = 1;
+
+// Frame size / interval enumeration types
+pub const V4L2_FRMSIZE_TYPE_DISCRETE: u32 = 1;
+pub const V4L2_FRMIVAL_TYPE_DISCRETE: u32 = 1;
+
+// Streaming I/O
+pub const V4L2_MEMORY_MMAP: u32 = 1;
+pub const V4L2_BUF_FLAG_TIMESTAMP_MASK: u32 = 0x0000_e000;
+pub const V4L2_BUF_FLAG_TIMESTAMP_MONOTONIC: u32 = 0x0000_2000;
+
+// User controls (VIDIOC_QUERYCTRL / G_CTRL / S_CTRL)
+pub const V4L2_CID_BRIGHTNESS: u32 = 0x0098_0900;
+pub const V4L2_CID_AUTOGAIN: u32 = 0x0098_0912;
+pub const V4L2_CID_GAIN: u32 = 0x0098_0913;
+pub const V4L2_CID_EXPOSURE_AUTO: u32 = 0x009a_0901;
+pub const V4L2_CID_EXPOSURE_ABSOLUTE: u32 = 0x009a_0902;
+pub const V4L2_EXPOSURE_MANUAL: i32 = 1;
+pub const V4L2_CTRL_FLAG_DISABLED: u32 = 0x0001;
+pub const V4L2_CTRL_FLAG_READ_ONLY: u32 = 0x0004;
+
+// UVC extension unit query codes (linux/usb/video.h)
+pub const UVC_SET_CUR: u8 = 0x01;
+pub const UVC_GET_CUR: u8 = 0x81;
+pub const UVC_GET_LEN: u8 = 0x85;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_capability {
+    pub driver: [u8; 16],
+    pub card: [u8; 32],
+    pub bus_info: [u8; 32],
+    pub version: u32,
+    pub capabilities: u32,
+    pub device_caps: u32,
+    pub reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_fmtdesc {
+    pub index: u32,
+    pub type_: u32,
+    pub flags: u32,
+    pub description: [u8; 32],
+    pub pixelformat: u32,
+    pub mbus_code: u32,
+    pub reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct v4l2_frmsize_discrete {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct v4l2_frmsize_stepwise {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub step_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub step_height: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_frmsizeenum {
+    pub index: u32,
+    pub pixel_format: u32,
+    pub type_: u32,
+    // union { discrete; stepwise; } — stepwise is the larger member
+    pub stepwise: v4l2_frmsize_stepwise,
+    pub reserved: [u32; 2],
+}
+
+impl v4l2_frmsizeenum {
+    pub fn discrete(&self) -> v4l2_frmsize_discrete {
+        // The discrete member overlays the first two words of the stepwise one
+        v4l2_frmsize_discrete { width: self.stepwise.min_width, height: self.stepwise.max_width }
+    }
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct v4l2_fract {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct v4l2_frmival_stepwise {
+    pub min: v4l2_fract,
+    pub max: v4l2_fract,
+    pub step: v4l2_fract,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_frmivalenum {
+    pub index: u32,
+    pub pixel_format: u32,
+    pub width: u32,
+    pub height: u32,
+    pub type_: u32,
+    // union { discrete; stepwise; } — discrete aliases stepwise.min
+    pub stepwise: v4l2_frmival_stepwise,
+    pub reserved: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct v4l2_pix_format {
+    pub width: u32,
+    pub height: u32,
+    pub pixelformat: u32,
+    pub field: u32,
+    pub bytesperline: u32,
+    pub sizeimage: u32,
+    pub colorspace: u32,
+    pub priv_: u32,
+    pub flags: u32,
+    pub ycbcr_enc: u32,
+    pub quantization: u32,
+    pub xfer_func: u32,
+}
+
+#[repr(C)]
+pub union v4l2_format_union {
+    pub pix: v4l2_pix_format,
+    pub raw_data: [u8; 200],
+    // The kernel union contains pointers (v4l2_window), forcing 8-byte alignment
+    _align: [u64; 25],
+}
+
+#[repr(C)]
+pub struct v4l2_format {
+    pub type_: u32,
+    pub fmt: v4l2_format_union,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_requestbuffers {
+    pub count: u32,
+    pub type_: u32,
+    pub memory: u32,
+    pub capabilities: u32,
+    pub flags: u8,
+    pub reserved: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct v4l2_timecode {
+    pub type_: u32,
+    pub flags: u32,
+    pub frames: u8,
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub userbits: [u8; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union v4l2_buffer_m {
+    pub offset: u32,
+    pub userptr: libc::c_ulong,
+    pub fd: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct v4l2_buffer {
+    pub index: u32,
+    pub type_: u32,
+    pub bytesused: u32,
+    pub flags: u32,
+    pub field: u32,
+    pub timestamp: libc::timeval,
+    pub timecode: v4l2_timecode,
+    pub sequence: u32,
+    pub memory: u32,
+    pub m: v4l2_buffer_m,
+    pub length: u32,
+    pub reserved2: u32,
+    pub request_fd: i32,
+}
+
+impl v4l2_buffer {
+    pub fn new(index: u32) -> Self {
+        Self {
+            index,
+            type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+            bytesused: 0,
+            flags: 0,
+            field: 0,
+            timestamp: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            timecode: v4l2_timecode::default(),
+            sequence: 0,
+            memory: V4L2_MEMORY_MMAP,
+            m: v4l2_buffer_m { userptr: 0 },
+            length: 0,
+            reserved2: 0,
+            request_fd: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_exportbuffer {
+    pub type_: u32,
+    pub index: u32,
+    pub plane: u32,
+    pub flags: u32,
+    pub fd: i32,
+    pub reserved: [u32; 11],
+}
+
+/// `struct uvc_xu_control_query` from `linux/uvcvideo.h`.
+#[repr(C)]
+pub struct uvc_xu_control_query {
+    pub unit: u8,
+    pub selector: u8,
+    pub query: u8,
+    pub size: u16,
+    pub data: *mut u8,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_queryctrl {
+    pub id: u32,
+    pub type_: u32,
+    pub name: [u8; 32],
+    pub minimum: i32,
+    pub maximum: i32,
+    pub step: i32,
+    pub default_value: i32,
+    pub flags: u32,
+    pub reserved: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_control {
+    pub id: u32,
+    pub value: i32,
+}
+
+nix::ioctl_read!(vidioc_querycap, b'V', 0, v4l2_capability);
+nix::ioctl_readwrite!(vidioc_enum_fmt, b'V', 2, v4l2_fmtdesc);
+nix::ioctl_readwrite!(vidioc_s_fmt, b'V', 5, v4l2_format);
+nix::ioctl_readwrite!(vidioc_reqbufs, b'V', 8, v4l2_requestbuffers);
+nix::ioctl_readwrite!(vidioc_querybuf, b'V', 9, v4l2_buffer);
+nix::ioctl_readwrite!(vidioc_qbuf, b'V', 15, v4l2_buffer);
+nix::ioctl_readwrite!(vidioc_expbuf, b'V', 16, v4l2_exportbuffer);
+nix::ioctl_readwrite!(vidioc_dqbuf, b'V', 17, v4l2_buffer);
+nix::ioctl_write_ptr!(vidioc_streamon, b'V', 18, libc::c_int);
+nix::ioctl_write_ptr!(vidioc_streamoff, b'V', 19, libc::c_int);
+nix::ioctl_readwrite!(vidioc_g_ctrl, b'V', 27, v4l2_control);
+nix::ioctl_readwrite!(vidioc_s_ctrl, b'V', 28, v4l2_control);
+nix::ioctl_readwrite!(vidioc_queryctrl, b'V', 36, v4l2_queryctrl);
+nix::ioctl_readwrite!(vidioc_enum_framesizes, b'V', 74, v4l2_frmsizeenum);
+nix::ioctl_readwrite!(vidioc_enum_frameintervals, b'V', 75, v4l2_frmivalenum);
+nix::ioctl_readwrite!(uvcioc_ctrl_query, b'u', 0x21, uvc_xu_control_query);
+
+/// Builds a V4L2 fourcc code from its four ASCII characters.
+pub const fn fourcc(code: &[u8; 4]) -> u32 {
+    (code[0] as u32) | ((code[1] as u32) << 8) | ((code[2] as u32) << 16) | ((code[3] as u32) << 24)
+}
+
+/// Renders a fourcc code as a printable string ("YUYV", "MJPG", ...).
+pub fn fourcc_to_string(code: u32) -> String {
+    code.to_le_bytes()
+        .iter()
+        .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// Converts a NUL-padded C string field into a Rust string.
+pub fn cstr_field(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+/// Opens a device node non-blocking so probing never stalls on a busy driver.
+pub fn open_device(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+        .open(path)
+}
+
+pub fn query_cap(file: &File) -> nix::Result<v4l2_capability> {
+    let mut cap = v4l2_capability::default();
+    unsafe { vidioc_querycap(file.as_raw_fd(), &mut cap)? };
+    Ok(cap)
+}
+
+/// Negotiates a capture format; the driver may adjust size and stride.
+pub fn set_format(file: &File, width: u32, height: u32, pixelformat: u32) -> nix::Result<v4l2_pix_format> {
+    let mut fmt = v4l2_format {
+        type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+        fmt: v4l2_format_union { raw_data: [0; 200] },
+    };
+    fmt.fmt.pix = v4l2_pix_format {
+        width,
+        height,
+        pixelformat,
+        field: V4L2_FIELD_NONE,
+        ..Default::default()
+    };
+    unsafe {
+        vidioc_s_fmt(file.as_raw_fd(), &mut fmt)?;
+        Ok(fmt.fmt.pix)
+    }
+}
+
+pub fn enum_formats(file: &File, buf_type: u32) -> Vec<v4l2_fmtdesc> {
+    let mut out = Vec::new();
+    for index in 0.. {
+        let mut desc = v4l2_fmtdesc { index, type_: buf_type, ..Default::default() };
+        if unsafe { vidioc_enum_fmt(file.as_raw_fd(), &mut desc) }.is_err() {
+            break;
+        }
+        out.push(desc);
+    }
+    out
+}
+
+pub fn enum_frame_sizes(file: &File, pixel_format: u32) -> Vec<v4l2_frmsizeenum> {
+    let mut out = Vec::new();
+    for index in 0.. {
+        let mut size = v4l2_frmsizeenum { index, pixel_format, ..Default::default() };
+        if unsafe { vidioc_enum_framesizes(file.as_raw_fd(), &mut size) }.is_err() {
+            break;
+        }
+        let stepwise = size.type_ != V4L2_FRMSIZE_TYPE_DISCRETE;
+        out.push(size);
+        // Stepwise/continuous ranges are reported as a single entry
+        if stepwise {
+            break;
+        }
+    }
+    out
+}
+
+pub fn enum_frame_intervals(file: &File, pixel_format: u32, width: u32, height: u32) -> Vec<v4l2_frmivalenum> {
+    let mut out = Vec::new();
+    for index in 0.. {
+        let mut ival = v4l2_frmivalenum { index, pixel_format, width, height, ..Default::default() };
+        if unsafe { vidioc_enum_frameintervals(file.as_raw_fd(), &mut ival) }.is_err() {
+            break;
+        }
+        let stepwise = ival.type_ != V4L2_FRMIVAL_TYPE_DISCRETE;
+        out.push(ival);
+        if stepwise {
+            break;
+        }
+    }
+    out
+}
+
+/// Asks for `count` MMAP buffers; the driver may grant fewer. Zero frees them.
+pub fn request_buffers(file: &File, count: u32) -> nix::Result<u32> {
+    let mut req = v4l2_requestbuffers {
+        count,
+        type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+        memory: V4L2_MEMORY_MMAP,
+        ..Default::default()
+    };
+    unsafe { vidioc_reqbufs(file.as_raw_fd(), &mut req)? };
+    Ok(req.count)
+}
+
+pub fn query_buffer(file: &File, index: u32) -> nix::Result<v4l2_buffer> {
+    let mut buf = v4l2_buffer::new(index);
+    unsafe { vidioc_querybuf(file.as_raw_fd(), &mut buf)? };
+    Ok(buf)
+}
+
+pub fn queue_buffer(file: &File, index: u32) -> nix::Result<()> {
+    let mut buf = v4l2_buffer::new(index);
+    unsafe { vidioc_qbuf(file.as_raw_fd(), &mut buf)? };
+    Ok(())
+}
+
+/// Takes the oldest filled buffer off the queue. EAGAIN if none is ready.
+pub fn dequeue_buffer(file: &File) -> nix::Result<v4l2_buffer> {
+    let mut buf = v4l2_buffer::new(0);
+    unsafe { vidioc_dqbuf(file.as_raw_fd(), &mut buf)? };
+    Ok(buf)
+}
+
+/// Exports an MMAP buffer as a DMABUF file descriptor.
+pub fn export_buffer(file: &File, index: u32) -> nix::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+    let mut exp = v4l2_exportbuffer {
+        type_: V4L2_BUF_TYPE_VIDEO_CAPTURE,
+        index,
+        flags: (libc::O_RDONLY | libc::O_CLOEXEC) as u32,
+        ..Default::default()
+    };
+    unsafe {
+        vidioc_expbuf(file.as_raw_fd(), &mut exp)?;
+        Ok(std::os::fd::OwnedFd::from_raw_fd(exp.fd))
+    }
+}
+
+pub fn stream_on(file: &File) -> nix::Result<()> {
+    let type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE as libc::c_int;
+    unsafe { vidioc_streamon(file.as_raw_fd(), &type_)? };
+    Ok(())
+}
+
+pub fn stream_off(file: &File) -> nix::Result<()> {
+    let type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE as libc::c_int;
+    unsafe { vidioc_streamoff(file.as_raw_fd(), &type_)? };
+    Ok(())
+}
+
+/// Describes a control; EINVAL when the device does not have it.
+pub fn query_control(file: &File, id: u32) -> nix::Result<v4l2_queryctrl> {
+    let mut ctrl = v4l2_queryctrl { id, ..Default::default() };
+    unsafe { vidioc_queryctrl(file.as_raw_fd(), &mut ctrl)? };
+    Ok(ctrl)
+}
+
+pub fn get_control(file: &File, id: u32) -> nix::Result<i32> {
+    let mut ctrl = v4l2_control { id, value: 0 };
+    unsafe { vidioc_g_ctrl(file.as_raw_fd(), &mut ctrl)? };
+    Ok(ctrl.value)
+}
+
+pub fn set_control(file: &File, id: u32, value: i32) -> nix::Result<()> {
+    let mut ctrl = v4l2_control { id, value };
+    unsafe { vidioc_s_ctrl(file.as_raw_fd(), &mut ctrl)? };
+    Ok(())
+}
+
+/// Runs one UVC extension unit query. `data` is read for SET_CUR and filled
+/// in for the GET_* queries.
+pub fn uvc_xu_query(file: &File, unit: u8, selector: u8, query: u8, data: &mut [u8]) -> nix::Result<()> {
+    let mut q = uvc_xu_control_query {
+        unit,
+        selector,
+        query,
+        size: data.len() as u16,
+        data: data.as_mut_ptr(),
+    };
+    unsafe { uvcioc_ctrl_query(file.as_raw_fd(), &mut q)? };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn struct_layouts_match_kernel_abi() {
+        assert_eq!(std::mem::size_of::<v4l2_capability>(), 104);
+        assert_eq!(std::mem::size_of::<v4l2_fmtdesc>(), 64);
+        assert_eq!(std::mem::size_of::<v4l2_frmsizeenum>(), 44);
+        assert_eq!(std::mem::size_of::<v4l2_frmivalenum>(), 52);
+        assert_eq!(std::mem::size_of::<v4l2_format>(), 208);
+        assert_eq!(std::mem::size_of::<uvc_xu_control_query>(), 8 + std::mem::size_of::<usize>());
+        assert_eq!(std::mem::size_of::<v4l2_requestbuffers>(), 20);
+        assert_eq!(std::mem::size_of::<v4l2_exportbuffer>(), 64);
+        assert_eq!(std::mem::size_of::<v4l2_queryctrl>(), 68);
+        assert_eq!(std::mem::size_of::<v4l2_control>(), 8);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(std::mem::size_of::<v4l2_buffer>(), 88);
+    }
+
+    #[test]
+    fn fourcc_round_trip() {
+        let yuyv = fourcc(b"YUYV");
+        assert_eq!(yuyv, 0x5659_5559);
+        assert_eq!(fourcc_to_string(yuyv), "YUYV");
+        assert_eq!(fourcc_to_string(fourcc(b"Y8  ")), "Y8");
+    }
+}
@@ -0,0 +1,198 @@
+// src/v4l2_stream.rs
+//! V4L2 MMAP streaming capture.
+//!
+//! The driver fills a ring of kernel buffers that we map once at open time.
+//! `next_frame` hands out a borrowed view of the buffer it just dequeued and
+//! gives that buffer back to the driver on the following call, so frames reach
+//! the pipeline without a copy. Each buffer is also exported as a DMABUF fd
+//! where the driver allows it, for consumers that can import one directly.
+use anyhow::Context;
+use std::borrow::Cow;
+use std::fs::File;
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+use super::frame::{monotonic_now, Frame, PixelFormat};
+use super::frame_source::{negotiate_format, node_caps, FrameSource};
+use super::v4l2;
+
+/// Buffers in the ring. Enough that the driver never starves while we
+/// hold one, without pinning much memory.
+const STREAM_BUFFERS: u32 = 4;
+
+/// How long we wait for the driver to fill a buffer.
+const DEQUEUE_TIMEOUT_MS: i32 = 2000;
+
+struct MappedBuffer {
+    ptr: *mut u8,
+    len: usize,
+    dmabuf: Option<OwnedFd>,
+}
+
+// The mapping is only touched through &self/&mut self of the owning source
+unsafe impl Send for MappedBuffer {}
+
+impl MappedBuffer {
+    fn map(file: &File, index: u32) -> anyhow::Result<Self> {
+        let info = v4l2::query_buffer(file, index).context("VIDIOC_QUERYBUF")?;
+        let len = info.length as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                info.m.offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error()).context("mmap of capture buffer");
+        }
+        Ok(Self { ptr: ptr as *mut u8, len, dmabuf: v4l2::export_buffer(file, index).ok() })
+    }
+
+    fn bytes(&self, used: usize) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, used.min(self.len)) }
+    }
+}
+
+impl Drop for MappedBuffer {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+pub struct StreamingSource {
+    file: File,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    stride: u32,
+    buffers: Vec<MappedBuffer>,
+    /// Buffer behind the last returned frame; re-queued on the next call.
+    held: Option<u32>,
+    sequence: u64,
+}
+
+impl StreamingSource {
+    pub fn open(path: &Path, width: u32, height: u32) -> anyhow::Result<Self> {
+        let file = v4l2::open_device(path).with_context(|| format!("opening {}", path.display()))?;
+        if node_caps(&file, path)? & v4l2::V4L2_CAP_STREAMING == 0 {
+            anyhow::bail!("{} does not support streaming I/O", path.display());
+        }
+        let (format, pix) = negotiate_format(&file, path, width, height)?;
+
+        let count = v4l2::request_buffers(&file, STREAM_BUFFERS).context("VIDIOC_REQBUFS")?;
+        if count < 2 {
+            anyhow::bail!("{} granted only {} capture buffer(s)", path.display(), count);
+        }
+        let mut source = Self {
+            file,
+            format,
+            width: pix.width,
+            height: pix.height,
+            stride: pix.bytesperline,
+            buffers: Vec::with_capacity(count as usize),
+            held: None,
+            sequence: 0,
+        };
+        // From here on Drop releases whatever has been set up
+        for index in 0..count {
+            source.buffers.push(MappedBuffer::map(&source.file, index)?);
+            v4l2::queue_buffer(&source.file, index).context("VIDIOC_QBUF")?;
+        }
+        v4l2::stream_on(&source.file).context("VIDIOC_STREAMON")?;
+        Ok(source)
+    }
+
+    /// DMABUF fd of the buffer behind the most recent frame, if the driver
+    /// supports exporting. Valid until the next `next_frame` call.
+    pub fn frame_dmabuf(&self) -> Option<BorrowedFd<'_>> {
+        let index = self.held?;
+        self.buffers[index as usize].dmabuf.as_ref().map(|fd| fd.as_fd())
+    }
+
+    fn wait_readable(&self) -> anyhow::Result<()> {
+        let mut pfd = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let ready = unsafe { libc::poll(&mut pfd, 1, DEQUEUE_TIMEOUT_MS) };
+        if ready < 0 {
+            return Err(std::io::Error::last_os_error()).context("polling camera");
+        }
+        if ready == 0 {
+            anyhow::bail!("camera did not deliver a frame within {} ms", DEQUEUE_TIMEOUT_MS);
+        }
+        Ok(())
+    }
+}
+
+impl FrameSource for StreamingSource {
+    fn backend(&self) -> &'static str {
+        if self.buffers.iter().all(|b| b.dmabuf.is_some()) {
+            "v4l2-mmap+dmabuf"
+        } else {
+            "v4l2-mmap"
+        }
+    }
+
+    fn next_frame(&mut self) -> anyhow::Result<Frame<'_>> {
+        if let Some(index) = self.held.take() {
+            v4l2::queue_buffer(&self.file, index).context("VIDIOC_QBUF")?;
+        }
+
+        let buf = loop {
+            self.wait_readable()?;
+            match v4l2::dequeue_buffer(&self.file) {
+                Ok(buf) => break buf,
+                // Woken without a filled buffer; wait again
+                Err(nix::errno::Errno::EAGAIN) => continue,
+                Err(e) => return Err(e).context("VIDIOC_DQBUF"),
+            }
+        };
+        self.held = Some(buf.index);
+        self.sequence += 1;
+
+        let timestamp = if buf.flags & v4l2::V4L2_BUF_FLAG_TIMESTAMP_MASK == v4l2::V4L2_BUF_FLAG_TIMESTAMP_MONOTONIC {
+            Duration::new(buf.timestamp.tv_sec as u64, buf.timestamp.tv_usec as u32 * 1000)
+        } else {
+            monotonic_now()
+        };
+        let mapped = self.buffers.get(buf.index as usize).context("driver returned an unknown buffer")?;
+        Ok(Frame {
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+            sequence: self.sequence,
+            timestamp,
+            data: Cow::Borrowed(mapped.bytes(buf.bytesused as usize)),
+        })
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(index) = self.held.take() {
+            v4l2::queue_buffer(&self.file, index).context("VIDIOC_QBUF")?;
+        }
+        // The device is non-blocking, so DQBUF fails with EAGAIN once the
+        // stale buffers are gone. Bounded in case the camera keeps up with us.
+        for _ in 0..self.buffers.len() * 2 {
+            match v4l2::dequeue_buffer(&self.file) {
+                Ok(buf) => v4l2::queue_buffer(&self.file, buf.index).context("VIDIOC_QBUF")?,
+                Err(nix::errno::Errno::EAGAIN) => break,
+                Err(e) => return Err(e).context("VIDIOC_DQBUF"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StreamingSource {
+    fn drop(&mut self) {
+        let _ = v4l2::stream_off(&self.file);
+        // Buffers must be unmapped before the driver will free them
+        self.buffers.clear();
+        let _ = v4l2::request_buffers(&self.file, 0);
+    }
+}
@@ -32,6 +32,23 @@ def send(method, params=None):
     finally:
         s.close()
 
+def send_all(calls):
+    """Sends several requests over one connection, for per-connection state."""
+    s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    s.settimeout(TIMEOUT)
+    s.connect(SOCKET)
+    f = s.makefile("rwb")
+    try:
+        out = []
+        for method, params in calls:
+            f.write((json.dumps({"id": 1, "method": method, "params": params or {}}) + "\n").encode("utf-8"))
+            f.flush()
+            out.append(json.loads(f.readline().decode("utf-8")))
+        return out
+    finally:
+        f.close()
+        s.close()
+
 def assert_ok(resp, name):
     if isinstance(resp, dict) and resp.get("error"):
         print(f"[FAIL] {name}: error field => {resp}")
@@ -49,13 +66,174 @@ if __name__ == "__main__":
     r = send("list_cameras")
     assert_ok(r, "list_cameras")
 
+    camera_id = r["result"][0]["id"]
+
     r = send("capture_thumbnail", {"index": 0})
     assert_ok(r, "capture_thumbnail")
 
+    r = send("capture_thumbnail", {"camera": camera_id})
+    assert_ok(r, "capture_thumbnail (by id)")
+
+    r = send("capture_thumbnail", {"index": 0, "format": "jpeg", "max_width": 160, "max_height": 120, "quality": 60})
+    assert_ok(r, "capture_thumbnail (jpeg)")
+    if r["result"]["mime_type"] != "image/jpeg" or r["result"]["width"] > 160:
+        print(f"[FAIL] capture_thumbnail (jpeg): unexpected result {r['result']}")
+        sys.exit(2)
+
     r = send("verify_once")
     assert_ok(r, "verify_once")
 
+    r = send("verify_once", {"camera": camera_id})
+    assert_ok(r, "verify_once (by id)")
+    if "faces" not in r["result"]:
+        print(f"[FAIL] verify_once: no face detection results {r['result']}")
+        sys.exit(2)
+    if "score" not in r["result"] or "template_id" not in r["result"]:
+        print(f"[FAIL] verify_once: no match results {r['result']}")
+        sys.exit(2)
+    if "liveness" not in r["result"]:
+        print(f"[FAIL] verify_once: no liveness results {r['result']}")
+        sys.exit(2)
+    # No models in the test environment
+    if r["result"]["schema_version"] != 1 or r["result"]["code"] != "MODEL_UNAVAILABLE" or "diagnostics" not in r["result"]:
+        print(f"[FAIL] verify_once: no reason code {r['result']}")
+        sys.exit(2)
+    # Nothing is learned from a failed verification
+    if r["result"]["diagnostics"].get("template_update", "missing") is not None:
+        print(f"[FAIL] verify_once: unexpected template update {r['result']['diagnostics']}")
+        sys.exit(2)
+
+    if r["result"]["frames"] < 1 or "frame_scores" not in r["result"] or "elapsed_ms" not in r["result"]:
+        print(f"[FAIL] verify_once: frame counts not reported {r['result']}")
+        sys.exit(2)
+
+    r = send("verify_once", {"camera": camera_id, "challenge": "turn_left"})
+    assert_ok(r, "verify_once (challenge)")
+
+    r = send("verify_once", {"camera": camera_id, "timeout_ms": 60000})
+    assert_ok(r, "verify_once (timeout_ms)")
+
+    # No faces are matched without models, so the response is still the first line
+    r = send("verify_once", {"camera": camera_id, "guidance": True})
+    assert_ok(r, "verify_once (guidance)")
+    if r["result"]["code"] != "MODEL_UNAVAILABLE" or r["result"]["diagnostics"].get("low_quality_frames") != 0 \
+            or "exposure" not in r["result"]["diagnostics"]:
+        print(f"[FAIL] verify_once: unexpected quality result {r['result']}")
+        sys.exit(2)
+
+    r = send("verify_once", {"camera": camera_id, "challenge": "nod"})
+    if "error" not in r or "challenge" not in (r["error"] or ""):
+        print(f"[FAIL] verify_once: unknown challenge accepted {r}")
+        sys.exit(2)
+    print("[PASS] verify_once rejects unknown challenge")
+
+    r = send("identify", {"camera": camera_id})
+    assert_ok(r, "identify")
+    # No models in the test environment, but the result is complete
+    if r["result"]["code"] != "MODEL_UNAVAILABLE" or r["result"]["uid"] is not None or "candidates" not in r["result"]:
+        print(f"[FAIL] identify: unexpected result {r['result']}")
+        sys.exit(2)
+
+    r = send("identify", {"camera": camera_id, "uids": "everyone"})
+    if "error" not in r or "uids" not in (r["error"] or ""):
+        print(f"[FAIL] identify: invalid uids accepted {r}")
+        sys.exit(2)
+    print("[PASS] identify rejects invalid uids")
+
+    # Admin only, and needs the embedder to know which templates are stale
+    for method in ("list_stale_users", "migrate_templates"):
+        r = send(method)
+        error = r.get("error") or ""
+        if "embedder_unavailable" not in error and "permission_denied" not in error:
+            print(f"[FAIL] {method}: {r}")
+            sys.exit(2)
+        print(f"[PASS] {method} refused without an embedder: {error}")
+
+    # No manifest and no model files: reloading swaps in an empty model set
+    r = send("reload_models")
+    error = r.get("error") or ""
+    if "permission_denied" in error:
+        print(f"[PASS] reload_models refused for non-root: {error}")
+    elif r.get("error") is None and isinstance(r["result"].get("models"), list):
+        print(f"[PASS] reload_models: {r['result']['models']}")
+    else:
+        print(f"[FAIL] reload_models: {r}")
+        sys.exit(2)
+
+    # A free camera is captured right away even when the client offers to wait
+    r = send("capture_thumbnail", {"camera": camera_id, "wait_ms": 2000})
+    assert_ok(r, "capture_thumbnail (wait_ms)")
+
     r = send("status")
     assert_ok(r, "status")
+    if r["result"]["capture"]["frames"] < 1 or r["result"]["capture"]["time_to_first_frame_ms"] is None:
+        print(f"[FAIL] status: capture latency not reported {r['result']}")
+        sys.exit(2)
+    # Back-to-back requests share one warm camera session
+    if r["result"]["sessions"]["reused"] < 1:
+        print(f"[FAIL] status: camera session was not kept warm {r['result']['sessions']}")
+        sys.exit(2)
+    if "detector" not in r["result"] or "embedder" not in r["result"] or "liveness" not in r["result"]:
+        print(f"[FAIL] status: face models not reported {r['result']}")
+        sys.exit(2)
+    if not isinstance(r["result"].get("models"), list):
+        print(f"[FAIL] status: model versions not reported {r['result']}")
+        sys.exit(2)
+    if r["result"]["queued"] != 0:
+        print(f"[FAIL] status: requests left waiting for the camera {r['result']}")
+        sys.exit(2)
+
+    # Enrollment is per connection and stores nothing until a complete commit
+    begin, frame, commit, cancel, after = send_all([
+        ("enroll_begin", {"camera": camera_id, "retain_crops": True}),
+        ("enroll_frame", None),
+        ("enroll_commit", None),
+        ("enroll_cancel", None),
+        ("enroll_commit", None),
+    ])
+    assert_ok(begin, "enroll_begin")
+    if not begin["result"]["needed"] or not begin["result"]["guidance"]:
+        print(f"[FAIL] enroll_begin: no progress target {begin}")
+        sys.exit(2)
+    # Without face models (synthetic mode) the frame is refused, not crashed on
+    if frame.get("error") and "unavailable" not in frame["error"]:
+        print(f"[FAIL] enroll_frame: {frame}")
+        sys.exit(2)
+    print(f"[OK] enroll_frame: {frame}")
+    if "enrollment_incomplete" not in (commit.get("error") or ""):
+        print(f"[FAIL] enroll_commit: incomplete session was committed {commit}")
+        sys.exit(2)
+    assert_ok(cancel, "enroll_cancel")
+    if cancel["result"]["cancelled"] is not True or not after.get("error"):
+        print(f"[FAIL] enroll_cancel: session survived {cancel} {after}")
+        sys.exit(2)
+    r = send("enroll_commit")
+    if not r.get("error"):
+        print(f"[FAIL] enroll_commit: session leaked across connections {r}")
+        sys.exit(2)
+    print("[OK] enrollment session lifecycle")
+
+    r = send("list_templates")
+    assert_ok(r, "list_templates")
+    if not isinstance(r["result"], list):
+        print(f"[FAIL] list_templates: not a list {r}")
+        sys.exit(2)
+    r = send("delete_template", {"id": "../../etc/passwd"})
+    if "template_not_found" not in (r.get("error") or ""):
+        print(f"[FAIL] delete_template: bogus id accepted {r}")
+        sys.exit(2)
+    r = send("rename_template", {"id": "0123456789ab", "label": "desk"})
+    if "template_not_found" not in (r.get("error") or ""):
+        print(f"[FAIL] rename_template: missing template renamed {r}")
+        sys.exit(2)
+    if os.getuid() != 0:
+        r = send("list_templates", {"uid": os.getuid() + 1})
+        if "permission_denied" not in (r.get("error") or ""):
+            print(f"[FAIL] list_templates: other user's templates visible {r}")
+            sys.exit(2)
+    print("[OK] template management")
+
+    r = send("subscribe")
+    assert_ok(r, "subscribe")
 
     print("All integration tests passed.")
@@ -104,6 +104,8 @@ Group=ola
 ExecStart=/usr/local/bin/ola-core
 Restart=on-failure
 RuntimeDirectory=ola
+StateDirectory=ola
+StateDirectoryMode=0700
 PermissionsStartOnly=yes
 Environment=RUST_LOG=info
 